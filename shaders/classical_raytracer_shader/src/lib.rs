@@ -1,8 +1,19 @@
 #![cfg_attr(
-    target_os = "spirv",
+    target_arch = "spirv",
     no_std,
     feature(register_attr),
     register_attr(spirv)
 )]
 
 pub mod vertex;
+pub mod scene_data;
+pub mod payload;
+pub mod random;
+pub mod ray_generation;
+pub mod miss;
+pub mod triangle;
+pub mod sphere;
+pub mod material;
+
+pub use vertex::Vertex;
+pub use scene_data::{InstanceData, MaterialData, RayGenerationConstants};
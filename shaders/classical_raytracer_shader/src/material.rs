@@ -0,0 +1,157 @@
+#[cfg(not(target_arch = "spirv"))]
+use spirv_std::macros::spirv;
+#[cfg(target_arch = "spirv")]
+use spirv_std::num_traits::Float;
+use spirv_std::arch::execute_callable;
+use spirv_std::glam::{Vec2, Vec3, Vec4, Vec4Swizzles};
+use spirv_std::image::SampledImage;
+use spirv_std::{Image, RuntimeArray};
+use crate::payload::{MaterialPayload, RayPayload};
+use crate::random;
+use crate::scene_data::{MaterialData, NO_TEXTURE};
+
+/// 登録できるマテリアルのcallableの数、SBTのindexはconstでしか渡せないのでここで決める
+pub const MATERIAL_CALLABLE_COUNT: u32 = 4;
+
+pub type Textures = RuntimeArray<SampledImage<Image!(2D, type = f32, sampled)>>;
+
+/// closest hitから呼ぶ、交差した点の情報を入れてからマテリアルのcallableで散乱させる
+pub fn shade(
+    payload: &mut RayPayload,
+    material_payload: &mut MaterialPayload,
+    materials: &[MaterialData],
+) {
+    material_payload.seed = payload.seed;
+    material_payload.scattered = 0;
+    material_payload.emitted = Vec3::ZERO;
+    material_payload.attenuation = Vec3::ZERO;
+
+    let callable_index = materials[material_payload.material as usize].callable_index;
+
+    unsafe {
+        match callable_index {
+            0 => execute_callable::<MaterialPayload, 0>(material_payload),
+            1 => execute_callable::<MaterialPayload, 1>(material_payload),
+            2 => execute_callable::<MaterialPayload, 2>(material_payload),
+            3 => execute_callable::<MaterialPayload, 3>(material_payload),
+            //ホストで範囲外のindexは弾いているので吸収されたものとして扱う
+            _ => {}
+        }
+    }
+
+    payload.seed = material_payload.seed;
+    payload.emitted = material_payload.emitted;
+    payload.attenuation = material_payload.attenuation;
+    payload.scattered = material_payload.scattered;
+    payload.origin = material_payload.position;
+    payload.direction = material_payload.direction;
+}
+
+/// albedoにテクスチャの色を掛ける
+pub fn albedo(material: &MaterialData, textures: &Textures, tex_coord: Vec2) -> Vec4 {
+    let albedo = Vec4::from(material.albedo_roughness).xyz().extend(1.0);
+
+    if material.albedo_texture == NO_TEXTURE {
+        return albedo;
+    }
+
+    //レイごとにテクスチャが違うのでmipmapは使わない
+    let texel: Vec4 = unsafe {
+        textures
+            .index(material.albedo_texture as usize)
+            .sample_by_lod(tex_coord, 0.0)
+    };
+
+    albedo * texel
+}
+
+fn scatter(payload: &mut MaterialPayload, attenuation: Vec3, direction: Vec3) {
+    payload.attenuation = attenuation;
+    payload.direction = direction.normalize();
+    payload.scattered = 1;
+}
+
+fn reflect(direction: Vec3, normal: Vec3) -> Vec3 {
+    direction - normal * 2.0 * direction.dot(normal)
+}
+
+fn refract(direction: Vec3, normal: Vec3, ratio: f32) -> Vec3 {
+    let cos_theta = (-direction).dot(normal).min(1.0);
+    let perpendicular = (direction + normal * cos_theta) * ratio;
+    let parallel = normal * -(1.0 - perpendicular.length_squared()).abs().sqrt();
+
+    perpendicular + parallel
+}
+
+//Schlickの近似
+fn reflectance(cosine: f32, ratio: f32) -> f32 {
+    let r0 = ((1.0 - ratio) / (1.0 + ratio)).powi(2);
+
+    r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
+}
+
+#[spirv(callable)]
+pub fn lambertian_callable(
+    #[spirv(incoming_callable_data)] payload: &mut MaterialPayload,
+    #[spirv(descriptor_set = 0, binding = 2, storage_buffer)] materials: &[MaterialData],
+    #[spirv(descriptor_set = 0, binding = 6)] textures: &Textures,
+) {
+    let material = &materials[payload.material as usize];
+    let albedo = albedo(material, textures, payload.tex_coord).xyz();
+
+    let direction = payload.normal + random::unit_vector(&mut payload.seed);
+
+    //法線と逆向きの乱数を引いた場合
+    let direction = if direction.length_squared() < 1e-8 { payload.normal } else { direction };
+
+    scatter(payload, albedo, direction);
+}
+
+#[spirv(callable)]
+pub fn metal_callable(
+    #[spirv(incoming_callable_data)] payload: &mut MaterialPayload,
+    #[spirv(descriptor_set = 0, binding = 2, storage_buffer)] materials: &[MaterialData],
+    #[spirv(descriptor_set = 0, binding = 6)] textures: &Textures,
+) {
+    let material = &materials[payload.material as usize];
+    let albedo = albedo(material, textures, payload.tex_coord).xyz();
+    let roughness = material.albedo_roughness[3];
+
+    let reflected = reflect(payload.direction, payload.normal) + random::in_unit_sphere(&mut payload.seed) * roughness;
+
+    //面の内側に向いた場合は吸収する
+    if reflected.dot(payload.normal) > 0.0 {
+        scatter(payload, albedo, reflected);
+    }
+}
+
+#[spirv(callable)]
+pub fn dielectric_callable(
+    #[spirv(incoming_callable_data)] payload: &mut MaterialPayload,
+    #[spirv(descriptor_set = 0, binding = 2, storage_buffer)] materials: &[MaterialData],
+) {
+    let ior = materials[payload.material as usize].emission_ior[3];
+    let ratio = if payload.front_face != 0 { 1.0 / ior } else { ior };
+
+    let cos_theta = (-payload.direction).dot(payload.normal).min(1.0);
+    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+
+    let direction = if ratio * sin_theta > 1.0 || reflectance(cos_theta, ratio) > random::next_f32(&mut payload.seed) {
+        reflect(payload.direction, payload.normal)
+    } else {
+        refract(payload.direction, payload.normal, ratio)
+    };
+
+    scatter(payload, Vec3::ONE, direction);
+}
+
+#[spirv(callable)]
+pub fn emissive_callable(
+    #[spirv(incoming_callable_data)] payload: &mut MaterialPayload,
+    #[spirv(descriptor_set = 0, binding = 2, storage_buffer)] materials: &[MaterialData],
+) {
+    //裏面は光らない
+    if payload.front_face != 0 {
+        payload.emitted = Vec4::from(materials[payload.material as usize].emission_ior).xyz();
+    }
+}
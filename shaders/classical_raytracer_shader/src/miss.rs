@@ -0,0 +1,20 @@
+#[cfg(not(target_arch = "spirv"))]
+use spirv_std::macros::spirv;
+use spirv_std::glam::Vec3;
+use crate::payload::RayPayload;
+
+/// host backendのskyと同じ空のグラデーション、光源を兼ねる
+pub fn sky(direction: Vec3) -> Vec3 {
+    let t = 0.5 * (direction.normalize().y + 1.0);
+
+    Vec3::ONE * (1.0 - t) + Vec3::new(0.5, 0.7, 1.0) * t
+}
+
+#[spirv(miss)]
+pub fn main_miss(
+    #[spirv(world_ray_direction)] world_ray_direction: Vec3,
+    #[spirv(incoming_ray_payload)] payload: &mut RayPayload,
+) {
+    payload.emitted = sky(world_ray_direction);
+    payload.scattered = 0;
+}
@@ -0,0 +1,33 @@
+use spirv_std::glam::{Vec2, Vec3};
+
+/// raygenとhit/missの間でやり取りするもの
+/// hitした場合は次に飛ばすレイをclosest hitが書き込む
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct RayPayload {
+    pub emitted: Vec3,
+    pub seed: u32,
+    pub attenuation: Vec3,
+    /// 0の場合はmissしたか吸収されたのでそこで打ち切る
+    pub scattered: u32,
+    pub origin: Vec3,
+    pub direction: Vec3,
+}
+
+/// closest hitとマテリアルのcallableの間でやり取りするもの
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct MaterialPayload {
+    pub position: Vec3,
+    pub material: u32,
+    /// 常にレイの来た側を向く
+    pub normal: Vec3,
+    pub front_face: u32,
+    /// 入ってきたレイの向き、callableが散乱した向きで上書きする
+    pub direction: Vec3,
+    pub seed: u32,
+    pub tex_coord: Vec2,
+    pub scattered: u32,
+    pub emitted: Vec3,
+    pub attenuation: Vec3,
+}
@@ -0,0 +1,37 @@
+use spirv_std::glam::Vec3;
+
+/// PCG、ピクセルとサンプルごとに状態を作る
+pub fn next_u32(state: &mut u32) -> u32 {
+    *state = state.wrapping_mul(747796405).wrapping_add(2891336453);
+
+    let word = ((*state >> ((*state >> 28) + 4)) ^ *state).wrapping_mul(277803737);
+
+    (word >> 22) ^ word
+}
+
+/// 0以上1未満
+pub fn next_f32(state: &mut u32) -> f32 {
+    (next_u32(state) >> 8) as f32 / (1u32 << 24) as f32
+}
+
+/// 隣のピクセルと乱数の列が似ないように一度混ぜる
+pub fn seed(seed: u32, x: u32, y: u32, sample: u32) -> u32 {
+    let mut state = seed ^ x.wrapping_mul(0x8da6_b343) ^ y.wrapping_mul(0xd816_3841) ^ sample.wrapping_mul(0xcb1a_b31f);
+    next_u32(&mut state);
+
+    state
+}
+
+pub fn in_unit_sphere(state: &mut u32) -> Vec3 {
+    loop {
+        let p = Vec3::new(next_f32(state), next_f32(state), next_f32(state)) * 2.0 - Vec3::ONE;
+
+        if p.length_squared() < 1.0 {
+            return p;
+        }
+    }
+}
+
+pub fn unit_vector(state: &mut u32) -> Vec3 {
+    in_unit_sphere(state).normalize_or_zero()
+}
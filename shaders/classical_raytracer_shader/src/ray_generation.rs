@@ -0,0 +1,94 @@
+#[cfg(not(target_arch = "spirv"))]
+use spirv_std::macros::spirv;
+use spirv_std::glam::{UVec2, UVec3, Vec2, Vec3, Vec4, Vec4Swizzles};
+use spirv_std::ray_tracing::{AccelerationStructure, RayFlags};
+use spirv_std::Image;
+use crate::payload::RayPayload;
+use crate::random;
+use crate::scene_data::RayGenerationConstants;
+
+//交差した面から出るレイが同じ面に当たらないようにする
+const RAY_T_MIN: f32 = 1e-4;
+const RAY_T_MAX: f32 = 1e30;
+
+/// ピクセルごとにsamples回レイを飛ばし、描画先の平均に足し込む
+#[spirv(ray_generation)]
+pub fn main_ray_generation(
+    #[spirv(launch_id)] launch_id: UVec3,
+    #[spirv(launch_size)] launch_size: UVec3,
+    #[spirv(ray_payload)] payload: &mut RayPayload,
+    #[spirv(push_constant)] constants: &RayGenerationConstants,
+    #[spirv(descriptor_set = 0, binding = 0)] top_level_acceleration_structure: &AccelerationStructure,
+    #[spirv(descriptor_set = 0, binding = 1)] image: &Image!(2D, format = rgba32f, sampled = false),
+) {
+    let origin = Vec4::from(constants.origin);
+    let forward = Vec4::from(constants.forward).xyz();
+    let right = Vec4::from(constants.right).xyz();
+    let up = Vec4::from(constants.up).xyz();
+    let half_height = origin.w;
+    let aspect = launch_size.x as f32 / launch_size.y as f32;
+
+    let mut sum = Vec3::ZERO;
+
+    for sample in 0..constants.samples {
+        payload.seed = random::seed(constants.seed, launch_id.x, launch_id.y, constants.sample_index + sample);
+
+        //画像は上の行から並ぶのでyを反転する
+        let jitter = Vec2::new(random::next_f32(&mut payload.seed), random::next_f32(&mut payload.seed));
+        let ndc = Vec2::new(
+            (launch_id.x as f32 + jitter.x) / launch_size.x as f32 * 2.0 - 1.0,
+            1.0 - (launch_id.y as f32 + jitter.y) / launch_size.y as f32 * 2.0,
+        );
+
+        let mut ray_origin = origin.xyz();
+        let mut ray_direction = (forward + right * ndc.x * half_height * aspect + up * ndc.y * half_height).normalize();
+        let mut radiance = Vec3::ZERO;
+        let mut throughput = Vec3::ONE;
+
+        for _ in 0..constants.max_depth {
+            unsafe {
+                top_level_acceleration_structure.trace_ray(
+                    RayFlags::OPAQUE,
+                    0xff,
+                    0,
+                    0,
+                    0,
+                    ray_origin,
+                    RAY_T_MIN,
+                    ray_direction,
+                    RAY_T_MAX,
+                    payload,
+                );
+            }
+
+            radiance += throughput * payload.emitted;
+
+            if payload.scattered == 0 {
+                break;
+            }
+
+            throughput *= payload.attenuation;
+            ray_origin = payload.origin;
+            ray_direction = payload.direction;
+        }
+
+        sum += radiance;
+    }
+
+    let coordinate = UVec2::new(launch_id.x, launch_id.y);
+    let total = constants.sample_index + constants.samples;
+
+    //前のdispatchまでの平均と合わせる
+    let previous = if constants.sample_index == 0 {
+        Vec3::ZERO
+    } else {
+        let texel: Vec4 = image.read(coordinate);
+        texel.xyz() * constants.sample_index as f32
+    };
+
+    let color = (previous + sum) / total as f32;
+
+    unsafe {
+        image.write(coordinate, color.extend(1.0));
+    }
+}
@@ -0,0 +1,54 @@
+//ホストと共有するbufferとpush constantのレイアウト
+//ホスト側でもそのまま使うのでglamの型ではなく配列で持つ
+
+/// テクスチャを使わないことを表すindex
+pub const NO_TEXTURE: u32 = u32::MAX;
+
+/// material bufferに入れるデータ、std430に合わせてvec4単位で並べる
+/// closest hitはcallable_indexでexecute_callableを呼ぶ
+#[derive(Copy, Clone, Default)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug, PartialEq))]
+#[repr(C)]
+pub struct MaterialData {
+    pub callable_index: u32,
+    /// テクスチャ配列の中のindex、ない場合はNO_TEXTURE
+    pub albedo_texture: u32,
+    pub _padding: [u32; 2],
+    /// xyzがalbedo, wがroughness
+    pub albedo_roughness: [f32; 4],
+    /// xyzがemission, wがior
+    pub emission_ior: [f32; 4],
+}
+
+/// instance bufferに入れるデータ、instance_custom_indexで引く
+/// meshは全て一つのvertex bufferとindex bufferに詰めているので先頭の位置を持つ
+#[derive(Copy, Clone, Default)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug, PartialEq))]
+#[repr(C)]
+pub struct InstanceData {
+    pub first_vertex: u32,
+    pub first_index: u32,
+    pub material: u32,
+    pub _padding: u32,
+    /// 法線をワールド座標に変換する行列の列、wは未使用
+    pub normal_matrix: [[f32; 4]; 3],
+}
+
+/// raygenに渡すpush constant、カメラの基底はホストで計算しておく
+#[derive(Copy, Clone, Default)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug, PartialEq))]
+#[repr(C)]
+pub struct RayGenerationConstants {
+    /// xyzがカメラの位置、wがtan(fov_y / 2)
+    pub origin: [f32; 4],
+    pub forward: [f32; 4],
+    pub right: [f32; 4],
+    pub up: [f32; 4],
+    /// すでに描画先に蓄積されているサンプル数、0の場合は前の内容を捨てる
+    pub sample_index: u32,
+    /// このdispatchでピクセルごとに追加するサンプル数
+    pub samples: u32,
+    pub seed: u32,
+    /// 反射と屈折を追う最大の回数
+    pub max_depth: u32,
+}
@@ -0,0 +1,75 @@
+#[cfg(not(target_arch = "spirv"))]
+use spirv_std::macros::spirv;
+#[cfg(target_arch = "spirv")]
+use spirv_std::num_traits::Float;
+use spirv_std::arch::report_intersection;
+use spirv_std::glam::{Vec2, Vec3};
+use crate::material::shade;
+use crate::payload::{MaterialPayload, RayPayload};
+use crate::scene_data::{InstanceData, MaterialData};
+use crate::triangle::normal_matrix;
+
+//hit kindはユーザーが決めて良いので表と裏で分ける
+const HIT_KIND_FRONT: u32 = 0;
+const HIT_KIND_BACK: u32 = 1;
+
+/// AABBの-1..1に収まる、原点が中心の半径1の球
+#[spirv(intersection)]
+pub fn sphere_intersection(
+    #[spirv(object_ray_origin)] object_ray_origin: Vec3,
+    #[spirv(object_ray_direction)] object_ray_direction: Vec3,
+    #[spirv(ray_tmin)] ray_t_min: f32,
+    #[spirv(ray_tmax)] ray_t_max: f32,
+) {
+    let a = object_ray_direction.length_squared();
+    let half_b = object_ray_origin.dot(object_ray_direction);
+    let c = object_ray_origin.length_squared() - 1.0;
+    let discriminant = half_b * half_b - a * c;
+
+    if discriminant < 0.0 {
+        return;
+    }
+
+    let sqrt_discriminant = discriminant.sqrt();
+    let near = (-half_b - sqrt_discriminant) / a;
+    let far = (-half_b + sqrt_discriminant) / a;
+
+    unsafe {
+        if near > ray_t_min && near < ray_t_max {
+            report_intersection(near, HIT_KIND_FRONT);
+        } else if far > ray_t_min && far < ray_t_max {
+            report_intersection(far, HIT_KIND_BACK);
+        }
+    }
+}
+
+#[spirv(closest_hit)]
+pub fn sphere_closest_hit(
+    #[spirv(incoming_ray_payload)] payload: &mut RayPayload,
+    #[spirv(callable_data)] material_payload: &mut MaterialPayload,
+    #[spirv(instance_custom_index)] instance_index: u32,
+    #[spirv(hit_kind)] hit_kind: u32,
+    #[spirv(object_ray_origin)] object_ray_origin: Vec3,
+    #[spirv(object_ray_direction)] object_ray_direction: Vec3,
+    #[spirv(world_ray_origin)] world_ray_origin: Vec3,
+    #[spirv(world_ray_direction)] world_ray_direction: Vec3,
+    #[spirv(ray_tmax)] hit_t: f32,
+    #[spirv(descriptor_set = 0, binding = 2, storage_buffer)] materials: &[MaterialData],
+    #[spirv(descriptor_set = 0, binding = 5, storage_buffer)] instances: &[InstanceData],
+) {
+    let instance = &instances[instance_index as usize];
+
+    //単位球なので交差した点がそのまま法線になる
+    let object_normal = object_ray_origin + object_ray_direction * hit_t;
+    let normal = (normal_matrix(instance) * object_normal).normalize();
+    let front_face = hit_kind == HIT_KIND_FRONT;
+
+    material_payload.position = world_ray_origin + world_ray_direction * hit_t;
+    material_payload.material = instance.material;
+    material_payload.normal = if front_face { normal } else { -normal };
+    material_payload.front_face = front_face as u32;
+    material_payload.direction = world_ray_direction;
+    material_payload.tex_coord = Vec2::ZERO;
+
+    shade(payload, material_payload, materials);
+}
@@ -0,0 +1,103 @@
+#[cfg(not(target_arch = "spirv"))]
+use spirv_std::macros::spirv;
+use spirv_std::arch::ignore_intersection;
+use spirv_std::glam::{Mat3, Vec2, Vec3, Vec4, Vec4Swizzles};
+use crate::material::{albedo, shade, Textures};
+use crate::payload::{MaterialPayload, RayPayload};
+use crate::scene_data::{InstanceData, MaterialData};
+use crate::vertex::Vertex;
+
+//アルファがこれより小さい場所は当たらなかったことにする
+const ALPHA_CUTOFF: f32 = 0.5;
+
+pub fn normal_matrix(instance: &InstanceData) -> Mat3 {
+    Mat3::from_cols(
+        Vec4::from(instance.normal_matrix[0]).xyz(),
+        Vec4::from(instance.normal_matrix[1]).xyz(),
+        Vec4::from(instance.normal_matrix[2]).xyz(),
+    )
+}
+
+fn triangle_vertices<'a>(
+    instance: &InstanceData,
+    primitive_id: u32,
+    vertices: &'a [Vertex],
+    indices: &[u32],
+) -> [&'a Vertex; 3] {
+    let first = (instance.first_index + primitive_id * 3) as usize;
+    let vertex = |i: usize| &vertices[(instance.first_vertex + indices[first + i]) as usize];
+
+    [vertex(0), vertex(1), vertex(2)]
+}
+
+fn interpolate_tex_coord(triangle: &[&Vertex; 3], barycentrics: Vec3) -> Vec2 {
+    triangle[0].tex_coord * barycentrics.x
+        + triangle[1].tex_coord * barycentrics.y
+        + triangle[2].tex_coord * barycentrics.z
+}
+
+#[spirv(closest_hit)]
+pub fn triangle_closest_hit(
+    #[spirv(hit_attribute)] attributes: &mut Vec2,
+    #[spirv(incoming_ray_payload)] payload: &mut RayPayload,
+    #[spirv(callable_data)] material_payload: &mut MaterialPayload,
+    #[spirv(primitive_id)] primitive_id: u32,
+    #[spirv(instance_custom_index)] instance_index: u32,
+    #[spirv(world_ray_origin)] world_ray_origin: Vec3,
+    #[spirv(world_ray_direction)] world_ray_direction: Vec3,
+    #[spirv(ray_tmax)] hit_t: f32,
+    #[spirv(descriptor_set = 0, binding = 2, storage_buffer)] materials: &[MaterialData],
+    #[spirv(descriptor_set = 0, binding = 3, storage_buffer)] vertices: &[Vertex],
+    #[spirv(descriptor_set = 0, binding = 4, storage_buffer)] indices: &[u32],
+    #[spirv(descriptor_set = 0, binding = 5, storage_buffer)] instances: &[InstanceData],
+) {
+    let instance = &instances[instance_index as usize];
+    let triangle = triangle_vertices(instance, primitive_id, vertices, indices);
+    let barycentrics = Vec3::new(1.0 - attributes.x - attributes.y, attributes.x, attributes.y);
+    let normal_matrix = normal_matrix(instance);
+
+    let p0 = Vec3::from(triangle[0].position);
+    let face_normal = (normal_matrix
+        * (Vec3::from(triangle[1].position) - p0).cross(Vec3::from(triangle[2].position) - p0))
+        .normalize();
+
+    let interpolated = (normal_matrix
+        * (Vec3::from(triangle[0].normal) * barycentrics.x
+            + Vec3::from(triangle[1].normal) * barycentrics.y
+            + Vec3::from(triangle[2].normal) * barycentrics.z))
+        .normalize_or_zero();
+    let normal = if interpolated == Vec3::ZERO { face_normal } else { interpolated };
+
+    let front_face = world_ray_direction.dot(face_normal) < 0.0;
+
+    material_payload.position = world_ray_origin + world_ray_direction * hit_t;
+    material_payload.material = instance.material;
+    material_payload.normal = if front_face { normal } else { -normal };
+    material_payload.front_face = front_face as u32;
+    material_payload.direction = world_ray_direction;
+    material_payload.tex_coord = interpolate_tex_coord(&triangle, barycentrics);
+
+    shade(payload, material_payload, materials);
+}
+
+/// albedoテクスチャのアルファで切り抜く
+#[spirv(any_hit)]
+pub fn triangle_any_hit(
+    #[spirv(hit_attribute)] attributes: &mut Vec2,
+    #[spirv(primitive_id)] primitive_id: u32,
+    #[spirv(instance_custom_index)] instance_index: u32,
+    #[spirv(descriptor_set = 0, binding = 2, storage_buffer)] materials: &[MaterialData],
+    #[spirv(descriptor_set = 0, binding = 3, storage_buffer)] vertices: &[Vertex],
+    #[spirv(descriptor_set = 0, binding = 4, storage_buffer)] indices: &[u32],
+    #[spirv(descriptor_set = 0, binding = 5, storage_buffer)] instances: &[InstanceData],
+    #[spirv(descriptor_set = 0, binding = 6)] textures: &Textures,
+) {
+    let instance = &instances[instance_index as usize];
+    let triangle = triangle_vertices(instance, primitive_id, vertices, indices);
+    let barycentrics = Vec3::new(1.0 - attributes.x - attributes.y, attributes.x, attributes.y);
+    let tex_coord = interpolate_tex_coord(&triangle, barycentrics);
+
+    if albedo(&materials[instance.material as usize], textures, tex_coord).w < ALPHA_CUTOFF {
+        unsafe { ignore_intersection() };
+    }
+}
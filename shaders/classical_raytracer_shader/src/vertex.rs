@@ -1,4 +1,4 @@
-use spirv_std::glam::Vec3A;
+use spirv_std::glam::{Vec2, Vec3A, Vec4};
 
 #[derive(Copy, Clone)]
 #[repr(C)]
@@ -6,4 +6,9 @@ pub struct Vertex {
     //Aがついている型はSIMDが使用される
     pub position: Vec3A,
     pub normal: Vec3A,
-}
\ No newline at end of file
+    //xyzが接線、wが従法線の向き(1か-1)、接線がない場合は全て0
+    pub tangent: Vec4,
+    //UVがない場合は0
+    pub tex_coord: Vec2,
+    pub _padding: Vec2,
+}
@@ -0,0 +1,553 @@
+use std::str::FromStr;
+use glam::{Mat4, Quat, Vec3};
+use crate::camera::Camera;
+use crate::error::{CottonError, Result};
+use crate::scene_description::SceneDescription;
+
+pub const DEFAULT_FPS: f32 = 24.0;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    /// 次のキーまで前のキーの値のまま
+    Step,
+    Linear,
+    /// 接線をCatmull-Romで決める3次エルミート補間
+    Cubic,
+}
+
+/// キーフレームの間を補間できる値
+pub trait Interpolate: Copy {
+    fn lerp(a: Self, b: Self, t: f32) -> Self;
+
+    /// m0とm1は1秒あたりの変化量、dtは区間の秒数
+    fn hermite(p0: Self, m0: Self, p1: Self, m1: Self, t: f32, dt: f32) -> Self;
+
+    /// (b - a) / dt
+    fn slope(a: Self, b: Self, dt: f32) -> Self;
+
+    /// quaternionのように同じ値に二つの表し方があるものをreferenceの側にそろえる
+    fn align(self, _reference: Self) -> Self {
+        self
+    }
+}
+
+fn hermite_weights(t: f32) -> [f32; 4] {
+    let t2 = t * t;
+    let t3 = t2 * t;
+
+    [
+        2.0 * t3 - 3.0 * t2 + 1.0,
+        t3 - 2.0 * t2 + t,
+        -2.0 * t3 + 3.0 * t2,
+        t3 - t2,
+    ]
+}
+
+impl Interpolate for f32 {
+    fn lerp(a: Self, b: Self, t: f32) -> Self {
+        a + (b - a) * t
+    }
+
+    fn hermite(p0: Self, m0: Self, p1: Self, m1: Self, t: f32, dt: f32) -> Self {
+        let [h00, h10, h01, h11] = hermite_weights(t);
+
+        h00 * p0 + h10 * dt * m0 + h01 * p1 + h11 * dt * m1
+    }
+
+    fn slope(a: Self, b: Self, dt: f32) -> Self {
+        (b - a) / dt
+    }
+}
+
+impl Interpolate for Vec3 {
+    fn lerp(a: Self, b: Self, t: f32) -> Self {
+        a.lerp(b, t)
+    }
+
+    fn hermite(p0: Self, m0: Self, p1: Self, m1: Self, t: f32, dt: f32) -> Self {
+        let [h00, h10, h01, h11] = hermite_weights(t);
+
+        p0 * h00 + m0 * (h10 * dt) + p1 * h01 + m1 * (h11 * dt)
+    }
+
+    fn slope(a: Self, b: Self, dt: f32) -> Self {
+        (b - a) / dt
+    }
+}
+
+impl Interpolate for Quat {
+    fn lerp(a: Self, b: Self, t: f32) -> Self {
+        a.slerp(b.align(a), t)
+    }
+
+    //成分ごとに補間してから正規化する
+    fn hermite(p0: Self, m0: Self, p1: Self, m1: Self, t: f32, dt: f32) -> Self {
+        let [h00, h10, h01, h11] = hermite_weights(t);
+
+        (p0 * h00 + m0 * (h10 * dt) + p1.align(p0) * h01 + m1 * (h11 * dt)).normalize()
+    }
+
+    fn slope(a: Self, b: Self, dt: f32) -> Self {
+        (b.align(a) - a) * (1.0 / dt)
+    }
+
+    fn align(self, reference: Self) -> Self {
+        if self.dot(reference) < 0.0 { -self } else { self }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Keyframe<T> {
+    /// 秒
+    pub time: f32,
+    pub value: T,
+}
+
+/// キーは常に時間順に並べておく
+#[derive(Clone, Debug, PartialEq)]
+pub struct Track<T> {
+    pub interpolation: Interpolation,
+    keyframes: Vec<Keyframe<T>>,
+}
+
+impl<T> Default for Track<T> {
+    fn default() -> Self {
+        Self::new(Interpolation::Linear)
+    }
+}
+
+impl<T> Track<T> {
+    pub fn new(interpolation: Interpolation) -> Self {
+        Self {
+            interpolation,
+            keyframes: vec![],
+        }
+    }
+
+    pub fn step() -> Self {
+        Self::new(Interpolation::Step)
+    }
+
+    pub fn linear() -> Self {
+        Self::new(Interpolation::Linear)
+    }
+
+    pub fn cubic() -> Self {
+        Self::new(Interpolation::Cubic)
+    }
+
+    /// 同じ時間のキーがあれば置き換える
+    pub fn key(mut self, time: f32, value: T) -> Self {
+        let i = self.keyframes.partition_point(|keyframe| keyframe.time < time);
+
+        match self.keyframes.get_mut(i) {
+            Some(keyframe) if keyframe.time == time => keyframe.value = value,
+            _ => self.keyframes.insert(i, Keyframe { time, value }),
+        }
+
+        self
+    }
+
+    pub fn keyframes(&self) -> &[Keyframe<T>] {
+        &self.keyframes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keyframes.is_empty()
+    }
+
+    pub fn end_time(&self) -> f32 {
+        self.keyframes.last().map_or(0.0, |keyframe| keyframe.time)
+    }
+}
+
+impl<T: Interpolate> Track<T> {
+    /// キーがなければNone、最初のキーより前と最後のキーより後はその値のまま
+    /// timeがNaNの場合は最初のキーの値
+    pub fn evaluate(&self, time: f32) -> Option<T> {
+        let keyframes = &self.keyframes;
+        let first = keyframes.first()?;
+        let last = keyframes.last()?;
+
+        //NaNはどの比較もfalseになりpartition_pointが0を返すので先に弾く
+        if time.is_nan() || time <= first.time {
+            return Some(first.value);
+        }
+
+        if time >= last.time {
+            return Some(last.value);
+        }
+
+        //keyframes[i].time <= time < keyframes[i + 1].time
+        let i = keyframes.partition_point(|keyframe| keyframe.time <= time) - 1;
+        let k0 = keyframes[i];
+        let k1 = keyframes[i + 1];
+
+        let dt = k1.time - k0.time;
+        let t = (time - k0.time) / dt;
+
+        let value = match self.interpolation {
+            Interpolation::Step => k0.value,
+            Interpolation::Linear => T::lerp(k0.value, k1.value, t),
+            Interpolation::Cubic => {
+                let p1 = k1.value.align(k0.value);
+
+                T::hermite(k0.value, self.tangent(i, k0.value), p1, self.tangent(i + 1, p1), t, dt)
+            }
+        };
+
+        Some(value)
+    }
+
+    //両隣のキーを結んだ傾き、端のキーでは隣のキーとの傾き
+    //valueはi番目のキーの値をalignしたもので、両隣もそちらにそろえる
+    fn tangent(&self, i: usize, value: T) -> T {
+        let keyframes = &self.keyframes;
+        let previous = keyframes[i.saturating_sub(1)];
+        let next = keyframes[(i + 1).min(keyframes.len() - 1)];
+
+        T::slope(previous.value.align(value), next.value.align(value), next.time - previous.time)
+    }
+}
+
+/// 空のトラックは元のtransformの値をそのまま使う
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TransformTrack {
+    pub translation: Track<Vec3>,
+    pub rotation: Track<Quat>,
+    pub scale: Track<Vec3>,
+}
+
+impl TransformTrack {
+    pub fn is_empty(&self) -> bool {
+        self.translation.is_empty() && self.rotation.is_empty() && self.scale.is_empty()
+    }
+
+    pub fn end_time(&self) -> f32 {
+        self.translation.end_time()
+            .max(self.rotation.end_time())
+            .max(self.scale.end_time())
+    }
+
+    pub fn evaluate(&self, base: Mat4, time: f32) -> Mat4 {
+        //分解すると誤差が出るので何も動かさない場合はそのまま返す
+        if self.is_empty() {
+            return base;
+        }
+
+        let (scale, rotation, translation) = base.to_scale_rotation_translation();
+
+        Mat4::from_scale_rotation_translation(
+            self.scale.evaluate(time).unwrap_or(scale),
+            self.rotation.evaluate(time).unwrap_or(rotation),
+            self.translation.evaluate(time).unwrap_or(translation),
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct InstanceTrack {
+    /// SceneDescription::instancesの中のindex
+    pub instance: usize,
+    pub transform: TransformTrack,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CameraTrack {
+    pub position: Track<Vec3>,
+    pub yaw: Track<f32>,
+    pub pitch: Track<f32>,
+    pub fov_y: Track<f32>,
+}
+
+impl CameraTrack {
+    pub fn end_time(&self) -> f32 {
+        self.position.end_time()
+            .max(self.yaw.end_time())
+            .max(self.pitch.end_time())
+            .max(self.fov_y.end_time())
+    }
+
+    pub fn evaluate(&self, base: Camera, time: f32) -> Camera {
+        Camera {
+            position: self.position.evaluate(time).unwrap_or(base.position),
+            yaw: self.yaw.evaluate(time).unwrap_or(base.yaw),
+            pitch: self.pitch.evaluate(time).unwrap_or(base.pitch),
+            fov_y: self.fov_y.evaluate(time).unwrap_or(base.fov_y),
+        }
+    }
+}
+
+/// 発光するマテリアルのemissionに掛ける倍率
+#[derive(Clone, Debug, PartialEq)]
+pub struct LightTrack {
+    /// SceneDescription::materialsの中のindex
+    pub material: usize,
+    pub intensity: Track<f32>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Animation {
+    pub instances: Vec<InstanceTrack>,
+    pub camera: CameraTrack,
+    pub lights: Vec<LightTrack>,
+}
+
+/// ある時間でのシーンとカメラ
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnimatedFrame {
+    pub scene: SceneDescription,
+    pub camera: Camera,
+}
+
+impl Animation {
+    /// classicalの三角形を2秒で一回転させ、カメラを前後させる
+    pub fn classical() -> Self {
+        //slerpが逆回りしないように1/4回転ごとにキーを置く
+        let rotation = (0..=4).fold(Track::linear(), |track, i| {
+            let angle = i as f32 * std::f32::consts::FRAC_PI_2;
+            track.key(i as f32 * 0.5, Quat::from_rotation_y(angle))
+        });
+
+        Self {
+            instances: vec![InstanceTrack {
+                instance: 0,
+                transform: TransformTrack {
+                    rotation,
+                    ..TransformTrack::default()
+                },
+            }],
+            camera: CameraTrack {
+                position: Track::cubic()
+                    .key(0.0, Vec3::new(0.0, 1.0, 5.0))
+                    .key(1.0, Vec3::new(0.0, 1.0, 3.5))
+                    .key(2.0, Vec3::new(0.0, 1.0, 5.0)),
+                ..CameraTrack::default()
+            },
+            lights: vec![],
+        }
+    }
+
+    /// 全てのトラックの最後のキーの時間
+    pub fn end_time(&self) -> f32 {
+        self.instances
+            .iter()
+            .map(|track| track.transform.end_time())
+            .chain(self.lights.iter().map(|track| track.intensity.end_time()))
+            .fold(self.camera.end_time(), f32::max)
+    }
+
+    pub fn validate(&self, scene: &SceneDescription) -> Result<()> {
+        for track in self.instances.iter() {
+            if track.instance >= scene.instances.len() {
+                return Err(CottonError::InvalidScene(format!(
+                    "animation refers to instance {}, but there are only {} instances",
+                    track.instance,
+                    scene.instances.len(),
+                )));
+            }
+        }
+
+        for track in self.lights.iter() {
+            if track.material >= scene.materials.len() {
+                return Err(CottonError::InvalidScene(format!(
+                    "animation refers to material {}, but there are only {} materials",
+                    track.material,
+                    scene.materials.len(),
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 範囲外を参照するトラックは無視する、先にvalidateで確認しておく
+    pub fn evaluate(&self, scene: &SceneDescription, camera: Camera, time: f32) -> AnimatedFrame {
+        let mut scene = scene.clone();
+
+        for track in self.instances.iter() {
+            if let Some(instance) = scene.instances.get_mut(track.instance) {
+                instance.transform = track.transform.evaluate(instance.transform, time);
+            }
+        }
+
+        for track in self.lights.iter() {
+            if let (Some(material), Some(intensity)) = (scene.materials.get_mut(track.material), track.intensity.evaluate(time)) {
+                material.emission = material.emission.map(|emission| emission * intensity);
+            }
+        }
+
+        AnimatedFrame {
+            scene,
+            camera: self.camera.evaluate(camera, time),
+        }
+    }
+}
+
+/// CLIの--framesで指定する、両端を含む
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FrameRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl FrameRange {
+    pub fn frames(&self) -> impl Iterator<Item = u32> {
+        self.start..=self.end
+    }
+}
+
+/// CLIの--fpsで指定する、0以下と有限でない値はフレームの時間が決まらないので弾く
+pub fn parse_fps(s: &str) -> Result<f32> {
+    let fps = s
+        .trim()
+        .parse::<f32>()
+        .map_err(|_| CottonError::InvalidRenderSettings(format!("invalid fps `{}`", s)))?;
+
+    if !fps.is_finite() || fps <= 0.0 {
+        return Err(CottonError::InvalidRenderSettings(format!("fps must be a positive number, got {}", fps)));
+    }
+
+    Ok(fps)
+}
+
+/// 範囲の始まりに関係なくフレーム0が0秒になる
+pub fn frame_time(frame: u32, fps: f32) -> f32 {
+    frame as f32 / fps
+}
+
+impl FromStr for FrameRange {
+    type Err = CottonError;
+
+    /// `1-48`か`12`の形
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || CottonError::InvalidRenderSettings(format!(
+            "invalid frame range `{}`, expected START-END or FRAME",
+            s,
+        ));
+
+        let (start, end) = match s.split_once('-') {
+            Some((start, end)) => (start.trim().parse().map_err(|_| invalid())?, end.trim().parse().map_err(|_| invalid())?),
+            None => {
+                let frame = s.trim().parse().map_err(|_| invalid())?;
+                (frame, frame)
+            }
+        };
+
+        if start > end {
+            return Err(CottonError::InvalidRenderSettings(format!(
+                "frame range {}-{} ends before it starts",
+                start,
+                end,
+            )));
+        }
+
+        Ok(Self { start, end })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn step_holds_the_previous_key() {
+        let track = Track::step().key(0.0, 1.0).key(1.0, 2.0).key(2.0, 3.0);
+
+        assert_eq!(track.evaluate(0.0), Some(1.0));
+        assert_eq!(track.evaluate(0.99), Some(1.0));
+        assert_eq!(track.evaluate(1.0), Some(2.0));
+        assert_eq!(track.evaluate(1.5), Some(2.0));
+    }
+
+    #[test]
+    fn linear_interpolates_between_keys() {
+        let track = Track::linear().key(0.0, Vec3::ZERO).key(2.0, Vec3::new(4.0, -2.0, 0.0));
+
+        assert_eq!(track.evaluate(0.5), Some(Vec3::new(1.0, -0.5, 0.0)));
+        assert_eq!(track.evaluate(1.0), Some(Vec3::new(2.0, -1.0, 0.0)));
+    }
+
+    #[test]
+    fn linear_rotation_takes_the_short_way() {
+        //-qは同じ回転なので、そのまま補間すると遠回りになる
+        let track = Track::linear()
+            .key(0.0, Quat::IDENTITY)
+            .key(1.0, -Quat::from_rotation_y(std::f32::consts::FRAC_PI_2));
+
+        let half = track.evaluate(0.5).unwrap();
+        let expected = Quat::from_rotation_y(std::f32::consts::FRAC_PI_4);
+
+        assert_near(half.dot(expected).abs(), 1.0);
+    }
+
+    #[test]
+    fn cubic_uses_catmull_rom_tangents() {
+        let track = Track::cubic().key(0.0, 0.0).key(1.0, 1.0).key(2.0, 0.0);
+
+        //キーは必ず通る
+        assert_near(track.evaluate(1.0).unwrap(), 1.0);
+        //接線は0秒で1、1秒で0
+        assert_near(track.evaluate(0.5).unwrap(), 0.625);
+        assert_near(track.evaluate(1.5).unwrap(), 0.625);
+    }
+
+    #[test]
+    fn values_are_clamped_outside_the_keys() {
+        for track in [Track::step(), Track::linear(), Track::cubic()] {
+            let track = track.key(1.0, 10.0).key(2.0, 20.0);
+
+            assert_eq!(track.evaluate(-5.0), Some(10.0));
+            assert_eq!(track.evaluate(1.0), Some(10.0));
+            assert_eq!(track.evaluate(2.0), Some(20.0));
+            assert_eq!(track.evaluate(100.0), Some(20.0));
+            assert_eq!(track.evaluate(f32::INFINITY), Some(20.0));
+        }
+    }
+
+    #[test]
+    fn nan_time_uses_the_first_key() {
+        let track = Track::cubic().key(0.0, 1.0).key(1.0, 2.0).key(2.0, 3.0);
+
+        assert_eq!(track.evaluate(f32::NAN), Some(1.0));
+        assert_eq!(Track::<f32>::linear().evaluate(f32::NAN), None);
+    }
+
+    #[test]
+    fn keys_are_sorted_and_replaced() {
+        let track = Track::linear().key(2.0, 2.0).key(0.0, 0.0).key(1.0, 5.0).key(1.0, 1.0);
+
+        let times: Vec<_> = track.keyframes().iter().map(|keyframe| (keyframe.time, keyframe.value)).collect();
+
+        assert_eq!(times, vec![(0.0, 0.0), (1.0, 1.0), (2.0, 2.0)]);
+        assert_eq!(track.end_time(), 2.0);
+        assert_eq!(Track::<f32>::linear().evaluate(1.0), None);
+    }
+
+    #[test]
+    fn frame_range_parsing() {
+        assert_eq!("1-48".parse::<FrameRange>().unwrap(), FrameRange { start: 1, end: 48 });
+        assert_eq!(" 12 ".parse::<FrameRange>().unwrap(), FrameRange { start: 12, end: 12 });
+        assert_eq!("3 - 5".parse::<FrameRange>().unwrap().frames().collect::<Vec<_>>(), vec![3, 4, 5]);
+
+        for invalid in ["", "5-3", "a-b", "-3", "1-", "1.5", "1-2-3"] {
+            assert!(invalid.parse::<FrameRange>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn fps_must_be_positive_and_finite() {
+        assert_eq!(parse_fps("30").unwrap(), 30.0);
+        assert_eq!(parse_fps("23.976").unwrap(), 23.976);
+
+        for invalid in ["0", "-24", "inf", "NaN", "abc", ""] {
+            assert!(parse_fps(invalid).is_err(), "{}", invalid);
+        }
+
+        assert_eq!(frame_time(48, 24.0), 2.0);
+    }
+}
@@ -1,137 +1,203 @@
 use std::env;
-use std::fs::File;
-use std::io::Write;
-use std::mem::swap;
-use std::path::Path;
-use ash::vk::{AccessFlags, CommandBufferBeginInfo, CommandBufferUsageFlags, DependencyFlags, Extent2D, Extent3D, Fence, Format, Image, ImageAspectFlags, ImageCopy, ImageCreateInfo, ImageLayout, ImageMemoryBarrier, ImageSubresource, ImageSubresourceLayers, ImageSubresourceRange, ImageTiling, ImageType, ImageUsageFlags, MemoryAllocateInfo, MemoryMapFlags, MemoryPropertyFlags, PipelineStageFlags, Queue, SampleCountFlags, SharingMode, SubmitInfo, WHOLE_SIZE};
-use log::debug;
-use log::Level::Debug;
-
-use cotton::constants::{DEFAULT_WINDOW_HEIGHT, DEFAULT_WINDOW_WIDTH};
-use cotton::get_memory_type_index;
+use std::time::Instant;
+use ash::vk::{Extent3D, Format, Queue};
+use log::{debug, info, warn};
+use winit::dpi::PhysicalSize;
+use winit::event::{Event, WindowEvent};
+use winit::event_loop::ControlFlow;
+use winit::platform::run_return::EventLoopExtRunReturn;
+
+use cotton::animation::{Animation, DEFAULT_FPS, frame_time, FrameRange, parse_fps};
+use cotton::camera::{Accumulation, CameraController};
+use cotton::constants::{DEFAULT_WINDOW_HEIGHT, DEFAULT_WINDOW_WIDTH, INTERACTIVE_MAX_DEPTH, INTERACTIVE_SAMPLES_PER_FRAME, MAX_FRAMES_IN_FLIGHT, TRIANGLE_HIT_GROUP_NAME};
+use cotton::interrupt;
+use cotton::mesh_processing::{MeshProcessingReport, MeshProcessingSettings, NormalMode, process_scene};
+use cotton::render_backend::{BackendChoice, RenderBackend, RenderSettings};
+use cotton::render_backend::host_backend::HostBackend;
+use cotton::render_backend::progress::{CancellationToken, RenderControl};
+use cotton::render_backend::vulkan_backend::VulkanBackend;
 use cotton::renderer::acceleration_structures::AccelerationStructures;
 use cotton::renderer::backends::Backends;
+use cotton::renderer::capability_report::CapabilityReport;
+use cotton::renderer::frame_state::{FrameAction, FrameState};
+use cotton::renderer::frames::FrameRing;
 use cotton::renderer::images::Images;
+use cotton::renderer::materials::{MaterialCallables, MaterialKind};
+use cotton::renderer::pipeline_caches::PipelineCaches;
 use cotton::renderer::pipelines::Pipelines;
 
 use cotton::renderer::render_passes::RenderPasses;
 use cotton::renderer::Renderer;
+use cotton::renderer::scene_buffers::SceneBuffers;
 use cotton::renderer::shader_module::ShaderModules;
+use cotton::renderer::shader_reload::ShaderHotReload;
 use cotton::renderer::swapchains::Swapchains;
+use cotton::renderer::texture_images::TextureImages;
 use cotton::scene::Scene;
+use cotton::scene_description::SceneDescription;
 use cotton::window_handlers::WindowHandlers;
 
-fn main() {
+//進捗バーの#と-の数
+const PROGRESS_BAR_WIDTH: usize = 40;
+
+fn main() -> anyhow::Result<()> {
     env::set_var("RUST_LOG", "info");
     env::set_var("RUST_LOG", "DEBUG");
     env_logger::init();
 
     debug!("Start");
 
-    //to_window();
-    to_image();
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    //指定された場合は埋め込まれたものではなくディスク上の.spvを使う
+    let shader_path = option_value(&args, "--shader");
+
+    //auto, vulkan, hostのいずれか
+    let backend_choice = option_value(&args, "--backend")
+        .map(str::parse::<BackendChoice>)
+        .transpose()?
+        .unwrap_or_default();
+
+    //hostで描画する場合のスレッド数、指定がなければ論理コア数
+    let threads = option_value(&args, "--threads")
+        .map(str::parse::<usize>)
+        .transpose()?;
+
+    //指定された場合はアニメーションを適用してout_0001.pngのように連番で書き出す
+    let frames = option_value(&args, "--frames")
+        .map(str::parse::<FrameRange>)
+        .transpose()?;
+
+    let fps = option_value(&args, "--fps")
+        .map(parse_fps)
+        .transpose()?
+        .unwrap_or(DEFAULT_FPS);
+
+    //指定された場合は読み込んだメッシュをそのまま使う
+    let process_meshes = !args.iter().any(|arg| arg == "--no-mesh-processing");
+
+    match args.first().map(|arg| arg.as_str()) {
+        Some("info") => info(&args[1..]),
+        Some("window") => to_window(shader_path),
+        //to_window()
+        _ => to_image(shader_path, backend_choice, threads, frames, fps, process_meshes),
+    }
 }
 
-fn to_window() {
-    let window_size = winit::dpi::LogicalSize::new(DEFAULT_WINDOW_WIDTH, DEFAULT_WINDOW_HEIGHT);
+fn option_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|i| args.get(i + 1))
+        .map(|value| value.as_str())
+}
 
-    let window_handlers = WindowHandlers::new(window_size);
+fn load_shader_code(shader_path: Option<&str>) -> anyhow::Result<Vec<u8>> {
+    let code = match shader_path {
+        Some(path) => std::fs::read(path)?,
+        None => include_bytes!(env!("classical_raytracer_shader.spv")).to_vec(),
+    };
 
-    let backends =
-        cotton::renderer::backends::Backends::new(Some(&window_handlers), true)
-            .expect("Failed to create backends");
+    Ok(code)
+}
 
-    //backends.display_support_extension();
+fn load_shader_modules<'a>(device: &'a ash::Device, shader_path: Option<&str>) -> anyhow::Result<ShaderModules<'a>> {
+    Ok(ShaderModules::new(device, &load_shader_code(shader_path)?)?)
+}
 
-    let graphics_queue = backends.create_graphics_queue(0);
-    let present_queue = backends.create_present_queue(0);
+fn info(args: &[String]) -> anyhow::Result<()> {
+    let report = CapabilityReport::query()?;
 
-    let swapchains = Swapchains::new(&backends, window_size);
-    let swapchain_images = swapchains.get_swapchain_images(&backends);
+    if args.iter().any(|arg| arg == "--json") {
+        println!("{}", report.to_json()?);
+    } else {
+        print!("{}", report);
+    }
 
-    let temp_image = Images::new(
-        &backends,
+    Ok(())
+}
+
+//swapchainの大きさに依存するもの、作り直すときはまとめて破棄する
+struct WindowTargets<'a> {
+    //swapchainのimage viewを参照しているので先に破棄する
+    render_passes: RenderPasses<'a>,
+    swapchain_images: Images<'a>,
+    target_images: Images<'a>,
+}
+
+fn create_window_targets<'a>(
+    backends: &'a Backends,
+    swapchains: &Swapchains,
+    graphics_queue: Queue,
+) -> anyhow::Result<WindowTargets<'a>> {
+    let swapchain_images = swapchains.get_swapchain_images(backends)?;
+
+    //to_imageと同じformatに描画してswapchainのformatへはblitで変換する
+    let target_images = Images::new(
+        backends,
         1,
-        swapchains.format,
+        Format::R32G32B32A32_SFLOAT,
         Extent3D::builder()
             .width(swapchains.extent.width)
             .height(swapchains.extent.height)
             .depth(1)
             .build(),
         graphics_queue,
-    );
+    )?;
 
-    let render_passes = RenderPasses::new(&backends, swapchains.format, swapchain_images.image_views.clone(), swapchains.extent);
+    let render_passes = RenderPasses::new(backends, swapchains.format, swapchain_images.image_views.clone(), swapchains.extent)?;
 
-    let code = include_bytes!(env!("classical_raytracer_shader.spv"));
-    let shader_modules = ShaderModules::new(&backends.device, code);
+    Ok(WindowTargets {
+        render_passes,
+        swapchain_images,
+        target_images,
+    })
+}
 
-    let acceleration_structures = AccelerationStructures::new(
-        &backends
-    );
+fn recreate_window_targets<'a>(
+    backends: &'a Backends,
+    swapchains: &mut Swapchains,
+    targets: &mut Option<WindowTargets<'a>>,
+    renderer: &mut Renderer<'a>,
+    frames: &mut FrameRing,
+    frame_state: &mut FrameState,
+    graphics_queue: Queue,
+) -> anyhow::Result<()> {
+    debug!("recreate swapchain");
 
-    let triangle_blas = acceleration_structures.create_triangle_blas(
-        graphics_queue
-    );
+    unsafe { backends.device.device_wait_idle()? };
 
-    let scene = Scene::build_scene(
-        &backends,
-        triangle_blas.get_device_address_info()
-    );
+    //古いswapchainのimageを参照しているので作り直す前に破棄する
+    *targets = None;
 
-    let tlas = acceleration_structures.create_tlas(
-        scene,
-        graphics_queue
-    );
+    let window_extent = frame_state.window_extent();
+    swapchains.recreate(backends, PhysicalSize::new(window_extent.width, window_extent.height))?;
 
-    let pipelines = Pipelines::new(
-        &backends,
-        shader_modules,
-        swapchains.extent,
-        &render_passes,
-        &triangle_blas.mesh_buffer,
-        tlas,
-        graphics_queue,
-        temp_image.image_views[0]
-    );
+    let new_targets = create_window_targets(backends, swapchains, graphics_queue)?;
+    renderer.set_target_image_view(new_targets.target_images.image_views[0])?;
+    frames.on_swapchain_recreated(new_targets.swapchain_images.images.len());
 
-    debug!("window close");
-}
+    *targets = Some(new_targets);
+    frame_state.on_recreated(swapchains.extent);
 
-//TODO
-fn to_image() {
+    Ok(())
+}
 
-    let extent3d = Extent3D::builder()
-        .width(DEFAULT_WINDOW_WIDTH)
-        .height(DEFAULT_WINDOW_HEIGHT)
-        .depth(1)
-        .build();
-    let extent2d = Extent2D::builder()
-        .width(extent3d.width)
-        .height(extent3d.height)
-        .build();
+fn to_window(shader_path: Option<&str>) -> anyhow::Result<()> {
+    let window_size = winit::dpi::LogicalSize::new(DEFAULT_WINDOW_WIDTH, DEFAULT_WINDOW_HEIGHT);
 
-    let format = Format::R32G32B32A32_SFLOAT;
+    let mut window_handlers = WindowHandlers::new(window_size)?;
 
     let backends =
-        cotton::renderer::backends::Backends::new(None, true)
-            .expect("Failed to create backends");
+        cotton::renderer::backends::Backends::new(Some(&window_handlers), true)?;
 
-    //backends.display_support_extension();
+    let graphics_queue = backends.create_graphics_queue(0)?;
+    let present_queue = backends.create_present_queue(0)?;
 
-    let graphics_queue = backends.create_graphics_queue(0);
+    let mut swapchains = Swapchains::new(&backends, window_handlers.window.inner_size())?;
 
-    let target_images = Images::new(&backends, 1, format, extent3d, graphics_queue);
+    let targets = create_window_targets(&backends, &swapchains, graphics_queue)?;
 
-    let render_passes = RenderPasses::new(
-        &backends,
-        format,
-        target_images.image_views.clone(),
-        extent2d,
-    );
-
-    let code = include_bytes!(env!("classical_raytracer_shader.spv"));
-    let shader_modules = ShaderModules::new(&backends.device, code);
+    let shader_modules = load_shader_modules(&backends.device, shader_path)?;
 
     let acceleration_structures = AccelerationStructures::new(
         &backends
@@ -139,284 +205,334 @@ fn to_image() {
 
     let triangle_blas = acceleration_structures.create_triangle_blas(
         graphics_queue
-    );
+    )?;
+
+    let shader_groups = Pipelines::classical_pipeline_desc().build()?;
+    let triangle_hit_group_offset = shader_groups
+        .hit_group_offset(TRIANGLE_HIT_GROUP_NAME)
+        .ok_or_else(|| anyhow::anyhow!("hit group {} is not registered", TRIANGLE_HIT_GROUP_NAME))?;
 
     let scene = Scene::build_scene(
         &backends,
-        triangle_blas.get_device_address_info()
-    );
+        triangle_blas.get_device_address_info(),
+        triangle_hit_group_offset,
+    )?;
 
     let tlas = acceleration_structures.create_tlas(
         scene,
         graphics_queue
-    );
+    )?;
 
-    let image = target_images.images[0];
-    let image_view = target_images.image_views[0];
+    //triangle_blasと同じ三角形一つのシーン
+    let material_callables = MaterialCallables::from_groups(&shader_groups, &MaterialKind::ALL)?;
+    let scene_buffers = SceneBuffers::new(&backends, &SceneDescription::classical(), &material_callables)?;
+
+    //windowの表示ではテクスチャを使わないので白だけ置く
+    let textures = TextureImages::new(&backends, &[], graphics_queue)?;
+
+    //hot reloadでpipelineを作り直してもキャッシュは使い回す
+    let pipeline_caches = PipelineCaches::new(&backends)?;
 
     let pipelines = Pipelines::new(
         &backends,
         shader_modules,
-        extent2d,
-        &render_passes,
-        &triangle_blas.mesh_buffer,
-        tlas,
+        shader_groups,
+        swapchains.extent,
+        &targets.render_passes,
+        &scene_buffers,
+        &tlas,
+        &textures,
+        &pipeline_caches,
         graphics_queue,
-        image_view,
-    );
+        targets.target_images.image_views[0]
+    )?;
 
-    let renderer = Renderer::new(
+    let mut renderer = Renderer::new(
         &backends,
         pipelines,
     );
 
-    renderer.rendering(
-        image,
-        graphics_queue
-    ).unwrap();
+    //ディスクから読み込んだ場合のみ監視する
+    let mut hot_reload = shader_path.map(ShaderHotReload::new);
+
+    let mut frames = FrameRing::new(&backends, MAX_FRAMES_IN_FLIGHT, targets.swapchain_images.images.len())?;
+    let mut frame_state = FrameState::new(swapchains.extent);
+    let mut targets = Some(targets);
+
+    let mut camera_controller = CameraController::default();
+    let mut accumulation = Accumulation::default();
+    let mut last_frame = Instant::now();
+
+    //イベントループの中で起きたエラーはループを抜けてから返す
+    let mut loop_result: anyhow::Result<()> = Ok(());
+
+    window_handlers.event_loop.run_return(|event, _, control_flow| {
+        match event {
+            Event::WindowEvent { event, .. } => {
+                camera_controller.handle_event(&event);
+
+                match event {
+                    WindowEvent::CloseRequested => frame_state.on_close_requested(),
+                    WindowEvent::Resized(size) => frame_state.on_resized(size.width, size.height),
+                    _ => {}
+                }
+            }
+            Event::MainEventsCleared => {
+                let now = Instant::now();
+
+                if let Some(hot_reload) = hot_reload.as_mut() {
+                    //失敗してもログを出して今までのpipelineを使い続ける
+                    hot_reload.update(now, |code| renderer.reload_shaders(code));
+                }
+
+                let camera_update = camera_controller.update((now - last_frame).as_secs_f32());
+                last_frame = now;
+
+                //動いた場合は描画しないフレームでも0から蓄積し直す
+                if camera_update.moved {
+                    accumulation = Accumulation::default();
+                }
+
+                *control_flow = ControlFlow::Poll;
+
+                let result = match frame_state.next_action() {
+                    FrameAction::Exit => {
+                        *control_flow = ControlFlow::Exit;
+                        Ok(())
+                    }
+                    FrameAction::Wait => {
+                        //最小化中はイベントが来るまで待つ
+                        *control_flow = ControlFlow::Wait;
+                        Ok(())
+                    }
+                    FrameAction::RecreateSwapchain => {
+                        //描画先も作り直すので前の結果は残っていない
+                        accumulation = Accumulation::default();
+
+                        recreate_window_targets(
+                            &backends,
+                            &mut swapchains,
+                            &mut targets,
+                            &mut renderer,
+                            &mut frames,
+                            &mut frame_state,
+                            graphics_queue,
+                        )
+                    }
+                    FrameAction::Render => match targets.as_ref() {
+                        Some(targets) => renderer
+                            .render_frame(
+                                &mut frame_state,
+                                &mut frames,
+                                targets.target_images.images[0],
+                                swapchains.extent,
+                                &camera_update.camera.ray_generation_constants(
+                                    accumulation.next_frame(false) * INTERACTIVE_SAMPLES_PER_FRAME,
+                                    INTERACTIVE_SAMPLES_PER_FRAME,
+                                    0,
+                                    INTERACTIVE_MAX_DEPTH,
+                                ),
+                                &swapchains,
+                                &targets.swapchain_images,
+                                graphics_queue,
+                                present_queue,
+                            )
+                            .map_err(Into::into),
+                        None => Ok(()),
+                    },
+                };
+
+                if let Err(err) = result {
+                    loop_result = Err(err);
+                    *control_flow = ControlFlow::Exit;
+                }
+            }
+            _ => {}
+        }
+    });
+
+    //破棄する前に使用中のものがなくなるまで待つ
+    unsafe { backends.device.device_wait_idle()? };
 
-    save_image(
-        &backends,
-        image,
-        format,
-        extent3d,
-        graphics_queue,
-        "./out.png"
-    ).unwrap();
+    debug!("window close");
 
-    debug!("done");
+    loop_result
 }
 
-fn save_image<P: AsRef<Path>>(
-    backends: &Backends,
-    target_image: Image,
-    format: Format,
-    extent3d: Extent3D,
-    graphics_queue: Queue,
-    image_file_path: P,
-) -> anyhow::Result<()> {
-
-    //transfer gpu to cpu
-
-    let host_image_create_info = ImageCreateInfo::builder()
-        .image_type(ImageType::TYPE_2D)
-        .format(format)
-        .extent(extent3d)
-        .mip_levels(1)
-        .initial_layout(ImageLayout::UNDEFINED)
-        .array_layers(1)
-        .samples(SampleCountFlags::TYPE_1)
-        .tiling(ImageTiling::LINEAR)
-        .usage(ImageUsageFlags::TRANSFER_DST)
-        .sharing_mode(SharingMode::EXCLUSIVE)
-        .build();
-
-    let host_image = unsafe {
-        backends.device.create_image(&host_image_create_info, None).unwrap()
-    };
-
-    let host_memory_requirement = unsafe {
-        backends.device.get_image_memory_requirements(host_image)
-    };
-    let host_memory_alloc_info = MemoryAllocateInfo::builder()
-        .allocation_size(host_memory_requirement.size)
-        .memory_type_index(get_memory_type_index(
-            &backends.device_memory_properties,
-            host_memory_requirement.memory_type_bits,
-            MemoryPropertyFlags::HOST_VISIBLE
-                | MemoryPropertyFlags::HOST_COHERENT,
-        ).unwrap()
-        );
-
-    let host_device_memory = unsafe {
-        backends.device.allocate_memory(&host_memory_alloc_info, None).unwrap()
-    };
+/// 書き出す一枚分、timeがNoneの場合はアニメーションを適用しない
+struct OutputFrame {
+    time: Option<f32>,
+    path: String,
+}
 
-    unsafe {
-        backends.device.bind_image_memory(host_image, host_device_memory, 0).unwrap()
-    }
+/// 描画するシーン、メッシュは頂点の結合と不要な三角形の除去をしてから使う
+fn load_scene(process_meshes: bool) -> anyhow::Result<SceneDescription> {
+    let scene = SceneDescription::classical();
 
-    let command_pool = backends.create_graphics_command_pool();
-    let command_buffers = backends.create_command_buffers(command_pool, 1);
-    let command_buffer = command_buffers[0];
-
-    unsafe {
-        backends
-            .device
-            .begin_command_buffer(
-                command_buffer,
-                &CommandBufferBeginInfo::builder()
-                    .flags(CommandBufferUsageFlags::ONE_TIME_SUBMIT)
-                    .build(),
-            ).unwrap();
-
-        let image_barrier = ImageMemoryBarrier::builder()
-            .src_access_mask(AccessFlags::empty())
-            .dst_access_mask(AccessFlags::TRANSFER_WRITE)
-            .old_layout(ImageLayout::UNDEFINED)
-            .new_layout(ImageLayout::TRANSFER_DST_OPTIMAL)
-            .image(host_image)
-            .subresource_range(
-                ImageSubresourceRange::builder()
-                    .aspect_mask(ImageAspectFlags::COLOR)
-                    .base_mip_level(0)
-                    .level_count(1)
-                    .base_array_layer(0)
-                    .layer_count(1)
-                    .build(),
-            )
-            .build();
-
-        backends.device.cmd_pipeline_barrier(
-            command_buffer,
-            PipelineStageFlags::TRANSFER,
-            PipelineStageFlags::TRANSFER,
-            DependencyFlags::empty(),
-            &[],
-            &[],
-            &[image_barrier],
-        );
-
-        //copy
-
-        let copy_region = ImageCopy::builder()
-            .src_subresource(
-                ImageSubresourceLayers::builder()
-                    .aspect_mask(ImageAspectFlags::COLOR)
-                    .layer_count(1)
-                    .build()
-            )
-            .dst_subresource(
-                ImageSubresourceLayers::builder()
-                    .aspect_mask(ImageAspectFlags::COLOR)
-                    .layer_count(1)
-                    .build()
-            )
-            .extent(extent3d)
-            .build();
-
-        backends.device.cmd_copy_image(
-            command_buffer,
-            target_image,
-            ImageLayout::GENERAL,
-            host_image,
-            ImageLayout::TRANSFER_DST_OPTIMAL,
-            &[copy_region],
-        );
-
-        let image_barrier = ImageMemoryBarrier::builder()
-            .src_access_mask(AccessFlags::TRANSFER_WRITE)
-            .dst_access_mask(AccessFlags::MEMORY_READ)
-            .old_layout(ImageLayout::TRANSFER_DST_OPTIMAL)
-            .new_layout(ImageLayout::GENERAL)
-            .image(host_image)
-            .subresource_range(
-                ImageSubresourceRange::builder()
-                    .aspect_mask(ImageAspectFlags::COLOR)
-                    .base_mip_level(0)
-                    .level_count(1)
-                    .base_array_layer(0)
-                    .layer_count(1)
-                    .build(),
-            )
-            .build();
-
-        backends.device.cmd_pipeline_barrier(
-            command_buffer,
-            PipelineStageFlags::TRANSFER,
-            PipelineStageFlags::TRANSFER,
-            DependencyFlags::empty(),
-            &[],
-            &[],
-            &[image_barrier]
-        );
-
-        backends.device.end_command_buffer(command_buffer).unwrap();
+    if !process_meshes {
+        return Ok(scene);
     }
 
-    let submit_infos = [
-        SubmitInfo::builder()
-            .command_buffers(&command_buffers)
-            .build()
-    ];
+    //書かれている法線は残し、ない場合のみ作る
+    let settings = MeshProcessingSettings {
+        normals: NormalMode::Keep,
+        ..MeshProcessingSettings::default()
+    };
 
-    unsafe {
-        backends
-            .device
-            .queue_submit(graphics_queue, &submit_infos, Fence::null())
-            .expect("queue submit failed");
+    let (scene, reports) = process_scene(&scene, &settings)?;
+    info!("mesh processing: {}", MeshProcessingReport::total(&reports));
 
-        backends.device.queue_wait_idle(graphics_queue).unwrap();
+    for (i, report) in reports.iter().enumerate().filter(|(_, report)| !report.is_unchanged()) {
+        debug!("mesh {}: {}", i, report);
     }
 
-    //png write
-
-    let subresource = ImageSubresource::builder()
-        .aspect_mask(ImageAspectFlags::COLOR)
-        .build();
+    Ok(scene)
+}
 
-    let subresource_layout = unsafe {
-        backends.device.get_image_subresource_layout(host_image, subresource)
+fn to_image(
+    shader_path: Option<&str>,
+    backend_choice: BackendChoice,
+    threads: Option<usize>,
+    frames: Option<FrameRange>,
+    fps: f32,
+    process_meshes: bool,
+) -> anyhow::Result<()> {
+    let scene = load_scene(process_meshes)?;
+    let settings = RenderSettings::new(DEFAULT_WINDOW_WIDTH, DEFAULT_WINDOW_HEIGHT);
+
+    let animation = Animation::classical();
+    animation.validate(&scene)?;
+
+    let outputs = match frames {
+        Some(frames) => frames
+            .frames()
+            .map(|frame| OutputFrame {
+                time: Some(frame_time(frame, fps)),
+                path: format!("./out_{:04}.png", frame),
+            })
+            .collect(),
+        None => vec![OutputFrame {
+            time: None,
+            path: "./out.png".to_owned(),
+        }],
     };
 
-    let data: *const u8 = unsafe {
-        backends
-            .device
-            .map_memory(
-                host_device_memory,
-                0,
-                WHOLE_SIZE,
-                MemoryMapFlags::empty(),
-            ).unwrap() as _
-    };
+    //Ctrl-Cで止めた場合もそこまでの結果を書き出す
+    interrupt::install_ctrl_c_handler();
 
-    let mut data = unsafe {
-        data.offset(subresource_layout.offset as isize)
-    };
+    match backend_choice {
+        BackendChoice::Host => render_with(&mut host_backend(threads), &scene, &animation, &settings, &outputs)?,
+        BackendChoice::Auto | BackendChoice::Vulkan => match Backends::new(None, true) {
+            Ok(backends) => render_with_vulkan(&backends, shader_path, &scene, &animation, &settings, &outputs)?,
+            Err(err) if backend_choice.falls_back_on(&err) => {
+                warn!("vulkan backend is unavailable, falling back to host: {}", err);
 
-    let mut png_encoder = png::Encoder::new(
-        File::create(image_file_path).unwrap(),
-        extent3d.width,
-        extent3d.height,
-    );
+                render_with(&mut host_backend(threads), &scene, &animation, &settings, &outputs)?
+            }
+            Err(err) => return Err(err.into()),
+        },
+    }
 
-    png_encoder.set_depth(png::BitDepth::Eight);
-    png_encoder.set_color(png::ColorType::Rgba);
+    debug!("done");
 
-    let mut png_writer = png_encoder
-        .write_header()
-        .unwrap()
-        .into_stream_writer_with_size((4 * extent3d.width) as usize)
-        .unwrap();
+    Ok(())
+}
 
-    //画像に詰めている時点で入っている値を全体の数で割って正規化していなかったのでここでしている?
-    for _ in 0..extent3d.height {
-        let row = unsafe {
-            std::slice::from_raw_parts(data, 4 * 4 * extent3d.width as usize)
-        };
-        let row_f32: &[f32] = bytemuck::cast_slice(row);
-        let row_rgba8: Vec<u8> = row_f32
-            .iter()
-            .map(|f| (256.0 * f.sqrt().clamp(0.0, 0.999)) as u8)
-            .collect();
-
-        png_writer.write_all(&row_rgba8).unwrap();
-        data = unsafe {
-            data.offset(subresource_layout.row_pitch as isize)
-        };
+fn host_backend(threads: Option<usize>) -> HostBackend {
+    match threads {
+        Some(threads) => HostBackend::new().threads(threads),
+        None => HostBackend::new(),
     }
+}
 
-    png_writer.finish().unwrap();
+fn render_with_vulkan(
+    backends: &Backends,
+    shader_path: Option<&str>,
+    scene: &SceneDescription,
+    animation: &Animation,
+    settings: &RenderSettings,
+    outputs: &[OutputFrame],
+) -> anyhow::Result<()> {
+    //オフラインで描く場合はbuildの時間よりメモリを優先する
+    let acceleration_structures = AccelerationStructures::new(backends).compact_blas(true);
+    let pipeline_caches = PipelineCaches::new(backends)?;
+
+    let mut backend = VulkanBackend::new(
+        backends,
+        &acceleration_structures,
+        &pipeline_caches,
+        load_shader_code(shader_path)?,
+    )?;
+
+    render_with(&mut backend, scene, animation, settings, outputs)
+}
 
-    unsafe {
-        backends.device.unmap_memory(host_device_memory);
-        backends.device.free_memory(host_device_memory, None);
-        backends.device.destroy_image(host_image, None);
+fn render_with(
+    backend: &mut dyn RenderBackend,
+    scene: &SceneDescription,
+    animation: &Animation,
+    settings: &RenderSettings,
+    outputs: &[OutputFrame],
+) -> anyhow::Result<()> {
+    info!("render with {}", backend.capabilities());
+
+    let cancellation = CancellationToken::new();
+    let mut previous_scene: Option<SceneDescription> = None;
+
+    for output_frame in outputs.iter() {
+        let (frame_scene, frame_settings) = match output_frame.time {
+            Some(time) => {
+                let frame = animation.evaluate(scene, settings.camera, time);
+                (frame.scene, RenderSettings { camera: frame.camera, ..*settings })
+            }
+            None => (scene.clone(), *settings),
+        };
 
-        backends.device.free_command_buffers(command_pool, &command_buffers);
-        backends.device.destroy_command_pool(command_pool, None);
+        //前のフレームから変わったものだけ作り直す
+        match previous_scene.as_ref() {
+            Some(previous_scene) => backend.update(&frame_scene, &frame_scene.changes_from(previous_scene))?,
+            None => {
+                backend.prepare(&frame_scene)?;
+
+                if let Some(stats) = backend.scene_stats() {
+                    info!("scene: {}", stats);
+                }
+            }
+        }
+
+        let mut control = RenderControl::new()
+            .cancellation(cancellation.clone())
+            .on_progress(|progress| {
+                //シグナルハンドラからは直接止められないので進捗のたびに確認する
+                if interrupt::is_interrupted() {
+                    cancellation.cancel();
+                }
+
+                eprint!("\r{} {}", output_frame.path, progress.progress_bar(PROGRESS_BAR_WIDTH));
+            });
+
+        let output = backend.render_with_control(&frame_settings, &mut control)?;
+        eprintln!();
+
+        //一つも描く前に中断された場合は真っ黒な画像で上書きしない
+        if output.samples_done == 0 {
+            warn!("render was cancelled before anything was rendered, {} was not written", output_frame.path);
+            break;
+        }
+
+        if output.cancelled {
+            warn!("render was cancelled, writing partial result");
+        }
+
+        output.image.save_png(&output_frame.path)?;
+
+        //残りのフレームは描かない
+        if output.cancelled {
+            break;
+        }
+
+        previous_scene = Some(frame_scene);
     }
 
-    debug!("save image");
     Ok(())
-}
\ No newline at end of file
+}
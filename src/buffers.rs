@@ -1,6 +1,8 @@
 use ash::Device;
 use ash::util::Align;
 use ash::vk::{Buffer, BufferCreateInfo, BufferDeviceAddressInfo, BufferUsageFlags, DeviceMemory, DeviceSize, MemoryAllocateFlags, MemoryAllocateFlagsInfo, MemoryAllocateInfo, MemoryMapFlags, MemoryPropertyFlags, PhysicalDeviceMemoryProperties, SharingMode};
+use crate::error::{CottonError, Result};
+use crate::get_memory_type_index;
 
 pub struct Buffers<'a> {
     device: &'a Device,
@@ -16,7 +18,7 @@ impl<'a> Buffers<'a> {
         size: DeviceSize,
         usage: BufferUsageFlags,
         memory_properties: MemoryPropertyFlags,
-    ) -> Self {
+    ) -> Result<Self> {
         let buffer_info = BufferCreateInfo::builder()
             .size(size)
             .usage(usage)
@@ -24,7 +26,7 @@ impl<'a> Buffers<'a> {
             .build();
 
         let buffer = unsafe {
-            device.create_buffer(&buffer_info, None).unwrap()
+            device.create_buffer(&buffer_info, None)?
         };
 
         //メモリサイズやアライメントなどの確保に必要な情報を持つ構造体
@@ -32,16 +34,22 @@ impl<'a> Buffers<'a> {
             device.get_buffer_memory_requirements(buffer)
         };
 
-        let mut memory_type_index = 0;
-
-        for i in 0..device_memory_properties.memory_type_count {
-            if (memory_requirements.memory_type_bits & 1) == 1 {
-                //PhysicalDeviceMemoryPropertiesの中からmemory_propertiesと同一のものを探す
-                if (device_memory_properties.memory_types[i as usize].property_flags & memory_properties) == memory_properties {
-                    memory_type_index = i;
-                }
+        //PhysicalDeviceMemoryPropertiesの中からmemory_propertiesと同一のものを探す
+        let memory_type_index = match get_memory_type_index(
+            &device_memory_properties,
+            memory_requirements.memory_type_bits,
+            memory_properties,
+        ) {
+            Some(memory_type_index) => memory_type_index,
+            None => {
+                unsafe { device.destroy_buffer(buffer, None) };
+
+                return Err(CottonError::NoMemoryType {
+                    type_filter: memory_requirements.memory_type_bits,
+                    property_flags: memory_properties,
+                });
             }
-        }
+        };
 
         let mut memory_allocate_flags_info = MemoryAllocateFlagsInfo::builder()
             //SHADER_DEVICE_ADDRESSの指定とvkGetDeviceMemoryOpaqueCaptureAddressでアドレスを取得できるようになる
@@ -62,20 +70,28 @@ impl<'a> Buffers<'a> {
             .build();
 
         //memoryはdeviceがネイティブに扱える管理単位
-        let memory = unsafe {
-            device.allocate_memory(&allocate_info, None).unwrap()
-        };
+        let memory = match unsafe { device.allocate_memory(&allocate_info, None) } {
+            Ok(memory) => memory,
+            Err(err) => {
+                unsafe { device.destroy_buffer(buffer, None) };
 
-        unsafe {
-            device.bind_buffer_memory(buffer, memory, 0).unwrap()
-        }
+                return Err(err.into());
+            }
+        };
 
-        Self {
+        //ここから先はDropで解放される
+        let buffers = Self {
             device,
             buffer,
             size,
             memory,
+        };
+
+        unsafe {
+            device.bind_buffer_memory(buffer, memory, 0)?;
         }
+
+        Ok(buffers)
     }
 
     pub fn get_buffer_address(&self) -> u64 {
@@ -89,23 +105,37 @@ impl<'a> Buffers<'a> {
         }
     }
 
-    pub fn store<T: Copy>(&mut self, data: &[T]) {
+    pub fn store<T: Copy>(&mut self, data: &[T]) -> Result<()> {
+        self.store_at(0, data)
+    }
+
+    /// offsetはbyte単位、offsetより前の内容はそのまま残る
+    pub fn store_at<T: Copy>(&mut self, offset: DeviceSize, data: &[T]) -> Result<()> {
         let size = (std::mem::size_of::<T>() * data.len()) as u64;
         //すでにBuffersが確保している領域よりも大きかったら弾く
-        assert!(self.size >= size);
-        let mapped_ptr = self.map(size);
+        if self.size < offset + size {
+            return Err(CottonError::BufferTooSmall {
+                required: offset + size,
+                allocated: self.size,
+            });
+        }
+        let mapped_ptr = unsafe {
+            self.device
+                .map_memory(self.memory, offset, size, MemoryMapFlags::empty())?
+        };
         let mut mapped_slice = unsafe {
             Align::new(mapped_ptr, std::mem::align_of::<T>() as u64, size)
         };
         mapped_slice.copy_from_slice(&data);
         self.unmap();
+
+        Ok(())
     }
 
-    pub fn map(&mut self, size: DeviceSize) -> *mut std::ffi::c_void {
+    pub fn map(&mut self, size: DeviceSize) -> Result<*mut std::ffi::c_void> {
         unsafe {
-            self.device
-                .map_memory(self.memory, 0, size, MemoryMapFlags::empty())
-                .unwrap()
+            Ok(self.device
+                .map_memory(self.memory, 0, size, MemoryMapFlags::empty())?)
         }
     }
 
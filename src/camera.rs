@@ -0,0 +1,534 @@
+use classical_raytracer_shader::RayGenerationConstants;
+use glam::{Mat4, Vec2, Vec3};
+use winit::dpi::PhysicalPosition;
+use winit::event::{ElementState, KeyboardInput, MouseButton, MouseScrollDelta, VirtualKeyCode, WindowEvent};
+
+//ピッチが真上と真下にならないように少しだけ狭める
+const MAX_PITCH: f32 = std::f32::consts::FRAC_PI_2 - 0.01;
+const MIN_ORBIT_DISTANCE: f32 = 0.1;
+//PixelDeltaをLineDelta相当に直す
+const PIXELS_PER_LINE: f32 = 20.0;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Camera {
+    pub position: Vec3,
+    /// y軸周りの回転、0で-z方向を向く
+    pub yaw: f32,
+    pub pitch: f32,
+    pub fov_y: f32,
+}
+
+impl Camera {
+    pub fn new(position: Vec3, yaw: f32, pitch: f32, fov_y: f32) -> Self {
+        Self {
+            position,
+            yaw,
+            pitch,
+            fov_y,
+        }
+    }
+
+    pub fn forward(&self) -> Vec3 {
+        Vec3::new(
+            -self.yaw.sin() * self.pitch.cos(),
+            self.pitch.sin(),
+            -self.yaw.cos() * self.pitch.cos(),
+        )
+    }
+
+    pub fn right(&self) -> Vec3 {
+        self.forward().cross(Vec3::Y).normalize()
+    }
+
+    pub fn up(&self) -> Vec3 {
+        self.right().cross(self.forward())
+    }
+
+    pub fn view_matrix(&self) -> Mat4 {
+        Mat4::look_at_rh(self.position, self.position + self.forward(), Vec3::Y)
+    }
+
+    /// raygenではこちらを使ってレイを飛ばす
+    pub fn inverse_view_matrix(&self) -> Mat4 {
+        self.view_matrix().inverse()
+    }
+
+    /// ndcは左下が(-1, -1)、右上が(1, 1)、aspectは幅/高さ
+    pub fn ray_direction(&self, ndc: Vec2, aspect: f32) -> Vec3 {
+        let half_height = (self.fov_y * 0.5).tan();
+
+        (self.forward()
+            + self.right() * ndc.x * half_height * aspect
+            + self.up() * ndc.y * half_height)
+            .normalize()
+    }
+
+    /// raygenのpush constant、aspectはシェーダーでlaunch_sizeから求める
+    /// seedは上位と下位を畳んでu32にする
+    pub fn ray_generation_constants(&self, sample_index: u32, samples: u32, seed: u64, max_depth: u32) -> RayGenerationConstants {
+        RayGenerationConstants {
+            origin: self.position.extend((self.fov_y * 0.5).tan()).to_array(),
+            forward: self.forward().extend(0.0).to_array(),
+            right: self.right().extend(0.0).to_array(),
+            up: self.up().extend(0.0).to_array(),
+            sample_index,
+            samples,
+            seed: (seed ^ (seed >> 32)) as u32,
+            max_depth,
+        }
+    }
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self::new(Vec3::new(0.0, 1.0, 5.0), 0.0, 0.0, 45f32.to_radians())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CameraMode {
+    /// WASDで移動、右ドラッグで視点移動
+    Fly,
+    /// 左ドラッグでtargetの周りを回転、スクロールで距離を変える
+    Orbit { target: Vec3, distance: f32 },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CameraSettings {
+    /// 1秒あたりの移動量
+    pub move_speed: f32,
+    /// 1pixelあたりの回転量
+    pub look_sensitivity: f32,
+    /// 1行あたりのorbitの距離の変化率
+    pub zoom_speed: f32,
+}
+
+impl Default for CameraSettings {
+    fn default() -> Self {
+        Self {
+            move_speed: 2.0,
+            look_sensitivity: 0.003,
+            zoom_speed: 0.1,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+struct MoveKeys {
+    forward: bool,
+    backward: bool,
+    left: bool,
+    right: bool,
+    up: bool,
+    down: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CameraUpdate {
+    pub camera: Camera,
+    /// 動いた場合は蓄積をやり直す
+    pub moved: bool,
+}
+
+/// winitのイベントを受け取りカメラを動かす
+/// 時間は外から渡すので合成したイベントだけで動かせる
+#[derive(Clone, Debug)]
+pub struct CameraController {
+    camera: Camera,
+    mode: CameraMode,
+    /// 最後にorbitで回っていた点、flyから切り替える場合の距離に使う
+    orbit_target: Vec3,
+    settings: CameraSettings,
+    keys: MoveKeys,
+    dragging: bool,
+    cursor: Option<PhysicalPosition<f64>>,
+    look_delta: (f32, f32),
+    scroll_lines: f32,
+}
+
+impl CameraController {
+    pub fn new(camera: Camera, mode: CameraMode, settings: CameraSettings) -> Self {
+        let mut controller = Self {
+            camera,
+            mode,
+            orbit_target: Vec3::ZERO,
+            settings,
+            keys: MoveKeys::default(),
+            dragging: false,
+            cursor: None,
+            look_delta: (0.0, 0.0),
+            scroll_lines: 0.0,
+        };
+
+        if let CameraMode::Orbit { target, distance } = mode {
+            controller.orbit_target = target;
+            controller.apply_orbit(target, distance);
+        }
+
+        controller
+    }
+
+    pub fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    pub fn mode(&self) -> CameraMode {
+        self.mode
+    }
+
+    /// 前のorbitのtargetまでの距離だけ視線の先に進んだ点をtargetにしてorbitに切り替える
+    /// orbitからはその位置のままflyに戻す
+    pub fn toggle_mode(&mut self) {
+        self.mode = match self.mode {
+            CameraMode::Fly => {
+                let distance = self.camera.position.distance(self.orbit_target).max(MIN_ORBIT_DISTANCE);
+                let target = self.camera.position + self.camera.forward() * distance;
+                self.orbit_target = target;
+
+                CameraMode::Orbit { target, distance }
+            }
+            CameraMode::Orbit { .. } => CameraMode::Fly,
+        };
+    }
+
+    fn drag_button(&self) -> MouseButton {
+        match self.mode {
+            CameraMode::Fly => MouseButton::Right,
+            CameraMode::Orbit { .. } => MouseButton::Left,
+        }
+    }
+
+    pub fn handle_event(&mut self, event: &WindowEvent) {
+        match event {
+            WindowEvent::KeyboardInput {
+                input: KeyboardInput { virtual_keycode: Some(key), state, .. },
+                ..
+            } => {
+                let pressed = *state == ElementState::Pressed;
+
+                match key {
+                    VirtualKeyCode::W => self.keys.forward = pressed,
+                    VirtualKeyCode::S => self.keys.backward = pressed,
+                    VirtualKeyCode::A => self.keys.left = pressed,
+                    VirtualKeyCode::D => self.keys.right = pressed,
+                    VirtualKeyCode::E | VirtualKeyCode::Space => self.keys.up = pressed,
+                    VirtualKeyCode::Q | VirtualKeyCode::LShift => self.keys.down = pressed,
+                    VirtualKeyCode::Tab if pressed => self.toggle_mode(),
+                    _ => {}
+                }
+            }
+            WindowEvent::MouseInput { state, button, .. } if *button == self.drag_button() => {
+                self.dragging = *state == ElementState::Pressed;
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                if let (Some(last), true) = (self.cursor, self.dragging) {
+                    self.look_delta.0 += (position.x - last.x) as f32;
+                    self.look_delta.1 += (position.y - last.y) as f32;
+                }
+
+                self.cursor = Some(*position);
+            }
+            WindowEvent::CursorLeft { .. } => {
+                self.cursor = None;
+            }
+            WindowEvent::Focused(false) => {
+                //フォーカスが外れるとキーを離したイベントが来ないことがある
+                self.keys = MoveKeys::default();
+                self.dragging = false;
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                self.scroll_lines += match delta {
+                    MouseScrollDelta::LineDelta(_, y) => *y,
+                    MouseScrollDelta::PixelDelta(position) => position.y as f32 / PIXELS_PER_LINE,
+                };
+            }
+            _ => {}
+        }
+    }
+
+    /// 毎フレーム前回からの経過秒数を渡す、移動量はdtに比例するのでフレームレートに依存しない
+    pub fn update(&mut self, dt: f32) -> CameraUpdate {
+        let before = self.camera;
+
+        let (dx, dy) = std::mem::take(&mut self.look_delta);
+        let scroll_lines = std::mem::take(&mut self.scroll_lines);
+
+        self.camera.yaw -= dx * self.settings.look_sensitivity;
+        self.camera.pitch = (self.camera.pitch - dy * self.settings.look_sensitivity).clamp(-MAX_PITCH, MAX_PITCH);
+
+        match self.mode {
+            CameraMode::Fly => {
+                let direction = self.move_direction();
+
+                if direction != Vec3::ZERO {
+                    self.camera.position += direction.normalize() * self.settings.move_speed * dt;
+                }
+            }
+            CameraMode::Orbit { target, distance } => {
+                //スクロール1行ごとに一定の割合で近づく
+                let distance = (distance * (1.0 - self.settings.zoom_speed).powf(scroll_lines)).max(MIN_ORBIT_DISTANCE);
+
+                self.mode = CameraMode::Orbit { target, distance };
+                self.apply_orbit(target, distance);
+            }
+        }
+
+        CameraUpdate {
+            camera: self.camera,
+            moved: self.camera != before,
+        }
+    }
+
+    fn move_direction(&self) -> Vec3 {
+        let axis = |positive: bool, negative: bool| (positive as i32 - negative as i32) as f32;
+
+        self.camera.forward() * axis(self.keys.forward, self.keys.backward)
+            + self.camera.right() * axis(self.keys.right, self.keys.left)
+            + Vec3::Y * axis(self.keys.up, self.keys.down)
+    }
+
+    fn apply_orbit(&mut self, target: Vec3, distance: f32) {
+        self.camera.position = target - self.camera.forward() * distance;
+    }
+}
+
+impl Default for CameraController {
+    fn default() -> Self {
+        Self::new(Camera::default(), CameraMode::Fly, CameraSettings::default())
+    }
+}
+
+/// プログレッシブレンダリングで蓄積したサンプル数
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Accumulation {
+    pub sample_count: u32,
+}
+
+impl Accumulation {
+    /// 描画する前に呼び、今回のフレームで使うサンプルの番号を返す
+    pub fn next_frame(&mut self, camera_moved: bool) -> u32 {
+        if camera_moved {
+            self.sample_count = 0;
+        }
+
+        let index = self.sample_count;
+        self.sample_count = self.sample_count.saturating_add(1);
+
+        index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(deprecated)]
+
+    use winit::event::{DeviceId, ModifiersState, TouchPhase};
+    use super::*;
+
+    fn device_id() -> DeviceId {
+        unsafe { DeviceId::dummy() }
+    }
+
+    fn key(key: VirtualKeyCode, state: ElementState) -> WindowEvent<'static> {
+        WindowEvent::KeyboardInput {
+            device_id: device_id(),
+            input: KeyboardInput {
+                scancode: 0,
+                state,
+                virtual_keycode: Some(key),
+                modifiers: ModifiersState::empty(),
+            },
+            is_synthetic: false,
+        }
+    }
+
+    fn mouse(button: MouseButton, state: ElementState) -> WindowEvent<'static> {
+        WindowEvent::MouseInput {
+            device_id: device_id(),
+            state,
+            button,
+            modifiers: ModifiersState::empty(),
+        }
+    }
+
+    fn cursor(x: f64, y: f64) -> WindowEvent<'static> {
+        WindowEvent::CursorMoved {
+            device_id: device_id(),
+            position: PhysicalPosition::new(x, y),
+            modifiers: ModifiersState::empty(),
+        }
+    }
+
+    fn scroll(lines: f32) -> WindowEvent<'static> {
+        WindowEvent::MouseWheel {
+            device_id: device_id(),
+            delta: MouseScrollDelta::LineDelta(0.0, lines),
+            phase: TouchPhase::Moved,
+            modifiers: ModifiersState::empty(),
+        }
+    }
+
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!(a.abs_diff_eq(b, 1e-4), "{} != {}", a, b);
+    }
+
+    #[test]
+    fn held_key_moves_proportionally_to_dt() {
+        let mut controller = CameraController::default();
+        let start = controller.camera().position;
+
+        controller.handle_event(&key(VirtualKeyCode::W, ElementState::Pressed));
+        let update = controller.update(0.5);
+
+        assert!(update.moved);
+        assert_near(update.camera.position, start + Vec3::new(0.0, 0.0, -1.0));
+
+        controller.handle_event(&key(VirtualKeyCode::W, ElementState::Released));
+        let update = controller.update(0.5);
+
+        assert!(!update.moved);
+    }
+
+    #[test]
+    fn opposite_keys_cancel_out() {
+        let mut controller = CameraController::default();
+
+        controller.handle_event(&key(VirtualKeyCode::A, ElementState::Pressed));
+        controller.handle_event(&key(VirtualKeyCode::D, ElementState::Pressed));
+
+        assert!(!controller.update(1.0).moved);
+    }
+
+    #[test]
+    fn losing_focus_releases_keys() {
+        let mut controller = CameraController::default();
+
+        controller.handle_event(&key(VirtualKeyCode::Space, ElementState::Pressed));
+        controller.handle_event(&WindowEvent::Focused(false));
+
+        assert!(!controller.update(1.0).moved);
+    }
+
+    #[test]
+    fn fly_looks_only_while_right_dragging() {
+        let mut controller = CameraController::default();
+
+        controller.handle_event(&cursor(0.0, 0.0));
+        controller.handle_event(&cursor(100.0, 0.0));
+        assert!(!controller.update(0.0).moved);
+
+        controller.handle_event(&mouse(MouseButton::Right, ElementState::Pressed));
+        controller.handle_event(&cursor(110.0, 20.0));
+        let update = controller.update(0.0);
+
+        //右に動かすと右を向き、下に動かすと下を向く
+        assert!((update.camera.yaw - -0.03).abs() < 1e-6);
+        assert!((update.camera.pitch - -0.06).abs() < 1e-6);
+        assert_eq!(update.camera.position, Camera::default().position);
+
+        //左ボタンはflyでは使わない
+        controller.handle_event(&mouse(MouseButton::Right, ElementState::Released));
+        controller.handle_event(&mouse(MouseButton::Left, ElementState::Pressed));
+        controller.handle_event(&cursor(200.0, 20.0));
+        assert!(!controller.update(0.0).moved);
+    }
+
+    #[test]
+    fn pitch_is_clamped() {
+        let mut controller = CameraController::default();
+
+        controller.handle_event(&cursor(0.0, 0.0));
+        controller.handle_event(&mouse(MouseButton::Right, ElementState::Pressed));
+        controller.handle_event(&cursor(0.0, -100_000.0));
+
+        assert_eq!(controller.update(0.0).camera.pitch, MAX_PITCH);
+    }
+
+    #[test]
+    fn orbit_keeps_looking_at_target() {
+        let target = Vec3::new(1.0, 0.0, -2.0);
+        let mut controller = CameraController::new(
+            Camera::default(),
+            CameraMode::Orbit { target, distance: 4.0 },
+            CameraSettings::default(),
+        );
+
+        controller.handle_event(&cursor(0.0, 0.0));
+        controller.handle_event(&mouse(MouseButton::Left, ElementState::Pressed));
+        controller.handle_event(&cursor(300.0, -100.0));
+        let camera = controller.update(0.0).camera;
+
+        assert!((camera.position.distance(target) - 4.0).abs() < 1e-4);
+        assert_near(camera.position + camera.forward() * 4.0, target);
+    }
+
+    #[test]
+    fn scrolling_zooms_orbit_towards_target() {
+        let target = Vec3::ZERO;
+        let mut controller = CameraController::new(
+            Camera::default(),
+            CameraMode::Orbit { target, distance: 4.0 },
+            CameraSettings::default(),
+        );
+
+        controller.handle_event(&scroll(2.0));
+        let update = controller.update(0.0);
+
+        assert!(update.moved);
+        assert!((update.camera.position.distance(target) - 4.0 * 0.9 * 0.9).abs() < 1e-4);
+
+        //いくら近づいても最小の距離で止まる
+        controller.handle_event(&scroll(1000.0));
+        let camera = controller.update(0.0).camera;
+        assert!((camera.position.distance(target) - MIN_ORBIT_DISTANCE).abs() < 1e-4);
+    }
+
+    #[test]
+    fn toggling_back_to_orbit_keeps_the_target_distance() {
+        let target = Vec3::new(5.0, 0.0, 0.0);
+        let mut controller = CameraController::new(
+            Camera::default(),
+            CameraMode::Orbit { target, distance: 2.0 },
+            CameraSettings::default(),
+        );
+
+        controller.handle_event(&key(VirtualKeyCode::Tab, ElementState::Pressed));
+        assert_eq!(controller.mode(), CameraMode::Fly);
+
+        //原点からの距離ではなくtargetまでの距離を使う
+        controller.handle_event(&key(VirtualKeyCode::Tab, ElementState::Pressed));
+        match controller.mode() {
+            CameraMode::Orbit { target: new_target, distance } => {
+                assert!((distance - 2.0).abs() < 1e-4);
+                assert_near(new_target, target);
+            }
+            CameraMode::Fly => panic!("expected orbit"),
+        }
+
+        //切り替えただけでは動かない
+        assert!(!controller.update(0.0).moved);
+    }
+
+    #[test]
+    fn accumulation_restarts_when_camera_moves() {
+        let mut accumulation = Accumulation::default();
+
+        assert_eq!(accumulation.next_frame(false), 0);
+        assert_eq!(accumulation.next_frame(false), 1);
+        assert_eq!(accumulation.next_frame(true), 0);
+        assert_eq!(accumulation.next_frame(false), 1);
+    }
+
+    #[test]
+    fn ray_generation_constants_match_camera() {
+        let camera = Camera::new(Vec3::new(1.0, 2.0, 3.0), 0.0, 0.0, 90f32.to_radians());
+        let constants = camera.ray_generation_constants(4, 2, 0x1_0000_0003, 8);
+
+        assert!((constants.origin[3] - 1.0).abs() < 1e-6);
+        assert_eq!(&constants.origin[..3], &[1.0, 2.0, 3.0]);
+        assert_eq!(constants.forward, [0.0, 0.0, -1.0, 0.0]);
+        assert_eq!(constants.right, [1.0, 0.0, 0.0, 0.0]);
+        assert_eq!(constants.up, [0.0, 1.0, 0.0, 0.0]);
+        assert_eq!((constants.sample_index, constants.samples, constants.seed, constants.max_depth), (4, 2, 2, 8));
+    }
+}
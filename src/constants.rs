@@ -1,10 +1,21 @@
 pub const DEFAULT_WINDOW_WIDTH: u32 = 1920;
 pub const DEFAULT_WINDOW_HEIGHT: u32 = 1080;
 
-pub const MAX_FRAMES_IN_FLIGHT: u32 = 1;
+//CPUがGPUより先に記録できるフレーム数
+pub const MAX_FRAMES_IN_FLIGHT: usize = 2;
+
+//ウィンドウ表示で1フレームに1ピクセルあたり飛ばすサンプル数と反射の回数
+pub const INTERACTIVE_SAMPLES_PER_FRAME: u32 = 1;
+pub const INTERACTIVE_MAX_DEPTH: u32 = 8;
 
 pub const APPLICATION_NAME: &str = "cotton";
 
+//name:, index:, uuid:のいずれかで使用するデバイスを指定する
+pub const DEVICE_SELECTION_ENV: &str = "COTTON_DEVICE";
+
+//pipeline cacheを保存するディレクトリ、指定がなければユーザーのキャッシュディレクトリ
+pub const PIPELINE_CACHE_DIR_ENV: &str = "COTTON_PIPELINE_CACHE_DIR";
+
 //マクロにしてくれ
 pub const VERTEX_SHADER_ENTRY_NAME: &str = "main_vertex";
 pub const VERTEX_SHADER_ENTRY_NAME_BYTE: &[u8] = b"main_vertex\0";
@@ -22,3 +33,12 @@ pub const TRIANGLE_CLOSEST_HIT_SHADER_ENTRY_NAME: &str = "triangle_closest_hit";
 pub const TRIANGLE_CLOSEST_HIT_SHADER_ENTRY_NAME_BYTE: &[u8] = b"triangle_closest_hit\0";
 pub const TRIANGLE_ANY_HIT_SHADER_ENTRY_NAME: &str = "triangle_any_hit";
 pub const TRIANGLE_ANY_HIT_SHADER_ENTRY_NAME_BYTE: &[u8] = b"triangle_any_hit\0";
+//マテリアルごとのcallable
+pub const LAMBERTIAN_CALLABLE_ENTRY_NAME: &str = "lambertian_callable";
+pub const METAL_CALLABLE_ENTRY_NAME: &str = "metal_callable";
+pub const DIELECTRIC_CALLABLE_ENTRY_NAME: &str = "dielectric_callable";
+pub const EMISSIVE_CALLABLE_ENTRY_NAME: &str = "emissive_callable";
+
+//シーンのinstanceはこの名前でhit groupを参照する
+pub const SPHERE_HIT_GROUP_NAME: &str = "sphere";
+pub const TRIANGLE_HIT_GROUP_NAME: &str = "triangle";
@@ -0,0 +1,121 @@
+use std::ffi::NulError;
+use ash::vk;
+use thiserror::Error;
+use crate::renderer::backends::physical_device_selector::DeviceRejection;
+
+pub type Result<T> = std::result::Result<T, CottonError>;
+
+#[derive(Debug, Error)]
+pub enum CottonError {
+    #[error("failed to load vulkan library: {0}")]
+    Loading(#[from] ash::LoadingError),
+
+    #[error("vulkan error: {0}")]
+    Vulkan(vk::Result),
+
+    //ERROR_OUT_OF_HOST_MEMORYとERROR_OUT_OF_DEVICE_MEMORYはこちらにまとめる
+    #[error("out of memory: {0}")]
+    OutOfMemory(vk::Result),
+
+    #[error("missing extensions: {0:?}")]
+    MissingExtensions(Vec<String>),
+
+    #[error("missing layers: {0:?}")]
+    MissingLayers(Vec<String>),
+
+    #[error("no suitable physical device: {}", join_rejections(.0))]
+    NoSuitablePhysicalDevice(Vec<DeviceRejection>),
+
+    #[error("no physical device matches {0}")]
+    PhysicalDeviceNotFound(String),
+
+    #[error("missing queue family: {0}")]
+    MissingQueueFamily(&'static str),
+
+    #[error("no memory type matches filter {type_filter:#b} with {property_flags:?}")]
+    NoMemoryType {
+        type_filter: u32,
+        property_flags: vk::MemoryPropertyFlags,
+    },
+
+    #[error("buffer too small: required {required} bytes, but allocated {allocated} bytes")]
+    BufferTooSmall {
+        required: vk::DeviceSize,
+        allocated: vk::DeviceSize,
+    },
+
+    #[error("format {format:?} does not support {features:?}")]
+    UnsupportedFormat {
+        format: vk::Format,
+        features: vk::FormatFeatureFlags,
+    },
+
+    #[error("no surface format available")]
+    NoSurfaceFormat,
+
+    #[error("swapchain images do not support {0:?}")]
+    UnsupportedSwapchainUsage(vk::ImageUsageFlags),
+
+    #[error("surface is required but backends are headless")]
+    SurfaceRequired,
+
+    #[error("unsupported window handle: {0}")]
+    UnsupportedWindowHandle(String),
+
+    #[error("failed to load shader: {0}")]
+    ShaderLoad(String),
+
+    #[error("shader does not match pipeline layout: {}", .0.join("; "))]
+    ShaderValidation(Vec<String>),
+
+    #[error("invalid ray tracing pipeline description: {0}")]
+    InvalidPipelineDesc(String),
+
+    #[error("invalid shader binding table: {0}")]
+    InvalidShaderBindingTable(String),
+
+    #[error("invalid descriptor set: {0}")]
+    InvalidDescriptorSet(String),
+
+    #[error("invalid scene: {0}")]
+    InvalidScene(String),
+
+    #[error("invalid render settings: {0}")]
+    InvalidRenderSettings(String),
+
+    #[error("render worker thread panicked")]
+    RenderWorkerPanicked,
+
+    #[error("failed to encode image: {0}")]
+    ImageEncode(#[from] png::EncodingError),
+
+    #[error("failed to decode texture: {0}")]
+    TextureDecode(String),
+
+    #[error("failed to create window: {0}")]
+    Window(#[from] winit::error::OsError),
+
+    #[error("invalid name: {0}")]
+    InvalidName(#[from] NulError),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl From<vk::Result> for CottonError {
+    fn from(result: vk::Result) -> Self {
+        match result {
+            vk::Result::ERROR_OUT_OF_HOST_MEMORY
+            | vk::Result::ERROR_OUT_OF_DEVICE_MEMORY => Self::OutOfMemory(result),
+            _ => Self::Vulkan(result),
+        }
+    }
+}
+
+fn join_rejections(rejections: &[DeviceRejection]) -> String {
+    rejections
+        .iter()
+        .map(|rejection| rejection.to_string())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
@@ -0,0 +1,96 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+//シグナルハンドラからはこれを立てるだけにする
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// 一回目のCtrl-Cはフラグを立てるだけにし、二回目は通常通り終了させる
+pub fn install_ctrl_c_handler() {
+    platform::install();
+}
+
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::os::raw::c_int;
+    use std::sync::atomic::Ordering;
+    use super::INTERRUPTED;
+
+    pub(super) const SIGINT: c_int = 2;
+    pub(super) const SIG_DFL: usize = 0;
+
+    extern "C" {
+        //sighandler_tはポインタと同じ大きさ
+        pub(super) fn signal(signum: c_int, handler: usize) -> usize;
+    }
+
+    pub(super) extern "C" fn on_sigint(_: c_int) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+
+        //次のCtrl-Cではそのまま終了する
+        unsafe { signal(SIGINT, SIG_DFL) };
+    }
+
+    pub fn install() {
+        unsafe { signal(SIGINT, on_sigint as extern "C" fn(c_int) as usize) };
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::sync::atomic::Ordering;
+    use super::INTERRUPTED;
+
+    extern "system" {
+        fn SetConsoleCtrlHandler(handler: Option<unsafe extern "system" fn(u32) -> i32>, add: i32) -> i32;
+    }
+
+    pub(super) unsafe extern "system" fn on_ctrl(_: u32) -> i32 {
+        //既に立っていれば0を返して既定のハンドラで終了させる
+        !INTERRUPTED.swap(true, Ordering::SeqCst) as i32
+    }
+
+    pub fn install() {
+        unsafe { SetConsoleCtrlHandler(Some(on_ctrl), 1) };
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod platform {
+    pub fn install() {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //本物のシグナルは送らずにハンドラを直接呼ぶ
+    #[cfg(unix)]
+    #[test]
+    fn first_ctrl_c_sets_the_flag_and_rearms_the_default() {
+        use platform::{on_sigint, signal, SIGINT, SIG_DFL};
+
+        install_ctrl_c_handler();
+
+        let handler = on_sigint as extern "C" fn(_) as usize;
+        assert_eq!(unsafe { signal(SIGINT, handler) }, handler);
+        assert!(!is_interrupted());
+
+        on_sigint(SIGINT);
+
+        assert!(is_interrupted());
+        //二回目はSIG_DFLで終了する
+        assert_eq!(unsafe { signal(SIGINT, SIG_DFL) }, SIG_DFL);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn second_ctrl_c_falls_through_to_the_default_handler() {
+        assert!(!is_interrupted());
+        assert_eq!(unsafe { platform::on_ctrl(0) }, 1);
+        assert!(is_interrupted());
+        assert_eq!(unsafe { platform::on_ctrl(0) }, 0);
+    }
+}
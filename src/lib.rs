@@ -2,7 +2,7 @@
 
 extern crate core;
 
-use ash::vk::{MemoryPropertyFlags, PhysicalDeviceMemoryProperties};
+use ash::vk::{DeviceSize, MemoryPropertyFlags, PhysicalDeviceMemoryProperties};
 
 mod classical_raytracer;
 pub mod window_handlers;
@@ -10,6 +10,15 @@ pub mod constants;
 pub mod renderer;
 pub mod buffers;
 pub mod scene;
+pub mod error;
+pub mod camera;
+pub mod scene_description;
+pub mod render_backend;
+pub mod interrupt;
+pub mod animation;
+pub mod mesh_processing;
+pub mod tangents;
+pub mod textures;
 
 pub fn get_memory_type_index(
     physical_device_memory_properties: &PhysicalDeviceMemoryProperties,
@@ -23,4 +32,28 @@ pub fn get_memory_type_index(
         }
     }
     None
-}
\ No newline at end of file
+}
+
+/// alignmentの倍数に切り上げる、0と1の場合はそのまま
+pub fn align_up(value: DeviceSize, alignment: DeviceSize) -> DeviceSize {
+    if alignment <= 1 {
+        return value;
+    }
+
+    (value + alignment - 1) / alignment * alignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn align_up_rounds_to_the_next_multiple() {
+        assert_eq!(align_up(0, 256), 0);
+        assert_eq!(align_up(1, 256), 256);
+        assert_eq!(align_up(256, 256), 256);
+        assert_eq!(align_up(257, 256), 512);
+        assert_eq!(align_up(13, 0), 13);
+        assert_eq!(align_up(13, 1), 13);
+    }
+}
@@ -0,0 +1,623 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use glam::Vec3;
+use crate::error::{CottonError, Result};
+use crate::scene_description::{MeshDescription, SceneDescription};
+
+//同じ向きとみなす法線の内積
+const SAME_NORMAL_COS: f32 = 1.0 - 1e-6;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NormalMode {
+    /// 元の法線を使う、positionsと数が合わない場合はSmoothのデフォルトで作り直す
+    Keep,
+    /// 同じ平面上の三角形のみ頂点を共有する
+    Flat,
+    /// 面の法線のなす角がcrease_angle(ラジアン)以下の三角形同士で角度の重み付きで平均する
+    Smooth { crease_angle: f32 },
+}
+
+impl NormalMode {
+    pub fn smooth() -> Self {
+        Self::Smooth {
+            crease_angle: DEFAULT_CREASE_ANGLE,
+        }
+    }
+}
+
+pub const DEFAULT_CREASE_ANGLE: f32 = std::f32::consts::FRAC_PI_3;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MeshProcessingSettings {
+    /// この距離以内の頂点を一つにまとめる、0の場合は位置が完全に同じものだけ
+    pub weld_epsilon: f32,
+    /// この面積以下の三角形を取り除く
+    pub degenerate_area: f32,
+    /// 頂点の組が同じ三角形を巻き順に関係なく一つにする
+    pub remove_duplicate_triangles: bool,
+    pub normals: NormalMode,
+}
+
+impl Default for MeshProcessingSettings {
+    fn default() -> Self {
+        Self {
+            weld_epsilon: 1e-5,
+            degenerate_area: 1e-12,
+            remove_duplicate_triangles: true,
+            normals: NormalMode::smooth(),
+        }
+    }
+}
+
+/// process_meshで変わったもの
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MeshProcessingReport {
+    pub input_vertices: usize,
+    pub input_triangles: usize,
+    /// 他の頂点にまとめて減った数
+    pub welded_vertices: usize,
+    pub degenerate_triangles: usize,
+    pub duplicate_triangles: usize,
+    /// どの三角形からも参照されずに取り除いた数
+    pub unused_vertices: usize,
+    /// 折り目で法線が分かれて増えた数
+    pub split_vertices: usize,
+    pub generated_normals: bool,
+    pub output_vertices: usize,
+    pub output_triangles: usize,
+}
+
+impl MeshProcessingReport {
+    pub fn is_unchanged(&self) -> bool {
+        self.welded_vertices == 0
+            && self.degenerate_triangles == 0
+            && self.duplicate_triangles == 0
+            && self.unused_vertices == 0
+            && self.split_vertices == 0
+            && !self.generated_normals
+    }
+
+    /// シーン全体で一行にまとめる
+    pub fn total(reports: &[Self]) -> Self {
+        reports.iter().fold(Self::default(), |total, report| Self {
+            input_vertices: total.input_vertices + report.input_vertices,
+            input_triangles: total.input_triangles + report.input_triangles,
+            welded_vertices: total.welded_vertices + report.welded_vertices,
+            degenerate_triangles: total.degenerate_triangles + report.degenerate_triangles,
+            duplicate_triangles: total.duplicate_triangles + report.duplicate_triangles,
+            unused_vertices: total.unused_vertices + report.unused_vertices,
+            split_vertices: total.split_vertices + report.split_vertices,
+            generated_normals: total.generated_normals || report.generated_normals,
+            output_vertices: total.output_vertices + report.output_vertices,
+            output_triangles: total.output_triangles + report.output_triangles,
+        })
+    }
+}
+
+impl fmt::Display for MeshProcessingReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} vertices, {} -> {} triangles (welded {}, degenerate {}, duplicate {}, unused {}, split {}",
+            self.input_vertices,
+            self.output_vertices,
+            self.input_triangles,
+            self.output_triangles,
+            self.welded_vertices,
+            self.degenerate_triangles,
+            self.duplicate_triangles,
+            self.unused_vertices,
+            self.split_vertices,
+        )?;
+
+        if self.generated_normals {
+            write!(f, ", generated normals")?;
+        }
+
+        write!(f, ")")
+    }
+}
+
+/// アセットをMeshBufferに渡す前に整える
+/// 法線を作り直した場合は接線も合わなくなるので取り除く
+pub fn process_mesh(
+    mesh: &MeshDescription,
+    settings: &MeshProcessingSettings,
+) -> Result<(MeshDescription, MeshProcessingReport)> {
+    validate_mesh(mesh)?;
+
+    let mut report = MeshProcessingReport {
+        input_vertices: mesh.positions.len(),
+        input_triangles: mesh.triangle_count(),
+        ..MeshProcessingReport::default()
+    };
+
+    let normal_mode = match settings.normals {
+        NormalMode::Keep if mesh.normals.len() != mesh.positions.len() => NormalMode::smooth(),
+        mode => mode,
+    };
+    let keep_normals = normal_mode == NormalMode::Keep;
+    report.generated_normals = !keep_normals;
+
+    //法線を残す場合は法線と接線も同じ頂点だけをまとめる、UVは常に同じものだけ
+    let remap = weld_vertices(&mesh.positions, settings.weld_epsilon, |a, b| {
+        (!keep_normals || mesh.normals[a].dot(mesh.normals[b]) >= SAME_NORMAL_COS)
+            && (!keep_normals || !mesh.has_tangents() || mesh.tangents[a] == mesh.tangents[b])
+            && (!mesh.has_tex_coords() || mesh.tex_coords[a] == mesh.tex_coords[b])
+    });
+
+    let mut triangles: Vec<[u32; 3]> = mesh
+        .indices
+        .chunks_exact(3)
+        .map(|indices| [remap[indices[0] as usize], remap[indices[1] as usize], remap[indices[2] as usize]])
+        .collect();
+
+    let before = triangles.len();
+    triangles.retain(|triangle| !is_degenerate(&mesh.positions, triangle, settings.degenerate_area));
+    report.degenerate_triangles = before - triangles.len();
+
+    if settings.remove_duplicate_triangles {
+        let mut seen = HashSet::new();
+        let before = triangles.len();
+
+        triangles.retain(|triangle| {
+            let mut key = *triangle;
+            key.sort_unstable();
+            seen.insert(key)
+        });
+
+        report.duplicate_triangles = before - triangles.len();
+    }
+
+    let welded_count = remap
+        .iter()
+        .enumerate()
+        .filter(|(i, representative)| *i == **representative as usize)
+        .count();
+    report.welded_vertices = mesh.positions.len() - welded_count;
+
+    let used_count = triangles
+        .iter()
+        .flat_map(|triangle| triangle.iter())
+        .collect::<HashSet<_>>()
+        .len();
+    report.unused_vertices = welded_count - used_count;
+
+    let processed = match normal_mode {
+        NormalMode::Keep => compact_vertices(mesh, &triangles),
+        NormalMode::Flat => with_generated_normals(mesh, &triangles, 0.0),
+        NormalMode::Smooth { crease_angle } => with_generated_normals(mesh, &triangles, crease_angle),
+    };
+
+    report.split_vertices = processed.positions.len() - used_count;
+    report.output_vertices = processed.positions.len();
+    report.output_triangles = processed.triangle_count();
+
+    Ok((processed, report))
+}
+
+/// シーンの全てのメッシュにprocess_meshをかける、reportはmeshesと同じ順番
+/// メッシュの番号は変わらないのでinstanceはそのまま使える
+pub fn process_scene(
+    scene: &SceneDescription,
+    settings: &MeshProcessingSettings,
+) -> Result<(SceneDescription, Vec<MeshProcessingReport>)> {
+    let (meshes, reports) = scene
+        .meshes
+        .iter()
+        .map(|mesh| process_mesh(mesh, settings))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .unzip();
+
+    let processed = SceneDescription {
+        meshes,
+        ..scene.clone()
+    };
+
+    Ok((processed, reports))
+}
+
+pub(crate) fn validate_mesh(mesh: &MeshDescription) -> Result<()> {
+    if mesh.indices.len() % 3 != 0 {
+        return Err(CottonError::InvalidScene(format!(
+            "mesh has {} indices, which is not a multiple of 3",
+            mesh.indices.len(),
+        )));
+    }
+
+    if let Some(index) = mesh.indices.iter().find(|index| **index as usize >= mesh.positions.len()) {
+        return Err(CottonError::InvalidScene(format!(
+            "mesh refers to vertex {}, but has only {} vertices",
+            index,
+            mesh.positions.len(),
+        )));
+    }
+
+    for (name, len) in [("texture coordinates", mesh.tex_coords.len()), ("tangents", mesh.tangents.len())] {
+        if len != 0 && len != mesh.positions.len() {
+            return Err(CottonError::InvalidScene(format!(
+                "mesh has {} {} for {} positions",
+                len,
+                name,
+                mesh.positions.len(),
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// 各頂点をまとめた先の頂点のindex、まとめた先は自分より前の頂点になる
+/// epsilonの大きさの格子に分けて隣の格子までを探す
+/// same_attributesは位置以外も同じとみなせる頂点のindexの組でtrueを返す
+pub fn weld_vertices(
+    positions: &[Vec3],
+    epsilon: f32,
+    same_attributes: impl Fn(usize, usize) -> bool,
+) -> Vec<u32> {
+    let epsilon = epsilon.max(0.0);
+    //0の場合は同じ位置なら同じ格子に入ればよいので適当な大きさにする
+    let cell_size = if epsilon > 0.0 { epsilon } else { 1e-4 };
+    let cell = |position: Vec3| {
+        let cell = (position / cell_size).floor();
+        (cell.x as i64, cell.y as i64, cell.z as i64)
+    };
+
+    let mut grid: HashMap<(i64, i64, i64), Vec<u32>> = HashMap::new();
+    let mut remap = Vec::with_capacity(positions.len());
+
+    for (i, position) in positions.iter().enumerate() {
+        let (x, y, z) = cell(*position);
+
+        let same = |candidate: u32| {
+            let candidate = candidate as usize;
+
+            positions[candidate].distance(*position) <= epsilon && same_attributes(candidate, i)
+        };
+
+        let existing = (-1..=1)
+            .flat_map(|dx| (-1..=1).flat_map(move |dy| (-1..=1).map(move |dz| (x + dx, y + dy, z + dz))))
+            .filter_map(|key| grid.get(&key))
+            .flat_map(|candidates| candidates.iter().copied())
+            .filter(|candidate| same(*candidate))
+            .min();
+
+        match existing {
+            Some(representative) => remap.push(representative),
+            None => {
+                grid.entry((x, y, z)).or_default().push(i as u32);
+                remap.push(i as u32);
+            }
+        }
+    }
+
+    remap
+}
+
+fn is_degenerate(positions: &[Vec3], triangle: &[u32; 3], degenerate_area: f32) -> bool {
+    if triangle[0] == triangle[1] || triangle[1] == triangle[2] || triangle[2] == triangle[0] {
+        return true;
+    }
+
+    let [p0, p1, p2] = triangle.map(|i| positions[i as usize]);
+
+    (p1 - p0).cross(p2 - p0).length() * 0.5 <= degenerate_area
+}
+
+//使われている頂点だけを元の順番のまま残す
+fn compact_vertices(mesh: &MeshDescription, triangles: &[[u32; 3]]) -> MeshDescription {
+    let mut new_index = vec![u32::MAX; mesh.positions.len()];
+    let mut processed = MeshDescription::default();
+
+    let mut used: Vec<u32> = triangles.iter().flat_map(|triangle| triangle.iter().copied()).collect();
+    used.sort_unstable();
+    used.dedup();
+
+    for old in used {
+        new_index[old as usize] = processed.positions.len() as u32;
+        processed.positions.push(mesh.positions[old as usize]);
+        processed.normals.push(mesh.normals[old as usize]);
+
+        if mesh.has_tex_coords() {
+            processed.tex_coords.push(mesh.tex_coords[old as usize]);
+        }
+
+        if mesh.has_tangents() {
+            processed.tangents.push(mesh.tangents[old as usize]);
+        }
+    }
+
+    processed.indices = triangles
+        .iter()
+        .flat_map(|triangle| triangle.iter().map(|i| new_index[*i as usize]))
+        .collect();
+
+    processed
+}
+
+/// 角度の重み付きの法線を作り、折り目では頂点を分ける
+/// crease_angleが0の場合は同じ平面上の三角形のみ頂点を共有する
+/// trianglesはmeshの頂点を参照する、法線が変わるので接線は残さない
+pub fn with_generated_normals(mesh: &MeshDescription, triangles: &[[u32; 3]], crease_angle: f32) -> MeshDescription {
+    let positions = &mesh.positions;
+    let face_normals: Vec<Vec3> = triangles
+        .iter()
+        .map(|triangle| {
+            let [p0, p1, p2] = triangle.map(|i| positions[i as usize]);
+            (p1 - p0).cross(p2 - p0).normalize_or_zero()
+        })
+        .collect();
+
+    //頂点ごとにそれを使う(三角形, 角)
+    let mut corners: Vec<Vec<(usize, usize)>> = vec![vec![]; positions.len()];
+    for (t, triangle) in triangles.iter().enumerate() {
+        for (c, i) in triangle.iter().enumerate() {
+            corners[*i as usize].push((t, c));
+        }
+    }
+
+    let corner_angle = |t: usize, c: usize| {
+        let triangle = triangles[t];
+        let p = positions[triangle[c] as usize];
+        let a = positions[triangle[(c + 1) % 3] as usize] - p;
+        let b = positions[triangle[(c + 2) % 3] as usize] - p;
+
+        a.angle_between(b)
+    };
+
+    //誤差で同じ平面の三角形が分かれないように少しだけ広げる
+    let crease_cos = crease_angle.cos().min(SAME_NORMAL_COS);
+
+    let mut processed = MeshDescription::default();
+    let mut indices = vec![[0u32; 3]; triangles.len()];
+
+    for (vertex, vertex_corners) in corners.iter().enumerate() {
+        //この頂点で作った(法線, 新しいindex)
+        let mut split: Vec<(Vec3, u32)> = vec![];
+
+        for (t, c) in vertex_corners.iter() {
+            let own = face_normals[*t];
+
+            let normal = vertex_corners
+                .iter()
+                .filter(|(other, _)| face_normals[*other].dot(own) >= crease_cos)
+                .map(|(other, other_c)| face_normals[*other] * corner_angle(*other, *other_c))
+                .fold(Vec3::ZERO, |sum, normal| sum + normal)
+                .normalize_or_zero();
+            let normal = if normal == Vec3::ZERO { own } else { normal };
+
+            let index = match split.iter().find(|(existing, _)| existing.dot(normal) >= SAME_NORMAL_COS) {
+                Some((_, index)) => *index,
+                None => {
+                    let index = processed.positions.len() as u32;
+                    processed.positions.push(positions[vertex]);
+                    processed.normals.push(normal);
+
+                    if mesh.has_tex_coords() {
+                        processed.tex_coords.push(mesh.tex_coords[vertex]);
+                    }
+
+                    split.push((normal, index));
+                    index
+                }
+            };
+
+            indices[*t][*c] = index;
+        }
+    }
+
+    processed.indices = indices.iter().flat_map(|triangle| triangle.iter().copied()).collect();
+
+    processed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //6面を独立した三角形で並べた1辺1の立方体、位置は少しだけずらす
+    //逆向きの重複した三角形と、面積のない三角形を最後に足す
+    fn cube_soup() -> MeshDescription {
+        let c = Vec3::new;
+        let quads = [
+            [c(0.0, 0.0, 1.0), c(1.0, 0.0, 1.0), c(1.0, 1.0, 1.0), c(0.0, 1.0, 1.0)],
+            [c(1.0, 0.0, 0.0), c(0.0, 0.0, 0.0), c(0.0, 1.0, 0.0), c(1.0, 1.0, 0.0)],
+            [c(1.0, 0.0, 1.0), c(1.0, 0.0, 0.0), c(1.0, 1.0, 0.0), c(1.0, 1.0, 1.0)],
+            [c(0.0, 0.0, 0.0), c(0.0, 0.0, 1.0), c(0.0, 1.0, 1.0), c(0.0, 1.0, 0.0)],
+            [c(0.0, 1.0, 1.0), c(1.0, 1.0, 1.0), c(1.0, 1.0, 0.0), c(0.0, 1.0, 0.0)],
+            [c(0.0, 0.0, 0.0), c(1.0, 0.0, 0.0), c(1.0, 0.0, 1.0), c(0.0, 0.0, 1.0)],
+        ];
+
+        let mut mesh = MeshDescription::default();
+
+        for quad in quads.iter() {
+            for i in [0, 1, 2, 0, 2, 3] {
+                mesh.indices.push(mesh.positions.len() as u32);
+                mesh.positions.push(quad[i] + Vec3::splat(1e-7));
+            }
+        }
+
+        let n = mesh.positions.len() as u32;
+        mesh.positions.extend([c(0.0, 0.0, 1.0), c(1.0, 1.0, 1.0), c(1.0, 0.0, 1.0)]);
+        mesh.indices.extend([n, n + 1, n + 2]);
+
+        let n = mesh.positions.len() as u32;
+        mesh.positions.extend([c(5.0, 5.0, 5.0), c(6.0, 5.0, 5.0), c(7.0, 5.0, 5.0)]);
+        mesh.indices.extend([n, n + 1, n + 2]);
+
+        mesh
+    }
+
+    fn settings(normals: NormalMode) -> MeshProcessingSettings {
+        MeshProcessingSettings {
+            normals,
+            ..MeshProcessingSettings::default()
+        }
+    }
+
+    #[test]
+    fn weld_merges_within_epsilon_only() {
+        let positions = [
+            Vec3::ZERO,
+            Vec3::new(1e-6, 0.0, 0.0),
+            Vec3::X,
+            Vec3::new(1.0 + 2e-5, 0.0, 0.0),
+        ];
+
+        assert_eq!(weld_vertices(&positions, 1e-5, |_, _| true), vec![0, 0, 2, 3]);
+    }
+
+    #[test]
+    fn weld_with_zero_epsilon_merges_exact_duplicates() {
+        let positions = [Vec3::X, Vec3::X, Vec3::new(1.0, 1e-7, 0.0), Vec3::X];
+
+        assert_eq!(weld_vertices(&positions, 0.0, |_, _| true), vec![0, 0, 2, 0]);
+        //負の値は0と同じ
+        assert_eq!(weld_vertices(&positions, -1.0, |_, _| true), vec![0, 0, 2, 0]);
+    }
+
+    #[test]
+    fn weld_looks_into_neighbouring_cells() {
+        //格子の境界をまたいでいてもまとめる
+        let positions = [Vec3::new(0.099, 0.0, 0.0), Vec3::new(0.101, 0.0, -0.001)];
+
+        assert_eq!(weld_vertices(&positions, 0.1, |_, _| true), vec![0, 0]);
+    }
+
+    #[test]
+    fn weld_respects_other_attributes() {
+        let positions = [Vec3::ZERO, Vec3::ZERO, Vec3::ZERO];
+
+        //0と2だけが同じ属性
+        assert_eq!(weld_vertices(&positions, 0.0, |a, b| (a + b) % 2 == 0), vec![0, 1, 0]);
+    }
+
+    #[test]
+    fn degenerate_and_duplicate_triangles_are_removed() {
+        let (processed, report) = process_mesh(&cube_soup(), &settings(NormalMode::Flat)).unwrap();
+
+        assert_eq!(report.degenerate_triangles, 1);
+        assert_eq!(report.duplicate_triangles, 1);
+        assert_eq!(processed.triangle_count(), 12);
+
+        //重複を残す場合は逆向きの三角形も残る
+        let keep_duplicates = MeshProcessingSettings {
+            remove_duplicate_triangles: false,
+            ..settings(NormalMode::Flat)
+        };
+        let (processed, report) = process_mesh(&cube_soup(), &keep_duplicates).unwrap();
+
+        assert_eq!(report.duplicate_triangles, 0);
+        assert_eq!(processed.triangle_count(), 13);
+    }
+
+    #[test]
+    fn flat_splits_every_face() {
+        let (flat, report) = process_mesh(&cube_soup(), &settings(NormalMode::Flat)).unwrap();
+
+        assert_eq!(flat.positions.len(), 24);
+        assert_eq!(report.split_vertices, 16);
+
+        //面ごとの法線は軸に平行
+        for normal in flat.normals.iter() {
+            assert!((normal.abs().max_element() - 1.0).abs() < 1e-5, "{}", normal);
+        }
+
+        //60度の折り目でも立方体の角は分かれる
+        let (smooth_default, _) = process_mesh(&cube_soup(), &MeshProcessingSettings::default()).unwrap();
+        assert_eq!(smooth_default.positions.len(), 24);
+    }
+
+    #[test]
+    fn smooth_shares_corners_within_crease_angle() {
+        let (smooth, report) = process_mesh(
+            &cube_soup(),
+            &settings(NormalMode::Smooth { crease_angle: std::f32::consts::PI }),
+        ).unwrap();
+
+        assert_eq!(smooth.positions.len(), 8);
+        assert_eq!(report.split_vertices, 0);
+
+        //どの角も3面が同じ角度で接するので対角線の向き
+        for (position, normal) in smooth.positions.iter().zip(smooth.normals.iter()) {
+            let diagonal = (*position - Vec3::splat(0.5)).normalize();
+            assert!(normal.dot(diagonal) > 1.0 - 1e-5, "{} at {}", normal, position);
+        }
+    }
+
+    #[test]
+    fn report_counts_every_step() {
+        let (_, report) = process_mesh(&cube_soup(), &settings(NormalMode::Flat)).unwrap();
+
+        assert_eq!(report, MeshProcessingReport {
+            input_vertices: 42,
+            input_triangles: 14,
+            //36 + 3が立方体の8頂点に、面積のない三角形の3頂点はそのまま
+            welded_vertices: 31,
+            degenerate_triangles: 1,
+            duplicate_triangles: 1,
+            unused_vertices: 3,
+            split_vertices: 16,
+            generated_normals: true,
+            output_vertices: 24,
+            output_triangles: 12,
+        });
+        assert!(!report.is_unchanged());
+        assert_eq!(
+            report.to_string(),
+            "42 -> 24 vertices, 14 -> 12 triangles (welded 31, degenerate 1, duplicate 1, unused 3, split 16, generated normals)",
+        );
+    }
+
+    #[test]
+    fn processed_mesh_is_unchanged_when_kept() {
+        let (flat, _) = process_mesh(&cube_soup(), &settings(NormalMode::Flat)).unwrap();
+        let (kept, report) = process_mesh(&flat, &settings(NormalMode::Keep)).unwrap();
+
+        assert!(report.is_unchanged(), "{}", report);
+        assert_eq!(kept, flat);
+    }
+
+    #[test]
+    fn scene_meshes_are_processed_in_order() {
+        let mut scene = SceneDescription::classical();
+        scene.meshes.insert(0, cube_soup());
+        scene.instances[0].mesh = 1;
+
+        let (processed, reports) = process_scene(&scene, &settings(NormalMode::Flat)).unwrap();
+
+        assert_eq!(reports.len(), 2);
+        assert_eq!(processed.meshes[0].triangle_count(), 12);
+        assert_eq!(processed.meshes[1].positions, scene.meshes[1].positions);
+        assert_eq!(processed.instances, scene.instances);
+        assert_eq!(processed.materials, scene.materials);
+
+        let total = MeshProcessingReport::total(&reports);
+        assert_eq!(total.input_triangles, 15);
+        assert_eq!(total.output_triangles, 13);
+        assert_eq!(total.welded_vertices, reports[0].welded_vertices + reports[1].welded_vertices);
+        assert_eq!(total.output_vertices, 24 + 3);
+        assert!(total.generated_normals);
+    }
+
+    #[test]
+    fn classical_scene_is_unchanged_when_normals_are_kept() {
+        let scene = SceneDescription::classical();
+        let (processed, reports) = process_scene(&scene, &settings(NormalMode::Keep)).unwrap();
+
+        assert!(MeshProcessingReport::total(&reports).is_unchanged());
+        assert_eq!(processed, scene);
+    }
+
+    #[test]
+    fn invalid_indices_are_rejected() {
+        let mut mesh = MeshDescription::triangle([Vec3::ZERO, Vec3::X, Vec3::Y]);
+        mesh.indices.push(0);
+        assert!(process_mesh(&mesh, &MeshProcessingSettings::default()).is_err());
+
+        let mut mesh = MeshDescription::triangle([Vec3::ZERO, Vec3::X, Vec3::Y]);
+        mesh.indices[2] = 3;
+        assert!(process_mesh(&mesh, &MeshProcessingSettings::default()).is_err());
+    }
+}
@@ -0,0 +1,452 @@
+use std::fmt;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::str::FromStr;
+use ash::vk;
+use crate::camera::Camera;
+use crate::error::{CottonError, Result};
+use crate::render_backend::progress::RenderControl;
+use crate::renderer::backends::physical_device_selector::DeviceSelection;
+use crate::scene_description::{SceneChanges, SceneDescription};
+
+pub mod host_backend;
+pub mod vulkan_backend;
+pub mod tiles;
+pub mod bvh;
+pub mod progress;
+
+/// Vulkanのray tracingとCPUのどちらでも同じ手順で描画できるようにする
+pub trait RenderBackend {
+    fn capabilities(&self) -> BackendCapabilities;
+
+    /// シーンが変わった場合のみ呼ぶ、renderの前に一度は必要
+    fn prepare(&mut self, scene: &SceneDescription) -> Result<()>;
+
+    /// prepare済みのシーンからchangesの分だけ変わったシーンに差し替える
+    fn update(&mut self, scene: &SceneDescription, changes: &SceneChanges) -> Result<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        self.prepare(scene)
+    }
+
+    /// prepareする前はNone
+    fn scene_stats(&self) -> Option<SceneStats>;
+
+    /// 中断された場合はそこまでに描いた結果を返す
+    fn render_with_control(&mut self, settings: &RenderSettings, control: &mut RenderControl) -> Result<RenderOutput>;
+
+    fn render(&mut self, settings: &RenderSettings) -> Result<FloatImage> {
+        Ok(self.render_with_control(settings, &mut RenderControl::new())?.image)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderOutput {
+    /// 描いていない部分はalphaが0のまま
+    pub image: FloatImage,
+    pub cancelled: bool,
+    /// 描いたピクセル数 x サンプル数、描く前に中断された場合は0
+    pub samples_done: u64,
+}
+
+/// prepareしたシーンの大きさ
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SceneStats {
+    pub mesh_count: usize,
+    pub instance_count: usize,
+    /// instanceごとに数える
+    pub triangle_count: usize,
+    /// BLASとTLAS、hostの場合は展開した三角形の大きさ
+    pub acceleration_structure_bytes: u64,
+    /// compactionしなかった場合の大きさ
+    pub uncompacted_bytes: u64,
+}
+
+impl SceneStats {
+    pub fn compaction_savings(&self) -> u64 {
+        self.uncompacted_bytes.saturating_sub(self.acceleration_structure_bytes)
+    }
+}
+
+impl fmt::Display for SceneStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} meshes, {} instances, {} triangles, acceleration structures {} bytes",
+            self.mesh_count,
+            self.instance_count,
+            self.triangle_count,
+            self.acceleration_structure_bytes,
+        )?;
+
+        if self.compaction_savings() > 0 {
+            write!(
+                f,
+                " (saved {} bytes, {:.1}% by compaction)",
+                self.compaction_savings(),
+                self.compaction_savings() as f64 / self.uncompacted_bytes as f64 * 100.0,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BackendKind {
+    Vulkan,
+    Host,
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Vulkan => write!(f, "vulkan"),
+            Self::Host => write!(f, "host"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackendCapabilities {
+    pub kind: BackendKind,
+    /// hostの場合はNone
+    pub device_name: Option<String>,
+    pub hardware_ray_tracing: bool,
+    pub max_image_dimension: u32,
+}
+
+impl fmt::Display for BackendCapabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+
+        if let Some(device_name) = &self.device_name {
+            write!(f, " ({})", device_name)?;
+        }
+
+        write!(
+            f,
+            ", hardware ray tracing: {}, max image dimension: {}",
+            self.hardware_ray_tracing,
+            self.max_image_dimension,
+        )
+    }
+}
+
+/// CLIの--backendで指定する
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BackendChoice {
+    /// Vulkanを試し、ray tracingに対応したデバイスがなければhostを使う
+    Auto,
+    Vulkan,
+    Host,
+}
+
+impl Default for BackendChoice {
+    fn default() -> Self {
+        Self::Auto
+    }
+}
+
+impl FromStr for BackendChoice {
+    type Err = CottonError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "vulkan" => Ok(Self::Vulkan),
+            "host" => Ok(Self::Host),
+            _ => Err(CottonError::InvalidRenderSettings(format!(
+                "unknown backend `{}`, expected auto, vulkan or host",
+                s,
+            ))),
+        }
+    }
+}
+
+impl BackendChoice {
+    /// Vulkanのバックエンドを作る際のエラーでhostに切り替えるかどうか
+    /// デバイスを明示的に指定していて見つからない場合は切り替えない
+    pub fn falls_back_on(&self, err: &CottonError) -> bool {
+        let unavailable = match err {
+            CottonError::Loading(_)
+            | CottonError::NoSuitablePhysicalDevice(_)
+            | CottonError::MissingExtensions(_) => true,
+            //Autoで一つもデバイスがない場合
+            CottonError::PhysicalDeviceNotFound(selection) => *selection == DeviceSelection::Auto.to_string(),
+            //ICDが入っていない場合はinstanceの作成で失敗する
+            CottonError::Vulkan(result) => matches!(
+                *result,
+                vk::Result::ERROR_INCOMPATIBLE_DRIVER | vk::Result::ERROR_INITIALIZATION_FAILED
+            ),
+            _ => false,
+        };
+
+        *self == Self::Auto && unavailable
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RenderSettings {
+    pub width: u32,
+    pub height: u32,
+    pub samples_per_pixel: u32,
+    /// 反射と屈折を追う最大の回数
+    pub max_depth: u32,
+    pub seed: u64,
+    pub camera: Camera,
+}
+
+impl RenderSettings {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            samples_per_pixel: 16,
+            max_depth: 8,
+            seed: 0,
+            camera: Camera::default(),
+        }
+    }
+
+    pub fn aspect(&self) -> f32 {
+        self.width as f32 / self.height as f32
+    }
+
+    pub fn validate(&self, capabilities: &BackendCapabilities) -> Result<()> {
+        if self.width == 0 || self.height == 0 {
+            return Err(CottonError::InvalidRenderSettings(format!(
+                "image size {}x{} is empty",
+                self.width,
+                self.height,
+            )));
+        }
+
+        if self.width > capabilities.max_image_dimension || self.height > capabilities.max_image_dimension {
+            return Err(CottonError::InvalidRenderSettings(format!(
+                "image size {}x{} exceeds {} backend limit {}",
+                self.width,
+                self.height,
+                capabilities.kind,
+                capabilities.max_image_dimension,
+            )));
+        }
+
+        if self.samples_per_pixel == 0 {
+            return Err(CottonError::InvalidRenderSettings("samples_per_pixel must be at least 1".to_owned()));
+        }
+
+        Ok(())
+    }
+}
+
+/// 線形のRGBA、バックエンドの出力はすべてこれにそろえる
+#[derive(Clone, Debug, PartialEq)]
+pub struct FloatImage {
+    pub width: u32,
+    pub height: u32,
+    /// 上の行から順に並べる
+    pub pixels: Vec<[f32; 4]>,
+}
+
+impl FloatImage {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![[0.0; 4]; width as usize * height as usize],
+        }
+    }
+
+    /// R32G32B32A32_SFLOATのimageから読み出したもの
+    pub fn from_rgba(width: u32, height: u32, data: &[f32]) -> Result<Self> {
+        let expected = width as usize * height as usize * 4;
+
+        if data.len() != expected {
+            return Err(CottonError::InvalidRenderSettings(format!(
+                "expected {} floats for {}x{} image, but got {}",
+                expected,
+                width,
+                height,
+                data.len(),
+            )));
+        }
+
+        let pixels = data
+            .chunks_exact(4)
+            .map(|pixel| [pixel[0], pixel[1], pixel[2], pixel[3]])
+            .collect();
+
+        Ok(Self {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [f32; 4] {
+        self.pixels[y as usize * self.width as usize + x as usize]
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, pixel: [f32; 4]) {
+        self.pixels[y as usize * self.width as usize + x as usize] = pixel;
+    }
+
+    /// ガンマ2で8bitにする
+    pub fn to_rgba8(&self) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .flat_map(|pixel| pixel.iter())
+            .map(|f| (256.0 * f.sqrt().clamp(0.0, 0.999)) as u8)
+            .collect()
+    }
+
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut png_encoder = png::Encoder::new(
+            BufWriter::new(File::create(path)?),
+            self.width,
+            self.height,
+        );
+
+        png_encoder.set_depth(png::BitDepth::Eight);
+        png_encoder.set_color(png::ColorType::Rgba);
+
+        let mut png_writer = png_encoder.write_header()?;
+        png_writer.write_image_data(&self.to_rgba8())?;
+        png_writer.finish()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use crate::renderer::acceleration_structures::triangle_bottom_level_acceleration_structure::size_after_compaction;
+    use crate::renderer::backends::physical_device_selector::DeviceRejection;
+    use super::*;
+
+    fn loading_error() -> CottonError {
+        let err = unsafe { ash::Entry::load_from("./no_such_vulkan_library") }.err().unwrap();
+        CottonError::Loading(err)
+    }
+
+    fn encoding_error() -> CottonError {
+        let mut bytes = Vec::new();
+        let mut writer = png::Encoder::new(&mut bytes, 1, 1).write_header().unwrap();
+
+        //1x1に足りないデータ
+        CottonError::ImageEncode(writer.write_image_data(&[]).unwrap_err())
+    }
+
+    //デバイスがない、またはray tracingに対応していない場合のエラー
+    fn unavailable_errors() -> Vec<CottonError> {
+        vec![
+            loading_error(),
+            CottonError::Vulkan(vk::Result::ERROR_INCOMPATIBLE_DRIVER),
+            CottonError::Vulkan(vk::Result::ERROR_INITIALIZATION_FAILED),
+            CottonError::MissingExtensions(vec!["VK_KHR_ray_tracing_pipeline".to_owned()]),
+            CottonError::NoSuitablePhysicalDevice(vec![DeviceRejection {
+                index: 0,
+                name: "llvmpipe".to_owned(),
+                missing_extensions: vec!["VK_KHR_acceleration_structure".to_owned()],
+                missing_features: vec![],
+            }]),
+            CottonError::PhysicalDeviceNotFound(DeviceSelection::Auto.to_string()),
+        ]
+    }
+
+    //Windowはwinitの外から作れないので含めない
+    fn other_errors() -> Vec<CottonError> {
+        vec![
+            CottonError::Vulkan(vk::Result::ERROR_DEVICE_LOST),
+            CottonError::OutOfMemory(vk::Result::ERROR_OUT_OF_DEVICE_MEMORY),
+            CottonError::MissingLayers(vec!["VK_LAYER_KHRONOS_validation".to_owned()]),
+            CottonError::PhysicalDeviceNotFound(DeviceSelection::Name("radeon".to_owned()).to_string()),
+            CottonError::PhysicalDeviceNotFound(DeviceSelection::Index(3).to_string()),
+            CottonError::PhysicalDeviceNotFound(DeviceSelection::Uuid([0; 16]).to_string()),
+            CottonError::MissingQueueFamily("graphics"),
+            CottonError::NoMemoryType {
+                type_filter: 0b101,
+                property_flags: vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            },
+            CottonError::BufferTooSmall { required: 8, allocated: 4 },
+            CottonError::UnsupportedFormat {
+                format: vk::Format::R32G32B32A32_SFLOAT,
+                features: vk::FormatFeatureFlags::SAMPLED_IMAGE,
+            },
+            CottonError::NoSurfaceFormat,
+            CottonError::UnsupportedSwapchainUsage(vk::ImageUsageFlags::TRANSFER_DST),
+            CottonError::SurfaceRequired,
+            CottonError::UnsupportedWindowHandle("web".to_owned()),
+            CottonError::ShaderLoad("missing.spv".to_owned()),
+            CottonError::ShaderValidation(vec!["binding 0".to_owned()]),
+            CottonError::InvalidPipelineDesc("no raygen".to_owned()),
+            CottonError::InvalidShaderBindingTable("empty".to_owned()),
+            CottonError::InvalidDescriptorSet("binding 1".to_owned()),
+            CottonError::InvalidScene("no meshes".to_owned()),
+            CottonError::InvalidRenderSettings("0x0".to_owned()),
+            CottonError::RenderWorkerPanicked,
+            encoding_error(),
+            CottonError::TextureDecode("truncated".to_owned()),
+            CottonError::InvalidName(CString::new("a\0b").unwrap_err()),
+            CottonError::Io(std::io::Error::new(std::io::ErrorKind::NotFound, "missing")),
+        ]
+    }
+
+    #[test]
+    fn compaction_savings_count_only_compacted_blases() {
+        let tlas_bytes = 256;
+        //(buildした大きさ, queryで得た大きさ)、2つ目と3つ目は小さくならない
+        let blases = [(4096, 1024), (2048, 2048), (1024, 0), (8192, 6144)];
+
+        let stats = SceneStats {
+            mesh_count: blases.len(),
+            instance_count: blases.len(),
+            triangle_count: 100,
+            acceleration_structure_bytes: tlas_bytes
+                + blases.iter().map(|(uncompacted, compacted)| size_after_compaction(*uncompacted, *compacted)).sum::<u64>(),
+            uncompacted_bytes: tlas_bytes + blases.iter().map(|(uncompacted, _)| uncompacted).sum::<u64>(),
+        };
+
+        assert_eq!(stats.acceleration_structure_bytes, 256 + 1024 + 2048 + 1024 + 6144);
+        assert_eq!(stats.compaction_savings(), 3072 + 2048);
+        assert!(stats.to_string().ends_with("(saved 5120 bytes, 32.8% by compaction)"), "{}", stats);
+    }
+
+    #[test]
+    fn no_savings_without_compaction() {
+        let stats = SceneStats {
+            mesh_count: 1,
+            instance_count: 1,
+            triangle_count: 12,
+            acceleration_structure_bytes: 1024,
+            uncompacted_bytes: 1024,
+        };
+
+        assert_eq!(stats.compaction_savings(), 0);
+        assert_eq!(stats.to_string(), "1 meshes, 1 instances, 12 triangles, acceleration structures 1024 bytes");
+    }
+
+    #[test]
+    fn auto_falls_back_when_vulkan_is_unavailable() {
+        for err in unavailable_errors() {
+            assert!(BackendChoice::Auto.falls_back_on(&err), "{}", err);
+        }
+
+        for err in other_errors() {
+            assert!(!BackendChoice::Auto.falls_back_on(&err), "{}", err);
+        }
+    }
+
+    #[test]
+    fn explicit_backend_never_falls_back() {
+        for err in unavailable_errors().into_iter().chain(other_errors()) {
+            assert!(!BackendChoice::Vulkan.falls_back_on(&err), "{}", err);
+            assert!(!BackendChoice::Host.falls_back_on(&err), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use glam::{const_vec3, Vec3};
+
+//これ以下の数になったら分割せずに葉にする
+const MAX_LEAF_SIZE: usize = 4;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    /// 何を足してもその大きさになる空の箱
+    pub const EMPTY: Self = Self {
+        min: const_vec3!([f32::INFINITY; 3]),
+        max: const_vec3!([f32::NEG_INFINITY; 3]),
+    };
+
+    pub fn from_points(points: &[Vec3]) -> Self {
+        points.iter().fold(Self::EMPTY, |aabb, point| aabb.grow(*point))
+    }
+
+    pub fn grow(self, point: Vec3) -> Self {
+        Self {
+            min: self.min.min(point),
+            max: self.max.max(point),
+        }
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    /// 箱に入る区間の始まり、外れた場合とt_maxより遠い場合はNone
+    /// 軸に平行なレイでinv_directionが無限大になってもNaNは無視される
+    pub fn intersect(&self, origin: Vec3, inv_direction: Vec3, t_max: f32) -> Option<f32> {
+        let mut t_near = 0.0f32;
+        let mut t_far = t_max;
+
+        for axis in 0..3 {
+            let t0 = (self.min[axis] - origin[axis]) * inv_direction[axis];
+            let t1 = (self.max[axis] - origin[axis]) * inv_direction[axis];
+
+            t_near = t_near.max(t0.min(t1));
+            t_far = t_far.min(t0.max(t1));
+        }
+
+        if t_near <= t_far {
+            Some(t_near)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct BvhNode {
+    bounds: Aabb,
+    /// 葉の場合はprimitive_indicesの先頭、内部nodeの場合は右の子、左の子はすぐ後ろに並ぶ
+    offset: u32,
+    /// 0の場合は内部node
+    count: u32,
+}
+
+/// CPUで描画する場合のAS、重心の一番長い軸の中央で二分する
+/// 同じ入力からは常に同じ木になるのでスレッド数で結果は変わらない
+#[derive(Clone, Debug, Default)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    primitive_indices: Vec<u32>,
+}
+
+impl Bvh {
+    pub fn new(bounds: &[Aabb]) -> Self {
+        let mut bvh = Self {
+            nodes: Vec::with_capacity(bounds.len() * 2),
+            primitive_indices: (0..bounds.len() as u32).collect(),
+        };
+
+        if !bounds.is_empty() {
+            bvh.build(bounds, 0, bounds.len());
+        }
+
+        bvh
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        std::mem::size_of::<BvhNode>() * self.nodes.len() + std::mem::size_of::<u32>() * self.primitive_indices.len()
+    }
+
+    //作ったnodeの番号を返す
+    fn build(&mut self, bounds: &[Aabb], start: usize, end: usize) -> usize {
+        let node_index = self.nodes.len();
+        let primitives = &mut self.primitive_indices[start..end];
+
+        let node_bounds = primitives
+            .iter()
+            .fold(Aabb::EMPTY, |aabb, i| aabb.union(bounds[*i as usize]));
+
+        self.nodes.push(BvhNode {
+            bounds: node_bounds,
+            offset: start as u32,
+            count: (end - start) as u32,
+        });
+
+        if end - start <= MAX_LEAF_SIZE {
+            return node_index;
+        }
+
+        let centroid_bounds = primitives
+            .iter()
+            .fold(Aabb::EMPTY, |aabb, i| aabb.grow(bounds[*i as usize].centroid()));
+        let extent = centroid_bounds.max - centroid_bounds.min;
+
+        //重心が全て同じ位置にある場合は分けても意味がない
+        if extent.max_element() <= 0.0 {
+            return node_index;
+        }
+
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+
+        //同じ重心の場合は番号順にして並びを決定的にする
+        let middle = (end - start) / 2;
+        primitives.select_nth_unstable_by(middle, |a, b| {
+            let centroid = |i: &u32| bounds[*i as usize].centroid()[axis];
+
+            centroid(a)
+                .partial_cmp(&centroid(b))
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(a.cmp(b))
+        });
+
+        self.build(bounds, start, start + middle);
+        let right = self.build(bounds, start + middle, end);
+
+        self.nodes[node_index].offset = right as u32;
+        self.nodes[node_index].count = 0;
+
+        node_index
+    }
+
+    /// t_maxより近い交差のうち一番近いもの
+    /// hitはprimitiveの番号とその時点で一番近い距離を受け取り、より近く交差した場合にその距離を返す
+    pub fn closest<T>(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        t_max: f32,
+        mut hit: impl FnMut(usize, f32) -> Option<(f32, T)>,
+    ) -> Option<(f32, T)> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let inv_direction = Vec3::ONE / direction;
+        let mut closest = None;
+        let mut t_max = t_max;
+
+        //二分木の深さはprimitiveの数のlog程度で収まる
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0usize);
+
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+
+            //popするまでに近い交差が見つかっていれば飛ばす
+            if node.bounds.intersect(origin, inv_direction, t_max).is_none() {
+                continue;
+            }
+
+            if node.count > 0 {
+                let start = node.offset as usize;
+
+                for primitive in &self.primitive_indices[start..start + node.count as usize] {
+                    if let Some((t, value)) = hit(*primitive as usize, t_max) {
+                        t_max = t;
+                        closest = Some((t, value));
+                    }
+                }
+
+                continue;
+            }
+
+            let left = node_index + 1;
+            let right = node.offset as usize;
+
+            let t_left = self.nodes[left].bounds.intersect(origin, inv_direction, t_max);
+            let t_right = self.nodes[right].bounds.intersect(origin, inv_direction, t_max);
+
+            //近い方を後に積んで先に調べる
+            match (t_left, t_right) {
+                (Some(t_left), Some(t_right)) if t_left <= t_right => {
+                    stack.push(right);
+                    stack.push(left);
+                }
+                (Some(_), Some(_)) => {
+                    stack.push(left);
+                    stack.push(right);
+                }
+                (Some(_), None) => stack.push(left),
+                (None, Some(_)) => stack.push(right),
+                (None, None) => {}
+            }
+        }
+
+        closest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //x軸に沿って並べた幅1の箱
+    fn boxes(count: usize) -> Vec<Aabb> {
+        (0..count)
+            .map(|i| {
+                let x = i as f32 * 2.0;
+                Aabb { min: Vec3::new(x, -0.5, -0.5), max: Vec3::new(x + 1.0, 0.5, 0.5) }
+            })
+            .collect()
+    }
+
+    //箱の手前の面までの距離を交差とする、同じ距離の場合は先に見つけた方
+    fn closest_box(bvh: &Bvh, bounds: &[Aabb], origin: Vec3, direction: Vec3) -> Option<(f32, usize)> {
+        let inv_direction = Vec3::ONE / direction;
+
+        bvh.closest(origin, direction, f32::INFINITY, |i, t_max| {
+            bounds[i]
+                .intersect(origin, inv_direction, t_max)
+                .filter(|t| *t < t_max)
+                .map(|t| (t, i))
+        })
+    }
+
+    #[test]
+    fn every_primitive_is_in_exactly_one_leaf() {
+        let bvh = Bvh::new(&boxes(37));
+
+        let mut leaves: Vec<u32> = bvh
+            .nodes
+            .iter()
+            .filter(|node| node.count > 0)
+            .flat_map(|node| bvh.primitive_indices[node.offset as usize..(node.offset + node.count) as usize].to_vec())
+            .collect();
+        leaves.sort_unstable();
+
+        assert_eq!(leaves, (0..37).collect::<Vec<_>>());
+        assert!(bvh.nodes.iter().all(|node| node.count as usize <= MAX_LEAF_SIZE));
+    }
+
+    #[test]
+    fn finds_the_nearest_box_from_either_side() {
+        let bounds = boxes(20);
+        let bvh = Bvh::new(&bounds);
+
+        assert_eq!(closest_box(&bvh, &bounds, Vec3::new(-5.0, 0.0, 0.0), Vec3::X), Some((5.0, 0)));
+        assert_eq!(closest_box(&bvh, &bounds, Vec3::new(45.0, 0.0, 0.0), -Vec3::X), Some((6.0, 19)));
+        //箱の隙間から横に飛ばす
+        assert_eq!(closest_box(&bvh, &bounds, Vec3::new(11.5, 0.0, 5.0), -Vec3::Z), None);
+        assert_eq!(closest_box(&bvh, &bounds, Vec3::new(12.5, 0.0, 5.0), -Vec3::Z), Some((4.5, 6)));
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        let bounds = boxes(50);
+        let bvh = Bvh::new(&bounds);
+
+        for i in 0..100 {
+            let origin = Vec3::new(i as f32 - 10.0, 3.0, 1.0);
+            let direction = Vec3::new(0.3, -1.0, -0.2).normalize();
+            let inv_direction = Vec3::ONE / direction;
+
+            let brute_force = bounds
+                .iter()
+                .enumerate()
+                .filter_map(|(i, aabb)| aabb.intersect(origin, inv_direction, f32::INFINITY).map(|t| (t, i)))
+                .fold(None, |closest: Option<(f32, usize)>, hit| match closest {
+                    Some(closest) if closest.0 <= hit.0 => Some(closest),
+                    _ => Some(hit),
+                });
+
+            assert_eq!(closest_box(&bvh, &bounds, origin, direction), brute_force);
+        }
+    }
+
+    #[test]
+    fn empty_and_stacked_primitives() {
+        assert_eq!(closest_box(&Bvh::new(&[]), &[], Vec3::ZERO, Vec3::X), None);
+
+        //重心が全て同じ場合は一つの葉になる
+        let bounds = vec![Aabb { min: Vec3::splat(-1.0), max: Vec3::ONE }; 10];
+        let bvh = Bvh::new(&bounds);
+
+        assert_eq!(bvh.node_count(), 1);
+        assert_eq!(closest_box(&bvh, &bounds, Vec3::new(0.0, 0.0, -5.0), Vec3::Z), Some((4.0, 0)));
+    }
+}
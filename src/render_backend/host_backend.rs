@@ -0,0 +1,691 @@
+use std::num::NonZeroUsize;
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::Instant;
+use glam::{Vec2, Vec3};
+use log::debug;
+use crate::camera::Camera;
+use crate::error::{CottonError, Result};
+use crate::render_backend::{BackendCapabilities, BackendKind, FloatImage, RenderBackend, RenderOutput, RenderSettings, SceneStats};
+use crate::render_backend::bvh::{Aabb, Bvh};
+use crate::render_backend::progress::{ProgressTracker, RenderControl};
+use crate::render_backend::tiles::{DEFAULT_TILE_SIZE, split_tiles, Tile, TileQueues};
+use crate::renderer::materials::{Material, MaterialKind};
+use crate::scene_description::{SceneChanges, SceneDescription};
+use crate::textures::Texture;
+
+//メモリが足りる範囲で適当に制限しておく
+const HOST_MAX_IMAGE_DIMENSION: u32 = 16384;
+
+//交差した面から出るレイが同じ面に当たらないようにする
+const RAY_EPSILON: f32 = 1e-4;
+
+/// ray tracingに対応したデバイスがない環境でも動くCPUのパストレーサー
+/// 画像をタイルに分けてスレッドで分担する
+#[derive(Clone, Debug)]
+pub struct HostBackend {
+    scene: Option<Arc<HostScene>>,
+    threads: usize,
+    tile_size: u32,
+}
+
+impl Default for HostBackend {
+    fn default() -> Self {
+        Self {
+            scene: None,
+            threads: thread::available_parallelism().map(NonZeroUsize::get).unwrap_or(1),
+            tile_size: DEFAULT_TILE_SIZE,
+        }
+    }
+}
+
+impl HostBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 結果はスレッド数に依存しない
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub fn tile_size(mut self, tile_size: u32) -> Self {
+        self.tile_size = tile_size.max(1);
+        self
+    }
+}
+
+impl RenderBackend for HostBackend {
+    fn capabilities(&self) -> BackendCapabilities {
+        BackendCapabilities {
+            kind: BackendKind::Host,
+            device_name: None,
+            hardware_ray_tracing: false,
+            max_image_dimension: HOST_MAX_IMAGE_DIMENSION,
+        }
+    }
+
+    fn prepare(&mut self, scene: &SceneDescription) -> Result<()> {
+        debug!("prepare host scene");
+
+        self.scene = Some(Arc::new(HostScene::new(scene)?));
+
+        Ok(())
+    }
+
+    fn update(&mut self, scene: &SceneDescription, changes: &SceneChanges) -> Result<()> {
+        match self.scene.as_mut() {
+            //三角形はtransformを適用して展開しているのでmaterialとtextureだけ変わった場合のみ差し替える
+            Some(host_scene) if !changes.meshes && !changes.instances && !changes.transforms => {
+                if changes.materials || changes.textures {
+                    scene.validate()?;
+
+                    let host_scene = Arc::make_mut(host_scene);
+                    host_scene.materials = scene.materials.clone();
+                    host_scene.textures = scene.textures.clone();
+                }
+
+                Ok(())
+            }
+            _ => self.prepare(scene),
+        }
+    }
+
+    fn scene_stats(&self) -> Option<SceneStats> {
+        self.scene.as_ref().map(|scene| scene.stats)
+    }
+
+    fn render_with_control(&mut self, settings: &RenderSettings, control: &mut RenderControl) -> Result<RenderOutput> {
+        settings.validate(&self.capabilities())?;
+
+        let scene = self
+            .scene
+            .clone()
+            .ok_or_else(|| CottonError::InvalidScene("prepare must be called before render".to_owned()))?;
+
+        let tiles = split_tiles(settings.width, settings.height, self.tile_size);
+        let worker_count = self.threads.min(tiles.len()).max(1);
+        let samples_total = tiles.iter().map(|tile| tile_samples(tile, settings)).sum();
+        let mut tracker = ProgressTracker::new(tiles.len(), samples_total, Instant::now());
+        let queues = Arc::new(TileQueues::new(tiles, worker_count));
+
+        debug!("render {} tiles with {} threads", queues.remaining(), worker_count);
+
+        let (sender, receiver) = mpsc::channel();
+
+        let workers = (0..worker_count)
+            .map(|worker| {
+                let scene = Arc::clone(&scene);
+                let queues = Arc::clone(&queues);
+                let sender = sender.clone();
+                let settings = *settings;
+                let cancellation = control.cancellation.clone();
+
+                thread::Builder::new()
+                    .name(format!("host-render-{}", worker))
+                    .spawn(move || {
+                        //中断された場合は描いている途中のタイルだけ終わらせる
+                        while !cancellation.is_cancelled() {
+                            let tile = match queues.next(worker) {
+                                Some(tile) => tile,
+                                None => break,
+                            };
+
+                            //受け取る側がいなくなった場合は残りを捨てる
+                            if sender.send((tile, render_tile(&scene, &settings, &tile))).is_err() {
+                                break;
+                            }
+                        }
+                    })
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        //全てのワーカーが終わるとreceiverのループを抜ける
+        drop(sender);
+
+        let mut image = FloatImage::new(settings.width, settings.height);
+
+        for (tile, pixels) in receiver {
+            for ((x, y), pixel) in tile.pixels().zip(pixels) {
+                image.set_pixel(x, y, pixel);
+            }
+
+            let progress = tracker.on_tile_done(tile_samples(&tile, settings), Instant::now());
+            control.report(&progress);
+        }
+
+        for worker in workers {
+            worker.join().map_err(|_| CottonError::RenderWorkerPanicked)?;
+        }
+
+        Ok(RenderOutput {
+            image,
+            cancelled: !tracker.progress().is_complete(),
+            samples_done: tracker.progress().samples_done,
+        })
+    }
+}
+
+fn tile_samples(tile: &Tile, settings: &RenderSettings) -> u64 {
+    tile.pixel_count() as u64 * settings.samples_per_pixel as u64
+}
+
+/// タイルの中を上の行から順に並べたもの
+pub fn render_tile(scene: &HostScene, settings: &RenderSettings, tile: &Tile) -> Vec<[f32; 4]> {
+    tile.pixels()
+        .map(|(x, y)| {
+            let color = render_pixel(scene, settings, x, y);
+            [color.x, color.y, color.z, 1.0]
+        })
+        .collect()
+}
+
+/// samples_per_pixel回サンプリングした平均
+/// 乱数はピクセルごとに作るのでどのスレッドがどの順番で描いても同じ結果になる
+pub fn render_pixel(scene: &HostScene, settings: &RenderSettings, x: u32, y: u32) -> Vec3 {
+    let mut rng = Rng::new(pixel_seed(settings.seed, x, y));
+    let mut sum = Vec3::ZERO;
+
+    for _ in 0..settings.samples_per_pixel {
+        let jitter = Vec2::new(rng.next_f32(), rng.next_f32());
+        let ray = primary_ray(&settings.camera, settings.width, settings.height, x, y, jitter);
+
+        sum += trace(scene, ray, settings.max_depth, &mut rng);
+    }
+
+    sum / settings.samples_per_pixel as f32
+}
+
+/// 隣のピクセルと乱数の列が似ないように一度混ぜる
+pub fn pixel_seed(seed: u64, x: u32, y: u32) -> u64 {
+    Rng::new(seed.wrapping_add(((y as u64) << 32) | x as u64)).next_u64()
+}
+
+/// jitterはピクセル内の位置で0..1
+pub fn primary_ray(camera: &Camera, width: u32, height: u32, x: u32, y: u32, jitter: Vec2) -> Ray {
+    //画像は上の行から並ぶのでyを反転する
+    let ndc = Vec2::new(
+        (x as f32 + jitter.x) / width as f32 * 2.0 - 1.0,
+        1.0 - (y as f32 + jitter.y) / height as f32 * 2.0,
+    );
+
+    Ray {
+        origin: camera.position,
+        direction: camera.ray_direction(ndc, width as f32 / height as f32),
+    }
+}
+
+pub fn trace(scene: &HostScene, ray: Ray, max_depth: u32, rng: &mut Rng) -> Vec3 {
+    let mut radiance = Vec3::ZERO;
+    let mut throughput = Vec3::ONE;
+    let mut ray = ray;
+
+    for _ in 0..max_depth {
+        let hit = match scene.intersect(&ray, f32::INFINITY) {
+            Some(hit) => hit,
+            None => {
+                radiance += throughput * sky(ray.direction);
+                break;
+            }
+        };
+
+        let scattered = scatter(&scene.materials[hit.material], &scene.textures, &ray, &hit, rng);
+        radiance += throughput * scattered.emitted;
+
+        match scattered.ray {
+            Some(next) => {
+                throughput *= scattered.attenuation;
+                ray = next;
+            }
+            None => break,
+        }
+    }
+
+    radiance
+}
+
+/// missシェーダーと同じ空のグラデーション、光源を兼ねる
+pub fn sky(direction: Vec3) -> Vec3 {
+    let t = 0.5 * (direction.normalize().y + 1.0);
+
+    Vec3::ONE * (1.0 - t) + Vec3::new(0.5, 0.7, 1.0) * t
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Ray {
+    pub origin: Vec3,
+    /// 正規化しておく
+    pub direction: Vec3,
+}
+
+impl Ray {
+    pub fn at(&self, t: f32) -> Vec3 {
+        self.origin + self.direction * t
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Hit {
+    pub t: f32,
+    pub position: Vec3,
+    /// 常にレイの来た側を向く
+    pub normal: Vec3,
+    pub front_face: bool,
+    pub material: usize,
+    /// UVを持たないmeshの場合は0
+    pub tex_coord: Vec2,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Triangle {
+    p0: Vec3,
+    e1: Vec3,
+    e2: Vec3,
+    normals: [Vec3; 3],
+    tex_coords: [Vec2; 3],
+    material: usize,
+}
+
+/// instanceのtransformを適用してワールド座標の三角形に展開したもの
+#[derive(Clone, Debug, Default)]
+pub struct HostScene {
+    triangles: Vec<Triangle>,
+    bvh: Bvh,
+    materials: Vec<Material>,
+    textures: Vec<Texture>,
+    pub stats: SceneStats,
+}
+
+impl HostScene {
+    pub fn new(scene: &SceneDescription) -> Result<Self> {
+        scene.validate()?;
+
+        let mut triangles = Vec::with_capacity(scene.triangle_count());
+
+        for instance in scene.instances.iter() {
+            let mesh = &scene.meshes[instance.mesh];
+            let normal_matrix = instance.transform.inverse().transpose();
+
+            for indices in mesh.indices.chunks_exact(3) {
+                let position = |i: u32| instance.transform.transform_point3(mesh.positions[i as usize]);
+                let normal = |i: u32| normal_matrix.transform_vector3(mesh.normals[i as usize]).normalize_or_zero();
+                let tex_coord = |i: u32| mesh.tex_coords.get(i as usize).copied().unwrap_or(Vec2::ZERO);
+
+                let p0 = position(indices[0]);
+
+                triangles.push(Triangle {
+                    p0,
+                    e1: position(indices[1]) - p0,
+                    e2: position(indices[2]) - p0,
+                    normals: [normal(indices[0]), normal(indices[1]), normal(indices[2])],
+                    tex_coords: [tex_coord(indices[0]), tex_coord(indices[1]), tex_coord(indices[2])],
+                    material: instance.material,
+                });
+            }
+        }
+
+        let bounds: Vec<_> = triangles
+            .iter()
+            .map(|triangle| Aabb::from_points(&[triangle.p0, triangle.p0 + triangle.e1, triangle.p0 + triangle.e2]))
+            .collect();
+        let bvh = Bvh::new(&bounds);
+
+        //展開した三角形の配列とBVHをASの代わりとして数える
+        let acceleration_structure_bytes = (std::mem::size_of::<Triangle>() * triangles.len() + bvh.size_in_bytes()) as u64;
+
+        let stats = SceneStats {
+            mesh_count: scene.meshes.len(),
+            instance_count: scene.instances.len(),
+            triangle_count: triangles.len(),
+            acceleration_structure_bytes,
+            uncompacted_bytes: acceleration_structure_bytes,
+        };
+
+        Ok(Self {
+            triangles,
+            bvh,
+            materials: scene.materials.clone(),
+            textures: scene.textures.clone(),
+            stats,
+        })
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.triangles.len()
+    }
+
+    /// 一番近い交差
+    pub fn intersect(&self, ray: &Ray, t_max: f32) -> Option<Hit> {
+        let (t, (u, v, triangle)) = self.bvh.closest(ray.origin, ray.direction, t_max, |i, t_max| {
+            let triangle = &self.triangles[i];
+
+            intersect_triangle(ray, triangle, t_max).map(|(t, u, v)| (t, (u, v, triangle)))
+        })?;
+
+        let face_normal = triangle.e1.cross(triangle.e2).normalize();
+        let interpolated = (triangle.normals[0] * (1.0 - u - v)
+            + triangle.normals[1] * u
+            + triangle.normals[2] * v)
+            .normalize_or_zero();
+        let normal = if interpolated == Vec3::ZERO { face_normal } else { interpolated };
+
+        let front_face = ray.direction.dot(face_normal) < 0.0;
+
+        Some(Hit {
+            t,
+            position: ray.at(t),
+            normal: if front_face { normal } else { -normal },
+            front_face,
+            material: triangle.material,
+            tex_coord: triangle.tex_coords[0] * (1.0 - u - v)
+                + triangle.tex_coords[1] * u
+                + triangle.tex_coords[2] * v,
+        })
+    }
+}
+
+//Möller–Trumbore、重心座標のu, vも返す
+fn intersect_triangle(ray: &Ray, triangle: &Triangle, t_max: f32) -> Option<(f32, f32, f32)> {
+    let p = ray.direction.cross(triangle.e2);
+    let det = triangle.e1.dot(p);
+
+    //レイと平行か、面積のない三角形
+    if det.abs() < f32::EPSILON {
+        return None;
+    }
+
+    let inv_det = 1.0 / det;
+    let s = ray.origin - triangle.p0;
+    let u = s.dot(p) * inv_det;
+
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+
+    let q = s.cross(triangle.e1);
+    let v = ray.direction.dot(q) * inv_det;
+
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let t = triangle.e2.dot(q) * inv_det;
+
+    if t <= RAY_EPSILON || t >= t_max {
+        return None;
+    }
+
+    Some((t, u, v))
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Scattered {
+    pub emitted: Vec3,
+    pub attenuation: Vec3,
+    /// 吸収された場合はNone
+    pub ray: Option<Ray>,
+}
+
+/// albedoにテクスチャの色を掛ける
+pub fn albedo(material: &Material, textures: &[Texture], tex_coord: Vec2) -> Vec3 {
+    let albedo = Vec3::from(material.albedo);
+
+    match material.albedo_texture.and_then(|texture| textures.get(texture)) {
+        Some(texture) => albedo * texture.sample(tex_coord).truncate(),
+        None => albedo,
+    }
+}
+
+/// callableシェーダーと同じ分け方でマテリアルを評価する
+pub fn scatter(material: &Material, textures: &[Texture], ray: &Ray, hit: &Hit, rng: &mut Rng) -> Scattered {
+    let albedo = albedo(material, textures, hit.tex_coord);
+
+    let (attenuation, direction) = match material.kind {
+        MaterialKind::Lambertian => {
+            let direction = hit.normal + rng.unit_vector();
+
+            //法線と逆向きの乱数を引いた場合
+            let direction = if direction.length_squared() < 1e-8 { hit.normal } else { direction };
+
+            (albedo, Some(direction))
+        }
+        MaterialKind::Metal => {
+            let reflected = reflect(ray.direction, hit.normal) + rng.in_unit_sphere() * material.roughness;
+
+            //面の内側に向いた場合は吸収する
+            let direction = if reflected.dot(hit.normal) > 0.0 { Some(reflected) } else { None };
+
+            (albedo, direction)
+        }
+        MaterialKind::Dielectric => {
+            let ratio = if hit.front_face { 1.0 / material.ior } else { material.ior };
+
+            let cos_theta = (-ray.direction).dot(hit.normal).min(1.0);
+            let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+
+            let direction = if ratio * sin_theta > 1.0 || reflectance(cos_theta, ratio) > rng.next_f32() {
+                reflect(ray.direction, hit.normal)
+            } else {
+                refract(ray.direction, hit.normal, ratio)
+            };
+
+            (Vec3::ONE, Some(direction))
+        }
+        MaterialKind::Emissive => (Vec3::ZERO, None),
+    };
+
+    let emitted = match material.kind {
+        MaterialKind::Emissive if hit.front_face => Vec3::from(material.emission),
+        _ => Vec3::ZERO,
+    };
+
+    Scattered {
+        emitted,
+        attenuation,
+        ray: direction.map(|direction| Ray {
+            origin: hit.position,
+            direction: direction.normalize(),
+        }),
+    }
+}
+
+fn reflect(direction: Vec3, normal: Vec3) -> Vec3 {
+    direction - normal * 2.0 * direction.dot(normal)
+}
+
+fn refract(direction: Vec3, normal: Vec3, ratio: f32) -> Vec3 {
+    let cos_theta = (-direction).dot(normal).min(1.0);
+    let perpendicular = (direction + normal * cos_theta) * ratio;
+    let parallel = normal * -(1.0 - perpendicular.length_squared()).abs().sqrt();
+
+    perpendicular + parallel
+}
+
+//Schlickの近似
+fn reflectance(cosine: f32, ratio: f32) -> f32 {
+    let r0 = ((1.0 - ratio) / (1.0 + ratio)).powi(2);
+
+    r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
+}
+
+/// 同じseedなら同じ結果になる乱数、splitmix64
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+
+        z ^ (z >> 31)
+    }
+
+    /// 0以上1未満
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    pub fn in_unit_sphere(&mut self) -> Vec3 {
+        loop {
+            let p = Vec3::new(self.next_f32(), self.next_f32(), self.next_f32()) * 2.0 - Vec3::ONE;
+
+            if p.length_squared() < 1.0 {
+                return p;
+            }
+        }
+    }
+
+    pub fn unit_vector(&mut self) -> Vec3 {
+        self.in_unit_sphere().normalize_or_zero()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Mat4;
+    use crate::render_backend::progress::CancellationToken;
+    use crate::scene_description::{InstanceDescription, MeshDescription};
+    use super::*;
+
+    //y = 0の平面をcells x cellsの四角形に分けたもの
+    fn grid(cells: u32) -> MeshDescription {
+        let mut mesh = MeshDescription::default();
+
+        for z in 0..=cells {
+            for x in 0..=cells {
+                mesh.positions.push(Vec3::new(x as f32 / cells as f32 - 0.5, 0.0, z as f32 / cells as f32 - 0.5));
+                mesh.normals.push(Vec3::Y);
+            }
+        }
+
+        for z in 0..cells {
+            for x in 0..cells {
+                let i = z * (cells + 1) + x;
+                mesh.indices.extend_from_slice(&[i, i + cells + 1, i + 1, i + 1, i + cells + 1, i + cells + 2]);
+            }
+        }
+
+        mesh
+    }
+
+    //床の上に材質の違う三角形を並べる
+    fn scene() -> SceneDescription {
+        let triangle = MeshDescription::triangle([Vec3::new(-0.5, 0.0, 0.0), Vec3::new(0.5, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)]);
+
+        let mut instances = vec![InstanceDescription {
+            mesh: 0,
+            transform: Mat4::from_scale(Vec3::splat(10.0)),
+            material: 0,
+        }];
+
+        for i in 0..4 {
+            instances.push(InstanceDescription {
+                mesh: 1,
+                transform: Mat4::from_translation(Vec3::new(i as f32 - 1.5, 0.0, -(i as f32))),
+                material: i + 1,
+            });
+        }
+
+        SceneDescription {
+            meshes: vec![grid(8), triangle],
+            instances,
+            materials: vec![
+                Material::lambertian([0.5; 3]),
+                Material::lambertian([0.8, 0.3, 0.3]),
+                Material::metal([0.9; 3], 0.2),
+                Material::dielectric(1.5),
+                Material::emissive([4.0; 3]),
+            ],
+            textures: vec![],
+        }
+    }
+
+    fn render(threads: usize) -> FloatImage {
+        let mut settings = RenderSettings::new(37, 23);
+        settings.samples_per_pixel = 4;
+        settings.max_depth = 4;
+        settings.seed = 7;
+        settings.camera = Camera::new(Vec3::new(0.0, 1.0, 4.0), 0.0, -0.2, 60f32.to_radians());
+
+        let mut backend = HostBackend::new().threads(threads).tile_size(8);
+        backend.prepare(&scene()).unwrap();
+        backend.render(&settings).unwrap()
+    }
+
+    #[test]
+    fn thread_count_does_not_change_the_image() {
+        let single = render(1);
+        let bits = |image: &FloatImage| -> Vec<[u32; 4]> {
+            image.pixels.iter().map(|pixel| pixel.map(f32::to_bits)).collect()
+        };
+
+        //何か写っていることを確かめる
+        assert!(single.pixels.iter().any(|pixel| pixel[0] != single.pixels[0][0]));
+
+        for threads in [2, 5] {
+            assert_eq!(bits(&render(threads)), bits(&single), "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn cancelling_before_render_reports_nothing_done() {
+        let mut backend = HostBackend::new().threads(2);
+        backend.prepare(&scene()).unwrap();
+
+        let cancellation = CancellationToken::new();
+        cancellation.cancel();
+
+        let output = backend
+            .render_with_control(&RenderSettings::new(16, 16), &mut RenderControl::new().cancellation(cancellation))
+            .unwrap();
+
+        assert!(output.cancelled);
+        assert_eq!(output.samples_done, 0);
+
+        let output = backend.render_with_control(&RenderSettings::new(16, 16), &mut RenderControl::new()).unwrap();
+
+        assert!(!output.cancelled);
+        assert_eq!(output.samples_done, 16 * 16 * 16);
+    }
+
+    #[test]
+    fn bvh_finds_the_same_hit_as_brute_force() {
+        let scene = HostScene::new(&scene()).unwrap();
+        let mut rng = Rng::new(1);
+        let mut hits = 0;
+
+        for _ in 0..500 {
+            let ray = Ray {
+                origin: Vec3::new(0.0, 2.0, 4.0) + rng.in_unit_sphere(),
+                direction: (Vec3::new(0.0, -0.5, -1.0) + rng.in_unit_sphere() * 0.8).normalize(),
+            };
+
+            let brute_force = scene
+                .triangles
+                .iter()
+                .filter_map(|triangle| intersect_triangle(&ray, triangle, f32::INFINITY).map(|(t, _, _)| (t, triangle.material)))
+                .fold(None, |closest: Option<(f32, usize)>, hit| match closest {
+                    Some(closest) if closest.0 <= hit.0 => Some(closest),
+                    _ => Some(hit),
+                });
+
+            let hit = scene.intersect(&ray, f32::INFINITY).map(|hit| (hit.t, hit.material));
+
+            assert_eq!(hit, brute_force);
+            hits += hit.is_some() as usize;
+        }
+
+        //ほとんどのレイは床に当たる
+        assert!(hits > 250, "{} hits", hits);
+    }
+}
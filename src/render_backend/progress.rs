@@ -0,0 +1,272 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// cloneしたものは同じフラグを共有する、タイルやdispatchの合間に確認する
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RenderProgress {
+    /// hostはタイル、Vulkanはdispatchの数
+    pub tiles_done: usize,
+    pub tiles_total: usize,
+    /// ピクセル数 x samples_per_pixel
+    pub samples_done: u64,
+    pub samples_total: u64,
+    pub elapsed: Duration,
+}
+
+impl RenderProgress {
+    pub fn fraction(&self) -> f64 {
+        if self.samples_total == 0 {
+            return 1.0;
+        }
+
+        self.samples_done as f64 / self.samples_total as f64
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.samples_done >= self.samples_total
+    }
+
+    /// ここまでの速度で残りを描いた場合の時間、まだ何も終わっていなければNone
+    pub fn estimated_remaining(&self) -> Option<Duration> {
+        if self.samples_done == 0 {
+            return None;
+        }
+
+        let remaining = self.samples_total.saturating_sub(self.samples_done);
+
+        Some(self.elapsed.mul_f64(remaining as f64 / self.samples_done as f64))
+    }
+
+    /// `[#####-----]  50.0% 12/24 tiles, ETA 00:03`の形
+    pub fn progress_bar(&self, width: usize) -> String {
+        let filled = ((self.fraction() * width as f64) as usize).min(width);
+
+        let eta = match self.estimated_remaining() {
+            Some(remaining) => format_duration(remaining),
+            None => "--:--".to_owned(),
+        };
+
+        format!(
+            "[{}{}] {:5.1}% {}/{} tiles, ETA {}",
+            "#".repeat(filled),
+            "-".repeat(width - filled),
+            self.fraction() * 100.0,
+            self.tiles_done,
+            self.tiles_total,
+            eta,
+        )
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+
+    if seconds >= 60 * 60 {
+        format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+    } else {
+        format!("{:02}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+/// 終わったタイルを数えて進捗を作る、時間は外から渡す
+#[derive(Copy, Clone, Debug)]
+pub struct ProgressTracker {
+    progress: RenderProgress,
+    started: Instant,
+}
+
+impl ProgressTracker {
+    pub fn new(tiles_total: usize, samples_total: u64, started: Instant) -> Self {
+        Self {
+            progress: RenderProgress {
+                tiles_done: 0,
+                tiles_total,
+                samples_done: 0,
+                samples_total,
+                elapsed: Duration::ZERO,
+            },
+            started,
+        }
+    }
+
+    pub fn progress(&self) -> RenderProgress {
+        self.progress
+    }
+
+    pub fn on_tile_done(&mut self, samples: u64, now: Instant) -> RenderProgress {
+        self.progress.tiles_done += 1;
+        self.progress.samples_done += samples;
+        self.progress.elapsed = now.saturating_duration_since(self.started);
+
+        self.progress
+    }
+}
+
+pub type ProgressCallback<'a> = Box<dyn FnMut(&RenderProgress) + 'a>;
+
+/// renderに渡す進捗の通知先と中断のフラグ
+#[derive(Default)]
+pub struct RenderControl<'a> {
+    pub cancellation: CancellationToken,
+    on_progress: Option<ProgressCallback<'a>>,
+}
+
+impl<'a> RenderControl<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = cancellation;
+        self
+    }
+
+    /// renderを呼んだスレッドから呼ばれる
+    pub fn on_progress<F: FnMut(&RenderProgress) + 'a>(mut self, on_progress: F) -> Self {
+        self.on_progress = Some(Box::new(on_progress));
+        self
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+
+    pub fn report(&mut self, progress: &RenderProgress) {
+        if let Some(on_progress) = self.on_progress.as_mut() {
+            on_progress(progress);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn progress_with_tiles(tiles_done: usize, tiles_total: usize, samples_done: u64, samples_total: u64, elapsed: Duration) -> RenderProgress {
+        RenderProgress {
+            tiles_done,
+            tiles_total,
+            samples_done,
+            samples_total,
+            elapsed,
+        }
+    }
+
+    //1タイル1サンプル
+    fn progress(samples_done: u64, samples_total: u64, elapsed: Duration) -> RenderProgress {
+        progress_with_tiles(samples_done as usize, samples_total as usize, samples_done, samples_total, elapsed)
+    }
+
+    #[test]
+    fn empty_and_complete_progress() {
+        let start = progress(0, 8, Duration::ZERO);
+        assert_eq!(start.fraction(), 0.0);
+        assert!(!start.is_complete());
+        assert_eq!(start.progress_bar(4), "[----]   0.0% 0/8 tiles, ETA --:--");
+
+        let done = progress(8, 8, Duration::from_secs(5));
+        assert_eq!(done.fraction(), 1.0);
+        assert!(done.is_complete());
+        assert_eq!(done.estimated_remaining(), Some(Duration::ZERO));
+        assert_eq!(done.progress_bar(4), "[####] 100.0% 8/8 tiles, ETA 00:00");
+
+        //描くものがない場合は終わっている扱い
+        let nothing = progress(0, 0, Duration::ZERO);
+        assert_eq!(nothing.fraction(), 1.0);
+        assert!(nothing.is_complete());
+    }
+
+    #[test]
+    fn eta_uses_the_rate_so_far() {
+        assert_eq!(progress(0, 100, Duration::from_secs(3)).estimated_remaining(), None);
+        assert_eq!(progress(25, 100, Duration::from_secs(3)).estimated_remaining(), Some(Duration::from_secs(9)));
+        assert_eq!(progress(50, 100, Duration::from_secs(3)).progress_bar(10), "[#####-----]  50.0% 50/100 tiles, ETA 00:03");
+    }
+
+    #[test]
+    fn bar_is_clamped_to_width() {
+        //samples_totalを超えても溢れない
+        assert_eq!(progress(12, 8, Duration::from_secs(1)).progress_bar(4), "[####] 150.0% 12/8 tiles, ETA 00:00");
+        assert_eq!(progress(1, 3, Duration::from_secs(1)).progress_bar(0), "[]  33.3% 1/3 tiles, ETA 00:02");
+        assert_eq!(progress(1, 3, Duration::from_secs(1)).progress_bar(10), "[###-------]  33.3% 1/3 tiles, ETA 00:02");
+    }
+
+    #[test]
+    fn durations_are_formatted_as_clock_time() {
+        assert_eq!(format_duration(Duration::ZERO), "00:00");
+        assert_eq!(format_duration(Duration::from_millis(59_999)), "00:59");
+        assert_eq!(format_duration(Duration::from_secs(61)), "01:01");
+        assert_eq!(format_duration(Duration::from_secs(3599)), "59:59");
+        assert_eq!(format_duration(Duration::from_secs(3600)), "1:00:00");
+        assert_eq!(format_duration(Duration::from_secs(30 * 3600 + 62)), "30:01:02");
+    }
+
+    #[test]
+    fn tracker_counts_tiles_and_elapsed_time() {
+        let started = Instant::now();
+        let mut tracker = ProgressTracker::new(3, 300, started);
+        assert_eq!(tracker.progress(), progress_with_tiles(0, 3, 0, 300, Duration::ZERO));
+
+        tracker.on_tile_done(100, started + Duration::from_secs(2));
+        let second = tracker.on_tile_done(50, started + Duration::from_secs(3));
+
+        assert_eq!(second, progress_with_tiles(2, 3, 150, 300, Duration::from_secs(3)));
+        assert_eq!(second.estimated_remaining(), Some(Duration::from_secs(3)));
+        assert_eq!(tracker.progress(), second);
+
+        //時計が戻っても0にする
+        assert_eq!(tracker.on_tile_done(150, started - Duration::from_secs(1)).elapsed, Duration::ZERO);
+        assert!(tracker.progress().is_complete());
+    }
+
+    #[test]
+    fn cancellation_is_shared_between_clones() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        let control = RenderControl::new().cancellation(token.clone());
+
+        assert!(!token.is_cancelled() && !clone.is_cancelled() && !control.is_cancelled());
+
+        clone.cancel();
+
+        assert!(token.is_cancelled());
+        assert!(control.is_cancelled());
+        //別に作ったものには影響しない
+        assert!(!CancellationToken::new().is_cancelled());
+    }
+
+    #[test]
+    fn reports_go_to_the_callback() {
+        let mut reported = vec![];
+
+        {
+            let mut control = RenderControl::new().on_progress(|progress| reported.push(progress.samples_done));
+            control.report(&progress(1, 4, Duration::ZERO));
+            control.report(&progress(4, 4, Duration::ZERO));
+        }
+
+        assert_eq!(reported, vec![1, 4]);
+        //通知先がなくても何もしない
+        RenderControl::new().report(&progress(1, 4, Duration::ZERO));
+    }
+}
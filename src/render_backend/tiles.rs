@@ -0,0 +1,206 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+pub const DEFAULT_TILE_SIZE: u32 = 32;
+
+/// 画像の中の矩形、端のタイルは小さくなる
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Tile {
+    pub fn pixel_count(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+
+    /// 上の行から順に画像上の座標を返す
+    pub fn pixels(&self) -> impl Iterator<Item = (u32, u32)> {
+        let tile = *self;
+
+        (tile.y..tile.y + tile.height)
+            .flat_map(move |y| (tile.x..tile.x + tile.width).map(move |x| (x, y)))
+    }
+}
+
+/// 上の行から順にtile_size四方で分割する
+pub fn split_tiles(width: u32, height: u32, tile_size: u32) -> Vec<Tile> {
+    let tile_size = tile_size.max(1);
+    let mut tiles = vec![];
+
+    for y in (0..height).step_by(tile_size as usize) {
+        for x in (0..width).step_by(tile_size as usize) {
+            tiles.push(Tile {
+                x,
+                y,
+                width: tile_size.min(width - x),
+                height: tile_size.min(height - y),
+            });
+        }
+    }
+
+    tiles
+}
+
+/// ワーカーごとのタイルのキュー
+/// 自分のキューは前から取り、空になったら一番残っているワーカーの後ろから盗む
+#[derive(Debug)]
+pub struct TileQueues {
+    queues: Vec<Mutex<VecDeque<Tile>>>,
+}
+
+impl TileQueues {
+    /// 近いタイルが同じワーカーに行くように連続した範囲で分ける
+    pub fn new(tiles: Vec<Tile>, worker_count: usize) -> Self {
+        let worker_count = worker_count.max(1);
+        let per_worker = (tiles.len() + worker_count - 1) / worker_count;
+
+        let mut tiles = tiles.into_iter();
+        let queues = (0..worker_count)
+            .map(|_| Mutex::new(tiles.by_ref().take(per_worker).collect()))
+            .collect();
+
+        Self { queues }
+    }
+
+    pub fn worker_count(&self) -> usize {
+        self.queues.len()
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.queues
+            .iter()
+            .map(|queue| lock(queue).len())
+            .sum()
+    }
+
+    pub fn next(&self, worker: usize) -> Option<Tile> {
+        if let Some(tile) = self.queues.get(worker).and_then(|queue| lock(queue).pop_front()) {
+            return Some(tile);
+        }
+
+        self.steal(worker)
+    }
+
+    fn steal(&self, worker: usize) -> Option<Tile> {
+        loop {
+            //長さを見てから盗むまでに他のワーカーが取っていくことがあるのでやり直す
+            let victim = self.queues
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| *i != worker)
+                .map(|(i, queue)| (i, lock(queue).len()))
+                .filter(|(_, len)| *len > 0)
+                .max_by_key(|(_, len)| *len)
+                .map(|(i, _)| i)?;
+
+            if let Some(tile) = lock(&self.queues[victim]).pop_back() {
+                return Some(tile);
+            }
+        }
+    }
+}
+
+//他のワーカーがpanicしても残りのタイルは処理できるようにする
+fn lock(queue: &Mutex<VecDeque<Tile>>) -> std::sync::MutexGuard<'_, VecDeque<Tile>> {
+    queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::thread;
+    use super::*;
+
+    #[test]
+    fn tiles_cover_the_image_once() {
+        let tiles = split_tiles(70, 33, 32);
+
+        assert_eq!(tiles.len(), 6);
+        assert_eq!(tiles[2], Tile { x: 64, y: 0, width: 6, height: 32 });
+        assert_eq!(tiles[5], Tile { x: 64, y: 32, width: 6, height: 1 });
+
+        let pixels: HashSet<_> = tiles.iter().flat_map(Tile::pixels).collect();
+        assert_eq!(pixels.len(), 70 * 33);
+        assert_eq!(tiles.iter().map(Tile::pixel_count).sum::<usize>(), 70 * 33);
+    }
+
+    #[test]
+    fn workers_take_their_own_tiles_in_order() {
+        let queues = TileQueues::new(split_tiles(4, 1, 1), 2);
+
+        assert_eq!(queues.worker_count(), 2);
+        assert_eq!(queues.next(0).map(|tile| tile.x), Some(0));
+        assert_eq!(queues.next(1).map(|tile| tile.x), Some(2));
+        assert_eq!(queues.next(0).map(|tile| tile.x), Some(1));
+        assert_eq!(queues.remaining(), 1);
+    }
+
+    #[test]
+    fn idle_worker_steals_from_the_back_of_the_longest_queue() {
+        //ワーカー0に0..4、ワーカー1に4..8、ワーカー2に8..10
+        let queues = TileQueues::new(split_tiles(10, 1, 1), 3);
+
+        for _ in 0..3 {
+            queues.next(1);
+        }
+        for _ in 0..2 {
+            queues.next(2);
+        }
+
+        //ワーカー2は空なので、一番残っているワーカー0の最後を盗む
+        assert_eq!(queues.next(2).map(|tile| tile.x), Some(3));
+        assert_eq!(queues.next(2).map(|tile| tile.x), Some(2));
+        assert_eq!(queues.next(2).map(|tile| tile.x), Some(1));
+        //残りが同じ数になった場合もどこかから盗む
+        let mut rest = vec![queues.next(2).unwrap().x, queues.next(2).unwrap().x];
+        rest.sort_unstable();
+        assert_eq!(rest, vec![0, 7]);
+        assert_eq!(queues.next(2), None);
+        assert_eq!(queues.next(0), None);
+        assert_eq!(queues.remaining(), 0);
+    }
+
+    #[test]
+    fn concurrent_workers_render_every_tile_once() {
+        let tiles = split_tiles(100, 100, 7);
+        let tile_count = tiles.len();
+        let queues = Arc::new(TileQueues::new(tiles, 4));
+
+        let workers: Vec<_> = (0..4)
+            .map(|worker| {
+                let queues = Arc::clone(&queues);
+
+                thread::spawn(move || {
+                    let mut taken = vec![];
+
+                    while let Some(tile) = queues.next(worker) {
+                        taken.push((tile.x, tile.y));
+                    }
+
+                    taken
+                })
+            })
+            .collect();
+
+        let taken: Vec<_> = workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect();
+        let unique: HashSet<_> = taken.iter().collect();
+
+        assert_eq!(taken.len(), tile_count);
+        assert_eq!(unique.len(), tile_count);
+    }
+
+    #[test]
+    fn single_worker_drains_other_queues() {
+        let queues = TileQueues::new(split_tiles(9, 1, 1), 3);
+
+        let mut taken: Vec<_> = std::iter::from_fn(|| queues.next(0)).map(|tile| tile.x).collect();
+        taken.sort_unstable();
+
+        assert_eq!(taken, (0..9).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,371 @@
+use std::ffi::CStr;
+use std::time::Instant;
+use ash::vk::{AccelerationStructureInstanceKHR, Extent2D, Extent3D, Format, Queue};
+use log::debug;
+use crate::constants::TRIANGLE_HIT_GROUP_NAME;
+use crate::error::{CottonError, Result};
+use crate::render_backend::{BackendCapabilities, BackendKind, FloatImage, RenderBackend, RenderOutput, RenderSettings, SceneStats};
+use crate::render_backend::progress::{ProgressTracker, RenderControl};
+use crate::renderer::acceleration_structures::AccelerationStructures;
+use crate::renderer::acceleration_structures::top_level_acceleration_structures::TopLevelAccelerationStructures;
+use crate::renderer::acceleration_structures::triangle_bottom_level_acceleration_structure::TriangleBottomLevelAccelerationStructure;
+use crate::renderer::backends::Backends;
+use crate::renderer::frames::Frame;
+use crate::renderer::image_readback::read_rgba32f_image;
+use crate::renderer::images::Images;
+use crate::renderer::materials::{MaterialCallables, MaterialKind};
+use crate::renderer::pipeline_caches::PipelineCaches;
+use crate::renderer::pipelines::Pipelines;
+use crate::renderer::ray_tracing_pipeline_desc::RayTracingShaderGroups;
+use crate::renderer::render_passes::RenderPasses;
+use crate::renderer::scene_buffers::SceneBuffers;
+use crate::renderer::shader_module::ShaderModules;
+use crate::renderer::texture_images::TextureImages;
+use crate::renderer::Renderer;
+use crate::scene::{to_transform_matrix, Scene};
+use crate::scene_description::{SceneChanges, SceneDescription};
+
+//to_imageと同じく描画結果をそのまま読み出せるformat
+const TARGET_FORMAT: Format = Format::R32G32B32A32_SFLOAT;
+
+/// BackendsとAccelerationStructuresとPipelineCachesは呼び出し側で作って貸す
+pub struct VulkanBackend<'a> {
+    backends: &'a Backends,
+    acceleration_structures: &'a AccelerationStructures<'a>,
+    pipeline_caches: &'a PipelineCaches<'a>,
+    graphics_queue: Queue,
+    shader_code: Vec<u8>,
+    prepared: Option<PreparedScene<'a>>,
+}
+
+//1回のdispatchで1ピクセルあたりに飛ばすサンプル数
+const SAMPLES_PER_DISPATCH: u32 = 4;
+
+/// 描画先とそれを参照するpipeline、大きさが変わらない間は使い回す
+//imageより先にdescriptorを持つrendererを破棄する
+struct RenderTarget<'a> {
+    extent: Extent2D,
+    renderer: Renderer<'a>,
+    frame: Frame<'a>,
+    images: Images<'a>,
+}
+
+impl<'a> RenderTarget<'a> {
+    fn new(
+        backends: &'a Backends,
+        shader_code: &[u8],
+        pipeline_caches: &'a PipelineCaches<'a>,
+        prepared: &PreparedScene<'a>,
+        extent: Extent2D,
+        graphics_queue: Queue,
+    ) -> Result<Self> {
+        debug!("create vulkan render target {}x{}", extent.width, extent.height);
+
+        let extent3d = Extent3D::builder()
+            .width(extent.width)
+            .height(extent.height)
+            .depth(1)
+            .build();
+
+        let images = Images::new(backends, 1, TARGET_FORMAT, extent3d, graphics_queue)?;
+
+        //ray tracingでは使わないのでpipelineを作った後は破棄する
+        let render_passes = RenderPasses::new(
+            backends,
+            TARGET_FORMAT,
+            images.image_views.clone(),
+            extent,
+        )?;
+
+        let shader_modules = ShaderModules::new(&backends.device, shader_code)?;
+
+        let pipelines = Pipelines::new(
+            backends,
+            shader_modules,
+            prepared.shader_groups.clone(),
+            extent,
+            &render_passes,
+            &prepared.scene_buffers,
+            &prepared.tlas,
+            &prepared.textures,
+            pipeline_caches,
+            graphics_queue,
+            images.image_views[0],
+        )?;
+
+        Ok(Self {
+            extent,
+            renderer: Renderer::new(backends, pipelines),
+            frame: Frame::new(backends)?,
+            images,
+        })
+    }
+}
+
+//TLASより先にBLASを破棄しないように宣言順に並べる
+//descriptorがシーンを参照するので描画先を最初に破棄する
+struct PreparedScene<'a> {
+    target: Option<RenderTarget<'a>>,
+    tlas: TopLevelAccelerationStructures<'a>,
+    blases: Vec<TriangleBottomLevelAccelerationStructure<'a>>,
+    shader_groups: RayTracingShaderGroups,
+    material_callables: MaterialCallables,
+    scene_buffers: SceneBuffers<'a>,
+    textures: TextureImages<'a>,
+    mesh_count: usize,
+    triangle_count: usize,
+}
+
+impl PreparedScene<'_> {
+    fn stats(&self) -> SceneStats {
+        let tlas_bytes = self.tlas.top_level_acceleration_structure_buffer.size;
+        let blas_bytes: u64 = self.blases.iter().map(|blas| blas.bottom_acceleration_buffer.size).sum();
+        let uncompacted_blas_bytes: u64 = self.blases.iter().map(|blas| blas.uncompacted_size).sum();
+
+        SceneStats {
+            mesh_count: self.mesh_count,
+            instance_count: self.tlas.scene.instances.len(),
+            triangle_count: self.triangle_count,
+            acceleration_structure_bytes: tlas_bytes + blas_bytes,
+            uncompacted_bytes: tlas_bytes + uncompacted_blas_bytes,
+        }
+    }
+}
+
+impl<'a> VulkanBackend<'a> {
+    pub fn new(
+        backends: &'a Backends,
+        acceleration_structures: &'a AccelerationStructures<'a>,
+        pipeline_caches: &'a PipelineCaches<'a>,
+        shader_code: Vec<u8>,
+    ) -> Result<Self> {
+        let graphics_queue = backends.create_graphics_queue(0)?;
+
+        Ok(Self {
+            backends,
+            acceleration_structures,
+            pipeline_caches,
+            graphics_queue,
+            shader_code,
+            prepared: None,
+        })
+    }
+
+    fn create_instances(
+        scene: &SceneDescription,
+        blases: &[TriangleBottomLevelAccelerationStructure<'a>],
+        shader_groups: &RayTracingShaderGroups,
+    ) -> Result<Vec<AccelerationStructureInstanceKHR>> {
+        let triangle_hit_group_offset = shader_groups
+            .hit_group_offset(TRIANGLE_HIT_GROUP_NAME)
+            .ok_or_else(|| CottonError::InvalidPipelineDesc(format!(
+                "hit group {} is not registered",
+                TRIANGLE_HIT_GROUP_NAME,
+            )))?;
+
+        let instances = scene
+            .instances
+            .iter()
+            .enumerate()
+            .map(|(i, instance)| Scene::create_triangle_instance(
+                blases[instance.mesh].get_device_address_info(),
+                i as u32,
+                triangle_hit_group_offset,
+                to_transform_matrix(instance.transform),
+            ))
+            .collect();
+
+        Ok(instances)
+    }
+}
+
+impl<'a> RenderBackend for VulkanBackend<'a> {
+    fn capabilities(&self) -> BackendCapabilities {
+        let properties = unsafe {
+            self.backends.instance.get_physical_device_properties(self.backends.physical_device)
+        };
+
+        let device_name = unsafe { CStr::from_ptr(properties.device_name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+
+        BackendCapabilities {
+            kind: BackendKind::Vulkan,
+            device_name: Some(device_name),
+            hardware_ray_tracing: true,
+            max_image_dimension: properties.limits.max_image_dimension2_d,
+        }
+    }
+
+    fn prepare(&mut self, scene: &SceneDescription) -> Result<()> {
+        debug!("prepare vulkan scene");
+
+        scene.validate()?;
+
+        //前のシーンのASを先に破棄する
+        self.prepared = None;
+
+        let shader_groups = Pipelines::classical_pipeline_desc().build()?;
+        let material_callables = MaterialCallables::from_groups(&shader_groups, &MaterialKind::ALL)?;
+
+        let blases = self.acceleration_structures.create_mesh_blases(
+            scene
+                .meshes
+                .iter()
+                .map(|mesh| (mesh.vertices(), mesh.indices.clone()))
+                .collect(),
+            self.graphics_queue,
+        )?;
+
+        let tlas = self.acceleration_structures.create_tlas(
+            Scene::from_instances(self.backends, Self::create_instances(scene, &blases, &shader_groups)?)?,
+            self.graphics_queue,
+        )?;
+
+        let scene_buffers = SceneBuffers::new(self.backends, scene, &material_callables)?;
+        let textures = TextureImages::new(self.backends, &scene.textures, self.graphics_queue)?;
+
+        self.prepared = Some(PreparedScene {
+            target: None,
+            tlas,
+            blases,
+            shader_groups,
+            material_callables,
+            scene_buffers,
+            textures,
+            mesh_count: scene.meshes.len(),
+            triangle_count: scene.triangle_count(),
+        });
+
+        Ok(())
+    }
+
+    fn update(&mut self, scene: &SceneDescription, changes: &SceneChanges) -> Result<()> {
+        //テクスチャは数が変わるとdescriptorも作り直しになるのでまとめて作り直す
+        if changes.meshes || changes.textures {
+            return self.prepare(scene);
+        }
+
+        if !changes.instances && !changes.transforms && !changes.materials {
+            return Ok(());
+        }
+
+        scene.validate()?;
+
+        let prepared = match self.prepared.as_mut() {
+            Some(prepared) => prepared,
+            None => return self.prepare(scene),
+        };
+
+        //数が変わった場合はbufferの大きさが変わるので作り直す
+        if scene.materials.len() != prepared.scene_buffers.material_count()
+            || scene.instances.len() != prepared.scene_buffers.instance_count()
+        {
+            return self.prepare(scene);
+        }
+
+        if changes.materials {
+            prepared.scene_buffers.write_materials(scene, &prepared.material_callables)?;
+        }
+
+        if !changes.instances && !changes.transforms {
+            return Ok(());
+        }
+
+        prepared.scene_buffers.write_instances(scene)?;
+
+        //transformだけが変わった場合はrefitになる
+        let instances = Self::create_instances(scene, &prepared.blases, &prepared.shader_groups)?;
+        let tlas_handle = prepared.tlas.top_level_acceleration_structure_khr;
+        let tlas_update = prepared.tlas.update(instances, self.graphics_queue)?;
+
+        //作り直された場合はdescriptorが古いTLASを参照しているのでpipelineごと作り直す
+        if prepared.tlas.top_level_acceleration_structure_khr != tlas_handle {
+            prepared.target = None;
+        }
+        prepared.triangle_count = scene.triangle_count();
+
+        debug!("update vulkan scene: tlas {:?}", tlas_update);
+
+        Ok(())
+    }
+
+    fn scene_stats(&self) -> Option<SceneStats> {
+        self.prepared.as_ref().map(PreparedScene::stats)
+    }
+
+    fn render_with_control(&mut self, settings: &RenderSettings, control: &mut RenderControl) -> Result<RenderOutput> {
+        settings.validate(&self.capabilities())?;
+
+        let pixel_count = settings.width as u64 * settings.height as u64;
+        let samples_total = pixel_count * settings.samples_per_pixel as u64;
+        let dispatch_count = (settings.samples_per_pixel + SAMPLES_PER_DISPATCH - 1) / SAMPLES_PER_DISPATCH;
+        let mut tracker = ProgressTracker::new(dispatch_count as usize, samples_total, Instant::now());
+
+        if control.is_cancelled() {
+            return Ok(RenderOutput {
+                image: FloatImage::new(settings.width, settings.height),
+                cancelled: true,
+                samples_done: 0,
+            });
+        }
+
+        let prepared = self
+            .prepared
+            .as_mut()
+            .ok_or_else(|| CottonError::InvalidScene("prepare must be called before render".to_owned()))?;
+
+        let extent = Extent2D::builder()
+            .width(settings.width)
+            .height(settings.height)
+            .build();
+
+        //大きさが変わらない場合はpipelineを使い回す
+        if prepared.target.as_ref().map(|target| target.extent) != Some(extent) {
+            //古いものを先に破棄する
+            prepared.target = None;
+            prepared.target = Some(RenderTarget::new(
+                self.backends,
+                &self.shader_code,
+                self.pipeline_caches,
+                prepared,
+                extent,
+                self.graphics_queue,
+            )?);
+        }
+
+        let target = prepared.target.as_ref().expect("render target was just created");
+        let target_image = target.images.images[0];
+
+        //dispatchの間でキャンセルを確認する、描画先にはそれまでのサンプルの平均が入っている
+        let mut sample_index = 0;
+
+        while sample_index < settings.samples_per_pixel {
+            let samples = SAMPLES_PER_DISPATCH.min(settings.samples_per_pixel - sample_index);
+            let constants = settings.camera.ray_generation_constants(sample_index, samples, settings.seed, settings.max_depth);
+
+            target.renderer.rendering(&target.frame, target_image, extent, &constants, self.graphics_queue)?;
+            sample_index += samples;
+
+            control.report(&tracker.on_tile_done(pixel_count * samples as u64, Instant::now()));
+
+            if control.is_cancelled() {
+                break;
+            }
+        }
+
+        let extent3d = Extent3D::builder()
+            .width(settings.width)
+            .height(settings.height)
+            .depth(1)
+            .build();
+
+        let data = read_rgba32f_image(self.backends, target_image, extent3d, self.graphics_queue)?;
+
+        Ok(RenderOutput {
+            image: FloatImage::from_rgba(settings.width, settings.height, &data)?,
+            cancelled: sample_index < settings.samples_per_pixel,
+            samples_done: tracker.progress().samples_done,
+        })
+    }
+}
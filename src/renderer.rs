@@ -1,7 +1,14 @@
-use ash::vk::{AccessFlags, ClearColorValue, CommandBufferBeginInfo, CommandBufferResetFlags, CommandBufferUsageFlags, DependencyFlags, Fence, Image, ImageAspectFlags, ImageLayout, ImageMemoryBarrier, ImageSubresourceRange, ImageView, PipelineStageFlags, Queue, SubmitInfo};
+use ash::vk::{AccessFlags, CommandBuffer, CommandBufferBeginInfo, CommandBufferUsageFlags, DependencyFlags, Extent2D, Fence, Filter, Image, ImageAspectFlags, ImageBlit, ImageLayout, ImageMemoryBarrier, ImageSubresourceLayers, ImageSubresourceRange, ImageView, Offset3D, PipelineStageFlags, Queue, SubmitInfo};
+use classical_raytracer_shader::RayGenerationConstants;
 use log::debug;
+use crate::error::Result;
 use crate::renderer::backends::Backends;
+use crate::renderer::frame_state::FrameState;
+use crate::renderer::frames::{Frame, FrameRing};
+use crate::renderer::images::Images;
 use crate::renderer::pipelines::Pipelines;
+use crate::renderer::shader_module::ShaderModules;
+use crate::renderer::swapchains::Swapchains;
 
 pub mod backends;
 pub mod swapchains;
@@ -12,7 +19,20 @@ pub mod render_passes;
 pub mod pipelines;
 pub mod acceleration_structures;
 pub mod mesh_buffer;
+pub mod scene_buffers;
 pub mod shader_module;
+pub mod capability_report;
+pub mod shader_binding_table;
+pub mod descriptor_sets;
+pub mod spirv_reflection;
+pub mod shader_reload;
+pub mod pipeline_caches;
+pub mod ray_tracing_pipeline_desc;
+pub mod materials;
+pub mod frame_state;
+pub mod frames;
+pub mod image_readback;
+pub mod texture_images;
 
 pub struct Renderer<'a> {
     backends: &'a Backends,
@@ -30,111 +50,296 @@ impl<'a> Renderer<'a> {
         }
     }
 
-    pub fn rendering(
+    /// 検証に失敗した場合は今までのpipelineのまま
+    pub fn reload_shaders(&mut self, code: &[u8]) -> Result<()> {
+        let shader_modules = ShaderModules::new(&self.backends.device, code)?;
+
+        self.pipelines.reload(self.backends, shader_modules)
+    }
+
+    /// リサイズで描画先を作り直した場合に呼ぶ
+    pub fn set_target_image_view(&mut self, target_image_view: ImageView) -> Result<()> {
+        self.pipelines.set_target_image_view(target_image_view)
+    }
+
+    fn record_rendering(
         &self,
+        command_buffer: CommandBuffer,
         image: Image,
-        graphics_queue: Queue,
-    ) -> anyhow::Result<()> {
-        debug!("rendering");
-
-        let command_buffer_begin_info = CommandBufferBeginInfo::builder()
-            .flags(CommandBufferUsageFlags::SIMULTANEOUS_USE)
+        extent: Extent2D,
+        constants: &RayGenerationConstants,
+    ) {
+        //前の結果を読んで足し合わせるので、前のフレームの書き込みとblitの読み込みを待つ
+        let trace_barrier = ImageMemoryBarrier::builder()
+            .src_access_mask(AccessFlags::SHADER_WRITE | AccessFlags::TRANSFER_READ)
+            .dst_access_mask(AccessFlags::SHADER_READ | AccessFlags::SHADER_WRITE)
+            .old_layout(ImageLayout::GENERAL)
+            .new_layout(ImageLayout::GENERAL)
+            .image(image)
+            .subresource_range(color_subresource_range())
             .build();
 
-        let command_pool = self.backends.create_graphics_command_pool();
-        let command_buffers = self.backends.create_command_buffers(command_pool, 1);
-        let command_buffer = command_buffers[0];
-
-        //clear image
         unsafe {
-            self.backends
-                .device
-                .reset_command_buffer(command_buffer, CommandBufferResetFlags::RELEASE_RESOURCES)
-                .unwrap();
-
-            self
-                .backends
-                .device
-                .begin_command_buffer(
-                    command_buffer,
-                    &command_buffer_begin_info
-                ).unwrap();
-
-            let range = ImageSubresourceRange::builder()
-                .aspect_mask(ImageAspectFlags::COLOR)
-                .base_mip_level(0)
-                .level_count(1)
-                .base_array_layer(0)
-                .layer_count(1)
-                .build();
-
-            self.backends.device.cmd_clear_color_image(
+            self.backends.device.cmd_pipeline_barrier(
                 command_buffer,
-                image,
-                ImageLayout::GENERAL,
-                &ClearColorValue {
-                    float32: [0.0, 0.0, 1.0, 1.0],
-                },
-                &[range]
+                PipelineStageFlags::RAY_TRACING_SHADER_KHR | PipelineStageFlags::TRANSFER,
+                PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+                DependencyFlags::empty(),
+                &[],
+                &[],
+                &[trace_barrier]
             );
+        }
 
-            let image_barrier = ImageMemoryBarrier::builder()
-                .src_access_mask(AccessFlags::COLOR_ATTACHMENT_WRITE)
-                .dst_access_mask(AccessFlags::SHADER_WRITE | AccessFlags::SHADER_READ)
-                .old_layout(ImageLayout::GENERAL)
-                .new_layout(ImageLayout::GENERAL)
-                .image(image)
-                .subresource_range(
-                    ImageSubresourceRange::builder()
-                        .aspect_mask(ImageAspectFlags::COLOR)
-                        .base_mip_level(0)
-                        .level_count(1)
-                        .base_array_layer(0)
-                        .layer_count(1)
-                        .build(),
-                )
-                .build();
+        self.pipelines.record_trace_rays(command_buffer, constants, extent);
 
+        //読み出しとblitはどちらもtransfer
+        let transfer_barrier = ImageMemoryBarrier::builder()
+            .src_access_mask(AccessFlags::SHADER_WRITE)
+            .dst_access_mask(AccessFlags::TRANSFER_READ)
+            .old_layout(ImageLayout::GENERAL)
+            .new_layout(ImageLayout::GENERAL)
+            .image(image)
+            .subresource_range(color_subresource_range())
+            .build();
+
+        unsafe {
             self.backends.device.cmd_pipeline_barrier(
                 command_buffer,
-                PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
                 PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+                PipelineStageFlags::TRANSFER,
                 DependencyFlags::empty(),
                 &[],
                 &[],
-                &[image_barrier]
+                &[transfer_barrier]
             );
+        }
+    }
+
+    /// フレームのコマンドを使い回すので毎回poolを作らず、queue全体ではなくfenceで待つ
+    pub fn rendering(
+        &self,
+        frame: &Frame,
+        image: Image,
+        extent: Extent2D,
+        constants: &RayGenerationConstants,
+        graphics_queue: Queue,
+    ) -> Result<()> {
+        debug!("rendering");
+
+        frame.wait()?;
+        frame.reset()?;
 
-            self.backends.device.end_command_buffer(command_buffer).unwrap();
+        let command_buffer = frame.command_buffer;
+
+        unsafe {
+            self
+                .backends
+                .device
+                .begin_command_buffer(
+                    command_buffer,
+                    &CommandBufferBeginInfo::builder()
+                        .flags(CommandBufferUsageFlags::ONE_TIME_SUBMIT)
+                        .build()
+                )?;
         }
 
+        self.record_rendering(command_buffer, image, extent, constants);
+
+        unsafe {
+            self.backends.device.end_command_buffer(command_buffer)?;
+        }
+
+        let command_buffers = [command_buffer];
+
         let submit_infos = [
             SubmitInfo::builder()
-                .command_buffers(&[command_buffer])
+                .command_buffers(&command_buffers)
                 .build()
         ];
 
         unsafe {
             self.backends
                 .device
-                .queue_submit(graphics_queue, &submit_infos, Fence::null())
-                .unwrap();
+                .queue_submit(graphics_queue, &submit_infos, frame.in_flight)?;
+        }
 
+        //結果を読み出すのでここで終わるまで待つ
+        frame.wait()
+    }
+
+    /// offscreenに描画してswapchainのimageにblitしてpresentする
+    /// 作り直しが必要になった場合はframe_stateに記録される
+    pub fn render_frame(
+        &self,
+        frame_state: &mut FrameState,
+        frames: &mut FrameRing,
+        target_image: Image,
+        target_extent: Extent2D,
+        constants: &RayGenerationConstants,
+        swapchains: &Swapchains,
+        swapchain_images: &Images,
+        graphics_queue: Queue,
+        present_queue: Queue,
+    ) -> Result<()> {
+        frames.wait_current()?;
+
+        let acquired = swapchains.acquire_next_image(frames.current().image_available)?;
+
+        let image_index = match frame_state.on_acquire(acquired) {
+            Some(image_index) => image_index,
+            None => return Ok(()),
+        };
+
+        frames.claim_image(image_index)?;
+
+        let frame = frames.current();
+        let swapchain_image = swapchain_images.images[image_index as usize];
+
+        //acquireに失敗した場合にfenceをresetしてしまうと次で待ち続けるのでここでreset
+        frame.reset()?;
+
+        let command_buffer = frame.command_buffer;
+
+        unsafe {
+            self.backends.device.begin_command_buffer(
+                command_buffer,
+                &CommandBufferBeginInfo::builder()
+                    .flags(CommandBufferUsageFlags::ONE_TIME_SUBMIT)
+                    .build(),
+            )?;
+        }
+
+        self.record_rendering(command_buffer, target_image, target_extent, constants);
+        self.record_blit(command_buffer, target_image, target_extent, swapchain_image, swapchains.extent);
+
+        unsafe {
+            self.backends.device.end_command_buffer(command_buffer)?;
+        }
+
+        let wait_semaphores = [frame.image_available];
+        //swapchainのimageに最初に書き込むのはblit
+        let wait_stages = [PipelineStageFlags::TRANSFER];
+        let command_buffers = [command_buffer];
+        let signal_semaphores = [frame.render_finished];
+
+        let submit_infos = [
+            SubmitInfo::builder()
+                .wait_semaphores(&wait_semaphores)
+                .wait_dst_stage_mask(&wait_stages)
+                .command_buffers(&command_buffers)
+                .signal_semaphores(&signal_semaphores)
+                .build()
+        ];
+
+        unsafe {
             self.backends
                 .device
-                .queue_wait_idle(graphics_queue)
-                .unwrap();
-            self.backends
-                .device
-                .free_command_buffers(
-                    command_pool,
-                    &[command_buffer]
-                );
-            self.backends
-                .device
-                .destroy_command_pool(command_pool, None);
+                .queue_submit(graphics_queue, &submit_infos, frame.in_flight)?;
         }
 
+        frames.on_submitted();
+
+        let presented = swapchains.present(present_queue, image_index, signal_semaphores[0])?;
+        frame_state.on_present(presented);
+
+        frames.advance();
+
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn record_blit(
+        &self,
+        command_buffer: CommandBuffer,
+        target_image: Image,
+        target_extent: Extent2D,
+        swapchain_image: Image,
+        swapchain_extent: Extent2D,
+    ) {
+        let device = &self.backends.device;
+
+        //描画の書き込みはrecord_renderingで待っている
+        //前のフレームの内容は要らないのでUNDEFINEDから
+        let swapchain_barrier = ImageMemoryBarrier::builder()
+            .src_access_mask(AccessFlags::empty())
+            .dst_access_mask(AccessFlags::TRANSFER_WRITE)
+            .old_layout(ImageLayout::UNDEFINED)
+            .new_layout(ImageLayout::TRANSFER_DST_OPTIMAL)
+            .image(swapchain_image)
+            .subresource_range(color_subresource_range())
+            .build();
+
+        let subresource = ImageSubresourceLayers::builder()
+            .aspect_mask(ImageAspectFlags::COLOR)
+            .mip_level(0)
+            .base_array_layer(0)
+            .layer_count(1)
+            .build();
+
+        let blit = ImageBlit::builder()
+            .src_subresource(subresource)
+            .src_offsets([
+                Offset3D::default(),
+                Offset3D { x: target_extent.width as i32, y: target_extent.height as i32, z: 1 },
+            ])
+            .dst_subresource(subresource)
+            .dst_offsets([
+                Offset3D::default(),
+                Offset3D { x: swapchain_extent.width as i32, y: swapchain_extent.height as i32, z: 1 },
+            ])
+            .build();
+
+        let present_barrier = ImageMemoryBarrier::builder()
+            .src_access_mask(AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(AccessFlags::empty())
+            .old_layout(ImageLayout::TRANSFER_DST_OPTIMAL)
+            .new_layout(ImageLayout::PRESENT_SRC_KHR)
+            .image(swapchain_image)
+            .subresource_range(color_subresource_range())
+            .build();
+
+        unsafe {
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                PipelineStageFlags::TRANSFER,
+                PipelineStageFlags::TRANSFER,
+                DependencyFlags::empty(),
+                &[],
+                &[],
+                &[swapchain_barrier],
+            );
+
+            //formatが違ってもblitなら変換される
+            //大きさは同じなので拡大縮小はなく、floatのformatはlinear filterに対応していないことがあるのでNEAREST
+            device.cmd_blit_image(
+                command_buffer,
+                target_image,
+                ImageLayout::GENERAL,
+                swapchain_image,
+                ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[blit],
+                Filter::NEAREST,
+            );
+
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                PipelineStageFlags::TRANSFER,
+                PipelineStageFlags::BOTTOM_OF_PIPE,
+                DependencyFlags::empty(),
+                &[],
+                &[],
+                &[present_barrier],
+            );
+        }
+    }
+}
+
+fn color_subresource_range() -> ImageSubresourceRange {
+    ImageSubresourceRange::builder()
+        .aspect_mask(ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(1)
+        .base_array_layer(0)
+        .layer_count(1)
+        .build()
+}
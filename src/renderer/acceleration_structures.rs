@@ -1,18 +1,23 @@
 use ash::Device;
 use ash::extensions::khr::AccelerationStructure;
 use ash::vk::{PhysicalDeviceMemoryProperties, Queue};
+use classical_raytracer_shader::Vertex;
 use log::debug;
 use crate::renderer::acceleration_structures::top_level_acceleration_structures::TopLevelAccelerationStructures;
 use crate::renderer::acceleration_structures::triangle_bottom_level_acceleration_structure::TriangleBottomLevelAccelerationStructure;
+use crate::error::Result;
 use crate::renderer::backends::Backends;
 use crate::scene::Scene;
 
 pub mod triangle_bottom_level_acceleration_structure;
 pub mod top_level_acceleration_structures;
+pub mod scratch_plan;
 
 pub struct AccelerationStructures<'a> {
     backends: &'a Backends,
     pub acceleration_structure: AccelerationStructure,
+    /// trueの場合create_mesh_blasで作ったBLASをcompactionする
+    pub compact_blas: bool,
 }
 
 impl<'a> AccelerationStructures<'a>{
@@ -25,13 +30,19 @@ impl<'a> AccelerationStructures<'a>{
         Self {
             backends: &backends,
             acceleration_structure,
+            compact_blas: false,
         }
     }
 
+    pub fn compact_blas(mut self, compact_blas: bool) -> Self {
+        self.compact_blas = compact_blas;
+        self
+    }
+
     pub fn create_triangle_blas(
         &self,
         graphics_queue: Queue,
-    ) -> TriangleBottomLevelAccelerationStructure {
+    ) -> Result<TriangleBottomLevelAccelerationStructure> {
         debug!("create triangle blas");
 
         TriangleBottomLevelAccelerationStructure::new(
@@ -41,11 +52,46 @@ impl<'a> AccelerationStructures<'a>{
         )
     }
 
-    pub fn create_tlas(
+    pub fn create_mesh_blas(
         &self,
-        scene: Scene,
+        vertices: Vec<Vertex>,
+        indices: Vec<u32>,
+        graphics_queue: Queue,
+    ) -> Result<TriangleBottomLevelAccelerationStructure> {
+        debug!("create mesh blas");
+
+        TriangleBottomLevelAccelerationStructure::from_mesh(
+            self.backends,
+            &self.acceleration_structure,
+            vertices,
+            indices,
+            self.compact_blas,
+            graphics_queue
+        )
+    }
+
+    /// まとめてbuildするのでmeshごとにcreate_mesh_blasを呼ぶより速い
+    pub fn create_mesh_blases(
+        &self,
+        meshes: Vec<(Vec<Vertex>, Vec<u32>)>,
+        graphics_queue: Queue,
+    ) -> Result<Vec<TriangleBottomLevelAccelerationStructure>> {
+        debug!("create {} mesh blases", meshes.len());
+
+        TriangleBottomLevelAccelerationStructure::build_batch(
+            self.backends,
+            &self.acceleration_structure,
+            meshes,
+            self.compact_blas,
+            graphics_queue
+        )
+    }
+
+    pub fn create_tlas<'s>(
+        &'s self,
+        scene: Scene<'s>,
         graphics_queue: Queue,
-    ) -> TopLevelAccelerationStructures {
+    ) -> Result<TopLevelAccelerationStructures<'s>> {
         debug!("create tlas");
 
         TopLevelAccelerationStructures::new(
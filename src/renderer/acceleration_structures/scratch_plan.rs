@@ -0,0 +1,145 @@
+use ash::vk::DeviceSize;
+use crate::align_up;
+
+//scratch bufferの大きさの上限、入りきらない分は前のbuildが終わってから同じ領域を使い回す
+pub const DEFAULT_MAX_SCRATCH_SIZE: DeviceSize = 64 * 1024 * 1024;
+
+/// buildはscratch_sizesの中のindex、offsetはscratch bufferの先頭から
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ScratchSlice {
+    pub build: usize,
+    pub offset: DeviceSize,
+    pub size: DeviceSize,
+}
+
+/// 同じコマンドで同時にbuildするもの、scratchの範囲は重ならない
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScratchBatch {
+    pub slices: Vec<ScratchSlice>,
+}
+
+impl ScratchBatch {
+    pub fn end(&self) -> DeviceSize {
+        self.slices
+            .last()
+            .map_or(0, |slice| slice.offset + slice.size)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScratchPlan {
+    /// batchの間にはscratchを使い回すためのbarrierが必要
+    pub batches: Vec<ScratchBatch>,
+    pub buffer_size: DeviceSize,
+}
+
+impl ScratchPlan {
+    pub fn barrier_count(&self) -> usize {
+        self.batches.len().saturating_sub(1)
+    }
+}
+
+/// buildの順番は変えずに先頭から詰める
+/// 一つでmax_scratch_sizeを超えるものはそれだけでbatchにし、bufferもその大きさにする
+pub fn plan_scratch(
+    scratch_sizes: &[DeviceSize],
+    alignment: DeviceSize,
+    max_scratch_size: DeviceSize,
+) -> ScratchPlan {
+    let mut batches = vec![];
+    let mut current = ScratchBatch::default();
+
+    for (build, size) in scratch_sizes.iter().enumerate() {
+        let mut offset = align_up(current.end(), alignment);
+
+        if !current.slices.is_empty() && offset + size > max_scratch_size {
+            batches.push(std::mem::take(&mut current));
+            offset = 0;
+        }
+
+        current.slices.push(ScratchSlice {
+            build,
+            offset,
+            size: *size,
+        });
+    }
+
+    if !current.slices.is_empty() {
+        batches.push(current);
+    }
+
+    let buffer_size = batches
+        .iter()
+        .map(ScratchBatch::end)
+        .max()
+        .unwrap_or(0);
+
+    ScratchPlan {
+        batches,
+        buffer_size,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(plan: &ScratchPlan) -> Vec<Vec<(usize, DeviceSize)>> {
+        plan.batches
+            .iter()
+            .map(|batch| batch.slices.iter().map(|slice| (slice.build, slice.offset)).collect())
+            .collect()
+    }
+
+    #[test]
+    fn offsets_are_padded_to_alignment() {
+        let plan = plan_scratch(&[100, 200, 50], 256, 1024);
+
+        assert_eq!(layout(&plan), vec![vec![(0, 0), (1, 256), (2, 512)]]);
+        assert_eq!(plan.buffer_size, 562);
+        assert_eq!(plan.barrier_count(), 0);
+    }
+
+    #[test]
+    fn splits_when_max_scratch_size_is_exceeded() {
+        let plan = plan_scratch(&[400, 400, 400], 1, 1000);
+
+        assert_eq!(layout(&plan), vec![vec![(0, 0), (1, 400)], vec![(2, 0)]]);
+        assert_eq!(plan.buffer_size, 800);
+        assert_eq!(plan.barrier_count(), 1);
+
+        //ちょうど入る場合は分けない
+        assert_eq!(plan_scratch(&[500, 500], 1, 1000).batches.len(), 1);
+    }
+
+    #[test]
+    fn padding_counts_towards_the_limit() {
+        //詰めれば950で入るが、揃えると768 + 350で超える
+        let plan = plan_scratch(&[600, 350], 256, 1000);
+
+        assert_eq!(layout(&plan), vec![vec![(0, 0)], vec![(1, 0)]]);
+        assert_eq!(plan.buffer_size, 600);
+    }
+
+    #[test]
+    fn oversized_build_gets_its_own_batch() {
+        let plan = plan_scratch(&[100, 5000, 100], 1, 1000);
+
+        assert_eq!(layout(&plan), vec![vec![(0, 0)], vec![(1, 0)], vec![(2, 0)]]);
+        //bufferは一番大きいものに合わせる
+        assert_eq!(plan.buffer_size, 5000);
+        assert_eq!(plan.barrier_count(), 2);
+
+        let plan = plan_scratch(&[5000], 256, 1000);
+        assert_eq!(layout(&plan), vec![vec![(0, 0)]]);
+        assert_eq!(plan.buffer_size, 5000);
+    }
+
+    #[test]
+    fn empty_input_needs_no_buffer() {
+        let plan = plan_scratch(&[], 256, DEFAULT_MAX_SCRATCH_SIZE);
+
+        assert_eq!(plan, ScratchPlan::default());
+        assert_eq!(plan.barrier_count(), 0);
+    }
+}
@@ -1,55 +1,97 @@
-use ash::Device;
 use ash::extensions::khr::AccelerationStructure;
-use ash::vk::{AccelerationStructureBuildGeometryInfoKHR, AccelerationStructureBuildRangeInfoKHR, AccelerationStructureBuildTypeKHR, AccelerationStructureCreateInfoKHR, AccelerationStructureGeometryDataKHR, AccelerationStructureGeometryInstancesDataKHR, AccelerationStructureGeometryKHR, AccelerationStructureKHR, AccelerationStructureTypeKHR, AccessFlags, BufferUsageFlags, BuildAccelerationStructureFlagsKHR, BuildAccelerationStructureModeKHR, CommandBufferAllocateInfo, CommandBufferBeginInfo, CommandBufferLevel, CommandBufferUsageFlags, DependencyFlags, DeviceOrHostAddressConstKHR, DeviceOrHostAddressKHR, Fence, GeometryTypeKHR, MemoryBarrier, MemoryPropertyFlags, PipelineStageFlags, Queue, SubmitInfo};
+use ash::vk::{AccelerationStructureBuildGeometryInfoKHR, AccelerationStructureBuildRangeInfoKHR, AccelerationStructureBuildTypeKHR, AccelerationStructureCreateInfoKHR, AccelerationStructureGeometryDataKHR, AccelerationStructureGeometryInstancesDataKHR, AccelerationStructureGeometryKHR, AccelerationStructureInstanceKHR, AccelerationStructureKHR, AccelerationStructureTypeKHR, AccessFlags, BufferUsageFlags, BuildAccelerationStructureFlagsKHR, BuildAccelerationStructureModeKHR, CommandBufferBeginInfo, CommandBufferUsageFlags, DependencyFlags, DeviceAddress, DeviceOrHostAddressConstKHR, DeviceOrHostAddressKHR, Fence, GeometryTypeKHR, MemoryBarrier, MemoryPropertyFlags, PipelineStageFlags, Queue, SubmitInfo};
+use log::debug;
+use crate::align_up;
 use crate::buffers::Buffers;
+use crate::error::Result;
+use crate::renderer::acceleration_structures::triangle_bottom_level_acceleration_structure::scratch_offset_alignment;
 use crate::renderer::backends::Backends;
 use crate::scene::Scene;
 
+//refitを繰り返すとトレースが遅くなるのでこの回数ごとに作り直す
+pub const MAX_CONSECUTIVE_REFITS: u32 = 16;
+
+//refitするにはbuildの時点でALLOW_UPDATEが必要
+const TLAS_BUILD_FLAGS: BuildAccelerationStructureFlagsKHR = BuildAccelerationStructureFlagsKHR::from_raw(
+    BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE.as_raw()
+        | BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE.as_raw(),
+);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TlasUpdate {
+    Unchanged,
+    /// 今のASをsrcにしてUPDATEモードでbuildし直す
+    Refit,
+    /// instanceの数が同じ場合は同じバッファにBUILDし直す
+    Rebuild,
+}
+
+/// transformだけが変わった場合のみrefitする
+/// BLASやhit groupが変わった場合やrefitがmax_consecutive_refits回続いた場合は作り直す
+pub fn plan_tlas_update(
+    current: &[AccelerationStructureInstanceKHR],
+    next: &[AccelerationStructureInstanceKHR],
+    refits_since_build: u32,
+    max_consecutive_refits: u32,
+) -> TlasUpdate {
+    if current.len() != next.len() {
+        return TlasUpdate::Rebuild;
+    }
+
+    let pairs = || current.iter().zip(next.iter());
+
+    if pairs().any(|(current, next)| !same_except_transform(current, next)) {
+        return TlasUpdate::Rebuild;
+    }
+
+    if pairs().all(|(current, next)| current.transform.matrix == next.transform.matrix) {
+        return TlasUpdate::Unchanged;
+    }
+
+    if refits_since_build >= max_consecutive_refits {
+        TlasUpdate::Rebuild
+    } else {
+        TlasUpdate::Refit
+    }
+}
+
+fn same_except_transform(a: &AccelerationStructureInstanceKHR, b: &AccelerationStructureInstanceKHR) -> bool {
+    //device_handleもhost_handleも同じ64bitなのでどちらで比べても同じ
+    let reference = |instance: &AccelerationStructureInstanceKHR| unsafe {
+        instance.acceleration_structure_reference.device_handle
+    };
+
+    a.instance_custom_index_and_mask == b.instance_custom_index_and_mask
+        && a.instance_shader_binding_table_record_offset_and_flags == b.instance_shader_binding_table_record_offset_and_flags
+        && reference(a) == reference(b)
+}
+
 pub struct TopLevelAccelerationStructures<'a> {
     backends: &'a Backends,
     acceleration_structure: &'a AccelerationStructure,
     pub top_level_acceleration_structure_khr: AccelerationStructureKHR,
     pub top_level_acceleration_structure_buffer: Buffers<'a>,
+    /// refitの際に書き換えるのでbuild後も持っておく
+    pub scene: Scene<'a>,
+    //BUILDとUPDATEの大きい方の大きさで確保する
+    scratch_buffer: Buffers<'a>,
+    //scratch_bufferの中でalignmentに揃えた位置
+    scratch_address: DeviceAddress,
+    refits_since_build: u32,
 }
 
 impl<'a> TopLevelAccelerationStructures<'a> {
     pub fn new(
         backends: &'a Backends,
         acceleration_structure: &'a AccelerationStructure,
-        scene: Scene,
+        scene: Scene<'a>,
         graphics_queue: Queue,
-    ) -> Self {
-        let build_range_info = AccelerationStructureBuildRangeInfoKHR::builder()
-            .first_vertex(0)
-            //BLASの個数
-            .primitive_count(scene.instances.len() as u32)
-            .primitive_offset(0)
-            .transform_offset(0)
-            .build();
-
-        let command_pool = backends.create_graphics_command_pool();
-        let command_buffers = backends.create_command_buffers(command_pool, 1);
-        let build_command_buffer = command_buffers[0];
-
-        let instances = AccelerationStructureGeometryInstancesDataKHR::builder()
-            .array_of_pointers(false)
-            .data(DeviceOrHostAddressConstKHR {
-                device_address: scene.instance_buffer.get_buffer_address()
-            })
-            .build();
+    ) -> Result<Self> {
+        let geometries = [instances_geometry(&scene)];
 
-        let geometry = AccelerationStructureGeometryKHR::builder()
-            .geometry_type(GeometryTypeKHR::INSTANCES)
-            .geometry(AccelerationStructureGeometryDataKHR{
-                instances
-            })
-            .build();
-
-        let geometries = [geometry];
-
-        let mut build_info = AccelerationStructureBuildGeometryInfoKHR::builder()
+        let build_info = AccelerationStructureBuildGeometryInfoKHR::builder()
             .ty(AccelerationStructureTypeKHR::TOP_LEVEL)
-            .flags(BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
+            .flags(TLAS_BUILD_FLAGS)
             .geometries(&geometries)
             .mode(BuildAccelerationStructureModeKHR::BUILD)
             .build();
@@ -58,7 +100,8 @@ impl<'a> TopLevelAccelerationStructures<'a> {
             acceleration_structure.get_acceleration_structure_build_sizes(
                 AccelerationStructureBuildTypeKHR::DEVICE,
                 &build_info,
-                &[build_range_info.primitive_count],
+                //BLASの個数
+                &[scene.instances.len() as u32],
             )
         };
 
@@ -70,7 +113,7 @@ impl<'a> TopLevelAccelerationStructures<'a> {
                 | BufferUsageFlags::SHADER_DEVICE_ADDRESS
                 | BufferUsageFlags::STORAGE_BUFFER,
             MemoryPropertyFlags::DEVICE_LOCAL
-        );
+        )?;
 
         let accel_create_info = AccelerationStructureCreateInfoKHR::builder()
             .ty(build_info.ty)
@@ -81,25 +124,119 @@ impl<'a> TopLevelAccelerationStructures<'a> {
 
         let top_level_acceleration_structure_khr = unsafe {
             acceleration_structure
-                .create_acceleration_structure(&accel_create_info, None)
-                .unwrap()
+                .create_acceleration_structure(&accel_create_info, None)?
         };
 
-        build_info.dst_acceleration_structure = top_level_acceleration_structure_khr;
-
+        //BLASと同じく先頭がalignmentにそろうように余分に確保してずらす
+        let alignment = scratch_offset_alignment(backends);
         let scratch_buffer = Buffers::new(
             &backends.device,
             backends.device_memory_properties,
-            memory_requirements.build_scratch_size,
+            memory_requirements.build_scratch_size.max(memory_requirements.update_scratch_size) + alignment,
             BufferUsageFlags::SHADER_DEVICE_ADDRESS
                 | BufferUsageFlags::STORAGE_BUFFER,
             MemoryPropertyFlags::DEVICE_LOCAL
+        )?;
+        let scratch_address = align_up(scratch_buffer.get_buffer_address(), alignment);
+
+        let top_level_acceleration_structures = Self {
+            backends,
+            acceleration_structure,
+            top_level_acceleration_structure_khr,
+            top_level_acceleration_structure_buffer,
+            scene,
+            scratch_buffer,
+            scratch_address,
+            refits_since_build: 0,
+        };
+
+        top_level_acceleration_structures.build(BuildAccelerationStructureModeKHR::BUILD, graphics_queue)?;
+
+        Ok(top_level_acceleration_structures)
+    }
+
+    /// 前回のbuildから何回refitしたか
+    pub fn refits_since_build(&self) -> u32 {
+        self.refits_since_build
+    }
+
+    /// instancesの数が変わった場合はASを作り直すのでhandleが変わる
+    /// TLASを参照しているdescriptorは呼んだ後に更新する
+    pub fn update(
+        &mut self,
+        instances: Vec<AccelerationStructureInstanceKHR>,
+        graphics_queue: Queue,
+    ) -> Result<TlasUpdate> {
+        let plan = plan_tlas_update(
+            &self.scene.instances,
+            &instances,
+            self.refits_since_build,
+            MAX_CONSECUTIVE_REFITS,
         );
 
-        build_info.scratch_data = DeviceOrHostAddressKHR {
-            device_address: scratch_buffer.get_buffer_address()
+        debug!("update tlas: {:?}", plan);
+
+        match plan {
+            TlasUpdate::Unchanged => {}
+            TlasUpdate::Refit => {
+                self.scene.write_instances(0, &instances)?;
+                self.build(BuildAccelerationStructureModeKHR::UPDATE, graphics_queue)?;
+                self.refits_since_build += 1;
+            }
+            TlasUpdate::Rebuild if instances.len() == self.scene.instances.len() => {
+                self.scene.write_instances(0, &instances)?;
+                self.build(BuildAccelerationStructureModeKHR::BUILD, graphics_queue)?;
+                self.refits_since_build = 0;
+            }
+            TlasUpdate::Rebuild => {
+                //大きさが変わるのでバッファごと作り直す、古いものはここで破棄される
+                *self = Self::new(
+                    self.backends,
+                    self.acceleration_structure,
+                    Scene::from_instances(self.backends, instances)?,
+                    graphics_queue,
+                )?;
+            }
+        }
+
+        Ok(plan)
+    }
+
+    //UPDATEの場合は今のASをsrcにしてその場で書き換える
+    fn build(&self, mode: BuildAccelerationStructureModeKHR, graphics_queue: Queue) -> Result<()> {
+        let build_range_info = AccelerationStructureBuildRangeInfoKHR::builder()
+            .first_vertex(0)
+            //BLASの個数
+            .primitive_count(self.scene.instances.len() as u32)
+            .primitive_offset(0)
+            .transform_offset(0)
+            .build();
+
+        let geometries = [instances_geometry(&self.scene)];
+
+        let src_acceleration_structure = if mode == BuildAccelerationStructureModeKHR::UPDATE {
+            self.top_level_acceleration_structure_khr
+        } else {
+            AccelerationStructureKHR::null()
         };
 
+        let build_info = AccelerationStructureBuildGeometryInfoKHR::builder()
+            .ty(AccelerationStructureTypeKHR::TOP_LEVEL)
+            .flags(TLAS_BUILD_FLAGS)
+            .geometries(&geometries)
+            .mode(mode)
+            .src_acceleration_structure(src_acceleration_structure)
+            .dst_acceleration_structure(self.top_level_acceleration_structure_khr)
+            .scratch_data(DeviceOrHostAddressKHR {
+                device_address: self.scratch_address,
+            })
+            .build();
+
+        let backends = self.backends;
+        let command_pool = backends.create_graphics_command_pool()?;
+        let command_buffers = backends.create_command_buffers(command_pool, 1)?;
+        let build_command_buffer = command_buffers[0];
+
         unsafe {
             backends.device
                 .begin_command_buffer(
@@ -107,8 +244,7 @@ impl<'a> TopLevelAccelerationStructures<'a> {
                     &CommandBufferBeginInfo::builder()
                         .flags(CommandBufferUsageFlags::ONE_TIME_SUBMIT)
                         .build(),
-                )
-                .unwrap();
+                )?;
 
             let memory_barrier = MemoryBarrier::builder()
                 .src_access_mask(AccessFlags::TRANSFER_WRITE)
@@ -125,36 +261,135 @@ impl<'a> TopLevelAccelerationStructures<'a> {
                 &[]
             );
 
-            acceleration_structure.cmd_build_acceleration_structures(
+            self.acceleration_structure.cmd_build_acceleration_structures(
                 build_command_buffer,
                 &[build_info],
                 &[&[build_range_info]]
             );
-            backends.device.end_command_buffer(build_command_buffer).unwrap();
+            backends.device.end_command_buffer(build_command_buffer)?;
             backends.device.queue_submit(
                 graphics_queue,
                 &[SubmitInfo::builder()
                     .command_buffers(&[build_command_buffer])
                     .build()],
                 Fence::null()
-            ).expect("failed queue submit");
+            )?;
 
-            backends.device.queue_wait_idle(graphics_queue).unwrap();
+            backends.device.queue_wait_idle(graphics_queue)?;
             backends.device.free_command_buffers(command_pool, &command_buffers);
             backends.device.destroy_command_pool(command_pool, None);
         }
 
-        Self {
-            backends,
-            acceleration_structure,
-            top_level_acceleration_structure_khr,
-            top_level_acceleration_structure_buffer,
-        }
+        Ok(())
     }
 }
 
+fn instances_geometry(scene: &Scene) -> AccelerationStructureGeometryKHR {
+    let instances = AccelerationStructureGeometryInstancesDataKHR::builder()
+        .array_of_pointers(false)
+        .data(DeviceOrHostAddressConstKHR {
+            device_address: scene.instance_buffer.get_buffer_address()
+        })
+        .build();
+
+    AccelerationStructureGeometryKHR::builder()
+        .geometry_type(GeometryTypeKHR::INSTANCES)
+        .geometry(AccelerationStructureGeometryDataKHR{
+            instances
+        })
+        .build()
+}
+
 impl Drop for TopLevelAccelerationStructures<'_> {
     fn drop(&mut self) {
-        //todo!()
+        //refitやrebuildで作り直すたびに残らないように破棄する
+        unsafe {
+            self.acceleration_structure
+                .destroy_acceleration_structure(self.top_level_acceleration_structure_khr, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{Mat4, Vec3};
+    use crate::scene::{to_transform_matrix, Scene};
+    use super::*;
+
+    fn instance(blas: u64, custom_index: u32, hit_group_offset: u32, x: f32) -> AccelerationStructureInstanceKHR {
+        Scene::create_triangle_instance(
+            blas,
+            custom_index,
+            hit_group_offset,
+            to_transform_matrix(Mat4::from_translation(Vec3::new(x, 0.0, 0.0))),
+        )
+    }
+
+    fn scene() -> Vec<AccelerationStructureInstanceKHR> {
+        vec![instance(0x1000, 0, 0, 0.0), instance(0x2000, 1, 0, 1.0)]
+    }
+
+    fn plan(next: &[AccelerationStructureInstanceKHR], refits_since_build: u32) -> TlasUpdate {
+        plan_tlas_update(&scene(), next, refits_since_build, MAX_CONSECUTIVE_REFITS)
+    }
+
+    #[test]
+    fn unchanged_instances_do_nothing() {
+        assert_eq!(plan(&scene(), 0), TlasUpdate::Unchanged);
+        //refitが続いていても何もしない
+        assert_eq!(plan(&scene(), MAX_CONSECUTIVE_REFITS), TlasUpdate::Unchanged);
+    }
+
+    #[test]
+    fn transform_only_change_refits() {
+        let mut next = scene();
+        next[1] = instance(0x2000, 1, 0, 2.5);
+
+        assert_eq!(plan(&next, 0), TlasUpdate::Refit);
+        assert_eq!(plan(&next, MAX_CONSECUTIVE_REFITS - 1), TlasUpdate::Refit);
+    }
+
+    #[test]
+    fn too_many_refits_rebuild() {
+        let mut next = scene();
+        next[0] = instance(0x1000, 0, 0, -1.0);
+
+        assert_eq!(plan(&next, MAX_CONSECUTIVE_REFITS), TlasUpdate::Rebuild);
+        assert_eq!(plan_tlas_update(&scene(), &next, 3, 3), TlasUpdate::Rebuild);
+        assert_eq!(plan_tlas_update(&scene(), &next, 3, 4), TlasUpdate::Refit);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn instance_count_change_rebuilds() {
+        let mut next = scene();
+        next.push(instance(0x1000, 2, 0, 3.0));
+
+        assert_eq!(plan(&next, 0), TlasUpdate::Rebuild);
+        assert_eq!(plan(&next[..1], 0), TlasUpdate::Rebuild);
+        assert_eq!(plan(&[], 0), TlasUpdate::Rebuild);
+    }
+
+    #[test]
+    fn blas_change_rebuilds() {
+        let mut next = scene();
+        next[1] = instance(0x3000, 1, 0, 1.0);
+
+        assert_eq!(plan(&next, 0), TlasUpdate::Rebuild);
+    }
+
+    #[test]
+    fn hit_group_or_custom_index_change_rebuilds() {
+        let mut next = scene();
+        next[0] = instance(0x1000, 0, 1, 0.0);
+        assert_eq!(plan(&next, 0), TlasUpdate::Rebuild);
+
+        let mut next = scene();
+        next[0] = instance(0x1000, 5, 0, 0.0);
+        assert_eq!(plan(&next, 0), TlasUpdate::Rebuild);
+
+        //transformも同時に変わっていても作り直す
+        let mut next = scene();
+        next[1] = instance(0x2000, 1, 1, 4.0);
+        assert_eq!(plan(&next, 0), TlasUpdate::Rebuild);
+    }
+}
@@ -1,12 +1,15 @@
 use ash::Device;
 use ash::extensions::khr::AccelerationStructure;
-use ash::vk::{AabbPositionsKHR, AccelerationStructureBuildGeometryInfoKHR, AccelerationStructureBuildRangeInfoKHR, AccelerationStructureBuildTypeKHR, AccelerationStructureCreateInfoKHR, AccelerationStructureDeviceAddressInfoKHR, AccelerationStructureGeometryAabbsDataKHR, AccelerationStructureGeometryDataKHR, AccelerationStructureGeometryKHR, AccelerationStructureGeometryTrianglesDataKHR, AccelerationStructureInstanceKHR, AccelerationStructureKHR, AccelerationStructureTypeKHR, Buffer, BufferUsageFlags, BuildAccelerationStructureFlagsKHR, BuildAccelerationStructureModeKHR, CommandBuffer, CommandBufferBeginInfo, CommandBufferUsageFlags, CommandPool, DeviceAddress, DeviceOrHostAddressConstKHR, DeviceOrHostAddressKHR, DeviceSize, Fence, GeometryFlagsKHR, GeometryTypeKHR, IndexType, MemoryPropertyFlags, PhysicalDeviceMemoryProperties, Queue, SubmitInfo};
-use glam::{const_vec3a, vec3a, Vec3A};
+use ash::vk::{AabbPositionsKHR, AccelerationStructureBuildGeometryInfoKHR, AccelerationStructureBuildRangeInfoKHR, AccelerationStructureBuildTypeKHR, AccelerationStructureCreateInfoKHR, AccelerationStructureDeviceAddressInfoKHR, AccelerationStructureGeometryAabbsDataKHR, AccelerationStructureGeometryDataKHR, AccelerationStructureGeometryKHR, AccelerationStructureGeometryTrianglesDataKHR, AccelerationStructureInstanceKHR, AccelerationStructureKHR, AccelerationStructureTypeKHR, AccessFlags, Buffer, BufferUsageFlags, BuildAccelerationStructureFlagsKHR, BuildAccelerationStructureModeKHR, CommandBuffer, CommandPool, CopyAccelerationStructureInfoKHR, CopyAccelerationStructureModeKHR, DependencyFlags, DeviceAddress, DeviceOrHostAddressConstKHR, DeviceOrHostAddressKHR, DeviceSize, GeometryFlagsKHR, GeometryTypeKHR, IndexType, MemoryBarrier, MemoryPropertyFlags, PhysicalDeviceAccelerationStructurePropertiesKHR, PhysicalDeviceProperties2, PipelineStageFlags, QueryPool, QueryPoolCreateInfo, QueryResultFlags, QueryType, Queue};
+use glam::{const_vec3a, vec3a, Vec2, Vec3A, Vec4};
 use log::debug;
 use crate::buffers::Buffers;
+use crate::error::Result;
 use crate::renderer::backends::Backends;
 use classical_raytracer_shader::Vertex;
 use crate::renderer::mesh_buffer::MeshBuffer;
+use crate::align_up;
+use crate::renderer::acceleration_structures::scratch_plan::{DEFAULT_MAX_SCRATCH_SIZE, plan_scratch};
 
 //instanceを作って
 
@@ -17,15 +20,80 @@ pub struct TriangleBottomLevelAccelerationStructure<'a> {
     pub acceleration_structure: &'a AccelerationStructure,
     pub bottom_acceleration_structure: AccelerationStructureKHR,
     pub bottom_acceleration_buffer: Buffers<'a>,
+    /// compactionする前のacceleration_structure_size、しない場合はbufferの大きさと同じ
+    pub uncompacted_size: DeviceSize,
     pub mesh_buffer: MeshBuffer<'a>,
 }
 
+/// 小さくならない場合はコピーの手間を省く
+pub fn should_compact(uncompacted_size: DeviceSize, compacted_size: DeviceSize) -> bool {
+    compacted_size > 0 && compacted_size < uncompacted_size
+}
+
+/// compact_batchの後の大きさ、compactionしない場合は元の大きさのまま
+pub fn size_after_compaction(uncompacted_size: DeviceSize, compacted_size: DeviceSize) -> DeviceSize {
+    if should_compact(uncompacted_size, compacted_size) {
+        compacted_size
+    } else {
+        uncompacted_size
+    }
+}
+
+//build後の大きさを問い合わせるquery pool、途中でエラーになってもDropで破棄する
+struct CompactedSizeQuery<'a> {
+    device: &'a Device,
+    query_pool: QueryPool,
+    query_count: u32,
+}
+
+impl<'a> CompactedSizeQuery<'a> {
+    fn new(device: &'a Device, query_count: u32) -> Result<Self> {
+        let query_pool_info = QueryPoolCreateInfo::builder()
+            .query_type(QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR)
+            .query_count(query_count)
+            .build();
+
+        let query_pool = unsafe { device.create_query_pool(&query_pool_info, None)? };
+
+        Ok(Self {
+            device,
+            query_pool,
+            query_count,
+        })
+    }
+
+    //submitが終わった後に呼ぶ
+    fn compacted_sizes(&self) -> Result<Vec<DeviceSize>> {
+        let mut compacted_sizes = vec![0u64; self.query_count as usize];
+
+        unsafe {
+            self.device.get_query_pool_results(
+                self.query_pool,
+                0,
+                self.query_count,
+                &mut compacted_sizes,
+                QueryResultFlags::WAIT | QueryResultFlags::TYPE_64,
+            )?;
+        }
+
+        Ok(compacted_sizes)
+    }
+}
+
+impl Drop for CompactedSizeQuery<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_query_pool(self.query_pool, None);
+        }
+    }
+}
+
 impl<'a> TriangleBottomLevelAccelerationStructure<'a> {
     pub fn new(
         backends: &'a Backends,
         acceleration_structure: &'a AccelerationStructure,
         graphics_queue: Queue,
-    ) -> Self {
+    ) -> Result<Self> {
 
         //とりあえず定数(三角形)
         //TODO: 外部から入力できるようにする
@@ -33,188 +101,367 @@ impl<'a> TriangleBottomLevelAccelerationStructure<'a> {
             Vertex {
                 position: const_vec3a!([1.0, -1.0, 0.0]),
                 normal: const_vec3a!([0.0, 0.0, 1.0]),
+                tangent: Vec4::ZERO,
+                tex_coord: Vec2::ZERO,
+                _padding: Vec2::ZERO,
             },
             Vertex {
                 position: const_vec3a!([0.0, 1.0, 0.0]),
                 normal: const_vec3a!([0.0, 0.0, 1.0]),
+                tangent: Vec4::ZERO,
+                tex_coord: Vec2::ZERO,
+                _padding: Vec2::ZERO,
             },
             Vertex {
                 position: const_vec3a!([-1.0, -1.0, 0.0]),
                 normal: const_vec3a!([0.0, 0.0, 1.0]),
+                tangent: Vec4::ZERO,
+                tex_coord: Vec2::ZERO,
+                _padding: Vec2::ZERO,
             },
         ];
 
         let indices = vec![0, 1, 2];
 
-        let mesh_buffer = MeshBuffer::new(&backends.device, vertices, indices, backends.device_memory_properties);
-
-        //TODO: このbottom asをモデルごとに作成するようにしてtop asと紐づける
-        let (
-            bottom_acceleration_structure,
-            bottom_acceleration_buffer
-        ) = Self::create_bottom_acceleration(
-            &backends,
-            backends.device_memory_properties,
-            &acceleration_structure,
-            &mesh_buffer,
-            graphics_queue,
-        );
+        Self::from_mesh(backends, acceleration_structure, vertices, indices, false, graphics_queue)
+    }
 
-        Self {
+    pub fn from_mesh(
+        backends: &'a Backends,
+        acceleration_structure: &'a AccelerationStructure,
+        vertices: Vec<Vertex>,
+        indices: Vec<u32>,
+        //buildの後に必要な大きさのbufferへコピーして元を解放する
+        compact: bool,
+        graphics_queue: Queue,
+    ) -> Result<Self> {
+        let mut blases = Self::build_batch(
             backends,
             acceleration_structure,
-            bottom_acceleration_structure,
-            bottom_acceleration_buffer,
-            mesh_buffer,
-        }
+            vec![(vertices, indices)],
+            compact,
+            graphics_queue,
+        )?;
+
+        Ok(blases.remove(0))
     }
 
-    fn create_bottom_acceleration(
+    /// meshesのBLASをまとめてbuildし、submitは一度だけにする
+    /// scratch bufferは一つだけ確保し、入りきらない分はbarrierを挟んで同じ領域を使い回す
+    pub fn build_batch(
         backends: &'a Backends,
-        device_memory_properties: PhysicalDeviceMemoryProperties,
-        acceleration_structure: &AccelerationStructure,
-        mesh_buffer: &MeshBuffer,
+        acceleration_structure: &'a AccelerationStructure,
+        meshes: Vec<(Vec<Vertex>, Vec<u32>)>,
+        compact: bool,
         graphics_queue: Queue,
-    ) -> (AccelerationStructureKHR, Buffers<'a>) {
-        let geometry = AccelerationStructureGeometryKHR::builder()
+    ) -> Result<Vec<Self>> {
+        debug!("build {} blases", meshes.len());
+
+        if meshes.is_empty() {
+            return Ok(vec![]);
+        }
+
+        //ASのビルドよりもトレースの処理速度を優先する
+        let flags = if compact {
+            BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE
+                | BuildAccelerationStructureFlagsKHR::ALLOW_COMPACTION
+        } else {
+            BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE
+        };
+
+        let mesh_buffers = meshes
+            .into_iter()
+            .map(|(vertices, indices)| MeshBuffer::new(&backends.device, vertices, indices, backends.device_memory_properties))
+            .collect::<Result<Vec<_>>>()?;
+
+        //build_infoはポインタで参照するのでbuildが終わるまで持っておく
+        let geometries: Vec<[AccelerationStructureGeometryKHR; 1]> = mesh_buffers
+            .iter()
+            .map(|mesh_buffer| [Self::triangles_geometry(mesh_buffer)])
+            .collect();
+
+        let primitive_counts: Vec<u32> = mesh_buffers
+            .iter()
+            .map(|mesh_buffer| mesh_buffer.indices_count / 3)
+            .collect();
+
+        let memory_requirements: Vec<_> = geometries
+            .iter()
+            .zip(primitive_counts.iter())
+            .map(|(geometries, primitive_count)| {
+                let build_info = AccelerationStructureBuildGeometryInfoKHR::builder()
+                    .flags(flags)
+                    .geometries(geometries)
+                    .mode(BuildAccelerationStructureModeKHR::BUILD)
+                    .ty(AccelerationStructureTypeKHR::BOTTOM_LEVEL)
+                    .build();
+
+                unsafe {
+                    acceleration_structure.get_acceleration_structure_build_sizes(
+                        AccelerationStructureBuildTypeKHR::DEVICE,
+                        &build_info,
+                        //geometriesに対応するように配列を作成する
+                        &[*primitive_count]
+                    )
+                }
+            })
+            .collect();
+
+        let mut bottom_accels = memory_requirements
+            .iter()
+            .map(|requirements| Self::create_acceleration_structure_storage(
+                backends,
+                acceleration_structure,
+                requirements.acceleration_structure_size,
+            ))
+            .collect::<Result<Vec<_>>>()?;
+
+        let alignment = scratch_offset_alignment(backends);
+        let scratch_sizes: Vec<DeviceSize> = memory_requirements
+            .iter()
+            .map(|requirements| requirements.build_scratch_size)
+            .collect();
+        let plan = plan_scratch(&scratch_sizes, alignment, DEFAULT_MAX_SCRATCH_SIZE);
+
+        debug!(
+            "blas scratch buffer: {} bytes, {} batches",
+            plan.buffer_size,
+            plan.batches.len(),
+        );
+
+        //bufferの先頭のアドレスがalignmentにそろっているとは限らないので余分に確保してずらす
+        let scratch_buffer = Buffers::new(
+            &backends.device,
+            backends.device_memory_properties,
+            plan.buffer_size + alignment,
+            BufferUsageFlags::SHADER_DEVICE_ADDRESS
+                | BufferUsageFlags::STORAGE_BUFFER,
+            MemoryPropertyFlags::DEVICE_LOCAL,
+        )?;
+        let scratch_address = align_up(scratch_buffer.get_buffer_address(), alignment);
+
+        //compactionする場合のみbuild後の大きさを問い合わせる
+        let query = if compact {
+            Some(CompactedSizeQuery::new(&backends.device, bottom_accels.len() as u32)?)
+        } else {
+            None
+        };
+
+        backends.submit_and_wait(graphics_queue, |build_cb| unsafe {
+            if let Some(query) = &query {
+                backends.device.cmd_reset_query_pool(build_cb, query.query_pool, 0, query.query_count);
+            }
+
+            for (i, batch) in plan.batches.iter().enumerate() {
+                //前のbatchがscratchを使い終わるまで待つ
+                if i > 0 {
+                    acceleration_structure_build_barrier(backends, build_cb);
+                }
+
+                let build_infos: Vec<_> = batch
+                    .slices
+                    .iter()
+                    .map(|slice| AccelerationStructureBuildGeometryInfoKHR::builder()
+                        .flags(flags)
+                        .geometries(&geometries[slice.build])
+                        .mode(BuildAccelerationStructureModeKHR::BUILD)
+                        .ty(AccelerationStructureTypeKHR::BOTTOM_LEVEL)
+                        .scratch_data(DeviceOrHostAddressKHR {
+                            device_address: scratch_address + slice.offset,
+                        })
+                        .dst_acceleration_structure(bottom_accels[slice.build].0)
+                        .build())
+                    .collect();
+
+                let build_range_infos: Vec<[AccelerationStructureBuildRangeInfoKHR; 1]> = batch
+                    .slices
+                    .iter()
+                    .map(|slice| [AccelerationStructureBuildRangeInfoKHR::builder()
+                        .primitive_count(primitive_counts[slice.build])
+                        .build()])
+                    .collect();
+                let build_range_infos: Vec<&[_]> = build_range_infos
+                    .iter()
+                    .map(|build_range_info| &build_range_info[..])
+                    .collect();
+
+                acceleration_structure.cmd_build_acceleration_structures(
+                    build_cb,
+                    &build_infos,
+                    &build_range_infos,
+                );
+            }
+
+            if let Some(query) = &query {
+                //buildが終わってから大きさを書き込む
+                acceleration_structure_build_barrier(backends, build_cb);
+
+                let handles: Vec<_> = bottom_accels.iter().map(|(bottom_accel, _)| *bottom_accel).collect();
+
+                acceleration_structure.cmd_write_acceleration_structures_properties(
+                    build_cb,
+                    &handles,
+                    QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR,
+                    query.query_pool,
+                    0,
+                );
+            }
+        })?;
+
+        //scratch_bufferはDropで解放される
+        drop(scratch_buffer);
+
+        let uncompacted_sizes: Vec<DeviceSize> = memory_requirements
+            .iter()
+            .map(|requirements| requirements.acceleration_structure_size)
+            .collect();
+
+        if let Some(query) = query {
+            let compacted_sizes = query.compacted_sizes()?;
+            drop(query);
+
+            bottom_accels = Self::compact_batch(
+                backends,
+                acceleration_structure,
+                bottom_accels,
+                &uncompacted_sizes,
+                &compacted_sizes,
+                graphics_queue,
+            )?;
+        }
+
+        let blases = bottom_accels
+            .into_iter()
+            .zip(mesh_buffers)
+            .zip(uncompacted_sizes)
+            .map(|(((bottom_acceleration_structure, bottom_acceleration_buffer), mesh_buffer), uncompacted_size)| Self {
+                backends,
+                acceleration_structure,
+                bottom_acceleration_structure,
+                bottom_acceleration_buffer,
+                uncompacted_size,
+                mesh_buffer,
+            })
+            .collect();
+
+        Ok(blases)
+    }
+
+    fn triangles_geometry(mesh_buffer: &MeshBuffer) -> AccelerationStructureGeometryKHR {
+        AccelerationStructureGeometryKHR::builder()
             //Dataのタイプ
             .geometry_type(GeometryTypeKHR::TRIANGLES)
             //このASを作るためのデータ設定
             .geometry(AccelerationStructureGeometryDataKHR {
                 triangles: AccelerationStructureGeometryTrianglesDataKHR::builder()
                     .vertex_data(DeviceOrHostAddressConstKHR {
-                        device_address: unsafe {
-                            mesh_buffer.vertex_buffer.get_buffer_address()
-                        },
+                        device_address: mesh_buffer.vertex_buffer.get_buffer_address(),
                     })
                     .max_vertex(mesh_buffer.max_vertex)
                     .vertex_stride(mesh_buffer.vertex_stride)
                     .index_data(DeviceOrHostAddressConstKHR {
-                        device_address: unsafe {
-                            mesh_buffer.index_buffer.get_buffer_address()
-                        }
+                        device_address: mesh_buffer.index_buffer.get_buffer_address(),
                     })
                     .index_type(IndexType::UINT32)
                     .build(),
             })
             //OPAQUEはany-hitシェーダを呼び出さない
             //.flags(GeometryFlagsKHR::OPAQUE)
-            .build();
-
-        let build_range_info = AccelerationStructureBuildRangeInfoKHR::builder()
-            .primitive_count(mesh_buffer.indices_count / 3)
-            .build();
-
-        let geometries = [geometry];
-
-        let build_info = AccelerationStructureBuildGeometryInfoKHR::builder()
-            //ASのビルドよりもトレースの処理速度を優先する
-            .flags(BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
-            .geometries(&geometries)
-            .mode(BuildAccelerationStructureModeKHR::BUILD)
-            .ty(AccelerationStructureTypeKHR::BOTTOM_LEVEL)
-            .build();
-
-        let memory_requirements = unsafe {
-            acceleration_structure.get_acceleration_structure_build_sizes(
-                AccelerationStructureBuildTypeKHR::DEVICE,
-                &build_info,
-                //geometriesに対応するように配列を作成する
-                &[mesh_buffer.indices_count / 3]
-            )
-        };
-
-        let scratch_buffer = Buffers::new(
-            &backends.device,
-            device_memory_properties,
-            memory_requirements.build_scratch_size,
-            BufferUsageFlags::SHADER_DEVICE_ADDRESS
-                | BufferUsageFlags::STORAGE_BUFFER,
-            MemoryPropertyFlags::DEVICE_LOCAL,
-        );
-
-        let scratch_data = DeviceOrHostAddressKHR {
-            device_address: unsafe {
-                scratch_buffer.get_buffer_address()
-            }
-        };
+            .build()
+    }
 
+    fn create_acceleration_structure_storage(
+        backends: &'a Backends,
+        acceleration_structure: &AccelerationStructure,
+        size: DeviceSize,
+    ) -> Result<(AccelerationStructureKHR, Buffers<'a>)> {
         let bottom_accel_buffer = Buffers::new(
             &backends.device,
-            device_memory_properties,
-            memory_requirements.acceleration_structure_size,
+            backends.device_memory_properties,
+            size,
             BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR
                 | BufferUsageFlags::SHADER_DEVICE_ADDRESS
                 | BufferUsageFlags::STORAGE_BUFFER,
             MemoryPropertyFlags::DEVICE_LOCAL,
-        );
+        )?;
 
         let bottom_accel_create_info = AccelerationStructureCreateInfoKHR::builder()
             .ty(AccelerationStructureTypeKHR::BOTTOM_LEVEL)
-            .size(memory_requirements.acceleration_structure_size)
+            .size(size)
             .buffer(bottom_accel_buffer.buffer)
             .build();
 
         let bottom_accel = unsafe {
             acceleration_structure
-                .create_acceleration_structure(&bottom_accel_create_info, None)
-                .unwrap()
+                .create_acceleration_structure(&bottom_accel_create_info, None)?
         };
 
-        let mut build_info = AccelerationStructureBuildGeometryInfoKHR::builder()
-            .flags(BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
-            .geometries(&geometries)
-            .mode(BuildAccelerationStructureModeKHR::BUILD)
-            .ty(AccelerationStructureTypeKHR::BOTTOM_LEVEL)
-            .scratch_data(scratch_data)
-            .dst_acceleration_structure(bottom_accel)
-            .build();
-
-        let cb_begin_info = CommandBufferBeginInfo::builder()
-            .flags(CommandBufferUsageFlags::ONE_TIME_SUBMIT)
-            .build();
+        Ok((bottom_accel, bottom_accel_buffer))
+    }
 
-        let command_pool = backends.create_graphics_command_pool();
-        let command_buffers = backends.create_command_buffers(command_pool, 1);
-        let build_cb = command_buffers[0];
+    //小さくなるものだけを一度のsubmitでコピーし、元のASとbufferを解放する
+    fn compact_batch(
+        backends: &'a Backends,
+        acceleration_structure: &AccelerationStructure,
+        bottom_accels: Vec<(AccelerationStructureKHR, Buffers<'a>)>,
+        uncompacted_sizes: &[DeviceSize],
+        compacted_sizes: &[DeviceSize],
+        graphics_queue: Queue,
+    ) -> Result<Vec<(AccelerationStructureKHR, Buffers<'a>)>> {
+        let compacted = uncompacted_sizes
+            .iter()
+            .zip(compacted_sizes.iter())
+            .map(|(uncompacted_size, compacted_size)| {
+                if should_compact(*uncompacted_size, *compacted_size) {
+                    Self::create_acceleration_structure_storage(backends, acceleration_structure, *compacted_size).map(Some)
+                } else {
+                    Ok(None)
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        debug!(
+            "blas size: {} -> {} bytes after compaction",
+            uncompacted_sizes.iter().sum::<DeviceSize>(),
+            uncompacted_sizes
+                .iter()
+                .zip(compacted_sizes.iter())
+                .map(|(uncompacted_size, compacted_size)| size_after_compaction(*uncompacted_size, *compacted_size))
+                .sum::<DeviceSize>(),
+        );
 
-        unsafe {
-            backends.device.begin_command_buffer(
-                build_cb,
-                &cb_begin_info
-            ).unwrap();
+        if compacted.iter().all(Option::is_none) {
+            return Ok(bottom_accels);
         }
 
-        let build_infos = &[build_info];
-        let build_range_infos: &[&[_]] = &[&[build_range_info]];
-
-        unsafe {
-            acceleration_structure.cmd_build_acceleration_structures(
-                build_cb,
-                build_infos,
-                build_range_infos,
-            );
-            backends.device.end_command_buffer(build_cb).unwrap();
-
-            backends.device.queue_submit(
-                graphics_queue,
-                &[SubmitInfo::builder()
-                    .command_buffers(&[build_cb])
-                    .build()
-                ],
-                Fence::null(),
-            ).expect("submit failed");
-
-            //Queueの処理が終わるまで待機
-            backends.device.queue_wait_idle(graphics_queue).unwrap();
-            backends.device.free_command_buffers(command_pool, &command_buffers);
-            backends.device.destroy_command_pool(command_pool, None);
-
-            backends.device.destroy_buffer(scratch_buffer.buffer, None);
-            backends.device.free_memory(scratch_buffer.memory, None);
-        }
+        backends.submit_and_wait(graphics_queue, |copy_cb| {
+            for ((source, _), compacted) in bottom_accels.iter().zip(compacted.iter()) {
+                if let Some((destination, _)) = compacted {
+                    let copy_info = CopyAccelerationStructureInfoKHR::builder()
+                        .src(*source)
+                        .dst(*destination)
+                        .mode(CopyAccelerationStructureModeKHR::COMPACT)
+                        .build();
+
+                    unsafe { acceleration_structure.cmd_copy_acceleration_structure(copy_cb, &copy_info) };
+                }
+            }
+        })?;
+
+        let bottom_accels = bottom_accels
+            .into_iter()
+            .zip(compacted)
+            .map(|(original, compacted)| match compacted {
+                Some(compacted) => {
+                    //コピーが終わったので元のASを解放する、bufferはDropで解放される
+                    unsafe { acceleration_structure.destroy_acceleration_structure(original.0, None) };
+                    compacted
+                }
+                None => original,
+            })
+            .collect();
 
-        (bottom_accel, bottom_accel_buffer)
+        Ok(bottom_accels)
     }
 
     pub fn get_device_address_info(&self) -> DeviceAddress {
@@ -231,6 +478,69 @@ impl<'a> TriangleBottomLevelAccelerationStructure<'a> {
 
 impl Drop for TriangleBottomLevelAccelerationStructure<'_> {
     fn drop(&mut self) {
-        //todo!()
+        unsafe {
+            self.acceleration_structure
+                .destroy_acceleration_structure(self.bottom_acceleration_structure, None);
+        }
     }
-}
\ No newline at end of file
+}
+
+/// PhysicalDeviceAccelerationStructurePropertiesKHRのscratchのoffsetのalignment
+pub fn scratch_offset_alignment(backends: &Backends) -> DeviceSize {
+    let mut acceleration_structure_properties = PhysicalDeviceAccelerationStructurePropertiesKHR::default();
+
+    let mut properties2 = PhysicalDeviceProperties2::builder()
+        .push_next(&mut acceleration_structure_properties)
+        .build();
+
+    unsafe {
+        backends.instance
+            .get_physical_device_properties2(backends.physical_device, &mut properties2);
+    }
+
+    acceleration_structure_properties.min_acceleration_structure_scratch_offset_alignment as DeviceSize
+}
+
+//ASのbuild同士のscratchとASの読み書きの順番を守る
+fn acceleration_structure_build_barrier(backends: &Backends, command_buffer: CommandBuffer) {
+    let memory_barrier = MemoryBarrier::builder()
+        .src_access_mask(AccessFlags::ACCELERATION_STRUCTURE_WRITE_KHR)
+        .dst_access_mask(AccessFlags::ACCELERATION_STRUCTURE_READ_KHR | AccessFlags::ACCELERATION_STRUCTURE_WRITE_KHR)
+        .build();
+
+    unsafe {
+        backends.device.cmd_pipeline_barrier(
+            command_buffer,
+            PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR,
+            PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR,
+            DependencyFlags::empty(),
+            &[memory_barrier],
+            &[],
+            &[]
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compacts_only_when_smaller() {
+        assert!(should_compact(1024, 512));
+        assert!(should_compact(1024, 1023));
+        assert!(!should_compact(1024, 1024));
+        assert!(!should_compact(1024, 2048));
+        //queryが書き込まれなかった場合
+        assert!(!should_compact(1024, 0));
+    }
+
+    #[test]
+    fn size_after_compaction_keeps_the_original_when_not_compacted() {
+        assert_eq!(size_after_compaction(1024, 512), 512);
+        assert_eq!(size_after_compaction(1024, 1023), 1023);
+        assert_eq!(size_after_compaction(1024, 1024), 1024);
+        assert_eq!(size_after_compaction(1024, 2048), 1024);
+        assert_eq!(size_after_compaction(1024, 0), 1024);
+    }
+}
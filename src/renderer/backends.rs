@@ -1,20 +1,31 @@
 use std::ffi::{c_void, CStr, CString};
 use std::os::raw::c_char;
-use std::collections::HashSet;
 use ash::vk;
 use ash::{Device, Entry, Instance};
 use ash::extensions::ext::DebugUtils;
 use ash::extensions::khr::{AccelerationStructure, DeferredHostOperations, RayTracingPipeline, Surface, Swapchain, Win32Surface};
-use ash::vk::{CommandBuffer, CommandBufferAllocateInfo, CommandBufferLevel, CommandPool, CommandPoolCreateFlags, CommandPoolCreateInfo, DebugUtilsMessengerCreateInfoEXT, DeviceCreateInfo, DeviceQueueCreateInfo, ExtScalarBlockLayoutFn, KhrGetMemoryRequirements2Fn, KhrSpirv14Fn, PhysicalDevice, PhysicalDeviceAccelerationStructureFeaturesKHR, PhysicalDeviceBufferDeviceAddressFeatures, PhysicalDeviceDescriptorIndexingFeaturesEXT, PhysicalDeviceFeatures, PhysicalDeviceFeatures2, PhysicalDeviceImagelessFramebufferFeaturesKHR, PhysicalDeviceMemoryProperties, PhysicalDeviceProperties2, PhysicalDeviceRayTracingPipelineFeaturesKHR, PhysicalDeviceRayTracingPipelinePropertiesKHR, PhysicalDeviceScalarBlockLayoutFeaturesEXT, PhysicalDeviceShaderFloat16Int8Features, PhysicalDeviceVulkan12Features, PhysicalDeviceVulkanMemoryModelFeatures, PhysicalDeviceVulkanMemoryModelFeaturesKHR, Queue};
+use ash::vk::{CommandBuffer, CommandBufferAllocateInfo, CommandBufferBeginInfo, CommandBufferLevel, CommandPool, CommandPoolCreateFlags, CommandPoolCreateInfo, CommandBufferUsageFlags, DebugUtilsMessengerCreateInfoEXT, DeviceCreateInfo, DeviceQueueCreateInfo, ExtScalarBlockLayoutFn, Fence, KhrGetMemoryRequirements2Fn, KhrSpirv14Fn, PhysicalDevice, PhysicalDeviceAccelerationStructureFeaturesKHR, PhysicalDeviceBufferDeviceAddressFeatures, PhysicalDeviceDescriptorIndexingFeaturesEXT, PhysicalDeviceFeatures, PhysicalDeviceFeatures2, PhysicalDeviceImagelessFramebufferFeaturesKHR, PhysicalDeviceMemoryProperties, PhysicalDeviceProperties2, PhysicalDeviceRayTracingPipelineFeaturesKHR, PhysicalDeviceRayTracingPipelinePropertiesKHR, PhysicalDeviceScalarBlockLayoutFeaturesEXT, PhysicalDeviceShaderFloat16Int8Features, PhysicalDeviceVulkan12Features, PhysicalDeviceVulkanMemoryModelFeatures, PhysicalDeviceVulkanMemoryModelFeaturesKHR, Queue, SubmitInfo};
 use log::{debug, info};
 use tobj::LoadError::NormalParseError;
 use queue_family_indices::QueueFamilyIndices;
+use physical_device_selector::{DeviceSelection, PhysicalDeviceDescriptor, select_physical_device};
 use surfaces::Surfaces;
-use crate::renderer::validation_layer::{REQUIRED_LAYERS, ValidationLayer};
+use crate::error::{CottonError, Result};
+use crate::renderer::capability_report::CapabilityReport;
+use crate::renderer::validation_layer::{REQUIRED_LAYERS, ValidationLayer, ValidationMessages, ValidationSettings};
+use winit::window::Window;
 use crate::window_handlers::WindowHandlers;
 
 pub mod surfaces;
 pub mod queue_family_indices;
+pub mod physical_device_selector;
+
+#[derive(Clone, Debug, Default)]
+pub struct BackendsSettings {
+    pub enable_validation_layer: bool,
+    pub device_selection: DeviceSelection,
+    pub validation: ValidationSettings,
+}
 
 pub struct Backends {
     pub entry: Entry,
@@ -24,33 +35,65 @@ pub struct Backends {
     pub surfaces: Option<Surfaces>,
     pub device_memory_properties: PhysicalDeviceMemoryProperties,
     queue_family_indices: QueueFamilyIndices,
+    validation_layer: Option<ValidationLayer>,
+    //コールバックから参照されるのでinstanceを破棄するまで動かさずに持っておく
+    validation_settings: Box<ValidationSettings>,
 }
 
 impl Backends {
     //with surface
-    pub fn new(window_handlers: Option<&WindowHandlers> , enable_validation_layer: bool) -> anyhow::Result<Self> {
+    pub fn new(window_handlers: Option<&WindowHandlers> , enable_validation_layer: bool) -> Result<Self> {
+        Self::with_settings(
+            window_handlers,
+            BackendsSettings {
+                enable_validation_layer,
+                device_selection: DeviceSelection::from_env(),
+                ..Default::default()
+            },
+        )
+    }
+
+    pub fn with_settings(
+        window_handlers: Option<&WindowHandlers>,
+        settings: BackendsSettings,
+    ) -> Result<Self> {
         debug!("create backends");
 
         let entry = unsafe { Entry::load()? };
-        let instance = Self::create_instance(&entry, enable_validation_layer)?;
+
+        let enable_validation_layer = settings.enable_validation_layer
+            && ValidationLayer::is_validation_layer_available(&entry)?;
+
+        let validation_settings = Box::new(settings.validation);
+
+        let instance = Self::create_instance(
+            &entry,
+            window_handlers.map(|window_handlers| &window_handlers.window),
+            enable_validation_layer.then(|| validation_settings.as_ref()),
+        )?;
+
+        let validation_layer = if enable_validation_layer {
+            Some(ValidationLayer::new(&entry, &instance, &validation_settings)?)
+        } else {
+            None
+        };
 
         let surfaces = if let Some(window_handlers) = window_handlers {
             Some(
-                Surfaces::new(&instance, &entry, &window_handlers.window)
+                Surfaces::new(&instance, &entry, &window_handlers.window)?
             )
         } else {
             None
         };
 
-        let physical_device = Self::pick_physical_device(&instance, surfaces.as_ref(),
-        &[
-            Swapchain::name(),
-            AccelerationStructure::name(),
-            DeferredHostOperations::name(),
-            RayTracingPipeline::name(),
-        ]);
+        let physical_device = Self::pick_physical_device(
+            &instance,
+            surfaces.as_ref(),
+            &Self::required_device_extension_names(surfaces.is_some()),
+            &settings.device_selection,
+        )?;
 
-        let queue_family_indices = QueueFamilyIndices::new(&instance, surfaces.as_ref(), physical_device);
+        let queue_family_indices = QueueFamilyIndices::new(&instance, surfaces.as_ref(), physical_device)?;
 
         let device = Self::create_logical_device(
             &instance,
@@ -58,7 +101,7 @@ impl Backends {
             physical_device,
             &queue_family_indices,
             enable_validation_layer,
-        );
+        )?;
 
         let device_memory_properties = unsafe {
             instance.get_physical_device_memory_properties(physical_device)
@@ -72,32 +115,35 @@ impl Backends {
             surfaces,
             device_memory_properties,
             queue_family_indices,
+            validation_layer,
+            validation_settings,
         })
     }
 
-    pub fn create_command_pool(&self, queue_family_index: u32) -> CommandPool {
+    /// `ValidationSettings::collector`を渡した場合のみ
+    pub fn validation_messages(&self) -> Option<&ValidationMessages> {
+        self.validation_settings.collector.as_ref()
+    }
+
+    pub fn create_command_pool(&self, queue_family_index: u32) -> Result<CommandPool> {
         let command_pool_create_info = CommandPoolCreateInfo::builder()
             .queue_family_index(queue_family_index)
             .flags(CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
             .build();
 
         unsafe {
-            self.device.create_command_pool(&command_pool_create_info, None).unwrap()
+            Ok(self.device.create_command_pool(&command_pool_create_info, None)?)
         }
     }
 
-    pub fn create_graphics_command_pool(&self) -> CommandPool {
-        let command_pool_create_info = CommandPoolCreateInfo::builder()
-            .queue_family_index(self.queue_family_indices.graphics_family.unwrap())
-            .flags(CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
-            .build();
+    pub fn create_graphics_command_pool(&self) -> Result<CommandPool> {
+        let graphics_family = self.queue_family_indices.graphics_family
+            .ok_or(CottonError::MissingQueueFamily("graphics"))?;
 
-        unsafe {
-            self.device.create_command_pool(&command_pool_create_info, None).unwrap()
-        }
+        self.create_command_pool(graphics_family)
     }
 
-    pub fn create_command_buffers(&self, command_pool: CommandPool, size: u32) -> Vec<CommandBuffer> {
+    pub fn create_command_buffers(&self, command_pool: CommandPool, size: u32) -> Result<Vec<CommandBuffer>> {
         let command_buffer_alloc_info = CommandBufferAllocateInfo::builder()
             .command_pool(command_pool)
             .level(CommandBufferLevel::PRIMARY)
@@ -105,11 +151,64 @@ impl Backends {
             .build();
 
         unsafe {
-            self.device.allocate_command_buffers(&command_buffer_alloc_info).unwrap()
+            Ok(self.device.allocate_command_buffers(&command_buffer_alloc_info)?)
         }
     }
 
-    fn create_instance(entry: &Entry, enable_validation_layer: bool) -> anyhow::Result<Instance> {
+    /// recordで記録したコマンドを一度だけ実行し、終わるまで待つ
+    pub fn submit_and_wait<F: FnOnce(CommandBuffer)>(&self, graphics_queue: Queue, record: F) -> Result<()> {
+        let command_pool = self.create_graphics_command_pool()?;
+        let command_buffers = self.create_command_buffers(command_pool, 1)?;
+        let command_buffer = command_buffers[0];
+
+        unsafe {
+            self.device.begin_command_buffer(
+                command_buffer,
+                &CommandBufferBeginInfo::builder()
+                    .flags(CommandBufferUsageFlags::ONE_TIME_SUBMIT)
+                    .build(),
+            )?;
+        }
+
+        record(command_buffer);
+
+        unsafe {
+            self.device.end_command_buffer(command_buffer)?;
+
+            self.device.queue_submit(
+                graphics_queue,
+                &[SubmitInfo::builder()
+                    .command_buffers(&[command_buffer])
+                    .build()
+                ],
+                Fence::null(),
+            )?;
+
+            //Queueの処理が終わるまで待機
+            self.device.queue_wait_idle(graphics_queue)?;
+            self.device.free_command_buffers(command_pool, &command_buffers);
+            self.device.destroy_command_pool(command_pool, None);
+        }
+
+        Ok(())
+    }
+
+    /// デバイスの選択に使う拡張機能
+    pub fn required_device_extension_names(has_surface: bool) -> Vec<&'static CStr> {
+        let mut extension_names = Surfaces::swapchain_extension_names(has_surface);
+
+        extension_names.extend_from_slice(&[
+            AccelerationStructure::name(),
+            DeferredHostOperations::name(),
+            RayTracingPipeline::name(),
+        ]);
+
+        extension_names
+    }
+
+    //with surface
+    //validation_settingsがある場合のみvalidation layerを有効にする
+    fn create_instance(entry: &Entry, window: Option<&Window>, validation_settings: Option<&ValidationSettings>) -> Result<Instance> {
         let app_info = vk::ApplicationInfo::builder()
             .application_name(CString::new(crate::constants::APPLICATION_NAME)?.as_c_str())
             .application_version(0)
@@ -118,11 +217,14 @@ impl Backends {
             .api_version(vk::make_api_version(0, 1, 3, 0))
             .build();
 
-        let mut extension_names = Surfaces::require_surface_extension_names();
+        let mut extension_names: Vec<*const c_char> = Surfaces::require_surface_extension_names(window)?
+            .iter()
+            .map(|name| name.as_ptr())
+            .collect();
 
         let mut debug_extensions_names = ValidationLayer::require_debug_utils_extension_names_c_char();
 
-        if enable_validation_layer {
+        if validation_settings.is_some() {
             extension_names.append(&mut debug_extensions_names);
         }
 
@@ -133,11 +235,10 @@ impl Backends {
         let validation_extension_names = ValidationLayer::require_validation_layer_extension_names_cstring();
         let validation_extension_names_ptr = validation_extension_names.iter().map(|name| name.as_ptr()).collect::<Vec<_>>();
 
-        let debug_create_info = ValidationLayer::populate_debug_messenger_create_info();
-
-        if enable_validation_layer {
-            ValidationLayer::check_validation_layer_support(entry);
+        //instanceの作成と破棄の間のメッセージを受け取るためのもの
+        let debug_create_info = ValidationLayer::populate_debug_messenger_create_info(validation_settings);
 
+        if validation_settings.is_some() {
             instance_create_info = instance_create_info.enabled_layer_names(&validation_extension_names_ptr);
 
             instance_create_info.p_next = &debug_create_info as *const DebugUtilsMessengerCreateInfoEXT as *const c_void;
@@ -151,50 +252,29 @@ impl Backends {
         //with surface
         surfaces: Option<&Surfaces>,
         extensions: &[&CStr],
-    ) -> PhysicalDevice {
+        device_selection: &DeviceSelection,
+    ) -> Result<PhysicalDevice> {
         let physical_devices = unsafe {
-            instance
-                .enumerate_physical_devices()
-                .expect("You could not be retrieved a physical device")
-        };
-        
-        let physical_device = physical_devices
-            .into_iter()
-            .find_map(|physical_device| {
-                let indices = QueueFamilyIndices::new(instance, surfaces, physical_device);
-
-                if unsafe { instance.enumerate_device_extension_properties(physical_device) }.map(
-                    |exts| {
-                        let set: std::collections::HashSet<&CStr> = exts.iter()
-                            .map(|ext| unsafe { CStr::from_ptr(&ext.extension_name as * const c_char) })
-                            .collect();
-
-                        extensions.iter().all(|ext| set.contains(ext))
-                    }
-                ) != Ok(true) {
-                    return None;
-                }
-
-                if let Some(surfaces) = surfaces {
-                    //with surface
-                    if !indices.is_device_suitable_for_surface(instance, physical_device, surfaces) {
-                        return None;
-                    }
-                }
-
-                Some(physical_device)
-            })
-            .expect("Doesn't match physical device suitable");
-
-        let props = unsafe {
-            instance.get_physical_device_properties(physical_device)
+            instance.enumerate_physical_devices()?
         };
 
-        info!("Selected physical device: {:?}", unsafe {
-            CStr::from_ptr(props.device_name.as_ptr())
-        });
+        let descriptors = physical_devices
+            .iter()
+            .enumerate()
+            .map(|(index, physical_device)| PhysicalDeviceDescriptor::query(instance, surfaces, *physical_device, index))
+            .collect::<Result<Vec<_>>>()?;
+
+        let extensions: Vec<String> = extensions
+            .iter()
+            .map(|ext| ext.to_string_lossy().into_owned())
+            .collect();
+        let extensions: Vec<&str> = extensions.iter().map(|ext| ext.as_str()).collect();
+
+        let index = select_physical_device(&descriptors, &extensions, device_selection)?;
+
+        info!("Selected physical device: [{}] {}", index, descriptors[index].name);
 
-        physical_device
+        Ok(physical_devices[index])
     }
 
     //with surface
@@ -204,23 +284,29 @@ impl Backends {
         physical_device: PhysicalDevice,
         queue_family_indices: &QueueFamilyIndices,
         enable_validation_layer: bool,
-    ) -> Device {
+    ) -> Result<Device> {
+        let graphics_family = queue_family_indices.graphics_family
+            .ok_or(CottonError::MissingQueueFamily("graphics"))?;
+
         //with surface
         let mut queue_create_info = if surfaces.is_some() {
+            let present_family = queue_family_indices.present_family
+                .ok_or(CottonError::MissingQueueFamily("present"))?;
+
             vec![
                 DeviceQueueCreateInfo::builder()
-                    .queue_family_index(queue_family_indices.graphics_family.unwrap())
+                    .queue_family_index(graphics_family)
                     .queue_priorities(&[1.0f32])
                     .build(),
                 DeviceQueueCreateInfo::builder()
-                    .queue_family_index(queue_family_indices.present_family.unwrap())
+                    .queue_family_index(present_family)
                     .queue_priorities(&[1.0f32])
                     .build()
             ]
         } else {
             vec![
                 DeviceQueueCreateInfo::builder()
-                    .queue_family_index(queue_family_indices.graphics_family.unwrap())
+                    .queue_family_index(graphics_family)
                     .queue_priorities(&[1.0f32])
                     .build(),
             ]
@@ -252,14 +338,7 @@ impl Backends {
 
         unsafe { instance.get_physical_device_features2(physical_device, &mut features2) };
 
-        debug!("{:#?}", &scalar_block);
-        debug!("{:#?}", &descriptor_indexing);
-        debug!("{:#?}", &imageless_framebuffer);
-        debug!("{:#?}", &shader_float16_int8);
-        debug!("{:#?}", &vulkan_memory_model);
-        debug!("{:#?}", &get_buffer_device_address_features);
-        debug!("{:#?}", &acceleration_structure_features);
-        debug!("{:#?}", &ray_tracing_pipeline_features);
+        //サポート状況は`cotton info`で確認できる
 
         /*
         //PhysicalDeviceVulkan12Featuresは上部のPhysicalDeviceFeatures2のpNextに繋げばその機能がサポートされてるかの確認として使うことが出来るし、
@@ -282,12 +361,13 @@ impl Backends {
             .build();
         */
 
-        let mut extension_names = vec![
-            Swapchain::name().as_ptr(),
-            RayTracingPipeline::name().as_ptr(),
-            AccelerationStructure::name().as_ptr(),
-            //非同期処理をするに当たってのlockを提供する(?)
-            DeferredHostOperations::name().as_ptr(),
+        //DeferredHostOperationsは非同期処理をするに当たってのlockを提供する(?)
+        let mut extension_names: Vec<*const c_char> = Self::required_device_extension_names(surfaces.is_some())
+            .iter()
+            .map(|name| name.as_ptr())
+            .collect();
+
+        extension_names.extend_from_slice(&[
             //Vulkan1.2時代はSpir-vの1.4がサポートされているかは環境次第だった？
             //KhrSpirv14Fn::name().as_ptr(),
 
@@ -296,7 +376,7 @@ impl Backends {
             ExtScalarBlockLayoutFn::name().as_ptr(),
             //VkMemoryRequirementsやVkSparseImageMemoryRequirements構造体に対してsTypeやpNextを生やすようにする
             KhrGetMemoryRequirements2Fn::name().as_ptr(),
-        ];
+        ]);
 
         let mut device_create_info = DeviceCreateInfo::builder()
             .push_next(&mut features2)
@@ -308,68 +388,46 @@ impl Backends {
             .enabled_extension_names(&extension_names)
             .queue_create_infos(&queue_create_info);
 
-        let mut validation_extension_names = ValidationLayer::require_validation_layer_extension_names_c_char();
+        let validation_extension_names = ValidationLayer::require_validation_layer_extension_names_cstring();
+        let validation_extension_names_ptr = validation_extension_names.iter().map(|name| name.as_ptr()).collect::<Vec<_>>();
         if enable_validation_layer {
             device_create_info = device_create_info
-                .enabled_layer_names(validation_extension_names.as_slice());
+                .enabled_layer_names(&validation_extension_names_ptr);
         }
 
         let device_create_info = device_create_info.build();
 
         unsafe {
-            instance.create_device(physical_device, &device_create_info, None)
-                .expect("Failed to create logical Device")
+            Ok(instance.create_device(physical_device, &device_create_info, None)?)
         }
     }
 
     pub fn create_graphics_queue(
         &self,
         queue_index: u32,
-    ) -> Queue {
+    ) -> Result<Queue> {
         debug!("create graphics queue");
 
-        unsafe { self.device.get_device_queue(
-            self.queue_family_indices.graphics_family.expect("Failed to create graphics family queue"),
-                            queue_index
-        )}
+        let graphics_family = self.queue_family_indices.graphics_family
+            .ok_or(CottonError::MissingQueueFamily("graphics"))?;
+
+        unsafe { Ok(self.device.get_device_queue(graphics_family, queue_index)) }
     }
 
     pub fn create_present_queue(
         &self,
         queue_index: u32,
-    ) -> Queue {
+    ) -> Result<Queue> {
         debug!("create present queue");
 
-        unsafe { self.device.get_device_queue(
-            self.queue_family_indices.present_family.expect("Failed to create graphics family queue"),
-            queue_index
-        )}
+        let present_family = self.queue_family_indices.present_family
+            .ok_or(CottonError::MissingQueueFamily("present"))?;
+
+        unsafe { Ok(self.device.get_device_queue(present_family, queue_index)) }
     }
 
-    pub fn display_support_extension(&self) {
-        unsafe {
-            let extension_properties = self
-                .instance
-                .enumerate_device_extension_properties(self.physical_device).unwrap();
-            debug!("Extension properties:\n{:#?}", &extension_properties);
-
-            let supported_extensions: HashSet<String> = extension_properties
-                .iter()
-                .map(|ext| {
-                    CStr::from_ptr(ext.extension_name.as_ptr() as *const c_char)
-                        .to_string_lossy()
-                        .as_ref()
-                        .to_owned()
-                })
-                .collect();
-
-            for ext in extension_properties {
-                let ext = CStr::from_ptr(ext.extension_name.as_ptr()).to_string_lossy();
-                if !supported_extensions.contains(ext.as_ref()) {
-                    panic!("Device extension not supported: {}", ext);
-                }
-            }
-        }
+    pub fn capability_report(&self) -> Result<CapabilityReport> {
+        CapabilityReport::from_instance(&self.entry, &self.instance)
     }
 }
 
@@ -382,6 +440,9 @@ impl Drop for Backends {
                 surfaces.surface.destroy_surface(surfaces.surface_khr, None);
             }
 
+            //messengerはinstanceより先に破棄する
+            self.validation_layer.take();
+
             self.instance.destroy_instance(None);
         }
     }
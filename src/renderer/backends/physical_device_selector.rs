@@ -0,0 +1,535 @@
+use std::collections::HashSet;
+use std::env;
+use std::ffi::CStr;
+use std::fmt;
+use std::fmt::Formatter;
+use ash::Instance;
+use ash::vk::{MemoryHeapFlags, PhysicalDevice, PhysicalDeviceAccelerationStructureFeaturesKHR, PhysicalDeviceBufferDeviceAddressFeatures, PhysicalDeviceDescriptorIndexingFeaturesEXT, PhysicalDeviceFeatures2, PhysicalDeviceIDProperties, PhysicalDeviceProperties2, PhysicalDeviceRayTracingPipelineFeaturesKHR, PhysicalDeviceRayTracingPipelinePropertiesKHR, PhysicalDeviceType, UUID_SIZE};
+use ash::extensions::khr::{AccelerationStructure, RayTracingPipeline};
+use log::debug;
+use crate::constants::DEVICE_SELECTION_ENV;
+use crate::error::{CottonError, Result};
+use crate::renderer::backends::queue_family_indices::QueueFamilyIndices;
+use crate::renderer::backends::surfaces::Surfaces;
+
+/// 物理デバイスをスコアリングするための情報
+/// Vulkanに依存しないのでテストで組み立てることが出来る
+#[derive(Clone, Debug)]
+pub struct PhysicalDeviceDescriptor {
+    pub index: usize,
+    pub name: String,
+    pub uuid: [u8; UUID_SIZE],
+    pub device_type: PhysicalDeviceType,
+    pub extensions: Vec<String>,
+    pub max_ray_recursion_depth: u32,
+    pub device_local_memory: u64,
+    /// 足りていない機能(feature)の名前
+    pub missing_features: Vec<String>,
+}
+
+impl PhysicalDeviceDescriptor {
+    pub fn query(
+        instance: &Instance,
+        //with surface
+        surfaces: Option<&Surfaces>,
+        physical_device: PhysicalDevice,
+        index: usize,
+    ) -> Result<Self> {
+        let extensions: Vec<String> = unsafe {
+            instance.enumerate_device_extension_properties(physical_device)?
+        }
+            .iter()
+            .map(|ext| unsafe { CStr::from_ptr(ext.extension_name.as_ptr()) }.to_string_lossy().into_owned())
+            .collect();
+
+        //拡張機能がないデバイスに対して拡張の構造体をチェインしてはいけない
+        let supports_ray_tracing = [RayTracingPipeline::name(), AccelerationStructure::name()]
+            .iter()
+            .all(|name| extensions.iter().any(|ext| ext.as_str() == name.to_string_lossy()));
+
+        let mut id_properties = PhysicalDeviceIDProperties::default();
+        let mut ray_tracing_pipeline_properties = PhysicalDeviceRayTracingPipelinePropertiesKHR::default();
+
+        let mut properties2 = PhysicalDeviceProperties2::builder()
+            .push_next(&mut id_properties);
+
+        if supports_ray_tracing {
+            properties2 = properties2.push_next(&mut ray_tracing_pipeline_properties);
+        }
+
+        let mut properties2 = properties2.build();
+
+        unsafe { instance.get_physical_device_properties2(physical_device, &mut properties2) };
+
+        let properties = properties2.properties;
+
+        let mut missing_features = vec![];
+
+        let mut buffer_device_address_features = PhysicalDeviceBufferDeviceAddressFeatures::default();
+        let mut acceleration_structure_features = PhysicalDeviceAccelerationStructureFeaturesKHR::default();
+        let mut ray_tracing_pipeline_features = PhysicalDeviceRayTracingPipelineFeaturesKHR::default();
+        //テクスチャの可変長の配列に使う
+        let mut descriptor_indexing_features = PhysicalDeviceDescriptorIndexingFeaturesEXT::default();
+
+        let mut features2 = PhysicalDeviceFeatures2::builder()
+            .push_next(&mut buffer_device_address_features)
+            .push_next(&mut descriptor_indexing_features);
+
+        if supports_ray_tracing {
+            features2 = features2
+                .push_next(&mut acceleration_structure_features)
+                .push_next(&mut ray_tracing_pipeline_features);
+        }
+
+        let mut features2 = features2.build();
+
+        unsafe { instance.get_physical_device_features2(physical_device, &mut features2) };
+
+        if buffer_device_address_features.buffer_device_address == 0 {
+            missing_features.push("bufferDeviceAddress".to_owned());
+        }
+
+        if acceleration_structure_features.acceleration_structure == 0 {
+            missing_features.push("accelerationStructure".to_owned());
+        }
+
+        if ray_tracing_pipeline_features.ray_tracing_pipeline == 0 {
+            missing_features.push("rayTracingPipeline".to_owned());
+        }
+
+        let descriptor_indexing = [
+            ("runtimeDescriptorArray", descriptor_indexing_features.runtime_descriptor_array),
+            ("descriptorBindingVariableDescriptorCount", descriptor_indexing_features.descriptor_binding_variable_descriptor_count),
+            ("descriptorBindingPartiallyBound", descriptor_indexing_features.descriptor_binding_partially_bound),
+            ("shaderSampledImageArrayNonUniformIndexing", descriptor_indexing_features.shader_sampled_image_array_non_uniform_indexing),
+        ];
+
+        for (name, supported) in descriptor_indexing {
+            if supported == 0 {
+                missing_features.push(name.to_owned());
+            }
+        }
+
+        if let Some(surfaces) = surfaces {
+            //with surface
+            //問い合わせに失敗しても他のデバイスは選べるので、このデバイスだけ候補から外す
+            let suitable = QueueFamilyIndices::new(instance, Some(surfaces), physical_device)
+                .and_then(|indices| indices.is_device_suitable_for_surface(instance, physical_device, surfaces));
+
+            match suitable {
+                Ok(true) => {}
+                Ok(false) => missing_features.push("surface presentation".to_owned()),
+                Err(err) => missing_features.push(format!("surface presentation ({})", err)),
+            }
+        }
+
+        let memory_properties = unsafe { instance.get_physical_device_memory_properties(physical_device) };
+
+        let device_local_memory = memory_properties.memory_heaps[..memory_properties.memory_heap_count as usize]
+            .iter()
+            .filter(|heap| heap.flags.contains(MemoryHeapFlags::DEVICE_LOCAL))
+            .map(|heap| heap.size)
+            .sum();
+
+        let name = unsafe { CStr::from_ptr(properties.device_name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+
+        Ok(Self {
+            index,
+            name,
+            uuid: id_properties.device_uuid,
+            device_type: properties.device_type,
+            extensions,
+            max_ray_recursion_depth: ray_tracing_pipeline_properties.max_ray_recursion_depth,
+            device_local_memory,
+            missing_features,
+        })
+    }
+
+    pub fn missing_extensions(&self, required_extensions: &[&str]) -> Vec<String> {
+        let set: HashSet<&str> = self.extensions.iter().map(|ext| ext.as_str()).collect();
+
+        required_extensions
+            .iter()
+            .filter(|ext| !set.contains(*ext))
+            .map(|ext| ext.to_string())
+            .collect()
+    }
+}
+
+/// 比較はフィールドの上から順に行われる
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DeviceScore {
+    pub device_type_rank: u32,
+    pub max_ray_recursion_depth: u32,
+    pub device_local_memory: u64,
+}
+
+pub fn score_physical_device(descriptor: &PhysicalDeviceDescriptor) -> DeviceScore {
+    let device_type_rank = match descriptor.device_type {
+        PhysicalDeviceType::DISCRETE_GPU => 4,
+        PhysicalDeviceType::INTEGRATED_GPU => 3,
+        PhysicalDeviceType::VIRTUAL_GPU => 2,
+        PhysicalDeviceType::CPU => 1,
+        _ => 0,
+    };
+
+    DeviceScore {
+        device_type_rank,
+        max_ray_recursion_depth: descriptor.max_ray_recursion_depth,
+        device_local_memory: descriptor.device_local_memory,
+    }
+}
+
+/// 自動選択を上書きするための指定
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceSelection {
+    Auto,
+    /// デバイス名の部分一致(大文字小文字は区別しない)
+    Name(String),
+    Index(usize),
+    Uuid([u8; UUID_SIZE]),
+}
+
+impl Default for DeviceSelection {
+    fn default() -> Self {
+        Self::Auto
+    }
+}
+
+impl DeviceSelection {
+    /// `COTTON_DEVICE`から読み込む、未設定なら`Auto`
+    pub fn from_env() -> Self {
+        match env::var(DEVICE_SELECTION_ENV) {
+            Ok(value) => Self::parse(&value),
+            Err(_) => Self::Auto,
+        }
+    }
+
+    /// `index:1`, `uuid:...`, `name:...`の形式か、
+    /// 接頭辞なしの場合は数字ならindex、32桁の16進数ならUUID、それ以外は名前として扱う
+    pub fn parse(value: &str) -> Self {
+        let value = value.trim();
+
+        if value.is_empty() {
+            return Self::Auto;
+        }
+
+        if let Some(index) = value.strip_prefix("index:") {
+            if let Ok(index) = index.trim().parse() {
+                return Self::Index(index);
+            }
+        }
+
+        if let Some(uuid) = value.strip_prefix("uuid:") {
+            if let Some(uuid) = parse_uuid(uuid.trim()) {
+                return Self::Uuid(uuid);
+            }
+        }
+
+        if let Some(name) = value.strip_prefix("name:") {
+            return Self::Name(name.trim().to_owned());
+        }
+
+        if let Ok(index) = value.parse() {
+            return Self::Index(index);
+        }
+
+        if let Some(uuid) = parse_uuid(value) {
+            return Self::Uuid(uuid);
+        }
+
+        Self::Name(value.to_owned())
+    }
+
+    fn matches(&self, descriptor: &PhysicalDeviceDescriptor) -> bool {
+        match self {
+            Self::Auto => true,
+            Self::Name(name) => descriptor.name.to_lowercase().contains(&name.to_lowercase()),
+            Self::Index(index) => descriptor.index == *index,
+            Self::Uuid(uuid) => &descriptor.uuid == uuid,
+        }
+    }
+}
+
+impl fmt::Display for DeviceSelection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::Name(name) => write!(f, "name:{}", name),
+            Self::Index(index) => write!(f, "index:{}", index),
+            Self::Uuid(uuid) => write!(f, "uuid:{}", format_uuid(uuid)),
+        }
+    }
+}
+
+pub fn parse_uuid(value: &str) -> Option<[u8; UUID_SIZE]> {
+    let hex: String = value.chars().filter(|c| *c != '-').collect();
+
+    if hex.len() != UUID_SIZE * 2 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let mut uuid = [0u8; UUID_SIZE];
+
+    for (i, byte) in uuid.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+
+    Some(uuid)
+}
+
+pub fn format_uuid(uuid: &[u8; UUID_SIZE]) -> String {
+    uuid.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// 選ばれなかったデバイスとその理由
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceRejection {
+    pub index: usize,
+    pub name: String,
+    pub missing_extensions: Vec<String>,
+    pub missing_features: Vec<String>,
+}
+
+impl fmt::Display for DeviceRejection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}", self.index, self.name)?;
+
+        if !self.missing_extensions.is_empty() {
+            write!(f, ", missing extensions: {}", self.missing_extensions.join(", "))?;
+        }
+
+        if !self.missing_features.is_empty() {
+            write!(f, ", missing features: {}", self.missing_features.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// 条件を満たすデバイスの中からスコアが最も高いものの`index`を返す
+pub fn select_physical_device(
+    descriptors: &[PhysicalDeviceDescriptor],
+    required_extensions: &[&str],
+    selection: &DeviceSelection,
+) -> Result<usize> {
+    let candidates: Vec<&PhysicalDeviceDescriptor> = descriptors
+        .iter()
+        .filter(|descriptor| selection.matches(descriptor))
+        .collect();
+
+    if candidates.is_empty() {
+        return Err(CottonError::PhysicalDeviceNotFound(selection.to_string()));
+    }
+
+    let mut rejections = vec![];
+    let mut best: Option<(DeviceScore, &PhysicalDeviceDescriptor)> = None;
+
+    for descriptor in candidates {
+        let missing_extensions = descriptor.missing_extensions(required_extensions);
+
+        if !missing_extensions.is_empty() || !descriptor.missing_features.is_empty() {
+            rejections.push(DeviceRejection {
+                index: descriptor.index,
+                name: descriptor.name.clone(),
+                missing_extensions,
+                missing_features: descriptor.missing_features.clone(),
+            });
+
+            continue;
+        }
+
+        let score = score_physical_device(descriptor);
+
+        debug!("physical device [{}] {}: {:?}", descriptor.index, descriptor.name, score);
+
+        //同じスコアなら先に列挙されたものを優先する
+        if best.map_or(true, |(best_score, _)| score > best_score) {
+            best = Some((score, descriptor));
+        }
+    }
+
+    best.map(|(_, descriptor)| descriptor.index)
+        .ok_or(CottonError::NoSuitablePhysicalDevice(rejections))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAY_TRACING_EXTENSIONS: [&str; 2] = ["VK_KHR_acceleration_structure", "VK_KHR_ray_tracing_pipeline"];
+
+    fn descriptor(index: usize, name: &str, device_type: PhysicalDeviceType) -> PhysicalDeviceDescriptor {
+        PhysicalDeviceDescriptor {
+            index,
+            name: name.to_owned(),
+            uuid: [index as u8; UUID_SIZE],
+            device_type,
+            extensions: RAY_TRACING_EXTENSIONS.iter().map(|ext| ext.to_string()).collect(),
+            max_ray_recursion_depth: 31,
+            device_local_memory: 8 << 30,
+            missing_features: vec![],
+        }
+    }
+
+    #[test]
+    fn device_type_outranks_other_properties() {
+        let mut integrated = descriptor(0, "integrated", PhysicalDeviceType::INTEGRATED_GPU);
+        integrated.max_ray_recursion_depth = 64;
+        integrated.device_local_memory = 64 << 30;
+        let discrete = descriptor(1, "discrete", PhysicalDeviceType::DISCRETE_GPU);
+        let cpu = descriptor(2, "cpu", PhysicalDeviceType::CPU);
+
+        assert!(score_physical_device(&discrete) > score_physical_device(&integrated));
+        assert!(score_physical_device(&integrated) > score_physical_device(&cpu));
+    }
+
+    #[test]
+    fn same_type_compares_recursion_depth_then_memory() {
+        let mut shallow = descriptor(0, "shallow", PhysicalDeviceType::DISCRETE_GPU);
+        shallow.max_ray_recursion_depth = 1;
+        shallow.device_local_memory = 32 << 30;
+        let deep = descriptor(1, "deep", PhysicalDeviceType::DISCRETE_GPU);
+        let mut larger = descriptor(2, "larger", PhysicalDeviceType::DISCRETE_GPU);
+        larger.device_local_memory = 16 << 30;
+
+        assert!(score_physical_device(&deep) > score_physical_device(&shallow));
+        assert!(score_physical_device(&larger) > score_physical_device(&deep));
+    }
+
+    #[test]
+    fn selects_highest_score() {
+        let descriptors = [
+            descriptor(0, "llvmpipe", PhysicalDeviceType::CPU),
+            descriptor(1, "Intel UHD", PhysicalDeviceType::INTEGRATED_GPU),
+            descriptor(2, "NVIDIA GeForce RTX 3080", PhysicalDeviceType::DISCRETE_GPU),
+        ];
+
+        let selected = select_physical_device(&descriptors, &RAY_TRACING_EXTENSIONS, &DeviceSelection::Auto).unwrap();
+
+        assert_eq!(selected, 2);
+    }
+
+    #[test]
+    fn ties_keep_enumeration_order() {
+        let descriptors = [
+            descriptor(0, "first", PhysicalDeviceType::DISCRETE_GPU),
+            descriptor(1, "second", PhysicalDeviceType::DISCRETE_GPU),
+        ];
+
+        let selected = select_physical_device(&descriptors, &RAY_TRACING_EXTENSIONS, &DeviceSelection::Auto).unwrap();
+
+        assert_eq!(selected, 0);
+    }
+
+    #[test]
+    fn selection_overrides_score() {
+        let descriptors = [
+            descriptor(0, "Intel UHD", PhysicalDeviceType::INTEGRATED_GPU),
+            descriptor(1, "NVIDIA GeForce RTX 3080", PhysicalDeviceType::DISCRETE_GPU),
+        ];
+
+        let by_name = DeviceSelection::Name("intel".to_owned());
+        let by_uuid = DeviceSelection::Uuid([0; UUID_SIZE]);
+
+        assert_eq!(select_physical_device(&descriptors, &RAY_TRACING_EXTENSIONS, &by_name).unwrap(), 0);
+        assert_eq!(select_physical_device(&descriptors, &RAY_TRACING_EXTENSIONS, &DeviceSelection::Index(0)).unwrap(), 0);
+        assert_eq!(select_physical_device(&descriptors, &RAY_TRACING_EXTENSIONS, &by_uuid).unwrap(), 0);
+    }
+
+    #[test]
+    fn unmatched_selection_is_reported() {
+        let descriptors = [descriptor(0, "Intel UHD", PhysicalDeviceType::INTEGRATED_GPU)];
+
+        let err = select_physical_device(&descriptors, &RAY_TRACING_EXTENSIONS, &DeviceSelection::Index(3)).unwrap_err();
+
+        assert!(matches!(err, CottonError::PhysicalDeviceNotFound(selection) if selection == "index:3"));
+    }
+
+    #[test]
+    fn rejections_list_missing_extensions_and_features() {
+        let mut no_ray_tracing = descriptor(0, "old", PhysicalDeviceType::DISCRETE_GPU);
+        no_ray_tracing.extensions = vec!["VK_KHR_acceleration_structure".to_owned()];
+        let mut no_present = descriptor(1, "headless", PhysicalDeviceType::DISCRETE_GPU);
+        no_present.missing_features = vec!["surface presentation".to_owned()];
+
+        let err = select_physical_device(&[no_ray_tracing, no_present], &RAY_TRACING_EXTENSIONS, &DeviceSelection::Auto).unwrap_err();
+
+        let rejections = match err {
+            CottonError::NoSuitablePhysicalDevice(rejections) => rejections,
+            err => panic!("unexpected error: {}", err),
+        };
+
+        assert_eq!(rejections, vec![
+            DeviceRejection {
+                index: 0,
+                name: "old".to_owned(),
+                missing_extensions: vec!["VK_KHR_ray_tracing_pipeline".to_owned()],
+                missing_features: vec![],
+            },
+            DeviceRejection {
+                index: 1,
+                name: "headless".to_owned(),
+                missing_extensions: vec![],
+                missing_features: vec!["surface presentation".to_owned()],
+            },
+        ]);
+    }
+
+    #[test]
+    fn unsuitable_device_does_not_block_others() {
+        let mut broken = descriptor(0, "broken", PhysicalDeviceType::DISCRETE_GPU);
+        broken.missing_features = vec!["surface presentation (vulkan error: ERROR_SURFACE_LOST_KHR)".to_owned()];
+        let working = descriptor(1, "working", PhysicalDeviceType::INTEGRATED_GPU);
+
+        let selected = select_physical_device(&[broken, working], &RAY_TRACING_EXTENSIONS, &DeviceSelection::Auto).unwrap();
+
+        assert_eq!(selected, 1);
+    }
+
+    #[test]
+    fn parse_prefixed_selection() {
+        assert_eq!(DeviceSelection::parse("index:1"), DeviceSelection::Index(1));
+        assert_eq!(DeviceSelection::parse(" name: RTX "), DeviceSelection::Name("RTX".to_owned()));
+        assert_eq!(
+            DeviceSelection::parse("uuid:00112233-4455-6677-8899-aabbccddeeff"),
+            DeviceSelection::Uuid([0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]),
+        );
+    }
+
+    #[test]
+    fn parse_unprefixed_selection() {
+        assert_eq!(DeviceSelection::parse(""), DeviceSelection::Auto);
+        assert_eq!(DeviceSelection::parse("  "), DeviceSelection::Auto);
+        assert_eq!(DeviceSelection::parse("2"), DeviceSelection::Index(2));
+        assert_eq!(DeviceSelection::parse("ffffffffffffffffffffffffffffffff"), DeviceSelection::Uuid([0xff; UUID_SIZE]));
+        assert_eq!(DeviceSelection::parse("Radeon"), DeviceSelection::Name("Radeon".to_owned()));
+    }
+
+    #[test]
+    fn parse_invalid_prefixed_value_falls_back_to_name() {
+        assert_eq!(DeviceSelection::parse("index:gpu"), DeviceSelection::Name("index:gpu".to_owned()));
+        assert_eq!(DeviceSelection::parse("uuid:1234"), DeviceSelection::Name("uuid:1234".to_owned()));
+    }
+
+    #[test]
+    fn display_round_trips_through_parse() {
+        let selections = [
+            DeviceSelection::Auto,
+            DeviceSelection::Name("RTX".to_owned()),
+            DeviceSelection::Index(3),
+            DeviceSelection::Uuid([0x5a; UUID_SIZE]),
+        ];
+
+        for selection in selections {
+            let parsed = match selection {
+                DeviceSelection::Auto => DeviceSelection::parse(""),
+                _ => DeviceSelection::parse(&selection.to_string()),
+            };
+
+            assert_eq!(parsed, selection);
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use ash::Instance;
 use ash::vk::{PhysicalDevice, QueueFlags};
+use crate::error::Result;
 use crate::renderer::backends::surfaces::Surfaces;
 use crate::renderer::swapchain_support_details::SwapchainSupportDetails;
 
@@ -13,7 +14,7 @@ impl QueueFamilyIndices {
         instance: &Instance,
         surfaces: Option<&Surfaces>,
         physical_device: PhysicalDevice,
-    ) -> QueueFamilyIndices {
+    ) -> Result<QueueFamilyIndices> {
         let queue_families = unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
 
         let mut graphics_family = None;
@@ -26,19 +27,19 @@ impl QueueFamilyIndices {
 
             if let Some(surfaces) = surfaces {
                 let present_support = unsafe {
-                    surfaces.surface.get_physical_device_surface_support(physical_device, i as u32, surfaces.surface_khr)
+                    surfaces.surface.get_physical_device_surface_support(physical_device, i as u32, surfaces.surface_khr)?
                 };
 
-                if present_support.unwrap() {
+                if present_support {
                     present_family = Some(i as u32);
                 }
             }
         }
 
-        Self {
+        Ok(Self {
             graphics_family,
             present_family,
-        }
+        })
     }
 
     //with surface
@@ -47,21 +48,21 @@ impl QueueFamilyIndices {
         instance: &Instance,
         physical_device: PhysicalDevice,
         surfaces: &Surfaces,
-    ) -> bool {
+    ) -> Result<bool> {
 
-        let extension_support = Surfaces::check_swapchain_support(instance, physical_device);
+        let extension_support = Surfaces::check_swapchain_support(instance, physical_device)?;
 
         let mut swapchain_adequate = false;
 
         if extension_support {
             //Swapchainのもろもろの機能の確認
-            let swapchain_support_details = SwapchainSupportDetails::new(physical_device, surfaces);
+            let swapchain_support_details = SwapchainSupportDetails::new(physical_device, surfaces)?;
 
             swapchain_adequate = !swapchain_support_details.formats.is_empty()
                 && !swapchain_support_details.present_modes.is_empty();
         }
 
-        self.is_all_complete() && extension_support && swapchain_adequate
+        Ok(self.is_all_complete() && extension_support && swapchain_adequate)
     }
 
     //with surface
@@ -1,12 +1,15 @@
 use core::fmt;
 use std::ffi::{CStr, CString};
 use std::fmt::Formatter;
-use ash::extensions::khr::{Surface, Swapchain, Win32Surface};
+use ash::extensions::ext::MetalSurface;
+use ash::extensions::khr::{AndroidSurface, Surface, Swapchain, WaylandSurface, Win32Surface, XcbSurface, XlibSurface};
 use ash::vk::{PhysicalDevice, SurfaceKHR};
 use ash::{Entry, Instance};
 use log::info;
 use tobj::LoadError::NormalParseError;
 use winit::window::Window;
+use raw_window_handle::{HasRawWindowHandle, RawWindowHandle};
+use crate::error::{CottonError, Result};
 use crate::window_handlers::WindowHandlers;
 
 pub struct Surfaces {
@@ -19,28 +22,27 @@ impl Surfaces {
         instance: &Instance,
         entry: &Entry,
         window: &Window,
-    ) -> Self {
+    ) -> Result<Self> {
         let surface = Surface::new(entry, instance);
-        let surface_khr = unsafe { ash_window::create_surface(entry, instance, window, None).unwrap() };
+        let surface_khr = unsafe { ash_window::create_surface(entry, instance, window, None)? };
 
         info!("surface: {:?}", surface_khr);
 
-        Self {
+        Ok(Self {
             surface,
             surface_khr,
-        }
+        })
     }
 
     pub fn check_swapchain_support(
         instance: &Instance,
         physical_device: PhysicalDevice,
-    ) -> bool {
+    ) -> Result<bool> {
         let swapchain_name = [Swapchain::name()];
 
         let extensions = unsafe {
             instance
-                .enumerate_device_extension_properties(physical_device)
-                .unwrap()
+                .enumerate_device_extension_properties(physical_device)?
         };
 
         for required in swapchain_name.iter() {
@@ -50,14 +52,134 @@ impl Surfaces {
             });
 
             if !found {
-                return false;
+                return Ok(false);
             }
         }
 
-        true
+        Ok(true)
+    }
+
+    /// headlessの場合は空になる
+    pub fn require_surface_extension_names(window: Option<&Window>) -> Result<Vec<&'static CStr>> {
+        let platform = match window {
+            Some(window) => Some(SurfacePlatform::from_raw_window_handle(&window.raw_window_handle())?),
+            None => None,
+        };
+
+        Ok(Self::surface_extension_names(platform))
+    }
+
+    pub fn surface_extension_names(platform: Option<SurfacePlatform>) -> Vec<&'static CStr> {
+        let platform = match platform {
+            Some(platform) => platform,
+            None => return vec![],
+        };
+
+        let platform_extension_name = match platform {
+            SurfacePlatform::Win32 => Win32Surface::name(),
+            SurfacePlatform::Xlib => XlibSurface::name(),
+            SurfacePlatform::Xcb => XcbSurface::name(),
+            SurfacePlatform::Wayland => WaylandSurface::name(),
+            SurfacePlatform::Android => AndroidSurface::name(),
+            SurfacePlatform::Metal => MetalSurface::name(),
+        };
+
+        vec![Surface::name(), platform_extension_name]
     }
 
-    pub fn require_surface_extension_names() -> Vec<*const i8> {
-        vec![Surface::name().as_ptr(), Win32Surface::name().as_ptr()]
+    /// Swapchainはsurfaceがある時のみ必要
+    pub fn swapchain_extension_names(has_surface: bool) -> Vec<&'static CStr> {
+        if has_surface {
+            vec![Swapchain::name()]
+        } else {
+            vec![]
+        }
+    }
+}
+
+/// ウィンドウシステムごとにsurfaceの拡張機能が異なる
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SurfacePlatform {
+    Win32,
+    Xlib,
+    Xcb,
+    Wayland,
+    Android,
+    Metal,
+}
+
+impl SurfacePlatform {
+    pub fn from_raw_window_handle(handle: &RawWindowHandle) -> Result<Self> {
+        match handle {
+            RawWindowHandle::Win32(_) => Ok(Self::Win32),
+            RawWindowHandle::Xlib(_) => Ok(Self::Xlib),
+            RawWindowHandle::Xcb(_) => Ok(Self::Xcb),
+            RawWindowHandle::Wayland(_) => Ok(Self::Wayland),
+            RawWindowHandle::AndroidNdk(_) => Ok(Self::Android),
+            RawWindowHandle::AppKit(_) | RawWindowHandle::UiKit(_) => Ok(Self::Metal),
+            handle => Err(CottonError::UnsupportedWindowHandle(format!("{:?}", handle))),
+        }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use raw_window_handle::{WaylandHandle, WebHandle, Win32Handle, XcbHandle, XlibHandle};
+    use super::*;
+
+    #[test]
+    fn headless_has_no_surface_extensions() {
+        assert!(Surfaces::surface_extension_names(None).is_empty());
+        assert!(Surfaces::require_surface_extension_names(None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn each_platform_adds_its_own_surface_extension() {
+        let cases = [
+            (SurfacePlatform::Win32, Win32Surface::name()),
+            (SurfacePlatform::Xlib, XlibSurface::name()),
+            (SurfacePlatform::Xcb, XcbSurface::name()),
+            (SurfacePlatform::Wayland, WaylandSurface::name()),
+            (SurfacePlatform::Android, AndroidSurface::name()),
+            (SurfacePlatform::Metal, MetalSurface::name()),
+        ];
+
+        for (platform, platform_extension_name) in cases {
+            assert_eq!(
+                Surfaces::surface_extension_names(Some(platform)),
+                vec![Surface::name(), platform_extension_name],
+                "{:?}",
+                platform,
+            );
+        }
+    }
+
+    #[test]
+    fn swapchain_is_only_required_with_surface() {
+        assert_eq!(Surfaces::swapchain_extension_names(true), vec![Swapchain::name()]);
+        assert!(Surfaces::swapchain_extension_names(false).is_empty());
+    }
+
+    #[test]
+    fn platform_from_window_handle() {
+        let cases = [
+            (RawWindowHandle::Win32(Win32Handle::empty()), SurfacePlatform::Win32),
+            (RawWindowHandle::Xlib(XlibHandle::empty()), SurfacePlatform::Xlib),
+            (RawWindowHandle::Xcb(XcbHandle::empty()), SurfacePlatform::Xcb),
+            (RawWindowHandle::Wayland(WaylandHandle::empty()), SurfacePlatform::Wayland),
+        ];
+
+        for (handle, platform) in cases {
+            assert_eq!(SurfacePlatform::from_raw_window_handle(&handle).unwrap(), platform);
+        }
+    }
+
+    #[test]
+    fn unsupported_window_handle_is_an_error() {
+        let handle = RawWindowHandle::Web(WebHandle::empty());
+
+        assert!(matches!(
+            SurfacePlatform::from_raw_window_handle(&handle),
+            Err(CottonError::UnsupportedWindowHandle(_)),
+        ));
+    }
+}
@@ -0,0 +1,600 @@
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::fmt::Formatter;
+use ash::{Entry, Instance, vk};
+use ash::extensions::khr::{AccelerationStructure, RayTracingPipeline};
+use ash::vk::{ExtensionProperties, PhysicalDevice, PhysicalDeviceAccelerationStructureFeaturesKHR, PhysicalDeviceAccelerationStructurePropertiesKHR, PhysicalDeviceBufferDeviceAddressFeatures, PhysicalDeviceDescriptorIndexingFeaturesEXT, PhysicalDeviceFeatures2, PhysicalDeviceIDProperties, PhysicalDeviceImagelessFramebufferFeaturesKHR, PhysicalDeviceProperties2, PhysicalDeviceRayTracingPipelineFeaturesKHR, PhysicalDeviceRayTracingPipelinePropertiesKHR, PhysicalDeviceScalarBlockLayoutFeaturesEXT, PhysicalDeviceShaderFloat16Int8Features, PhysicalDeviceVulkanMemoryModelFeaturesKHR};
+use log::debug;
+use serde::Serialize;
+use crate::error::Result;
+use crate::renderer::backends::physical_device_selector::format_uuid;
+
+/// インスタンスと物理デバイスで使用できる機能の一覧
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct CapabilityReport {
+    pub instance_layers: Vec<LayerReport>,
+    pub instance_extensions: Vec<ExtensionReport>,
+    pub physical_devices: Vec<PhysicalDeviceReport>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LayerReport {
+    pub name: String,
+    pub spec_version: String,
+    pub implementation_version: u32,
+    pub description: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ExtensionReport {
+    pub name: String,
+    pub spec_version: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PhysicalDeviceReport {
+    pub index: usize,
+    pub name: String,
+    pub device_type: String,
+    pub vendor_id: u32,
+    pub device_id: u32,
+    pub api_version: String,
+    pub driver_version: u32,
+    pub uuid: String,
+    pub extensions: Vec<ExtensionReport>,
+    /// 順番を固定するためにBTreeMapを使う
+    pub features: BTreeMap<String, bool>,
+    pub ray_tracing_pipeline_properties: Option<RayTracingPipelinePropertiesReport>,
+    pub acceleration_structure_properties: Option<AccelerationStructurePropertiesReport>,
+    pub memory_heaps: Vec<MemoryHeapReport>,
+    pub queue_families: Vec<QueueFamilyReport>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RayTracingPipelinePropertiesReport {
+    pub shader_group_handle_size: u32,
+    pub max_ray_recursion_depth: u32,
+    pub max_shader_group_stride: u32,
+    pub shader_group_base_alignment: u32,
+    pub shader_group_handle_capture_replay_size: u32,
+    pub max_ray_dispatch_invocation_count: u32,
+    pub shader_group_handle_alignment: u32,
+    pub max_ray_hit_attribute_size: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AccelerationStructurePropertiesReport {
+    pub max_geometry_count: u64,
+    pub max_instance_count: u64,
+    pub max_primitive_count: u64,
+    pub max_per_stage_descriptor_acceleration_structures: u32,
+    pub max_per_stage_descriptor_update_after_bind_acceleration_structures: u32,
+    pub max_descriptor_set_acceleration_structures: u32,
+    pub max_descriptor_set_update_after_bind_acceleration_structures: u32,
+    pub min_acceleration_structure_scratch_offset_alignment: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MemoryHeapReport {
+    pub size: u64,
+    pub flags: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct QueueFamilyReport {
+    pub index: u32,
+    pub queue_count: u32,
+    pub flags: Vec<String>,
+    pub timestamp_valid_bits: u32,
+}
+
+impl CapabilityReport {
+    /// 拡張機能なしのインスタンスを一時的に作成して調べる
+    pub fn query() -> Result<Self> {
+        debug!("query capability report");
+
+        let entry = unsafe { Entry::load()? };
+
+        let app_name = CString::new(crate::constants::APPLICATION_NAME)?;
+        let app_info = vk::ApplicationInfo::builder()
+            .application_name(app_name.as_c_str())
+            .api_version(vk::make_api_version(0, 1, 3, 0))
+            .build();
+
+        let instance_create_info = vk::InstanceCreateInfo::builder()
+            .application_info(&app_info)
+            .build();
+
+        let instance = unsafe { entry.create_instance(&instance_create_info, None)? };
+
+        let report = Self::from_instance(&entry, &instance);
+
+        unsafe { instance.destroy_instance(None) };
+
+        report
+    }
+
+    pub fn from_instance(entry: &Entry, instance: &Instance) -> Result<Self> {
+        let instance_layers = entry
+            .enumerate_instance_layer_properties()?
+            .iter()
+            .map(|layer| LayerReport {
+                name: c_char_array_to_string(&layer.layer_name),
+                spec_version: version_to_string(layer.spec_version),
+                implementation_version: layer.implementation_version,
+                description: c_char_array_to_string(&layer.description),
+            })
+            .collect();
+
+        let instance_extensions = extension_reports(&entry.enumerate_instance_extension_properties(None)?);
+
+        let physical_devices = unsafe { instance.enumerate_physical_devices()? }
+            .into_iter()
+            .enumerate()
+            .map(|(index, physical_device)| PhysicalDeviceReport::query(instance, physical_device, index))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            instance_layers,
+            instance_extensions,
+            physical_devices,
+        })
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+impl PhysicalDeviceReport {
+    pub fn query(
+        instance: &Instance,
+        physical_device: PhysicalDevice,
+        index: usize,
+    ) -> Result<Self> {
+        let extension_properties = unsafe { instance.enumerate_device_extension_properties(physical_device)? };
+        let extensions = extension_reports(&extension_properties);
+
+        //拡張機能がないデバイスに対して拡張の構造体をチェインしてはいけない
+        let supports_ray_tracing = [RayTracingPipeline::name(), AccelerationStructure::name()]
+            .iter()
+            .all(|name| extensions.iter().any(|ext| ext.name.as_str() == name.to_string_lossy()));
+
+        let mut id_properties = PhysicalDeviceIDProperties::default();
+        let mut ray_tracing_pipeline_properties = PhysicalDeviceRayTracingPipelinePropertiesKHR::default();
+        let mut acceleration_structure_properties = PhysicalDeviceAccelerationStructurePropertiesKHR::default();
+
+        let mut properties2 = PhysicalDeviceProperties2::builder()
+            .push_next(&mut id_properties);
+
+        if supports_ray_tracing {
+            properties2 = properties2
+                .push_next(&mut ray_tracing_pipeline_properties)
+                .push_next(&mut acceleration_structure_properties);
+        }
+
+        let mut properties2 = properties2.build();
+
+        unsafe { instance.get_physical_device_properties2(physical_device, &mut properties2) };
+
+        let properties = properties2.properties;
+
+        let mut scalar_block = PhysicalDeviceScalarBlockLayoutFeaturesEXT::default();
+        let mut descriptor_indexing = PhysicalDeviceDescriptorIndexingFeaturesEXT::default();
+        let mut imageless_framebuffer = PhysicalDeviceImagelessFramebufferFeaturesKHR::default();
+        let mut shader_float16_int8 = PhysicalDeviceShaderFloat16Int8Features::default();
+        let mut vulkan_memory_model = PhysicalDeviceVulkanMemoryModelFeaturesKHR::default();
+        let mut buffer_device_address = PhysicalDeviceBufferDeviceAddressFeatures::default();
+        let mut acceleration_structure_features = PhysicalDeviceAccelerationStructureFeaturesKHR::default();
+        let mut ray_tracing_pipeline_features = PhysicalDeviceRayTracingPipelineFeaturesKHR::default();
+
+        let mut features2 = PhysicalDeviceFeatures2::builder()
+            .push_next(&mut scalar_block)
+            .push_next(&mut descriptor_indexing)
+            .push_next(&mut imageless_framebuffer)
+            .push_next(&mut shader_float16_int8)
+            .push_next(&mut vulkan_memory_model)
+            .push_next(&mut buffer_device_address);
+
+        if supports_ray_tracing {
+            features2 = features2
+                .push_next(&mut acceleration_structure_features)
+                .push_next(&mut ray_tracing_pipeline_features);
+        }
+
+        let mut features2 = features2.build();
+
+        unsafe { instance.get_physical_device_features2(physical_device, &mut features2) };
+
+        let features = [
+            ("shaderInt64", features2.features.shader_int64),
+            ("shaderFloat64", features2.features.shader_float64),
+            ("scalarBlockLayout", scalar_block.scalar_block_layout),
+            ("runtimeDescriptorArray", descriptor_indexing.runtime_descriptor_array),
+            ("descriptorBindingVariableDescriptorCount", descriptor_indexing.descriptor_binding_variable_descriptor_count),
+            ("descriptorBindingPartiallyBound", descriptor_indexing.descriptor_binding_partially_bound),
+            ("shaderSampledImageArrayNonUniformIndexing", descriptor_indexing.shader_sampled_image_array_non_uniform_indexing),
+            ("shaderStorageBufferArrayNonUniformIndexing", descriptor_indexing.shader_storage_buffer_array_non_uniform_indexing),
+            ("imagelessFramebuffer", imageless_framebuffer.imageless_framebuffer),
+            ("shaderFloat16", shader_float16_int8.shader_float16),
+            ("shaderInt8", shader_float16_int8.shader_int8),
+            ("vulkanMemoryModel", vulkan_memory_model.vulkan_memory_model),
+            ("bufferDeviceAddress", buffer_device_address.buffer_device_address),
+            ("accelerationStructure", acceleration_structure_features.acceleration_structure),
+            ("accelerationStructureHostCommands", acceleration_structure_features.acceleration_structure_host_commands),
+            ("rayTracingPipeline", ray_tracing_pipeline_features.ray_tracing_pipeline),
+            ("rayTracingPipelineTraceRaysIndirect", ray_tracing_pipeline_features.ray_tracing_pipeline_trace_rays_indirect),
+            ("rayTraversalPrimitiveCulling", ray_tracing_pipeline_features.ray_traversal_primitive_culling),
+        ]
+            .iter()
+            .map(|(name, value)| (name.to_string(), *value != 0))
+            .collect();
+
+        let (ray_tracing_pipeline_properties, acceleration_structure_properties) = if supports_ray_tracing {
+            (
+                Some(RayTracingPipelinePropertiesReport {
+                    shader_group_handle_size: ray_tracing_pipeline_properties.shader_group_handle_size,
+                    max_ray_recursion_depth: ray_tracing_pipeline_properties.max_ray_recursion_depth,
+                    max_shader_group_stride: ray_tracing_pipeline_properties.max_shader_group_stride,
+                    shader_group_base_alignment: ray_tracing_pipeline_properties.shader_group_base_alignment,
+                    shader_group_handle_capture_replay_size: ray_tracing_pipeline_properties.shader_group_handle_capture_replay_size,
+                    max_ray_dispatch_invocation_count: ray_tracing_pipeline_properties.max_ray_dispatch_invocation_count,
+                    shader_group_handle_alignment: ray_tracing_pipeline_properties.shader_group_handle_alignment,
+                    max_ray_hit_attribute_size: ray_tracing_pipeline_properties.max_ray_hit_attribute_size,
+                }),
+                Some(AccelerationStructurePropertiesReport {
+                    max_geometry_count: acceleration_structure_properties.max_geometry_count,
+                    max_instance_count: acceleration_structure_properties.max_instance_count,
+                    max_primitive_count: acceleration_structure_properties.max_primitive_count,
+                    max_per_stage_descriptor_acceleration_structures: acceleration_structure_properties.max_per_stage_descriptor_acceleration_structures,
+                    max_per_stage_descriptor_update_after_bind_acceleration_structures: acceleration_structure_properties.max_per_stage_descriptor_update_after_bind_acceleration_structures,
+                    max_descriptor_set_acceleration_structures: acceleration_structure_properties.max_descriptor_set_acceleration_structures,
+                    max_descriptor_set_update_after_bind_acceleration_structures: acceleration_structure_properties.max_descriptor_set_update_after_bind_acceleration_structures,
+                    min_acceleration_structure_scratch_offset_alignment: acceleration_structure_properties.min_acceleration_structure_scratch_offset_alignment,
+                }),
+            )
+        } else {
+            (None, None)
+        };
+
+        let memory_properties = unsafe { instance.get_physical_device_memory_properties(physical_device) };
+
+        let memory_heaps = memory_properties.memory_heaps[..memory_properties.memory_heap_count as usize]
+            .iter()
+            .map(|heap| MemoryHeapReport {
+                size: heap.size,
+                flags: flags_to_strings(heap.flags),
+            })
+            .collect();
+
+        let queue_families = unsafe { instance.get_physical_device_queue_family_properties(physical_device) }
+            .iter()
+            .enumerate()
+            .map(|(index, queue_family)| QueueFamilyReport {
+                index: index as u32,
+                queue_count: queue_family.queue_count,
+                flags: flags_to_strings(queue_family.queue_flags),
+                timestamp_valid_bits: queue_family.timestamp_valid_bits,
+            })
+            .collect();
+
+        Ok(Self {
+            index,
+            name: c_char_array_to_string(&properties.device_name),
+            device_type: format!("{:?}", properties.device_type),
+            vendor_id: properties.vendor_id,
+            device_id: properties.device_id,
+            api_version: version_to_string(properties.api_version),
+            driver_version: properties.driver_version,
+            uuid: format_uuid(&id_properties.device_uuid),
+            extensions,
+            features,
+            ray_tracing_pipeline_properties,
+            acceleration_structure_properties,
+            memory_heaps,
+            queue_families,
+        })
+    }
+}
+
+impl fmt::Display for CapabilityReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Instance layers:")?;
+        for layer in self.instance_layers.iter() {
+            writeln!(f, "  {} ({}): {}", layer.name, layer.spec_version, layer.description)?;
+        }
+
+        writeln!(f, "Instance extensions:")?;
+        for extension in self.instance_extensions.iter() {
+            writeln!(f, "  {} (rev {})", extension.name, extension.spec_version)?;
+        }
+
+        for device in self.physical_devices.iter() {
+            writeln!(f)?;
+            write!(f, "{}", device)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for PhysicalDeviceReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Physical device [{}]: {}", self.index, self.name)?;
+        writeln!(f, "  type: {}", self.device_type)?;
+        writeln!(f, "  vendor id: {:#06x}, device id: {:#06x}", self.vendor_id, self.device_id)?;
+        writeln!(f, "  api version: {}, driver version: {}", self.api_version, self.driver_version)?;
+        writeln!(f, "  uuid: {}", self.uuid)?;
+
+        writeln!(f, "  features:")?;
+        for (name, supported) in self.features.iter() {
+            writeln!(f, "    {}: {}", name, if *supported { "yes" } else { "no" })?;
+        }
+
+        match &self.ray_tracing_pipeline_properties {
+            Some(properties) => {
+                writeln!(f, "  ray tracing pipeline properties:")?;
+                writeln!(f, "    shader group handle size: {}", properties.shader_group_handle_size)?;
+                writeln!(f, "    shader group handle alignment: {}", properties.shader_group_handle_alignment)?;
+                writeln!(f, "    shader group base alignment: {}", properties.shader_group_base_alignment)?;
+                writeln!(f, "    max shader group stride: {}", properties.max_shader_group_stride)?;
+                writeln!(f, "    max ray recursion depth: {}", properties.max_ray_recursion_depth)?;
+                writeln!(f, "    max ray dispatch invocation count: {}", properties.max_ray_dispatch_invocation_count)?;
+                writeln!(f, "    max ray hit attribute size: {}", properties.max_ray_hit_attribute_size)?;
+            }
+            None => writeln!(f, "  ray tracing pipeline properties: unsupported")?,
+        }
+
+        match &self.acceleration_structure_properties {
+            Some(properties) => {
+                writeln!(f, "  acceleration structure properties:")?;
+                writeln!(f, "    max geometry count: {}", properties.max_geometry_count)?;
+                writeln!(f, "    max instance count: {}", properties.max_instance_count)?;
+                writeln!(f, "    max primitive count: {}", properties.max_primitive_count)?;
+                writeln!(f, "    min scratch offset alignment: {}", properties.min_acceleration_structure_scratch_offset_alignment)?;
+            }
+            None => writeln!(f, "  acceleration structure properties: unsupported")?,
+        }
+
+        writeln!(f, "  memory heaps:")?;
+        for (i, heap) in self.memory_heaps.iter().enumerate() {
+            writeln!(f, "    [{}] {} MiB {}", i, heap.size / (1024 * 1024), heap.flags.join(" | "))?;
+        }
+
+        writeln!(f, "  queue families:")?;
+        for queue_family in self.queue_families.iter() {
+            writeln!(f, "    [{}] count {} {}", queue_family.index, queue_family.queue_count, queue_family.flags.join(" | "))?;
+        }
+
+        writeln!(f, "  extensions:")?;
+        for extension in self.extensions.iter() {
+            writeln!(f, "    {} (rev {})", extension.name, extension.spec_version)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn extension_reports(extension_properties: &[ExtensionProperties]) -> Vec<ExtensionReport> {
+    let mut extensions: Vec<ExtensionReport> = extension_properties
+        .iter()
+        .map(|ext| ExtensionReport {
+            name: c_char_array_to_string(&ext.extension_name),
+            spec_version: ext.spec_version,
+        })
+        .collect();
+
+    extensions.sort_by(|a, b| a.name.cmp(&b.name));
+
+    extensions
+}
+
+fn c_char_array_to_string(chars: &[std::os::raw::c_char]) -> String {
+    unsafe { CStr::from_ptr(chars.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn version_to_string(version: u32) -> String {
+    format!(
+        "{}.{}.{}",
+        vk::api_version_major(version),
+        vk::api_version_minor(version),
+        vk::api_version_patch(version),
+    )
+}
+
+//ashのDebug実装は"A | B"の形式で出力される
+fn flags_to_strings<T: fmt::Debug>(flags: T) -> Vec<String> {
+    let flags = format!("{:?}", flags);
+
+    if flags.is_empty() {
+        return vec![];
+    }
+
+    flags.split(" | ").map(|flag| flag.to_owned()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fabricated_report() -> CapabilityReport {
+        CapabilityReport {
+            instance_layers: vec![LayerReport {
+                name: "VK_LAYER_KHRONOS_validation".to_owned(),
+                spec_version: "1.3.211".to_owned(),
+                implementation_version: 1,
+                description: "Khronos Validation Layer".to_owned(),
+            }],
+            instance_extensions: vec![ExtensionReport {
+                name: "VK_KHR_surface".to_owned(),
+                spec_version: 25,
+            }],
+            physical_devices: vec![PhysicalDeviceReport {
+                index: 0,
+                name: "Fake GPU".to_owned(),
+                device_type: "DISCRETE_GPU".to_owned(),
+                vendor_id: 0x10de,
+                device_id: 0x2206,
+                api_version: "1.3.205".to_owned(),
+                driver_version: 42,
+                uuid: format_uuid(&[0xab; vk::UUID_SIZE]),
+                extensions: vec![ExtensionReport {
+                    name: "VK_KHR_ray_tracing_pipeline".to_owned(),
+                    spec_version: 1,
+                }],
+                features: BTreeMap::from([
+                    ("bufferDeviceAddress".to_owned(), true),
+                    ("rayQuery".to_owned(), false),
+                ]),
+                ray_tracing_pipeline_properties: Some(RayTracingPipelinePropertiesReport {
+                    shader_group_handle_size: 32,
+                    max_ray_recursion_depth: 31,
+                    max_shader_group_stride: 4096,
+                    shader_group_base_alignment: 64,
+                    shader_group_handle_capture_replay_size: 32,
+                    max_ray_dispatch_invocation_count: 1 << 30,
+                    shader_group_handle_alignment: 32,
+                    max_ray_hit_attribute_size: 32,
+                }),
+                acceleration_structure_properties: None,
+                memory_heaps: vec![MemoryHeapReport {
+                    size: 8 << 30,
+                    flags: vec!["DEVICE_LOCAL".to_owned()],
+                }],
+                queue_families: vec![QueueFamilyReport {
+                    index: 0,
+                    queue_count: 16,
+                    flags: vec!["GRAPHICS".to_owned(), "COMPUTE".to_owned()],
+                    timestamp_valid_bits: 64,
+                }],
+            }],
+        }
+    }
+
+    #[test]
+    fn text_snapshot() {
+        let expected = "\
+Instance layers:
+  VK_LAYER_KHRONOS_validation (1.3.211): Khronos Validation Layer
+Instance extensions:
+  VK_KHR_surface (rev 25)
+
+Physical device [0]: Fake GPU
+  type: DISCRETE_GPU
+  vendor id: 0x10de, device id: 0x2206
+  api version: 1.3.205, driver version: 42
+  uuid: abababababababababababababababab
+  features:
+    bufferDeviceAddress: yes
+    rayQuery: no
+  ray tracing pipeline properties:
+    shader group handle size: 32
+    shader group handle alignment: 32
+    shader group base alignment: 64
+    max shader group stride: 4096
+    max ray recursion depth: 31
+    max ray dispatch invocation count: 1073741824
+    max ray hit attribute size: 32
+  acceleration structure properties: unsupported
+  memory heaps:
+    [0] 8192 MiB DEVICE_LOCAL
+  queue families:
+    [0] count 16 GRAPHICS | COMPUTE
+  extensions:
+    VK_KHR_ray_tracing_pipeline (rev 1)
+";
+
+        assert_eq!(fabricated_report().to_string(), expected);
+    }
+
+    #[test]
+    fn json_snapshot() {
+        let expected = r#"{
+  "instance_layers": [
+    {
+      "name": "VK_LAYER_KHRONOS_validation",
+      "spec_version": "1.3.211",
+      "implementation_version": 1,
+      "description": "Khronos Validation Layer"
+    }
+  ],
+  "instance_extensions": [
+    {
+      "name": "VK_KHR_surface",
+      "spec_version": 25
+    }
+  ],
+  "physical_devices": [
+    {
+      "index": 0,
+      "name": "Fake GPU",
+      "device_type": "DISCRETE_GPU",
+      "vendor_id": 4318,
+      "device_id": 8710,
+      "api_version": "1.3.205",
+      "driver_version": 42,
+      "uuid": "abababababababababababababababab",
+      "extensions": [
+        {
+          "name": "VK_KHR_ray_tracing_pipeline",
+          "spec_version": 1
+        }
+      ],
+      "features": {
+        "bufferDeviceAddress": true,
+        "rayQuery": false
+      },
+      "ray_tracing_pipeline_properties": {
+        "shader_group_handle_size": 32,
+        "max_ray_recursion_depth": 31,
+        "max_shader_group_stride": 4096,
+        "shader_group_base_alignment": 64,
+        "shader_group_handle_capture_replay_size": 32,
+        "max_ray_dispatch_invocation_count": 1073741824,
+        "shader_group_handle_alignment": 32,
+        "max_ray_hit_attribute_size": 32
+      },
+      "acceleration_structure_properties": null,
+      "memory_heaps": [
+        {
+          "size": 8589934592,
+          "flags": [
+            "DEVICE_LOCAL"
+          ]
+        }
+      ],
+      "queue_families": [
+        {
+          "index": 0,
+          "queue_count": 16,
+          "flags": [
+            "GRAPHICS",
+            "COMPUTE"
+          ],
+          "timestamp_valid_bits": 64
+        }
+      ]
+    }
+  ]
+}"#;
+
+        assert_eq!(fabricated_report().to_json().unwrap(), expected);
+    }
+
+    #[test]
+    fn flags_are_split() {
+        assert_eq!(
+            flags_to_strings(vk::QueueFlags::GRAPHICS | vk::QueueFlags::TRANSFER),
+            vec!["GRAPHICS".to_owned(), "TRANSFER".to_owned()],
+        );
+        assert!(flags_to_strings(vk::MemoryHeapFlags::empty()).is_empty());
+    }
+
+    #[test]
+    fn version_is_formatted() {
+        assert_eq!(version_to_string(vk::make_api_version(0, 1, 3, 211)), "1.3.211");
+    }
+}
@@ -0,0 +1,577 @@
+use std::collections::BTreeMap;
+use ash::Device;
+use ash::vk::{AccelerationStructureKHR, Buffer, DescriptorBindingFlags, DescriptorBufferInfo, DescriptorImageInfo, DescriptorPool, DescriptorPoolCreateInfo, DescriptorPoolSize, DescriptorSet, DescriptorSetAllocateInfo, DescriptorSetLayout, DescriptorSetLayoutBinding, DescriptorSetLayoutBindingFlagsCreateInfo, DescriptorSetLayoutCreateInfo, DescriptorSetVariableDescriptorCountAllocateInfo, DescriptorType, ImageLayout, ImageView, Sampler, ShaderStageFlags, WHOLE_SIZE, WriteDescriptorSet, WriteDescriptorSetAccelerationStructureKHR};
+use log::debug;
+use crate::error::{CottonError, Result};
+
+/// bindingに結びつけるリソース
+/// descriptor typeはリソースの種類から決まるのでlayoutとwriteで食い違うことがない
+#[derive(Clone, Debug)]
+pub enum DescriptorResource {
+    AccelerationStructures(Vec<AccelerationStructureKHR>),
+    StorageImages(Vec<DescriptorImageInfo>),
+    StorageBuffers(Vec<DescriptorBufferInfo>),
+    UniformBuffers(Vec<DescriptorBufferInfo>),
+    CombinedImageSamplers(Vec<DescriptorImageInfo>),
+}
+
+impl DescriptorResource {
+    pub fn acceleration_structure(acceleration_structure: AccelerationStructureKHR) -> Self {
+        Self::AccelerationStructures(vec![acceleration_structure])
+    }
+
+    pub fn storage_image(image_view: ImageView) -> Self {
+        Self::StorageImages(vec![
+            DescriptorImageInfo::builder()
+                .image_layout(ImageLayout::GENERAL)
+                .image_view(image_view)
+                .build()
+        ])
+    }
+
+    pub fn storage_buffer(buffer: Buffer) -> Self {
+        Self::StorageBuffers(vec![whole_buffer_info(buffer)])
+    }
+
+    pub fn uniform_buffer(buffer: Buffer) -> Self {
+        Self::UniformBuffers(vec![whole_buffer_info(buffer)])
+    }
+
+    pub fn combined_image_samplers(image_views: &[ImageView], sampler: Sampler) -> Self {
+        Self::CombinedImageSamplers(
+            image_views
+                .iter()
+                .map(|image_view| DescriptorImageInfo::builder()
+                    .image_layout(ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                    .image_view(*image_view)
+                    .sampler(sampler)
+                    .build())
+                .collect()
+        )
+    }
+
+    pub fn descriptor_type(&self) -> DescriptorType {
+        match self {
+            Self::AccelerationStructures(_) => DescriptorType::ACCELERATION_STRUCTURE_KHR,
+            Self::StorageImages(_) => DescriptorType::STORAGE_IMAGE,
+            Self::StorageBuffers(_) => DescriptorType::STORAGE_BUFFER,
+            Self::UniformBuffers(_) => DescriptorType::UNIFORM_BUFFER,
+            Self::CombinedImageSamplers(_) => DescriptorType::COMBINED_IMAGE_SAMPLER,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::AccelerationStructures(resources) => resources.len(),
+            Self::StorageImages(resources) => resources.len(),
+            Self::StorageBuffers(resources) => resources.len(),
+            Self::UniformBuffers(resources) => resources.len(),
+            Self::CombinedImageSamplers(resources) => resources.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn whole_buffer_info(buffer: Buffer) -> DescriptorBufferInfo {
+    DescriptorBufferInfo::builder()
+        .buffer(buffer)
+        .range(WHOLE_SIZE)
+        .build()
+}
+
+#[derive(Clone, Debug)]
+pub struct DescriptorBinding {
+    pub binding: u32,
+    pub stages: ShaderStageFlags,
+    pub resource: DescriptorResource,
+    /// Someの場合は可変長の配列、layoutはこの数で作りsetはリソースの数だけ確保する
+    pub max_count: Option<u32>,
+}
+
+impl DescriptorBinding {
+    pub fn descriptor_type(&self) -> DescriptorType {
+        self.resource.descriptor_type()
+    }
+
+    pub fn descriptor_count(&self) -> u32 {
+        self.resource.len() as u32
+    }
+
+    pub fn is_variable_count(&self) -> bool {
+        self.max_count.is_some()
+    }
+
+    /// 可変長の場合は全て書き込まなくてもよい
+    pub fn binding_flags(&self) -> DescriptorBindingFlags {
+        if self.is_variable_count() {
+            DescriptorBindingFlags::VARIABLE_DESCRIPTOR_COUNT | DescriptorBindingFlags::PARTIALLY_BOUND
+        } else {
+            DescriptorBindingFlags::empty()
+        }
+    }
+}
+
+/// 各bindingを一度だけ宣言し、そこからlayout, pool size, writeを作る
+#[derive(Clone, Debug, Default)]
+pub struct DescriptorSetBuilder {
+    bindings: Vec<DescriptorBinding>,
+}
+
+impl DescriptorSetBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn binding(mut self, binding: u32, stages: ShaderStageFlags, resource: DescriptorResource) -> Self {
+        self.bindings.push(DescriptorBinding {
+            binding,
+            stages,
+            resource,
+            max_count: None,
+        });
+        self
+    }
+
+    /// 要素数がシーンによって変わる配列、bindingは他のどれよりも大きくする
+    pub fn variable_binding(mut self, binding: u32, stages: ShaderStageFlags, resource: DescriptorResource, max_count: u32) -> Self {
+        self.bindings.push(DescriptorBinding {
+            binding,
+            stages,
+            resource,
+            max_count: Some(max_count),
+        });
+        self
+    }
+
+    /// bindingの重複やリソースが空のものがないかを確認する
+    pub fn build(self) -> Result<DescriptorSetDeclaration> {
+        let mut bindings = self.bindings;
+        bindings.sort_by_key(|binding| binding.binding);
+
+        for pair in bindings.windows(2) {
+            if pair[0].binding == pair[1].binding {
+                return Err(CottonError::InvalidDescriptorSet(format!(
+                    "binding {} is declared more than once",
+                    pair[0].binding,
+                )));
+            }
+        }
+
+        for binding in bindings.iter() {
+            if binding.resource.is_empty() {
+                return Err(CottonError::InvalidDescriptorSet(format!(
+                    "binding {} has no resource",
+                    binding.binding,
+                )));
+            }
+
+            if binding.stages.is_empty() {
+                return Err(CottonError::InvalidDescriptorSet(format!(
+                    "binding {} is not used from any stage",
+                    binding.binding,
+                )));
+            }
+
+            if let Some(max_count) = binding.max_count {
+                if binding.descriptor_count() > max_count {
+                    return Err(CottonError::InvalidDescriptorSet(format!(
+                        "binding {} has {} resources, but at most {} are allowed",
+                        binding.binding,
+                        binding.descriptor_count(),
+                        max_count,
+                    )));
+                }
+            }
+        }
+
+        //可変長にできるのはbinding番号が一番大きいものだけ
+        if let Some(position) = bindings.iter().position(DescriptorBinding::is_variable_count) {
+            if position != bindings.len() - 1 {
+                return Err(CottonError::InvalidDescriptorSet(format!(
+                    "variable count binding {} must be the last binding",
+                    bindings[position].binding,
+                )));
+            }
+        }
+
+        Ok(DescriptorSetDeclaration { bindings })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DescriptorSetDeclaration {
+    bindings: Vec<DescriptorBinding>,
+}
+
+impl DescriptorSetDeclaration {
+    pub fn bindings(&self) -> &[DescriptorBinding] {
+        &self.bindings
+    }
+
+    /// 同じ種類と数のリソースに差し替える、反映するにはupdateを呼ぶ
+    pub fn set_resource(&mut self, binding: u32, resource: DescriptorResource) -> Result<()> {
+        let declared = self.bindings
+            .iter_mut()
+            .find(|declared| declared.binding == binding)
+            .ok_or_else(|| CottonError::InvalidDescriptorSet(format!("binding {} is not declared", binding)))?;
+
+        if declared.descriptor_type() != resource.descriptor_type() || declared.resource.len() != resource.len() {
+            return Err(CottonError::InvalidDescriptorSet(format!(
+                "binding {} is declared as {} {:?}, but replaced with {} {:?}",
+                binding,
+                declared.resource.len(),
+                declared.descriptor_type(),
+                resource.len(),
+                resource.descriptor_type(),
+            )));
+        }
+
+        declared.resource = resource;
+
+        Ok(())
+    }
+
+    pub fn layout_bindings(&self) -> Vec<DescriptorSetLayoutBinding> {
+        self.bindings
+            .iter()
+            .map(|binding| DescriptorSetLayoutBinding::builder()
+                .binding(binding.binding)
+                .descriptor_type(binding.descriptor_type())
+                .descriptor_count(binding.max_count.unwrap_or_else(|| binding.descriptor_count()))
+                .stage_flags(binding.stages)
+                .build())
+            .collect()
+    }
+
+    /// 可変長のbindingの実際の数、ない場合はNone
+    pub fn variable_descriptor_count(&self) -> Option<u32> {
+        self.bindings
+            .iter()
+            .find(|binding| binding.is_variable_count())
+            .map(DescriptorBinding::descriptor_count)
+    }
+
+    /// 同じdescriptor typeはまとめる
+    pub fn pool_sizes(&self) -> Vec<DescriptorPoolSize> {
+        let mut counts: BTreeMap<i32, u32> = BTreeMap::new();
+
+        for binding in self.bindings.iter() {
+            *counts.entry(binding.descriptor_type().as_raw()).or_insert(0) += binding.descriptor_count();
+        }
+
+        counts
+            .into_iter()
+            .map(|(ty, descriptor_count)| DescriptorPoolSize {
+                ty: DescriptorType::from_raw(ty),
+                descriptor_count,
+            })
+            .collect()
+    }
+
+    /// writeはAccelerationStructureの情報を指すので、呼び出し側で`acceleration_structure_infos`を生存させておく
+    fn writes(
+        &self,
+        descriptor_set: DescriptorSet,
+        acceleration_structure_infos: &mut Vec<WriteDescriptorSetAccelerationStructureKHR>,
+    ) -> Vec<WriteDescriptorSet> {
+        //push_nextで指すので途中でreallocが起きないように先に確保しておく
+        acceleration_structure_infos.clear();
+        acceleration_structure_infos.reserve(self.bindings.len());
+
+        let mut writes = vec![];
+
+        for binding in self.bindings.iter() {
+            let write = WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(binding.binding)
+                .dst_array_element(0)
+                .descriptor_type(binding.descriptor_type());
+
+            let write = match &binding.resource {
+                DescriptorResource::AccelerationStructures(acceleration_structures) => {
+                    acceleration_structure_infos.push(
+                        WriteDescriptorSetAccelerationStructureKHR::builder()
+                            .acceleration_structures(acceleration_structures)
+                            .build()
+                    );
+
+                    let mut write = write.build();
+
+                    //AccelerationStructureだとdescriptor_countが自動セットされないので手動で設定する必要がある
+                    write.descriptor_count = acceleration_structures.len() as u32;
+                    write.p_next = acceleration_structure_infos.last().unwrap()
+                        as *const WriteDescriptorSetAccelerationStructureKHR as *const _;
+
+                    write
+                }
+                DescriptorResource::StorageImages(image_infos)
+                | DescriptorResource::CombinedImageSamplers(image_infos) => {
+                    write.image_info(image_infos).build()
+                }
+                DescriptorResource::StorageBuffers(buffer_infos)
+                | DescriptorResource::UniformBuffers(buffer_infos) => {
+                    write.buffer_info(buffer_infos).build()
+                }
+            };
+
+            writes.push(write);
+        }
+
+        writes
+    }
+
+    pub fn create<'a>(&self, device: &'a Device) -> Result<DescriptorSets<'a>> {
+        debug!("create descriptor sets");
+
+        let layout_bindings = self.layout_bindings();
+        let binding_flags: Vec<DescriptorBindingFlags> = self.bindings.iter().map(DescriptorBinding::binding_flags).collect();
+        let mut binding_flags_info = DescriptorSetLayoutBindingFlagsCreateInfo::builder()
+            .binding_flags(&binding_flags)
+            .build();
+
+        let mut layout_create_info = DescriptorSetLayoutCreateInfo::builder()
+            .bindings(&layout_bindings);
+
+        //descriptor indexingを使わない場合はflagsを繋がない
+        if self.variable_descriptor_count().is_some() {
+            layout_create_info = layout_create_info.push_next(&mut binding_flags_info);
+        }
+
+        let descriptor_set_layout = unsafe {
+            device.create_descriptor_set_layout(&layout_create_info.build(), None)?
+        };
+
+        let pool_sizes = self.pool_sizes();
+
+        let descriptor_pool_info = DescriptorPoolCreateInfo::builder()
+            .pool_sizes(&pool_sizes)
+            .max_sets(1)
+            .build();
+
+        let descriptor_pool = match unsafe { device.create_descriptor_pool(&descriptor_pool_info, None) } {
+            Ok(descriptor_pool) => descriptor_pool,
+            Err(err) => {
+                unsafe { device.destroy_descriptor_set_layout(descriptor_set_layout, None) };
+
+                return Err(err.into());
+            }
+        };
+
+        //ここから先はDropで解放される
+        let mut descriptor_sets = DescriptorSets {
+            device,
+            descriptor_set_layout,
+            descriptor_pool,
+            descriptor_set: DescriptorSet::null(),
+        };
+
+        let set_layouts = [descriptor_set_layout];
+        let variable_descriptor_counts = [self.variable_descriptor_count().unwrap_or(0)];
+        let mut variable_count_info = DescriptorSetVariableDescriptorCountAllocateInfo::builder()
+            .descriptor_counts(&variable_descriptor_counts)
+            .build();
+
+        let mut allocate_info = DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&set_layouts);
+
+        if self.variable_descriptor_count().is_some() {
+            allocate_info = allocate_info.push_next(&mut variable_count_info);
+        }
+
+        descriptor_sets.descriptor_set = unsafe {
+            device.allocate_descriptor_sets(&allocate_info.build())?[0]
+        };
+
+        self.update(&descriptor_sets)?;
+
+        Ok(descriptor_sets)
+    }
+
+    /// リソースを差し替えた宣言で既存のDescriptorSetを書き換える
+    pub fn update(&self, descriptor_sets: &DescriptorSets) -> Result<()> {
+        let mut acceleration_structure_infos = vec![];
+        let writes = self.writes(descriptor_sets.descriptor_set, &mut acceleration_structure_infos);
+
+        unsafe {
+            descriptor_sets.device.update_descriptor_sets(&writes, &[]);
+        }
+
+        Ok(())
+    }
+}
+
+pub struct DescriptorSets<'a> {
+    device: &'a Device,
+    pub descriptor_set_layout: DescriptorSetLayout,
+    pub descriptor_pool: DescriptorPool,
+    pub descriptor_set: DescriptorSet,
+}
+
+impl Drop for DescriptorSets<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            //poolを破棄すれば確保したsetも解放される
+            self.device.destroy_descriptor_pool(self.descriptor_pool, None);
+            self.device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ash::vk::Handle;
+    use super::*;
+
+    fn image_infos(count: u64) -> Vec<DescriptorImageInfo> {
+        (1..=count)
+            .map(|raw| DescriptorImageInfo::builder()
+                .image_layout(ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .image_view(ImageView::from_raw(raw))
+                .sampler(Sampler::from_raw(raw))
+                .build())
+            .collect()
+    }
+
+    fn classical_builder() -> DescriptorSetBuilder {
+        DescriptorSetBuilder::new()
+            .binding(
+                0,
+                ShaderStageFlags::RAYGEN_KHR,
+                DescriptorResource::acceleration_structure(AccelerationStructureKHR::from_raw(1)),
+            )
+            .binding(1, ShaderStageFlags::RAYGEN_KHR, DescriptorResource::storage_image(ImageView::from_raw(2)))
+            .binding(4, ShaderStageFlags::CLOSEST_HIT_KHR, DescriptorResource::storage_buffer(Buffer::from_raw(4)))
+            .binding(3, ShaderStageFlags::CLOSEST_HIT_KHR, DescriptorResource::storage_buffer(Buffer::from_raw(3)))
+    }
+
+    #[test]
+    fn layout_pool_sizes_and_writes_agree() {
+        let declaration = classical_builder().build().unwrap();
+
+        let layout_bindings = declaration.layout_bindings();
+        let mut acceleration_structure_infos = vec![];
+        let writes = declaration.writes(DescriptorSet::null(), &mut acceleration_structure_infos);
+
+        //宣言の順番に関係なくbinding番号順になる
+        assert_eq!(layout_bindings.iter().map(|binding| binding.binding).collect::<Vec<_>>(), vec![0, 1, 3, 4]);
+        assert_eq!(layout_bindings.len(), writes.len());
+
+        for (layout, write) in layout_bindings.iter().zip(writes.iter()) {
+            assert_eq!(layout.binding, write.dst_binding);
+            assert_eq!(layout.descriptor_type, write.descriptor_type);
+            assert_eq!(layout.descriptor_count, write.descriptor_count);
+        }
+
+        //writeの数の合計がpoolの大きさと一致する
+        for pool_size in declaration.pool_sizes() {
+            let written: u32 = writes
+                .iter()
+                .filter(|write| write.descriptor_type == pool_size.ty)
+                .map(|write| write.descriptor_count)
+                .sum();
+
+            assert_eq!(written, pool_size.descriptor_count, "{:?}", pool_size.ty);
+        }
+
+        assert_eq!(declaration.pool_sizes().len(), 3);
+        assert_eq!(acceleration_structure_infos.len(), 1);
+        assert!(!writes[0].p_next.is_null());
+        assert_eq!(declaration.variable_descriptor_count(), None);
+    }
+
+    #[test]
+    fn duplicate_binding_is_rejected() {
+        let result = classical_builder()
+            .binding(3, ShaderStageFlags::CLOSEST_HIT_KHR, DescriptorResource::uniform_buffer(Buffer::from_raw(5)))
+            .build();
+
+        assert!(matches!(result, Err(CottonError::InvalidDescriptorSet(_))));
+    }
+
+    #[test]
+    fn empty_resource_and_stages_are_rejected() {
+        let empty_resource = DescriptorSetBuilder::new()
+            .binding(0, ShaderStageFlags::RAYGEN_KHR, DescriptorResource::StorageBuffers(vec![]))
+            .build();
+        let no_stages = DescriptorSetBuilder::new()
+            .binding(0, ShaderStageFlags::empty(), DescriptorResource::storage_buffer(Buffer::from_raw(1)))
+            .build();
+
+        assert!(matches!(empty_resource, Err(CottonError::InvalidDescriptorSet(_))));
+        assert!(matches!(no_stages, Err(CottonError::InvalidDescriptorSet(_))));
+    }
+
+    #[test]
+    fn variable_binding_uses_max_count_for_layout_only() {
+        let declaration = classical_builder()
+            .variable_binding(
+                5,
+                ShaderStageFlags::CLOSEST_HIT_KHR | ShaderStageFlags::CALLABLE_KHR,
+                DescriptorResource::CombinedImageSamplers(image_infos(3)),
+                16,
+            )
+            .build()
+            .unwrap();
+
+        let layout = declaration.layout_bindings();
+        let textures = layout.last().unwrap();
+
+        assert_eq!(textures.binding, 5);
+        assert_eq!(textures.descriptor_count, 16);
+        assert_eq!(declaration.variable_descriptor_count(), Some(3));
+
+        let samplers = declaration
+            .pool_sizes()
+            .into_iter()
+            .find(|pool_size| pool_size.ty == DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .unwrap();
+        assert_eq!(samplers.descriptor_count, 3);
+
+        let flags: Vec<DescriptorBindingFlags> = declaration.bindings().iter().map(DescriptorBinding::binding_flags).collect();
+        assert!(flags[..4].iter().all(|flags| flags.is_empty()));
+        assert_eq!(flags[4], DescriptorBindingFlags::VARIABLE_DESCRIPTOR_COUNT | DescriptorBindingFlags::PARTIALLY_BOUND);
+
+        let mut acceleration_structure_infos = vec![];
+        let writes = declaration.writes(DescriptorSet::null(), &mut acceleration_structure_infos);
+        assert_eq!(writes.last().unwrap().descriptor_count, 3);
+    }
+
+    #[test]
+    fn variable_binding_must_be_last() {
+        let result = classical_builder()
+            .variable_binding(2, ShaderStageFlags::CLOSEST_HIT_KHR, DescriptorResource::CombinedImageSamplers(image_infos(1)), 16)
+            .build();
+
+        assert!(matches!(result, Err(CottonError::InvalidDescriptorSet(_))));
+    }
+
+    #[test]
+    fn variable_binding_over_max_count_is_rejected() {
+        let result = classical_builder()
+            .variable_binding(5, ShaderStageFlags::CLOSEST_HIT_KHR, DescriptorResource::CombinedImageSamplers(image_infos(4)), 2)
+            .build();
+
+        assert!(matches!(result, Err(CottonError::InvalidDescriptorSet(_))));
+    }
+
+    #[test]
+    fn set_resource_keeps_type_and_count() {
+        let mut declaration = classical_builder().build().unwrap();
+
+        declaration
+            .set_resource(1, DescriptorResource::storage_image(ImageView::from_raw(9)))
+            .unwrap();
+
+        assert!(declaration.set_resource(1, DescriptorResource::storage_buffer(Buffer::from_raw(9))).is_err());
+        assert!(declaration.set_resource(2, DescriptorResource::storage_image(ImageView::from_raw(9))).is_err());
+
+        match &declaration.bindings()[1].resource {
+            DescriptorResource::StorageImages(infos) => assert_eq!(infos[0].image_view, ImageView::from_raw(9)),
+            resource => panic!("unexpected resource {:?}", resource),
+        }
+    }
+}
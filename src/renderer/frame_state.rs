@@ -0,0 +1,207 @@
+use ash::vk::Extent2D;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AcquireOutcome {
+    Acquired { image_index: u32, suboptimal: bool },
+    /// ERROR_OUT_OF_DATE_KHR
+    OutOfDate,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PresentOutcome {
+    Presented { suboptimal: bool },
+    /// ERROR_OUT_OF_DATE_KHR
+    OutOfDate,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FrameAction {
+    Render,
+    RecreateSwapchain,
+    /// 最小化されている間は何もしない
+    Wait,
+    Exit,
+}
+
+/// ウィンドウのイベントとacquire, presentの結果から次に何をするかを決める
+/// Vulkanを呼ばないのでイベントを並べるだけで動かせる
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FrameState {
+    window_extent: Extent2D,
+    swapchain_extent: Extent2D,
+    swapchain_dirty: bool,
+    close_requested: bool,
+}
+
+impl FrameState {
+    pub fn new(swapchain_extent: Extent2D) -> Self {
+        Self {
+            window_extent: swapchain_extent,
+            swapchain_extent,
+            swapchain_dirty: false,
+            close_requested: false,
+        }
+    }
+
+    pub fn window_extent(&self) -> Extent2D {
+        self.window_extent
+    }
+
+    pub fn swapchain_extent(&self) -> Extent2D {
+        self.swapchain_extent
+    }
+
+    pub fn on_resized(&mut self, width: u32, height: u32) {
+        self.window_extent = Extent2D { width, height };
+
+        if self.window_extent != self.swapchain_extent {
+            self.swapchain_dirty = true;
+        }
+    }
+
+    pub fn on_close_requested(&mut self) {
+        self.close_requested = true;
+    }
+
+    /// 描画するswapchain imageのindexを返す、作り直しが必要な場合はNone
+    pub fn on_acquire(&mut self, outcome: AcquireOutcome) -> Option<u32> {
+        match outcome {
+            //suboptimalの場合はこのフレームは描画し、次のフレームの前に作り直す
+            AcquireOutcome::Acquired { image_index, suboptimal } => {
+                if suboptimal {
+                    self.swapchain_dirty = true;
+                }
+
+                Some(image_index)
+            }
+            AcquireOutcome::OutOfDate => {
+                self.swapchain_dirty = true;
+
+                None
+            }
+        }
+    }
+
+    pub fn on_present(&mut self, outcome: PresentOutcome) {
+        match outcome {
+            PresentOutcome::Presented { suboptimal: false } => {}
+            PresentOutcome::Presented { suboptimal: true } | PresentOutcome::OutOfDate => {
+                self.swapchain_dirty = true;
+            }
+        }
+    }
+
+    pub fn on_recreated(&mut self, swapchain_extent: Extent2D) {
+        self.swapchain_extent = swapchain_extent;
+        self.swapchain_dirty = false;
+    }
+
+    pub fn next_action(&self) -> FrameAction {
+        if self.close_requested {
+            return FrameAction::Exit;
+        }
+
+        if self.window_extent.width == 0 || self.window_extent.height == 0 {
+            return FrameAction::Wait;
+        }
+
+        if self.swapchain_dirty {
+            return FrameAction::RecreateSwapchain;
+        }
+
+        FrameAction::Render
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extent(width: u32, height: u32) -> Extent2D {
+        Extent2D { width, height }
+    }
+
+    #[test]
+    fn renders_until_something_happens() {
+        let state = FrameState::new(extent(800, 600));
+
+        assert_eq!(state.next_action(), FrameAction::Render);
+    }
+
+    #[test]
+    fn resize_marks_swapchain_dirty() {
+        let mut state = FrameState::new(extent(800, 600));
+
+        //同じ大きさの通知では作り直さない
+        state.on_resized(800, 600);
+        assert_eq!(state.next_action(), FrameAction::Render);
+
+        state.on_resized(1024, 768);
+        assert_eq!(state.window_extent(), extent(1024, 768));
+        assert_eq!(state.next_action(), FrameAction::RecreateSwapchain);
+
+        state.on_recreated(extent(1024, 768));
+        assert_eq!(state.swapchain_extent(), extent(1024, 768));
+        assert_eq!(state.next_action(), FrameAction::Render);
+    }
+
+    #[test]
+    fn minimized_window_waits() {
+        let mut state = FrameState::new(extent(800, 600));
+
+        state.on_resized(0, 0);
+        assert_eq!(state.next_action(), FrameAction::Wait);
+
+        state.on_resized(800, 0);
+        assert_eq!(state.next_action(), FrameAction::Wait);
+
+        //最小化した時点で大きさが変わっているので戻っても作り直す
+        state.on_resized(800, 600);
+        assert_eq!(state.next_action(), FrameAction::RecreateSwapchain);
+    }
+
+    #[test]
+    fn suboptimal_acquire_renders_then_recreates() {
+        let mut state = FrameState::new(extent(800, 600));
+
+        assert_eq!(state.on_acquire(AcquireOutcome::Acquired { image_index: 2, suboptimal: true }), Some(2));
+        assert_eq!(state.next_action(), FrameAction::RecreateSwapchain);
+    }
+
+    #[test]
+    fn out_of_date_acquire_skips_the_frame() {
+        let mut state = FrameState::new(extent(800, 600));
+
+        assert_eq!(state.on_acquire(AcquireOutcome::Acquired { image_index: 1, suboptimal: false }), Some(1));
+        assert_eq!(state.next_action(), FrameAction::Render);
+
+        assert_eq!(state.on_acquire(AcquireOutcome::OutOfDate), None);
+        assert_eq!(state.next_action(), FrameAction::RecreateSwapchain);
+    }
+
+    #[test]
+    fn suboptimal_or_out_of_date_present_recreates() {
+        let mut state = FrameState::new(extent(800, 600));
+
+        state.on_present(PresentOutcome::Presented { suboptimal: false });
+        assert_eq!(state.next_action(), FrameAction::Render);
+
+        state.on_present(PresentOutcome::Presented { suboptimal: true });
+        assert_eq!(state.next_action(), FrameAction::RecreateSwapchain);
+
+        state.on_recreated(extent(800, 600));
+        state.on_present(PresentOutcome::OutOfDate);
+        assert_eq!(state.next_action(), FrameAction::RecreateSwapchain);
+    }
+
+    #[test]
+    fn close_takes_precedence() {
+        let mut state = FrameState::new(extent(800, 600));
+
+        state.on_resized(0, 0);
+        state.on_acquire(AcquireOutcome::OutOfDate);
+        state.on_close_requested();
+
+        assert_eq!(state.next_action(), FrameAction::Exit);
+    }
+}
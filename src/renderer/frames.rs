@@ -0,0 +1,366 @@
+use ash::Device;
+use ash::vk::{CommandBuffer, CommandPool, CommandPoolResetFlags, Fence, FenceCreateFlags, FenceCreateInfo, Semaphore, SemaphoreCreateInfo};
+use log::debug;
+use crate::error::Result;
+use crate::renderer::backends::Backends;
+
+/// 1フレームの描画に使うコマンドと同期オブジェクト
+pub struct Frame<'a> {
+    device: &'a Device,
+    pub command_pool: CommandPool,
+    pub command_buffer: CommandBuffer,
+    pub image_available: Semaphore,
+    pub render_finished: Semaphore,
+    pub in_flight: Fence,
+}
+
+impl<'a> Frame<'a> {
+    pub fn new(backends: &'a Backends) -> Result<Self> {
+        debug!("create frame");
+
+        //失敗した場合でもDropで解放されるように先に作っておく、nullの破棄は何もしない
+        let mut frame = Self {
+            device: &backends.device,
+            command_pool: CommandPool::null(),
+            command_buffer: CommandBuffer::null(),
+            image_available: Semaphore::null(),
+            render_finished: Semaphore::null(),
+            in_flight: Fence::null(),
+        };
+
+        frame.command_pool = backends.create_graphics_command_pool()?;
+        frame.command_buffer = backends.create_command_buffers(frame.command_pool, 1)?[0];
+
+        unsafe {
+            frame.image_available = backends.device.create_semaphore(&SemaphoreCreateInfo::default(), None)?;
+            frame.render_finished = backends.device.create_semaphore(&SemaphoreCreateInfo::default(), None)?;
+
+            //最初のフレームで待たないようにsignal済みで作る
+            frame.in_flight = backends.device.create_fence(
+                &FenceCreateInfo::builder()
+                    .flags(FenceCreateFlags::SIGNALED)
+                    .build(),
+                None,
+            )?;
+        }
+
+        Ok(frame)
+    }
+
+    /// 前回このフレームで投げたコマンドが終わるまで待つ
+    pub fn wait(&self) -> Result<()> {
+        unsafe {
+            self.device.wait_for_fences(&[self.in_flight], true, u64::MAX)?;
+        }
+
+        Ok(())
+    }
+
+    /// コマンドを記録し直す前に呼ぶ
+    pub fn reset(&self) -> Result<()> {
+        unsafe {
+            self.device.reset_fences(&[self.in_flight])?;
+            self.device.reset_command_pool(self.command_pool, CommandPoolResetFlags::empty())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Frame<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            //poolを破棄すればcommand bufferも解放される
+            self.device.destroy_command_pool(self.command_pool, None);
+            self.device.destroy_semaphore(self.image_available, None);
+            self.device.destroy_semaphore(self.render_finished, None);
+            self.device.destroy_fence(self.in_flight, None);
+        }
+    }
+}
+
+/// swapchainのimageをどのフレームが使っているか
+/// acquireしたimageを前のフレームがまだ使っている場合はそのフレームを待つ必要がある
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImageOwners {
+    owners: Vec<Option<usize>>,
+}
+
+impl ImageOwners {
+    pub fn new(image_count: usize) -> Self {
+        Self {
+            owners: vec![None; image_count],
+        }
+    }
+
+    /// swapchainを作り直した場合は前のimageの情報は要らない
+    pub fn reset(&mut self, image_count: usize) {
+        self.owners = vec![None; image_count];
+    }
+
+    /// imageをframe_indexのフレームのものにし、待つ必要がある別のフレームがあればそれを返す
+    pub fn claim(&mut self, image_index: usize, frame_index: usize) -> Option<usize> {
+        if image_index >= self.owners.len() {
+            self.owners.resize(image_index + 1, None);
+        }
+
+        let previous = self.owners[image_index].replace(frame_index);
+
+        previous.filter(|previous| *previous != frame_index)
+    }
+}
+
+/// FrameRingのindexとfenceの状態、Vulkanは呼ばずに待つ必要があるフレームを決める
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FrameSchedule {
+    current: usize,
+    /// submitしてからまだwaitしていないフレーム
+    pending: Vec<bool>,
+    image_owners: ImageOwners,
+}
+
+impl FrameSchedule {
+    pub fn new(frame_count: usize, image_count: usize) -> Self {
+        Self {
+            current: 0,
+            //fenceはsignal済みで作るので最初は待たない
+            pending: vec![false; frame_count.max(1)],
+            image_owners: ImageOwners::new(image_count),
+        }
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    pub fn is_pending(&self, frame_index: usize) -> bool {
+        self.pending[frame_index]
+    }
+
+    /// 今のフレームのリソースを再利用する前に待つ必要があるか
+    pub fn must_wait_current(&self) -> bool {
+        self.is_pending(self.current)
+    }
+
+    /// acquireしたimageを今のフレームのものにし、まだ終わっていない前の持ち主がいればそれを返す
+    pub fn claim_image(&mut self, image_index: usize) -> Option<usize> {
+        self.image_owners
+            .claim(image_index, self.current)
+            .filter(|previous| self.pending[*previous])
+    }
+
+    pub fn on_waited(&mut self, frame_index: usize) {
+        self.pending[frame_index] = false;
+    }
+
+    /// 今のフレームのfenceでsubmitした後に呼ぶ
+    pub fn on_submitted(&mut self) {
+        self.pending[self.current] = true;
+    }
+
+    pub fn advance(&mut self) {
+        self.current = next_frame_index(self.current, self.pending.len());
+    }
+
+    /// device_wait_idleの後に呼ぶので全てのフレームは終わっている
+    pub fn on_swapchain_recreated(&mut self, image_count: usize) {
+        self.image_owners.reset(image_count);
+        self.pending.iter_mut().for_each(|pending| *pending = false);
+    }
+}
+
+/// MAX_FRAMES_IN_FLIGHT個のフレームを順番に使い回す
+/// フレームのfenceがsignalされるまではそのフレームのリソースは再利用しない
+pub struct FrameRing<'a> {
+    frames: Vec<Frame<'a>>,
+    schedule: FrameSchedule,
+}
+
+impl<'a> FrameRing<'a> {
+    pub fn new(backends: &'a Backends, frame_count: usize, image_count: usize) -> Result<Self> {
+        let schedule = FrameSchedule::new(frame_count, image_count);
+
+        let frames = (0..schedule.frame_count())
+            .map(|_| Frame::new(backends))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            frames,
+            schedule,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn current_index(&self) -> usize {
+        self.schedule.current()
+    }
+
+    pub fn current(&self) -> &Frame<'a> {
+        &self.frames[self.schedule.current()]
+    }
+
+    /// 今のフレームのリソースが前回の使用を終えるまで待つ
+    pub fn wait_current(&mut self) -> Result<()> {
+        if self.schedule.must_wait_current() {
+            self.current().wait()?;
+            self.schedule.on_waited(self.schedule.current());
+        }
+
+        Ok(())
+    }
+
+    /// acquireしたimageを別のフレームが使用中ならそれを待つ
+    pub fn claim_image(&mut self, image_index: u32) -> Result<()> {
+        if let Some(previous) = self.schedule.claim_image(image_index as usize) {
+            self.frames[previous].wait()?;
+            self.schedule.on_waited(previous);
+        }
+
+        Ok(())
+    }
+
+    /// 今のフレームのin_flightでqueue_submitした直後に呼ぶ
+    pub fn on_submitted(&mut self) {
+        self.schedule.on_submitted();
+    }
+
+    pub fn on_swapchain_recreated(&mut self, image_count: usize) {
+        self.schedule.on_swapchain_recreated(image_count);
+    }
+
+    pub fn advance(&mut self) {
+        self.schedule.advance();
+    }
+}
+
+pub fn next_frame_index(current: usize, frame_count: usize) -> usize {
+    if frame_count == 0 {
+        return 0;
+    }
+
+    (current + 1) % frame_count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //描画ループと同じ順に呼び、待ったフレームを返す
+    fn render(schedule: &mut FrameSchedule, image_index: usize) -> Vec<usize> {
+        let mut waited = vec![];
+
+        if schedule.must_wait_current() {
+            waited.push(schedule.current());
+            schedule.on_waited(schedule.current());
+        }
+
+        if let Some(previous) = schedule.claim_image(image_index) {
+            waited.push(previous);
+            schedule.on_waited(previous);
+        }
+
+        schedule.on_submitted();
+        schedule.advance();
+
+        waited
+    }
+
+    #[test]
+    fn next_index_wraps_around() {
+        assert_eq!(next_frame_index(0, 2), 1);
+        assert_eq!(next_frame_index(1, 2), 0);
+        assert_eq!(next_frame_index(0, 1), 0);
+        assert_eq!(next_frame_index(3, 0), 0);
+    }
+
+    #[test]
+    fn slots_are_reused_in_order_after_waiting() {
+        let mut schedule = FrameSchedule::new(2, 3);
+
+        //最初の一周はsignal済みなので待たない
+        assert!(render(&mut schedule, 0).is_empty());
+        assert!(render(&mut schedule, 1).is_empty());
+        assert_eq!(schedule.current(), 0);
+
+        //二周目からは同じスロットの前回のsubmitを待つ
+        assert_eq!(render(&mut schedule, 2), vec![0]);
+        //image 0はまだフレーム0が使っている
+        assert_eq!(render(&mut schedule, 0), vec![1, 0]);
+        assert_eq!(schedule.current(), 0);
+        //フレーム0は最後に待ったので次は待たない
+        assert!(!schedule.must_wait_current());
+        assert!(schedule.is_pending(1));
+    }
+
+    #[test]
+    fn pending_owner_of_the_acquired_image_is_waited() {
+        let mut schedule = FrameSchedule::new(3, 2);
+
+        assert!(render(&mut schedule, 0).is_empty());
+        assert!(render(&mut schedule, 1).is_empty());
+        //フレーム2がフレーム0のまだ終わっていないimageを受け取る
+        assert_eq!(render(&mut schedule, 0), vec![0]);
+        assert!(!schedule.is_pending(0));
+
+        //フレーム0は既に待ったのでもう一度待つ必要はない、image 1はフレーム1が使用中
+        assert_eq!(render(&mut schedule, 1), vec![1]);
+    }
+
+    #[test]
+    fn reacquiring_an_image_in_the_same_frame_does_not_wait_on_itself() {
+        let mut schedule = FrameSchedule::new(1, 2);
+
+        assert!(render(&mut schedule, 0).is_empty());
+        assert_eq!(render(&mut schedule, 0), vec![0]);
+        assert_eq!(render(&mut schedule, 1), vec![0]);
+    }
+
+    #[test]
+    fn unsubmitted_frame_is_not_waited() {
+        let mut schedule = FrameSchedule::new(2, 2);
+
+        //acquireの後にsubmitせずに抜けた場合
+        assert!(!schedule.must_wait_current());
+        assert_eq!(schedule.claim_image(0), None);
+        assert!(!schedule.must_wait_current());
+
+        schedule.on_submitted();
+        assert!(schedule.must_wait_current());
+    }
+
+    #[test]
+    fn recreating_the_swapchain_forgets_images_and_pending_fences() {
+        let mut schedule = FrameSchedule::new(2, 2);
+
+        render(&mut schedule, 0);
+        render(&mut schedule, 1);
+        schedule.on_swapchain_recreated(3);
+
+        assert!(!schedule.is_pending(0) && !schedule.is_pending(1));
+        assert_eq!(schedule.current(), 0);
+        //前のswapchainのimageの持ち主は残っていない
+        assert!(render(&mut schedule, 1).is_empty());
+        assert!(render(&mut schedule, 2).is_empty());
+        assert_eq!(render(&mut schedule, 0), vec![0]);
+    }
+
+    #[test]
+    fn at_least_one_frame() {
+        let mut schedule = FrameSchedule::new(0, 1);
+
+        assert_eq!(schedule.frame_count(), 1);
+        assert!(render(&mut schedule, 0).is_empty());
+        assert_eq!(render(&mut schedule, 0), vec![0]);
+    }
+}
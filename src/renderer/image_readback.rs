@@ -0,0 +1,222 @@
+use ash::vk::{AccessFlags, CommandBufferBeginInfo, CommandBufferUsageFlags, DependencyFlags, Extent3D, Fence, Format, Image, ImageAspectFlags, ImageCopy, ImageCreateInfo, ImageLayout, ImageMemoryBarrier, ImageSubresource, ImageSubresourceLayers, ImageTiling, ImageType, ImageUsageFlags, MemoryAllocateInfo, MemoryMapFlags, MemoryPropertyFlags, PipelineStageFlags, Queue, SampleCountFlags, SharingMode, SubmitInfo, WHOLE_SIZE};
+use log::debug;
+use crate::error::{CottonError, Result};
+use crate::get_memory_type_index;
+use crate::renderer::backends::Backends;
+use crate::renderer::color_subresource_range;
+
+//R32G32B32A32_SFLOATの1pixelのバイト数
+const RGBA32F_PIXEL_SIZE: usize = 4 * 4;
+
+/// GENERALのR32G32B32A32_SFLOATのimageをCPUから読める形にコピーする
+/// 返り値は上の行から並べたRGBA
+pub fn read_rgba32f_image(
+    backends: &Backends,
+    target_image: Image,
+    extent3d: Extent3D,
+    graphics_queue: Queue,
+) -> Result<Vec<f32>> {
+    debug!("read back image");
+
+    //transfer gpu to cpu
+
+    let host_image_create_info = ImageCreateInfo::builder()
+        .image_type(ImageType::TYPE_2D)
+        .format(Format::R32G32B32A32_SFLOAT)
+        .extent(extent3d)
+        .mip_levels(1)
+        .initial_layout(ImageLayout::UNDEFINED)
+        .array_layers(1)
+        .samples(SampleCountFlags::TYPE_1)
+        .tiling(ImageTiling::LINEAR)
+        .usage(ImageUsageFlags::TRANSFER_DST)
+        .sharing_mode(SharingMode::EXCLUSIVE)
+        .build();
+
+    let host_image = unsafe {
+        backends.device.create_image(&host_image_create_info, None)?
+    };
+
+    let host_memory_requirement = unsafe {
+        backends.device.get_image_memory_requirements(host_image)
+    };
+
+    let property_flags = MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT;
+
+    let memory_type_index = get_memory_type_index(
+        &backends.device_memory_properties,
+        host_memory_requirement.memory_type_bits,
+        property_flags,
+    ).ok_or(CottonError::NoMemoryType {
+        type_filter: host_memory_requirement.memory_type_bits,
+        property_flags,
+    });
+
+    let memory_type_index = match memory_type_index {
+        Ok(memory_type_index) => memory_type_index,
+        Err(err) => {
+            unsafe { backends.device.destroy_image(host_image, None) };
+
+            return Err(err);
+        }
+    };
+
+    let host_memory_alloc_info = MemoryAllocateInfo::builder()
+        .allocation_size(host_memory_requirement.size)
+        .memory_type_index(memory_type_index);
+
+    let host_device_memory = unsafe {
+        backends.device.allocate_memory(&host_memory_alloc_info, None)?
+    };
+
+    let command_pool = backends.create_graphics_command_pool()?;
+
+    //途中で失敗しても作ったものは最後にまとめて破棄する
+    let result = (|| -> Result<Vec<f32>> {
+        unsafe {
+            backends.device.bind_image_memory(host_image, host_device_memory, 0)?
+        }
+
+        let command_buffers = backends.create_command_buffers(command_pool, 1)?;
+        let command_buffer = command_buffers[0];
+
+        unsafe {
+            backends
+                .device
+                .begin_command_buffer(
+                    command_buffer,
+                    &CommandBufferBeginInfo::builder()
+                        .flags(CommandBufferUsageFlags::ONE_TIME_SUBMIT)
+                        .build(),
+                )?;
+
+            let image_barrier = ImageMemoryBarrier::builder()
+                .src_access_mask(AccessFlags::empty())
+                .dst_access_mask(AccessFlags::TRANSFER_WRITE)
+                .old_layout(ImageLayout::UNDEFINED)
+                .new_layout(ImageLayout::TRANSFER_DST_OPTIMAL)
+                .image(host_image)
+                .subresource_range(color_subresource_range())
+                .build();
+
+            backends.device.cmd_pipeline_barrier(
+                command_buffer,
+                PipelineStageFlags::TRANSFER,
+                PipelineStageFlags::TRANSFER,
+                DependencyFlags::empty(),
+                &[],
+                &[],
+                &[image_barrier],
+            );
+
+            //copy
+
+            let copy_region = ImageCopy::builder()
+                .src_subresource(
+                    ImageSubresourceLayers::builder()
+                        .aspect_mask(ImageAspectFlags::COLOR)
+                        .layer_count(1)
+                        .build()
+                )
+                .dst_subresource(
+                    ImageSubresourceLayers::builder()
+                        .aspect_mask(ImageAspectFlags::COLOR)
+                        .layer_count(1)
+                        .build()
+                )
+                .extent(extent3d)
+                .build();
+
+            backends.device.cmd_copy_image(
+                command_buffer,
+                target_image,
+                ImageLayout::GENERAL,
+                host_image,
+                ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[copy_region],
+            );
+
+            let image_barrier = ImageMemoryBarrier::builder()
+                .src_access_mask(AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(AccessFlags::MEMORY_READ)
+                .old_layout(ImageLayout::TRANSFER_DST_OPTIMAL)
+                .new_layout(ImageLayout::GENERAL)
+                .image(host_image)
+                .subresource_range(color_subresource_range())
+                .build();
+
+            backends.device.cmd_pipeline_barrier(
+                command_buffer,
+                PipelineStageFlags::TRANSFER,
+                PipelineStageFlags::TRANSFER,
+                DependencyFlags::empty(),
+                &[],
+                &[],
+                &[image_barrier]
+            );
+
+            backends.device.end_command_buffer(command_buffer)?;
+        }
+
+        let submit_infos = [
+            SubmitInfo::builder()
+                .command_buffers(&command_buffers)
+                .build()
+        ];
+
+        unsafe {
+            backends
+                .device
+                .queue_submit(graphics_queue, &submit_infos, Fence::null())?;
+
+            backends.device.queue_wait_idle(graphics_queue)?;
+        }
+
+        let subresource = ImageSubresource::builder()
+            .aspect_mask(ImageAspectFlags::COLOR)
+            .build();
+
+        let subresource_layout = unsafe {
+            backends.device.get_image_subresource_layout(host_image, subresource)
+        };
+
+        let data: *const u8 = unsafe {
+            backends
+                .device
+                .map_memory(
+                    host_device_memory,
+                    0,
+                    WHOLE_SIZE,
+                    MemoryMapFlags::empty(),
+                )? as _
+        };
+
+        let row_size = RGBA32F_PIXEL_SIZE * extent3d.width as usize;
+        let mut pixels: Vec<f32> = Vec::with_capacity(row_size / 4 * extent3d.height as usize);
+
+        //行ごとにrow_pitchだけずれているので詰め直す
+        for y in 0..extent3d.height as usize {
+            let row = unsafe {
+                std::slice::from_raw_parts(
+                    data.add(subresource_layout.offset as usize + y * subresource_layout.row_pitch as usize),
+                    row_size,
+                )
+            };
+
+            pixels.extend(row.chunks_exact(4).map(|bytes| f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])));
+        }
+
+        unsafe { backends.device.unmap_memory(host_device_memory) };
+
+        Ok(pixels)
+    })();
+
+    unsafe {
+        //poolを破棄すればcommand bufferも解放される
+        backends.device.destroy_command_pool(command_pool, None);
+        backends.device.free_memory(host_device_memory, None);
+        backends.device.destroy_image(host_image, None);
+    }
+
+    result
+}
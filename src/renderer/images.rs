@@ -1,7 +1,8 @@
 use std::ops::Deref;
 use ash::Device;
-use ash::vk::{AccessFlags, CommandBufferBeginInfo, CommandBufferUsageFlags, ComponentMapping, ComponentSwizzle, DependencyFlags, Extent2D, Extent3D, Fence, Format, Image, ImageAspectFlags, ImageCreateInfo, ImageLayout, ImageMemoryBarrier, ImageSubresourceRange, ImageTiling, ImageType, ImageUsageFlags, ImageView, ImageViewCreateInfo, ImageViewType, MemoryAllocateInfo, MemoryPropertyFlags, PipelineStageFlags, Queue, SampleCountFlags, SharingMode, SubmitInfo};
+use ash::vk::{AccessFlags, CommandBufferBeginInfo, CommandBufferUsageFlags, ComponentMapping, ComponentSwizzle, DependencyFlags, Extent2D, Extent3D, Fence, Format, Image, ImageAspectFlags, ImageCreateInfo, ImageLayout, ImageMemoryBarrier, ImageSubresourceRange, ImageTiling, ImageType, ImageUsageFlags, ImageView, ImageViewCreateInfo, ImageViewType, DeviceMemory, MemoryAllocateInfo, MemoryPropertyFlags, PipelineStageFlags, Queue, SampleCountFlags, SharingMode, SubmitInfo};
 use log::debug;
+use crate::error::{CottonError, Result};
 use crate::get_memory_type_index;
 use crate::renderer::backends::Backends;
 
@@ -9,6 +10,8 @@ pub struct Images<'a> {
     backends: &'a Backends,
     pub images: Vec<Image>,
     pub image_views: Vec<ImageView>,
+    //swapchainのimageはswapchainが破棄するので空のまま
+    device_memories: Vec<DeviceMemory>,
 }
 
 impl<'a> Images<'a> {
@@ -18,7 +21,7 @@ impl<'a> Images<'a> {
         format: Format,
         extent: Extent3D,
         graphics_queue: Queue,
-    ) -> Self {
+    ) -> Result<Self> {
 
         let image_create_info = ImageCreateInfo::builder()
             .image_type(ImageType::TYPE_2D)
@@ -38,31 +41,34 @@ impl<'a> Images<'a> {
             .build();
 
         let image = unsafe {
-            backends.device.create_image(&image_create_info, None).unwrap()
+            backends.device.create_image(&image_create_info, None)?
         };
 
         let memory_requirement = unsafe {
             backends.device.get_image_memory_requirements(image)
         };
 
+        let memory_type_index = get_memory_type_index(
+            &backends.device_memory_properties,
+            memory_requirement.memory_type_bits,
+            MemoryPropertyFlags::DEVICE_LOCAL,
+        ).ok_or(CottonError::NoMemoryType {
+            type_filter: memory_requirement.memory_type_bits,
+            property_flags: MemoryPropertyFlags::DEVICE_LOCAL,
+        })?;
+
         let memory_alloc_info = MemoryAllocateInfo::builder()
             .allocation_size(memory_requirement.size)
-            .memory_type_index(
-                get_memory_type_index(
-                    &backends.device_memory_properties,
-                    memory_requirement.memory_type_bits,
-                    MemoryPropertyFlags::DEVICE_LOCAL,
-                ).unwrap()
-            );
+            .memory_type_index(memory_type_index);
 
         let device_memory = unsafe {
-            backends.device.allocate_memory(&memory_alloc_info, None).unwrap()
+            backends.device.allocate_memory(&memory_alloc_info, None)?
         };
 
         unsafe {
-            backends.device.bind_image_memory(image, device_memory, 0).unwrap();
+            backends.device.bind_image_memory(image, device_memory, 0)?;
         }
-        
+
         let image_view_create_info = ImageViewCreateInfo::builder()
             .view_type(ImageViewType::TYPE_2D)
             .format(format)
@@ -79,12 +85,12 @@ impl<'a> Images<'a> {
             .build();
 
         let image_view = unsafe {
-            backends.device.create_image_view(&image_view_create_info, None).unwrap()
+            backends.device.create_image_view(&image_view_create_info, None)?
         };
 
         //Initialize
-        let command_pool = backends.create_graphics_command_pool();
-        let command_buffers = backends.create_command_buffers(command_pool, 1);
+        let command_pool = backends.create_graphics_command_pool()?;
+        let command_buffers = backends.create_command_buffers(command_pool, 1)?;
         let command_buffer = command_buffers[0];
 
         unsafe {
@@ -93,7 +99,7 @@ impl<'a> Images<'a> {
                 &CommandBufferBeginInfo::builder()
                     .flags(CommandBufferUsageFlags::ONE_TIME_SUBMIT)
                     .build(),
-            ).unwrap();
+            )?;
 
             let image_barrier = ImageMemoryBarrier::builder()
                 .src_access_mask(AccessFlags::empty())
@@ -121,7 +127,7 @@ impl<'a> Images<'a> {
                 &[image_barrier],
             );
 
-            backends.device.end_command_buffer(command_buffer).unwrap();
+            backends.device.end_command_buffer(command_buffer)?;
         }
 
         let submit_infos = [
@@ -133,22 +139,22 @@ impl<'a> Images<'a> {
         unsafe {
             backends
                 .device
-                .queue_submit(graphics_queue, &submit_infos, Fence::null())
-                .expect("queue submit failed");
+                .queue_submit(graphics_queue, &submit_infos, Fence::null())?;
 
-            backends.device.queue_wait_idle(graphics_queue).unwrap();
+            backends.device.queue_wait_idle(graphics_queue)?;
             backends.device.free_command_buffers(command_pool, &command_buffers);
             backends.device.destroy_command_pool(command_pool, None);
         }
 
         debug!("Image: {:?}, ImageView: {:?}", image, image_view);
 
-        Self {
+        Ok(Self {
             backends,
             //一つだけ生成
             images: vec![image],
             image_views: vec![image_view],
-        }
+            device_memories: vec![device_memory],
+        })
     }
 
     //画像単体で出力したいならvk::Imageを素のまま作ってそこに保存すれば良い
@@ -156,7 +162,7 @@ impl<'a> Images<'a> {
         backends: &'a Backends,
         images: Vec<Image>,
         swapchain_image_format: Format,
-    ) -> Self {
+    ) -> Result<Self> {
         let mut image_views = vec![];
 
         for image in images.iter() {
@@ -184,16 +190,17 @@ impl<'a> Images<'a> {
                 )
                 .build();
 
-            image_views.push(unsafe { backends.device.create_image_view(&create_info, None) }.unwrap());
+            image_views.push(unsafe { backends.device.create_image_view(&create_info, None) }?);
         }
 
         debug!("Create Swapchain Image Views");
 
-        Self {
+        Ok(Self {
             backends,
             images,
             image_views,
-        }
+            device_memories: vec![],
+        })
     }
 }
 
@@ -203,6 +210,17 @@ impl Drop for Images<'_> {
             for image_view in self.image_views.clone() {
                 self.backends.device.destroy_image_view(image_view, None);
             }
+
+            //自分で確保したimageのみ破棄する
+            if !self.device_memories.is_empty() {
+                for image in self.images.clone() {
+                    self.backends.device.destroy_image(image, None);
+                }
+
+                for device_memory in self.device_memories.clone() {
+                    self.backends.device.free_memory(device_memory, None);
+                }
+            }
         }
     }
 }
\ No newline at end of file
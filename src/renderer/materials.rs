@@ -0,0 +1,270 @@
+use classical_raytracer_shader::material::MATERIAL_CALLABLE_COUNT;
+use crate::constants::{DIELECTRIC_CALLABLE_ENTRY_NAME, EMISSIVE_CALLABLE_ENTRY_NAME, LAMBERTIAN_CALLABLE_ENTRY_NAME, METAL_CALLABLE_ENTRY_NAME};
+use crate::error::{CottonError, Result};
+use crate::renderer::ray_tracing_pipeline_desc::{RayTracingPipelineDesc, RayTracingShaderGroups};
+
+//シェーダーと同じレイアウトにするため定義はシェーダー側に置く
+pub use classical_raytracer_shader::scene_data::{MaterialData, NO_TEXTURE};
+
+/// マテリアルの種類ごとに評価するcallableを分ける
+/// 新しいマテリアルを足してもclosest hitが大きくならない
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MaterialKind {
+    Lambertian,
+    Metal,
+    Dielectric,
+    Emissive,
+}
+
+impl MaterialKind {
+    pub const ALL: [MaterialKind; 4] = [
+        MaterialKind::Lambertian,
+        MaterialKind::Metal,
+        MaterialKind::Dielectric,
+        MaterialKind::Emissive,
+    ];
+
+    pub fn callable_entry_name(&self) -> &'static str {
+        match self {
+            Self::Lambertian => LAMBERTIAN_CALLABLE_ENTRY_NAME,
+            Self::Metal => METAL_CALLABLE_ENTRY_NAME,
+            Self::Dielectric => DIELECTRIC_CALLABLE_ENTRY_NAME,
+            Self::Emissive => EMISSIVE_CALLABLE_ENTRY_NAME,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Material {
+    pub kind: MaterialKind,
+    pub albedo: [f32; 3],
+    /// Metalのみ使用
+    pub roughness: f32,
+    /// Dielectricのみ使用
+    pub ior: f32,
+    /// Emissiveのみ使用
+    pub emission: [f32; 3],
+    /// SceneDescription::texturesの中のindex、albedoに掛ける
+    pub albedo_texture: Option<usize>,
+}
+
+impl Material {
+    pub fn lambertian(albedo: [f32; 3]) -> Self {
+        Self {
+            kind: MaterialKind::Lambertian,
+            albedo,
+            roughness: 1.0,
+            ior: 1.0,
+            emission: [0.0; 3],
+            albedo_texture: None,
+        }
+    }
+
+    pub fn metal(albedo: [f32; 3], roughness: f32) -> Self {
+        Self {
+            kind: MaterialKind::Metal,
+            albedo,
+            roughness,
+            ..Self::lambertian(albedo)
+        }
+    }
+
+    pub fn dielectric(ior: f32) -> Self {
+        Self {
+            kind: MaterialKind::Dielectric,
+            ior,
+            ..Self::lambertian([1.0; 3])
+        }
+    }
+
+    pub fn emissive(emission: [f32; 3]) -> Self {
+        Self {
+            kind: MaterialKind::Emissive,
+            emission,
+            ..Self::lambertian([0.0; 3])
+        }
+    }
+
+    pub fn albedo_texture(mut self, texture: usize) -> Self {
+        self.albedo_texture = Some(texture);
+        self
+    }
+}
+
+/// マテリアルの種類とcallableのindexの対応
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MaterialCallables {
+    indices: Vec<(MaterialKind, u32)>,
+}
+
+impl MaterialCallables {
+    /// 各種類のcallableをpipelineに登録する
+    pub fn register(mut desc: RayTracingPipelineDesc, kinds: &[MaterialKind]) -> RayTracingPipelineDesc {
+        let mut registered: Vec<MaterialKind> = vec![];
+
+        for kind in kinds.iter() {
+            if registered.contains(kind) {
+                continue;
+            }
+
+            registered.push(*kind);
+            desc = desc.callable(kind.callable_entry_name());
+        }
+
+        desc
+    }
+
+    /// 他のcallableが先に登録されていてもずれないようにentry point名から求める
+    pub fn from_groups(groups: &RayTracingShaderGroups, kinds: &[MaterialKind]) -> Result<Self> {
+        let mut indices: Vec<(MaterialKind, u32)> = vec![];
+
+        for kind in kinds.iter() {
+            if indices.iter().any(|(registered, _)| registered == kind) {
+                continue;
+            }
+
+            let index = groups
+                .callable_index(kind.callable_entry_name())
+                .ok_or_else(|| CottonError::InvalidPipelineDesc(format!(
+                    "callable `{}` for {:?} is not registered",
+                    kind.callable_entry_name(),
+                    kind,
+                )))?;
+
+            //closest hitはこの数までしかexecute_callableを書いていない
+            if index >= MATERIAL_CALLABLE_COUNT {
+                return Err(CottonError::InvalidPipelineDesc(format!(
+                    "callable `{}` has index {}, but shaders dispatch only {} material callables",
+                    kind.callable_entry_name(),
+                    index,
+                    MATERIAL_CALLABLE_COUNT,
+                )));
+            }
+
+            indices.push((*kind, index));
+        }
+
+        Ok(Self { indices })
+    }
+
+    pub fn callable_index(&self, kind: MaterialKind) -> Option<u32> {
+        self.indices
+            .iter()
+            .find(|(registered, _)| *registered == kind)
+            .map(|(_, index)| *index)
+    }
+
+    pub fn material_data(&self, material: &Material) -> Result<MaterialData> {
+        let callable_index = self
+            .callable_index(material.kind)
+            .ok_or_else(|| CottonError::InvalidPipelineDesc(format!(
+                "no callable for {:?}",
+                material.kind,
+            )))?;
+
+        let [r, g, b] = material.albedo;
+        let [er, eg, eb] = material.emission;
+
+        Ok(MaterialData {
+            callable_index,
+            albedo_texture: material.albedo_texture.map_or(NO_TEXTURE, |texture| texture as u32),
+            _padding: [0; 2],
+            albedo_roughness: [r, g, b, material.roughness],
+            emission_ior: [er, eg, eb, material.ior],
+        })
+    }
+
+    pub fn materials_data(&self, materials: &[Material]) -> Result<Vec<MaterialData>> {
+        materials.iter().map(|material| self.material_data(material)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::renderer::pipelines::Pipelines;
+    use super::*;
+
+    #[test]
+    fn classical_pipeline_maps_every_kind_in_order() {
+        let groups = Pipelines::classical_pipeline_desc().build().unwrap();
+        let callables = MaterialCallables::from_groups(&groups, &MaterialKind::ALL).unwrap();
+
+        for (index, kind) in MaterialKind::ALL.iter().enumerate() {
+            assert_eq!(callables.callable_index(*kind), Some(index as u32));
+        }
+    }
+
+    #[test]
+    fn index_follows_entry_name_not_registration_order() {
+        //マテリアル以外のcallableが先にあってもずれない
+        let desc = RayTracingPipelineDesc::new()
+            .raygen("raygen")
+            .callable("shadow_callable");
+        let desc = MaterialCallables::register(desc, &[MaterialKind::Emissive, MaterialKind::Metal, MaterialKind::Emissive]);
+
+        let groups = desc.build().unwrap();
+        let callables = MaterialCallables::from_groups(&groups, &[MaterialKind::Metal, MaterialKind::Emissive]).unwrap();
+
+        assert_eq!(callables.callable_index(MaterialKind::Emissive), Some(1));
+        assert_eq!(callables.callable_index(MaterialKind::Metal), Some(2));
+        assert_eq!(callables.callable_index(MaterialKind::Lambertian), None);
+    }
+
+    #[test]
+    fn missing_callable_is_rejected() {
+        let groups = MaterialCallables::register(RayTracingPipelineDesc::new().raygen("raygen"), &[MaterialKind::Metal])
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            MaterialCallables::from_groups(&groups, &[MaterialKind::Dielectric]),
+            Err(CottonError::InvalidPipelineDesc(_)),
+        ));
+    }
+
+    #[test]
+    fn callable_beyond_shader_dispatch_is_rejected() {
+        let mut desc = RayTracingPipelineDesc::new().raygen("raygen");
+        for i in 0..MATERIAL_CALLABLE_COUNT {
+            desc = desc.callable(&format!("other_callable_{}", i));
+        }
+
+        let groups = MaterialCallables::register(desc, &[MaterialKind::Lambertian]).build().unwrap();
+
+        assert!(matches!(
+            MaterialCallables::from_groups(&groups, &[MaterialKind::Lambertian]),
+            Err(CottonError::InvalidPipelineDesc(_)),
+        ));
+    }
+
+    #[test]
+    fn material_data_carries_kind_and_parameters() {
+        let groups = Pipelines::classical_pipeline_desc().build().unwrap();
+        let callables = MaterialCallables::from_groups(&groups, &MaterialKind::ALL).unwrap();
+
+        let data = callables.materials_data(&[
+            Material::metal([0.8, 0.6, 0.2], 0.3).albedo_texture(2),
+            Material::dielectric(1.5),
+            Material::emissive([4.0, 3.0, 2.0]),
+        ]).unwrap();
+
+        assert_eq!(data[0], MaterialData {
+            callable_index: 1,
+            albedo_texture: 2,
+            _padding: [0; 2],
+            albedo_roughness: [0.8, 0.6, 0.2, 0.3],
+            emission_ior: [0.0, 0.0, 0.0, 1.0],
+        });
+        assert_eq!(data[1].callable_index, 2);
+        assert_eq!(data[1].albedo_texture, NO_TEXTURE);
+        assert_eq!(data[1].emission_ior[3], 1.5);
+        assert_eq!(data[2].callable_index, 3);
+        assert_eq!(data[2].emission_ior, [4.0, 3.0, 2.0, 1.0]);
+    }
+
+    #[test]
+    fn material_data_matches_std430_layout() {
+        assert_eq!(std::mem::size_of::<MaterialData>(), 48);
+        assert_eq!(std::mem::align_of::<MaterialData>(), 4);
+    }
+}
@@ -2,6 +2,7 @@ use ash::Device;
 use ash::vk::{BufferUsageFlags, DeviceSize, MemoryPropertyFlags, PhysicalDeviceMemoryProperties};
 use classical_raytracer_shader::Vertex;
 use crate::buffers::Buffers;
+use crate::error::Result;
 
 pub struct MeshBuffer<'a> {
     device: &'a Device,
@@ -18,7 +19,7 @@ impl<'a> MeshBuffer<'a> {
         vertices: Vec<Vertex>,
         indices: Vec<u32>,
         device_memory_properties: PhysicalDeviceMemoryProperties,
-    ) -> Self {
+    ) -> Result<Self> {
         let vertex_stride = std::mem::size_of::<Vertex>();
         let vertex_buffer_size = vertex_stride * vertices.len();
         let max_vertex = vertices.len() as u32 - 1;
@@ -32,9 +33,9 @@ impl<'a> MeshBuffer<'a> {
                 | BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR,
             MemoryPropertyFlags::HOST_VISIBLE
                 | MemoryPropertyFlags::HOST_COHERENT,
-        );
+        )?;
 
-        vertex_buffer.store(&vertices);
+        vertex_buffer.store(&vertices)?;
 
         let index_buffer_size = std::mem::size_of::<u32>() * indices.len();
 
@@ -47,19 +48,19 @@ impl<'a> MeshBuffer<'a> {
                 | BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR,
             MemoryPropertyFlags::HOST_VISIBLE
                 | MemoryPropertyFlags::HOST_COHERENT,
-        );
+        )?;
 
-        index_buffer.store(&indices);
+        index_buffer.store(&indices)?;
 
         let vertex_stride = vertex_stride as u64;
 
-        Self {
+        Ok(Self {
             device,
             vertex_stride,
             max_vertex,
             vertex_buffer,
             indices_count: indices.len() as u32,
             index_buffer,
-        }
+        })
     }
 }
\ No newline at end of file
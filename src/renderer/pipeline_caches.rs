@@ -0,0 +1,470 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use ash::Device;
+use ash::vk::{PhysicalDeviceProperties, PipelineCache, PipelineCacheCreateInfo, PipelineCacheHeaderVersion, UUID_SIZE};
+use log::{debug, info, warn};
+use crate::constants::{APPLICATION_NAME, PIPELINE_CACHE_DIR_ENV};
+use crate::error::Result;
+use crate::renderer::backends::Backends;
+
+//VK_PIPELINE_CACHE_HEADER_VERSION_ONEのヘッダーは32byte
+pub const PIPELINE_CACHE_HEADER_SIZE: usize = 16 + UUID_SIZE;
+
+const CACHE_FILE_PREFIX: &str = "pipeline_cache_";
+const CACHE_FILE_EXTENSION: &str = "bin";
+//write_atomicの一時ファイルは拡張子がtmp-<pid>になる
+const TEMP_FILE_EXTENSION_PREFIX: &str = "tmp-";
+
+//書き込み中の他のプロセスの一時ファイルを消さないように少し待つ
+pub const PIPELINE_CACHE_TEMP_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+//この期間使われていない他のデバイスのキャッシュは削除する
+pub const PIPELINE_CACHE_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PipelineCacheHeader {
+    pub header_size: u32,
+    pub header_version: u32,
+    pub vendor_id: u32,
+    pub device_id: u32,
+    pub pipeline_cache_uuid: [u8; UUID_SIZE],
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PipelineCacheMismatch {
+    TooShort(usize),
+    HeaderSize(u32),
+    HeaderVersion(u32),
+    VendorId { expected: u32, actual: u32 },
+    DeviceId { expected: u32, actual: u32 },
+    /// ドライバーが更新された場合など
+    PipelineCacheUuid,
+}
+
+impl PipelineCacheHeader {
+    pub fn from_properties(properties: &PhysicalDeviceProperties) -> Self {
+        Self {
+            header_size: PIPELINE_CACHE_HEADER_SIZE as u32,
+            header_version: PipelineCacheHeaderVersion::ONE.as_raw() as u32,
+            vendor_id: properties.vendor_id,
+            device_id: properties.device_id,
+            pipeline_cache_uuid: properties.pipeline_cache_uuid,
+        }
+    }
+
+    /// ヘッダーはリトルエンディアンで書かれている
+    pub fn parse(data: &[u8]) -> std::result::Result<Self, PipelineCacheMismatch> {
+        if data.len() < PIPELINE_CACHE_HEADER_SIZE {
+            return Err(PipelineCacheMismatch::TooShort(data.len()));
+        }
+
+        let read_u32 = |offset: usize| {
+            u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+        };
+
+        let mut pipeline_cache_uuid = [0u8; UUID_SIZE];
+        pipeline_cache_uuid.copy_from_slice(&data[16..16 + UUID_SIZE]);
+
+        Ok(Self {
+            header_size: read_u32(0),
+            header_version: read_u32(4),
+            vendor_id: read_u32(8),
+            device_id: read_u32(12),
+            pipeline_cache_uuid,
+        })
+    }
+
+    /// 今のデバイスで使えるキャッシュかどうか
+    pub fn validate(&self, expected: &Self) -> std::result::Result<(), PipelineCacheMismatch> {
+        if (self.header_size as usize) < PIPELINE_CACHE_HEADER_SIZE {
+            return Err(PipelineCacheMismatch::HeaderSize(self.header_size));
+        }
+
+        if self.header_version != expected.header_version {
+            return Err(PipelineCacheMismatch::HeaderVersion(self.header_version));
+        }
+
+        if self.vendor_id != expected.vendor_id {
+            return Err(PipelineCacheMismatch::VendorId { expected: expected.vendor_id, actual: self.vendor_id });
+        }
+
+        if self.device_id != expected.device_id {
+            return Err(PipelineCacheMismatch::DeviceId { expected: expected.device_id, actual: self.device_id });
+        }
+
+        if self.pipeline_cache_uuid != expected.pipeline_cache_uuid {
+            return Err(PipelineCacheMismatch::PipelineCacheUuid);
+        }
+
+        Ok(())
+    }
+
+    /// デバイスとドライバーごとにファイルを分ける
+    pub fn file_name(&self) -> String {
+        let uuid: String = self.pipeline_cache_uuid.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+        format!(
+            "{}{:08x}_{:08x}_{}.{}",
+            CACHE_FILE_PREFIX,
+            self.vendor_id,
+            self.device_id,
+            uuid,
+            CACHE_FILE_EXTENSION,
+        )
+    }
+}
+
+/// ファイルの中身をそのまま使えるか確認する
+pub fn validate_cache_data(data: &[u8], expected: &PipelineCacheHeader) -> std::result::Result<(), PipelineCacheMismatch> {
+    PipelineCacheHeader::parse(data)?.validate(expected)
+}
+
+/// 今のデバイスのもの以外で、同じデバイスの古いドライバーのものか長く使われていないものは削除対象
+/// 途中で落ちて残った一時ファイルも削除する
+pub fn is_stale_cache_file(
+    file_name: &str,
+    modified: SystemTime,
+    current: &PipelineCacheHeader,
+    now: SystemTime,
+    max_age: Duration,
+) -> bool {
+    if !file_name.starts_with(CACHE_FILE_PREFIX) {
+        return false;
+    }
+
+    let is_older_than = |age: Duration| now.duration_since(modified).map_or(false, |elapsed| elapsed > age);
+
+    let is_temp_file = file_name
+        .rsplit_once('.')
+        .map_or(false, |(_, extension)| extension.starts_with(TEMP_FILE_EXTENSION_PREFIX));
+    if is_temp_file {
+        return is_older_than(PIPELINE_CACHE_TEMP_MAX_AGE);
+    }
+
+    if !file_name.ends_with(CACHE_FILE_EXTENSION) {
+        return false;
+    }
+
+    let current_file_name = current.file_name();
+
+    if file_name == current_file_name {
+        return false;
+    }
+
+    //uuidの前までが同じなら同じデバイス
+    let device_prefix_len = current_file_name.len() - (UUID_SIZE * 2 + CACHE_FILE_EXTENSION.len() + 1);
+    if file_name.starts_with(&current_file_name[..device_prefix_len]) {
+        return true;
+    }
+
+    is_older_than(max_age)
+}
+
+/// 環境変数で指定されていなければOSごとのユーザーキャッシュディレクトリを使う
+pub fn default_cache_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os(PIPELINE_CACHE_DIR_ENV) {
+        return Some(PathBuf::from(dir));
+    }
+
+    let base = if cfg!(target_os = "windows") {
+        std::env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join("Library").join("Caches"))
+    } else {
+        std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+    };
+
+    base.map(|base| base.join(APPLICATION_NAME))
+}
+
+/// 一時ファイルに書いてからrenameするので、途中で落ちても壊れたキャッシュが残らない
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension(format!("{}{}", TEMP_FILE_EXTENSION_PREFIX, std::process::id()));
+
+    let result = fs::File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp_path, path));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+
+    Ok(result?)
+}
+
+pub struct PipelineCaches<'a> {
+    device: &'a Device,
+    pub pipeline_cache: PipelineCache,
+    /// Noneの場合はディスクに保存しない
+    path: Option<PathBuf>,
+    header: PipelineCacheHeader,
+}
+
+impl<'a> PipelineCaches<'a> {
+    pub fn new(backends: &'a Backends) -> Result<Self> {
+        Self::with_dir(backends, default_cache_dir())
+    }
+
+    /// キャッシュが使えない場合は空のキャッシュから始める
+    pub fn with_dir(backends: &'a Backends, dir: Option<PathBuf>) -> Result<Self> {
+        let properties = unsafe {
+            backends.instance.get_physical_device_properties(backends.physical_device)
+        };
+
+        let header = PipelineCacheHeader::from_properties(&properties);
+
+        let path = dir.and_then(|dir| match fs::create_dir_all(&dir) {
+            Ok(()) => Some(dir.join(header.file_name())),
+            Err(err) => {
+                warn!("pipeline cache directory {} is not available: {}", dir.display(), err);
+                None
+            }
+        });
+
+        if let Some(dir) = path.as_ref().and_then(|path| path.parent()) {
+            Self::evict_stale(dir, &header);
+        }
+
+        let initial_data = path
+            .as_ref()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|data| match validate_cache_data(&data, &header) {
+                Ok(()) => {
+                    info!("load pipeline cache ({} bytes)", data.len());
+                    Some(data)
+                }
+                Err(mismatch) => {
+                    warn!("discard pipeline cache: {:?}", mismatch);
+                    None
+                }
+            })
+            .unwrap_or_default();
+
+        let pipeline_cache = unsafe {
+            backends.device.create_pipeline_cache(
+                &PipelineCacheCreateInfo::builder()
+                    .initial_data(&initial_data)
+                    .build(),
+                None,
+            )?
+        };
+
+        Ok(Self {
+            device: &backends.device,
+            pipeline_cache,
+            path,
+            header,
+        })
+    }
+
+    fn evict_stale(dir: &Path, header: &PipelineCacheHeader) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+
+        let now = SystemTime::now();
+
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let modified = match entry.metadata().and_then(|metadata| metadata.modified()) {
+                Ok(modified) => modified,
+                Err(_) => continue,
+            };
+
+            if is_stale_cache_file(&file_name, modified, header, now, PIPELINE_CACHE_MAX_AGE) {
+                debug!("evict stale pipeline cache {}", file_name);
+
+                if let Err(err) = fs::remove_file(entry.path()) {
+                    warn!("failed to evict pipeline cache {}: {}", file_name, err);
+                }
+            }
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let data = unsafe { self.device.get_pipeline_cache_data(self.pipeline_cache)? };
+
+        //ドライバーが変なデータを返した場合は保存しない
+        if let Err(mismatch) = validate_cache_data(&data, &self.header) {
+            warn!("pipeline cache data from driver is invalid: {:?}", mismatch);
+            return Ok(());
+        }
+
+        write_atomic(path, &data)?;
+
+        debug!("save pipeline cache ({} bytes) to {}", data.len(), path.display());
+
+        Ok(())
+    }
+}
+
+impl Drop for PipelineCaches<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_pipeline_cache(self.pipeline_cache, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn header() -> PipelineCacheHeader {
+        PipelineCacheHeader {
+            header_size: PIPELINE_CACHE_HEADER_SIZE as u32,
+            header_version: PipelineCacheHeaderVersion::ONE.as_raw() as u32,
+            vendor_id: 0x10de,
+            device_id: 0x2484,
+            pipeline_cache_uuid: [0xab; UUID_SIZE],
+        }
+    }
+
+    //ドライバーが返すものと同じレイアウトのblobを作る
+    fn blob(header: &PipelineCacheHeader, payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![];
+        data.extend_from_slice(&header.header_size.to_le_bytes());
+        data.extend_from_slice(&header.header_version.to_le_bytes());
+        data.extend_from_slice(&header.vendor_id.to_le_bytes());
+        data.extend_from_slice(&header.device_id.to_le_bytes());
+        data.extend_from_slice(&header.pipeline_cache_uuid);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn parse_reads_little_endian_fields() {
+        let expected = header();
+        let data = blob(&expected, &[1, 2, 3]);
+
+        assert_eq!(PipelineCacheHeader::parse(&data), Ok(expected));
+        assert_eq!(validate_cache_data(&data, &expected), Ok(()));
+    }
+
+    #[test]
+    fn parse_rejects_truncated_data() {
+        let data = blob(&header(), &[]);
+
+        assert_eq!(PipelineCacheHeader::parse(&[]), Err(PipelineCacheMismatch::TooShort(0)));
+        assert_eq!(
+            PipelineCacheHeader::parse(&data[..PIPELINE_CACHE_HEADER_SIZE - 1]),
+            Err(PipelineCacheMismatch::TooShort(PIPELINE_CACHE_HEADER_SIZE - 1)),
+        );
+    }
+
+    #[test]
+    fn validate_reports_first_mismatch() {
+        let expected = header();
+
+        let cases = [
+            (PipelineCacheHeader { header_size: 16, ..expected }, PipelineCacheMismatch::HeaderSize(16)),
+            (PipelineCacheHeader { header_version: 2, ..expected }, PipelineCacheMismatch::HeaderVersion(2)),
+            (
+                PipelineCacheHeader { vendor_id: 0x1002, ..expected },
+                PipelineCacheMismatch::VendorId { expected: 0x10de, actual: 0x1002 },
+            ),
+            (
+                PipelineCacheHeader { device_id: 0x2204, ..expected },
+                PipelineCacheMismatch::DeviceId { expected: 0x2484, actual: 0x2204 },
+            ),
+            (
+                PipelineCacheHeader { pipeline_cache_uuid: [0xcd; UUID_SIZE], ..expected },
+                PipelineCacheMismatch::PipelineCacheUuid,
+            ),
+        ];
+
+        for (actual, mismatch) in cases {
+            assert_eq!(validate_cache_data(&blob(&actual, &[]), &expected), Err(mismatch));
+        }
+    }
+
+    #[test]
+    fn larger_header_size_is_accepted() {
+        let expected = header();
+        let actual = PipelineCacheHeader { header_size: 64, ..expected };
+
+        assert_eq!(actual.validate(&expected), Ok(()));
+    }
+
+    #[test]
+    fn file_name_contains_device_and_uuid() {
+        assert_eq!(
+            header().file_name(),
+            format!("pipeline_cache_000010de_00002484_{}.bin", "ab".repeat(UUID_SIZE)),
+        );
+    }
+
+    #[test]
+    fn stale_cache_files() {
+        let current = header();
+        let now = SystemTime::now();
+        let recent = now - DAY;
+        let old = now - PIPELINE_CACHE_MAX_AGE - DAY;
+
+        let old_driver = PipelineCacheHeader { pipeline_cache_uuid: [0xcd; UUID_SIZE], ..current }.file_name();
+        let other_device = PipelineCacheHeader { device_id: 0x2204, ..current }.file_name();
+
+        //今のデバイスのものは古くても残す
+        assert!(!is_stale_cache_file(&current.file_name(), old, &current, now, PIPELINE_CACHE_MAX_AGE));
+        //同じデバイスの古いドライバーのものはすぐに消す
+        assert!(is_stale_cache_file(&old_driver, recent, &current, now, PIPELINE_CACHE_MAX_AGE));
+        //他のデバイスのものは使われなくなってから消す
+        assert!(!is_stale_cache_file(&other_device, recent, &current, now, PIPELINE_CACHE_MAX_AGE));
+        assert!(is_stale_cache_file(&other_device, old, &current, now, PIPELINE_CACHE_MAX_AGE));
+        //関係ないファイルは消さない
+        assert!(!is_stale_cache_file("notes.txt", old, &current, now, PIPELINE_CACHE_MAX_AGE));
+        assert!(!is_stale_cache_file("pipeline_cache_notes.txt", old, &current, now, PIPELINE_CACHE_MAX_AGE));
+    }
+
+    #[test]
+    fn leftover_temp_files_are_stale() {
+        let current = header();
+        let now = SystemTime::now();
+        let temp_file = Path::new(&current.file_name())
+            .with_extension("tmp-4242")
+            .to_string_lossy()
+            .into_owned();
+
+        //書き込み中かもしれないので新しいものは残す
+        assert!(!is_stale_cache_file(&temp_file, now, &current, now, PIPELINE_CACHE_MAX_AGE));
+        assert!(is_stale_cache_file(
+            &temp_file,
+            now - PIPELINE_CACHE_TEMP_MAX_AGE - Duration::from_secs(1),
+            &current,
+            now,
+            PIPELINE_CACHE_MAX_AGE,
+        ));
+    }
+
+    #[test]
+    fn write_atomic_leaves_no_temp_file() {
+        let dir = std::env::temp_dir().join(format!("cotton_pipeline_cache_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(header().file_name());
+
+        write_atomic(&path, b"cache").unwrap();
+
+        let file_names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(file_names, vec![header().file_name()]);
+    }
+}
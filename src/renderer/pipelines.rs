@@ -1,389 +1,347 @@
 use std::ffi::{CStr, CString};
 use ash::{Device, Instance, vk};
 use ash::extensions::khr::{AccelerationStructure, RayTracingPipeline};
-use ash::vk::{AccelerationStructureNV, BufferUsageFlags, DeferredOperationKHR, DescriptorBufferInfo, DescriptorImageInfo, DescriptorPoolCreateInfo, DescriptorPoolSize, DescriptorSetAllocateInfo, DescriptorSetLayout, DescriptorSetLayoutBinding, DescriptorSetLayoutCreateInfo, DescriptorSetVariableDescriptorCountAllocateInfo, DescriptorType, DeviceSize, Extent2D, ImageAspectFlags, ImageLayout, ImageSubresourceRange, ImageView, MemoryPropertyFlags, PhysicalDevice, PhysicalDeviceProperties2, PhysicalDeviceRayTracingPipelinePropertiesKHR, Pipeline, PipelineCache, PipelineLayout, PipelineLayoutCreateInfo, PipelineShaderStageCreateInfo, PushConstantRange, Queue, RayTracingPipelineCreateInfoKHR, RayTracingShaderGroupCreateInfoKHR, RayTracingShaderGroupTypeKHR, SHADER_UNUSED_KHR, ShaderModule, ShaderStageFlags, StridedDeviceAddressRegionKHR, WHOLE_SIZE, WriteDescriptorSet, WriteDescriptorSetAccelerationStructureKHR};
+use ash::vk::{AccelerationStructureNV, BufferUsageFlags, CommandBuffer, DeferredOperationKHR, DescriptorBufferInfo, DescriptorImageInfo, DescriptorPoolCreateInfo, DescriptorPoolSize, DescriptorSetAllocateInfo, DescriptorSetLayout, DescriptorSetLayoutBinding, DescriptorSetLayoutCreateInfo, DescriptorSetVariableDescriptorCountAllocateInfo, DescriptorType, DeviceSize, Extent2D, ImageAspectFlags, ImageLayout, ImageSubresourceRange, ImageView, MemoryPropertyFlags, PhysicalDevice, PhysicalDeviceProperties2, PhysicalDeviceRayTracingPipelinePropertiesKHR, Pipeline, PipelineBindPoint, PipelineCache, PipelineLayout, PipelineLayoutCreateInfo, PipelineShaderStageCreateInfo, PushConstantRange, Queue, RayTracingPipelineCreateInfoKHR, RayTracingShaderGroupCreateInfoKHR, RayTracingShaderGroupTypeKHR, SHADER_UNUSED_KHR, ShaderModule, ShaderStageFlags, StridedDeviceAddressRegionKHR, WHOLE_SIZE, WriteDescriptorSet, WriteDescriptorSetAccelerationStructureKHR};
 use bytes::Buf;
-use log::debug;
+use classical_raytracer_shader::RayGenerationConstants;
+use log::{debug, warn};
 use crate::buffers::Buffers;
-use crate::constants::{FRAGMENT_SHADER_ENTRY_NAME, MISS_SHADER_ENTRY_NAME, MISS_SHADER_ENTRY_NAME_BYTE, RAY_GENERATION_SHADER_ENTRY_NAME, RAY_GENERATION_SHADER_ENTRY_NAME_BYTE, SPHERE_CLOSEST_HIT_SHADER_ENTRY_NAME, SPHERE_CLOSEST_HIT_SHADER_ENTRY_NAME_BYTE, SPHERE_INTERSECTION_SHADER_ENTRY_NAME, SPHERE_INTERSECTION_SHADER_ENTRY_NAME_BYTE, TRIANGLE_ANY_HIT_SHADER_ENTRY_NAME, TRIANGLE_ANY_HIT_SHADER_ENTRY_NAME_BYTE, TRIANGLE_CLOSEST_HIT_SHADER_ENTRY_NAME, TRIANGLE_CLOSEST_HIT_SHADER_ENTRY_NAME_BYTE, VERTEX_SHADER_ENTRY_NAME};
+use crate::error::{CottonError, Result};
+use crate::constants::{MISS_SHADER_ENTRY_NAME, RAY_GENERATION_SHADER_ENTRY_NAME, SPHERE_CLOSEST_HIT_SHADER_ENTRY_NAME, SPHERE_HIT_GROUP_NAME, SPHERE_INTERSECTION_SHADER_ENTRY_NAME, TRIANGLE_ANY_HIT_SHADER_ENTRY_NAME, TRIANGLE_CLOSEST_HIT_SHADER_ENTRY_NAME, TRIANGLE_HIT_GROUP_NAME};
 use crate::renderer::acceleration_structures::AccelerationStructures;
 use crate::renderer::acceleration_structures::top_level_acceleration_structures::TopLevelAccelerationStructures;
 use crate::renderer::acceleration_structures::triangle_bottom_level_acceleration_structure::TriangleBottomLevelAccelerationStructure;
 use crate::renderer::backends::Backends;
-use crate::renderer::mesh_buffer::MeshBuffer;
+use crate::renderer::descriptor_sets::{DescriptorResource, DescriptorSetBuilder, DescriptorSetDeclaration, DescriptorSets};
+use crate::renderer::materials::{MaterialCallables, MaterialKind};
+use crate::renderer::pipeline_caches::PipelineCaches;
+use crate::renderer::ray_tracing_pipeline_desc::{RayTracingPipelineDesc, RayTracingShaderGroups};
 use crate::renderer::render_passes::RenderPasses;
+use crate::renderer::scene_buffers::SceneBuffers;
+use crate::renderer::shader_binding_table::{ShaderBindingTable, ShaderGroupHandleProperties};
 use crate::renderer::shader_module::ShaderModules;
+use crate::renderer::texture_images::TextureImages;
+
+//raygenで使うpush constantのサイズ
+pub const PUSH_CONSTANT_SIZE: u32 = std::mem::size_of::<RayGenerationConstants>() as u32;
+
+//描画先のstorage image
+const TARGET_IMAGE_BINDING: u32 = 1;
+
+//シーンのデータ、シェーダーのbindingと合わせる
+const MATERIALS_BINDING: u32 = 2;
+const VERTICES_BINDING: u32 = 3;
+const INDICES_BINDING: u32 = 4;
+const INSTANCES_BINDING: u32 = 5;
+
+//マテリアルが参照するテクスチャの配列、可変長なので一番最後のbindingにする
+const TEXTURES_BINDING: u32 = 6;
+
+/// 一つのシーンで使えるテクスチャの数
+pub const MAX_TEXTURES: u32 = 1024;
 
 pub struct Pipelines<'a> {
     pub device: &'a Device,
     pub pipeline: Pipeline,
+    pub pipeline_layout: PipelineLayout,
+    pub shader_groups: RayTracingShaderGroups,
+    /// material bufferのcallable_indexを求めるのに使う
+    pub material_callables: MaterialCallables,
+    pub descriptor_set_declaration: DescriptorSetDeclaration,
+    pub descriptor_sets: DescriptorSets<'a>,
+    pub shader_binding_table: ShaderBindingTable<'a>,
+    /// reloadのたびにファイルを読み直さないように呼び出し側で一つだけ作る
+    pub pipeline_caches: &'a PipelineCaches<'a>,
 
     pub(crate) ray_tracing_pipeline: RayTracingPipeline,
     pub(crate) ray_tracing_pipeline_properties: PhysicalDeviceRayTracingPipelinePropertiesKHR,
 }
 
 impl<'a> Pipelines<'a> {
+    /// classical_raytracer_shaderのentry pointの組み合わせ
+    pub fn classical_pipeline_desc() -> RayTracingPipelineDesc {
+        let desc = RayTracingPipelineDesc::new()
+            .raygen(RAY_GENERATION_SHADER_ENTRY_NAME)
+            .miss(MISS_SHADER_ENTRY_NAME)
+            .procedural_hit_group(
+                SPHERE_HIT_GROUP_NAME,
+                SPHERE_INTERSECTION_SHADER_ENTRY_NAME,
+                Some(SPHERE_CLOSEST_HIT_SHADER_ENTRY_NAME),
+                None,
+            )
+            //intersectionを指定しない場合は組み込みの三角形との交差判定が使われる
+            .triangle_hit_group(
+                TRIANGLE_HIT_GROUP_NAME,
+                Some(TRIANGLE_CLOSEST_HIT_SHADER_ENTRY_NAME),
+                Some(TRIANGLE_ANY_HIT_SHADER_ENTRY_NAME),
+            )
+            //反射はraygenのループで追うのでclosest hitからはtrace_rayしない
+            .max_recursion_depth(1);
+
+        MaterialCallables::register(desc, &MaterialKind::ALL)
+    }
+
     //with raytracing
     pub fn new(
         backends: &'a Backends,
         shader_modules: ShaderModules,
+        //シーンのinstanceがhit groupを参照するので先に作っておく
+        shader_groups: RayTracingShaderGroups,
         swapchain_extent: Extent2D,
         render_passes: &RenderPasses,
 
-        //asとvertexとindexをまとめたほうが良い
-        mesh_buffer: &MeshBuffer,
-        top_level_acceleration_structures: TopLevelAccelerationStructures,
+        //全てのmeshとinstanceとmaterial
+        scene_buffers: &SceneBuffers,
+        //descriptorが参照するのでpipelineより長く生存させる
+        top_level_acceleration_structures: &TopLevelAccelerationStructures,
+        textures: &TextureImages,
+        pipeline_caches: &'a PipelineCaches<'a>,
 
         graphics_queue: Queue,
         target_image_view: ImageView,
-    ) -> Self {
+    ) -> Result<Self> {
         debug!("create pipeline");
 
         //Descriptor Binding
 
-        //参考にしたものから3番目のmaterial bufferを削除
-        let bindings = [
-            DescriptorSetLayoutBinding::builder()
-                .descriptor_count(1)
-                .descriptor_type(vk::DescriptorType::ACCELERATION_STRUCTURE_KHR)
-                .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR)
-                .binding(0)
-                .build(),
-            DescriptorSetLayoutBinding::builder()
-                .descriptor_count(1)
-                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
-                .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR)
-                .binding(1)
-                .build(),
-            //VertexBuffer
-            DescriptorSetLayoutBinding::builder()
-                .descriptor_count(1)
-                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
-                .stage_flags(vk::ShaderStageFlags::CLOSEST_HIT_KHR)
-                .binding(3)
-                .build(),
-            //IndexBuffer
-            DescriptorSetLayoutBinding::builder()
-                .descriptor_count(1)
-                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
-                .stage_flags(vk::ShaderStageFlags::CLOSEST_HIT_KHR)
-                .binding(4)
-                .build(),
-        ];
-
-        let (
-            pipeline_layout,
-            descriptor_set_layout
-        ) = Self::create_pipeline_layout(&backends.device, &bindings);
-
-        let descriptor_sizes = [
-            DescriptorPoolSize {
-                ty: DescriptorType::ACCELERATION_STRUCTURE_KHR,
-                descriptor_count: 1,
-            },
-            DescriptorPoolSize {
-                ty: DescriptorType::STORAGE_IMAGE,
-                descriptor_count: 1,
-            },
-            DescriptorPoolSize {
-                ty: DescriptorType::STORAGE_BUFFER,
-                descriptor_count: 1,
-            },
-            DescriptorPoolSize {
-                ty: DescriptorType::STORAGE_BUFFER,
-                descriptor_count: 1,
-            },
-        ];
-
-        let descriptor_pool_info = DescriptorPoolCreateInfo::builder()
-            .pool_sizes(&descriptor_sizes)
-            .max_sets(1)
-            .build();
+        let hit_stages = ShaderStageFlags::CLOSEST_HIT_KHR | ShaderStageFlags::ANY_HIT_KHR;
 
-        let descriptor_pool = unsafe {
-            backends.device.create_descriptor_pool(&descriptor_pool_info, None).unwrap()
-        };
+        let descriptor_set_declaration = DescriptorSetBuilder::new()
+            .binding(
+                0,
+                ShaderStageFlags::RAYGEN_KHR,
+                DescriptorResource::acceleration_structure(top_level_acceleration_structures.top_level_acceleration_structure_khr),
+            )
+            .binding(
+                TARGET_IMAGE_BINDING,
+                ShaderStageFlags::RAYGEN_KHR,
+                DescriptorResource::storage_image(target_image_view),
+            )
+            .binding(
+                MATERIALS_BINDING,
+                hit_stages | ShaderStageFlags::CALLABLE_KHR,
+                DescriptorResource::storage_buffer(scene_buffers.material_buffer.buffer),
+            )
+            .binding(
+                VERTICES_BINDING,
+                hit_stages,
+                DescriptorResource::storage_buffer(scene_buffers.vertex_buffer.buffer),
+            )
+            .binding(
+                INDICES_BINDING,
+                hit_stages,
+                DescriptorResource::storage_buffer(scene_buffers.index_buffer.buffer),
+            )
+            .binding(
+                INSTANCES_BINDING,
+                hit_stages,
+                DescriptorResource::storage_buffer(scene_buffers.instance_buffer.buffer),
+            )
+            .variable_binding(
+                TEXTURES_BINDING,
+                ShaderStageFlags::ANY_HIT_KHR | ShaderStageFlags::CALLABLE_KHR,
+                textures.descriptor_resource(),
+                MAX_TEXTURES,
+            )
+            .build()?;
 
-        let descriptor_counts = [1];
+        let material_callables = MaterialCallables::from_groups(&shader_groups, &MaterialKind::ALL)?;
 
-        let mut count_allocate_info = DescriptorSetVariableDescriptorCountAllocateInfo::builder()
-            .descriptor_counts(&descriptor_counts)
-            .build();
+        //Descriptor部分はPipelineに含めないほうが良い
+        let descriptor_sets = descriptor_set_declaration.create(&backends.device)?;
 
-        let descriptor_sets = unsafe {
-            //Descriptorはそもそも複数存在し、またDescriptorSetも複数存在する(?)
-            backends.device.allocate_descriptor_sets(
-                &DescriptorSetAllocateInfo::builder()
-                    .descriptor_pool(descriptor_pool)
-                    .set_layouts(&[descriptor_set_layout])
-                    .push_next(&mut count_allocate_info)
-                    .build()
-            ).unwrap()
-        };
+        let pipeline_layout = Self::create_pipeline_layout(&backends.device, descriptor_sets.descriptor_set_layout)?;
 
-        let descriptor_set = descriptor_sets[0];
+        let (rt_pipeline_properties, rt_pipeline)
+            = Self::create_raytracing_structure(&backends.instance, backends.physical_device, &backends.device);
 
-        let tlas_structs = [top_level_acceleration_structures.top_level_acceleration_structure_khr];
+        let (pipeline, shader_binding_table) = match Self::create_pipeline(
+            backends,
+            &shader_modules,
+            &shader_groups,
+            &descriptor_set_declaration,
+            pipeline_layout,
+            pipeline_caches.pipeline_cache,
+            &rt_pipeline,
+            &rt_pipeline_properties,
+        ) {
+            Ok(created) => created,
+            Err(err) => {
+                unsafe { backends.device.destroy_pipeline_layout(pipeline_layout, None) };
+
+                return Err(err);
+            }
+        };
 
-        let mut acceleration_structure_info = WriteDescriptorSetAccelerationStructureKHR::builder()
-            .acceleration_structures(&tlas_structs)
-            .build();
+        //保存できなくても次回コンパイルし直すだけなので続ける
+        if let Err(err) = pipeline_caches.save() {
+            warn!("failed to save pipeline cache: {}", err);
+        }
 
-        let mut acceleration_structure_write = WriteDescriptorSet::builder()
-            .dst_set(descriptor_set)
-            .dst_binding(0)
-            .dst_array_element(0)
-            .descriptor_type(DescriptorType::ACCELERATION_STRUCTURE_KHR)
-            .push_next(&mut acceleration_structure_info)
+        let range = ImageSubresourceRange::builder()
+            .aspect_mask(ImageAspectFlags::COLOR)
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1)
             .build();
 
-        //Descriptor部分はPipelineに含めないほうが良い
+        Ok(Self {
+            device: &backends.device,
+            pipeline,
+            pipeline_layout,
+            shader_groups,
+            material_callables,
+            descriptor_set_declaration,
+            descriptor_sets,
+            shader_binding_table,
+            pipeline_caches,
+            ray_tracing_pipeline_properties: rt_pipeline_properties,
+            ray_tracing_pipeline: rt_pipeline,
+        })
+    }
 
-        //AccelerationStructureだとdescriptor_countが自動セットされないので手動で設定する必要がある
-        acceleration_structure_write.descriptor_count = 1;
-
-        let image_info = [DescriptorImageInfo::builder()
-            .image_layout(ImageLayout::GENERAL)
-            .image_view(target_image_view)
-            .build()
-        ];
-
-        let image_write = WriteDescriptorSet::builder()
-            .dst_set(descriptor_set)
-            .dst_binding(1)
-            .dst_array_element(0)
-            .descriptor_type(DescriptorType::STORAGE_IMAGE)
-            .image_info(&image_info)
-            .build();
+    /// descriptor setとlayoutはそのままに、新しいシェーダーでpipelineとSBTを作り直す
+    /// 失敗した場合は今までのpipelineを使い続ける
+    pub fn reload(&mut self, backends: &'a Backends, shader_modules: ShaderModules) -> Result<()> {
+        debug!("reload pipeline");
+
+        let (pipeline, shader_binding_table) = Self::create_pipeline(
+            backends,
+            &shader_modules,
+            &self.shader_groups,
+            &self.descriptor_set_declaration,
+            self.pipeline_layout,
+            self.pipeline_caches.pipeline_cache,
+            &self.ray_tracing_pipeline,
+            &self.ray_tracing_pipeline_properties,
+        )?;
+
+        //使用中のpipelineとSBTを破棄するので終わるまで待つ
+        if let Err(err) = unsafe { self.device.device_wait_idle() } {
+            unsafe { self.device.destroy_pipeline(pipeline, None) };
+
+            return Err(err.into());
+        }
 
-        let vertex_info = [DescriptorBufferInfo::builder()
-            .buffer(mesh_buffer.vertex_buffer.buffer)
-            .range(WHOLE_SIZE)
-            .build()
-        ];
-
-        let vertex_write = WriteDescriptorSet::builder()
-            .dst_set(descriptor_set)
-            .dst_binding(2)
-            .dst_array_element(0)
-            .descriptor_type(DescriptorType::STORAGE_BUFFER)
-            .buffer_info(&vertex_info)
-            .build();
+        let old_pipeline = std::mem::replace(&mut self.pipeline, pipeline);
+        self.shader_binding_table = shader_binding_table;
 
-        let index_info = [DescriptorBufferInfo::builder()
-            .buffer(mesh_buffer.index_buffer.buffer)
-            .range(WHOLE_SIZE)
-            .build()
-        ];
-
-        let index_write = WriteDescriptorSet::builder()
-            .dst_set(descriptor_set)
-            .dst_binding(3)
-            .dst_array_element(0)
-            .descriptor_type(DescriptorType::STORAGE_BUFFER)
-            .buffer_info(&index_info)
-            .build();
+        unsafe { self.device.destroy_pipeline(old_pipeline, None) };
+
+        if let Err(err) = self.pipeline_caches.save() {
+            warn!("failed to save pipeline cache: {}", err);
+        }
+
+        Ok(())
+    }
+
+    /// raygenをextentの大きさでdispatchする、描画先はGENERALのままにしておく
+    pub fn record_trace_rays(&self, command_buffer: CommandBuffer, constants: &RayGenerationConstants, extent: Extent2D) {
+        let constants = unsafe {
+            std::slice::from_raw_parts(
+                (constants as *const RayGenerationConstants).cast::<u8>(),
+                PUSH_CONSTANT_SIZE as usize,
+            )
+        };
+
+        let [raygen, miss, hit, callable] = self.shader_binding_table.regions();
 
         unsafe {
-            backends.device.update_descriptor_sets(
-                &[
-                    acceleration_structure_write,
-                    image_write,
-                    vertex_write,
-                    index_write,
-                ],
+            self.device.cmd_bind_pipeline(command_buffer, PipelineBindPoint::RAY_TRACING_KHR, self.pipeline);
+
+            self.device.cmd_bind_descriptor_sets(
+                command_buffer,
+                PipelineBindPoint::RAY_TRACING_KHR,
+                self.pipeline_layout,
+                0,
+                &[self.descriptor_sets.descriptor_set],
                 &[],
-            )
+            );
+
+            self.device.cmd_push_constants(
+                command_buffer,
+                self.pipeline_layout,
+                ShaderStageFlags::RAYGEN_KHR,
+                0,
+                constants,
+            );
+
+            self.ray_tracing_pipeline.cmd_trace_rays(
+                command_buffer,
+                &raygen,
+                &miss,
+                &hit,
+                &callable,
+                extent.width,
+                extent.height,
+                1,
+            );
         }
+    }
 
-        //stage
-
-        let shader_stages = unsafe {
-            let ray_generation_stage_info = PipelineShaderStageCreateInfo::builder()
-                .stage(ShaderStageFlags::RAYGEN_KHR)
-                .module(shader_modules.shader_module)
-                .name(CStr::from_bytes_with_nul(RAY_GENERATION_SHADER_ENTRY_NAME_BYTE).unwrap())
-                .build();
-
-            let miss_stage_info = PipelineShaderStageCreateInfo::builder()
-                .stage(ShaderStageFlags::MISS_KHR)
-                .module(shader_modules.shader_module)
-                .name(CStr::from_bytes_with_nul(MISS_SHADER_ENTRY_NAME_BYTE).unwrap())
-                .build();
-
-            let sphere_intersection_stage_info = PipelineShaderStageCreateInfo::builder()
-                .stage(ShaderStageFlags::INTERSECTION_KHR)
-                .module(shader_modules.shader_module)
-                .name(CStr::from_bytes_with_nul(SPHERE_INTERSECTION_SHADER_ENTRY_NAME_BYTE).unwrap())
-                .build();
-
-            let sphere_closest_hit_stage_info = PipelineShaderStageCreateInfo::builder()
-                .stage(ShaderStageFlags::CLOSEST_HIT_KHR)
-                .module(shader_modules.shader_module)
-                .name(CStr::from_bytes_with_nul(SPHERE_CLOSEST_HIT_SHADER_ENTRY_NAME_BYTE).unwrap())
-                .build();
-
-            let triangle_closest_hit_stage_info = PipelineShaderStageCreateInfo::builder()
-                .stage(ShaderStageFlags::CLOSEST_HIT_KHR)
-                .module(shader_modules.shader_module)
-                .name(CStr::from_bytes_with_nul(TRIANGLE_CLOSEST_HIT_SHADER_ENTRY_NAME_BYTE).unwrap())
-                .build();
-
-            let triangle_any_hit_stage_info = PipelineShaderStageCreateInfo::builder()
-                .stage(ShaderStageFlags::ANY_HIT_KHR)
-                .module(shader_modules.shader_module)
-                .name(CStr::from_bytes_with_nul(TRIANGLE_ANY_HIT_SHADER_ENTRY_NAME_BYTE).unwrap())
-                .build();
-
-            [
-                ray_generation_stage_info,
-                miss_stage_info,
-                sphere_intersection_stage_info,
-                sphere_closest_hit_stage_info,
-                triangle_closest_hit_stage_info,
-                triangle_any_hit_stage_info,
-            ]
-        };
+    /// リサイズで描画先を作り直した場合に呼ぶ、descriptor setが使用中でないこと
+    pub fn set_target_image_view(&mut self, target_image_view: ImageView) -> Result<()> {
+        self.descriptor_set_declaration
+            .set_resource(TARGET_IMAGE_BINDING, DescriptorResource::storage_image(target_image_view))?;
 
-        let shader_groups = vec![
-            //ray_generation
-            RayTracingShaderGroupCreateInfoKHR::builder()
-                //RayTracingShaderGroupTypeKHRのGENERALはray_generation, miss, callableのどれかの時に使用
-                .ty(RayTracingShaderGroupTypeKHR::GENERAL)
-                //shader_stageのindex
-                .general_shader(0)
-                .closest_hit_shader(SHADER_UNUSED_KHR)
-                .any_hit_shader(SHADER_UNUSED_KHR)
-                .intersection_shader(SHADER_UNUSED_KHR)
-                .build(),
-            //miss
-            RayTracingShaderGroupCreateInfoKHR::builder()
-                .ty(RayTracingShaderGroupTypeKHR::GENERAL)
-                .general_shader(1)
-                .closest_hit_shader(SHADER_UNUSED_KHR)
-                .any_hit_shader(SHADER_UNUSED_KHR)
-                .intersection_shader(SHADER_UNUSED_KHR)
-                .build(),
-            //sphere closest and intersection
-            RayTracingShaderGroupCreateInfoKHR::builder()
-                .ty(RayTracingShaderGroupTypeKHR::PROCEDURAL_HIT_GROUP)
-                .general_shader(SHADER_UNUSED_KHR)
-                .closest_hit_shader(3)
-                .any_hit_shader(SHADER_UNUSED_KHR)
-                .intersection_shader(2)
-                .build(),
-            //triangle closest and intersection
-            RayTracingShaderGroupCreateInfoKHR::builder()
-                .ty(RayTracingShaderGroupTypeKHR::TRIANGLES_HIT_GROUP)
-                .general_shader(SHADER_UNUSED_KHR)
-                .closest_hit_shader(4)
-                .any_hit_shader(5)
-                //UNUSEDにするとデフォルトでtriangleが使用される？
-                .intersection_shader(SHADER_UNUSED_KHR)
-                .build(),
-        ];
+        self.descriptor_set_declaration.update(&self.descriptor_sets)
+    }
 
-        let (rt_pipeline_properties, rt_pipeline)
-            = Self::create_raytracing_structure(&backends.instance, backends.physical_device, &backends.device);
+    fn create_pipeline(
+        backends: &'a Backends,
+        shader_modules: &ShaderModules,
+        shader_groups: &RayTracingShaderGroups,
+        descriptor_set_declaration: &DescriptorSetDeclaration,
+        pipeline_layout: PipelineLayout,
+        pipeline_cache: PipelineCache,
+        ray_tracing_pipeline: &RayTracingPipeline,
+        ray_tracing_pipeline_properties: &PhysicalDeviceRayTracingPipelinePropertiesKHR,
+    ) -> Result<(Pipeline, ShaderBindingTable<'a>)> {
+        //パイプラインを作る前にシェーダーと食い違っていないかを確認する
+        shader_modules.reflection.validate(
+            &shader_groups.entry_points(),
+            descriptor_set_declaration,
+            PUSH_CONSTANT_SIZE,
+        )?;
+
+        let shader_stages = shader_groups.stage_create_infos(shader_modules.shader_module);
+        let group_create_infos = shader_groups.group_create_infos();
 
         let pipeline = unsafe {
-            rt_pipeline.create_ray_tracing_pipelines(
+            ray_tracing_pipeline.create_ray_tracing_pipelines(
                 DeferredOperationKHR::null(),
-                PipelineCache::null(),
+                pipeline_cache,
                 &[
                     RayTracingPipelineCreateInfoKHR::builder()
                         .stages(&shader_stages)
-                        .groups(&shader_groups)
-                        .max_pipeline_ray_recursion_depth(0)
+                        .groups(&group_create_infos)
+                        .max_pipeline_ray_recursion_depth(shader_groups.max_recursion_depth)
                         .layout(pipeline_layout)
                         .build()
                 ],
                 None,
                 //なんでVecで帰ってくる？
-            ).unwrap()[0]
+            )?[0]
         };
 
-        let shader_group_handle_size = rt_pipeline_properties.shader_group_handle_size as usize;
-        let shader_program_size = shader_group_handle_size;
-
-        let group_handles = unsafe {
-            rt_pipeline.get_ray_tracing_shader_group_handles(
+        let shader_binding_table = shader_groups
+            .shader_binding_table_builder()
+            .build(
+                backends,
+                ray_tracing_pipeline,
                 pipeline,
-                0,
-                shader_groups.len() as u32,
-                shader_groups.len() * shader_program_size,
-            ).unwrap()
-        };
-
-        debug!("sbt");
-
-        //alignmentしなくて良い？
-        let table_size =
-            shader_groups.len() * shader_group_handle_size;
-
-        let mut table_data = vec![0u8; table_size];
-        for i in 0..shader_groups.len() {
-            //handleサイズ分移動してから,
-            table_data[i * shader_program_size
-                ..i * shader_program_size
-                    + shader_program_size]
-                .copy_from_slice(
-                    &group_handles[i * shader_program_size
-                        ..i * shader_program_size
-                        + shader_program_size],
-                );
-        }
-
-        let mut shader_binding_table_buffer = Buffers::new(
-            &backends.device,
-            backends.device_memory_properties,
-            table_size as u64,
-            BufferUsageFlags::SHADER_DEVICE_ADDRESS,
-            MemoryPropertyFlags::HOST_VISIBLE
-                | MemoryPropertyFlags::HOST_COHERENT
-                | MemoryPropertyFlags::DEVICE_LOCAL
-        );
-
-        shader_binding_table_buffer.store(&table_data);
-
-        let sbt_address = shader_binding_table_buffer.get_buffer_address();
-
-        let shader_program_size = DeviceSize::from(shader_program_size as u64);
-
-        let sbt_raygen_region = StridedDeviceAddressRegionKHR::builder()
-            .device_address(sbt_address + 0)
-            .size(shader_program_size)
-            .stride(shader_program_size)
-            .build();
-
-        let sbt_miss_region = StridedDeviceAddressRegionKHR::builder()
-            .device_address(sbt_address + 1 * shader_program_size)
-            .size(shader_program_size)
-            .stride(shader_program_size)
-            .build();
+                group_create_infos.len() as u32,
+                ShaderGroupHandleProperties::from(ray_tracing_pipeline_properties),
+            );
 
-        let sbt_hit_region = StridedDeviceAddressRegionKHR::builder()
-            .device_address(sbt_address + 2 * shader_program_size)
-            .size(2 * shader_program_size)
-            .stride(shader_program_size)
-            .build();
-
-        //なし
-        let sbt_call_region = StridedDeviceAddressRegionKHR::default();
-
-        let range = ImageSubresourceRange::builder()
-            .aspect_mask(ImageAspectFlags::COLOR)
-            .base_mip_level(0)
-            .level_count(1)
-            .base_array_layer(0)
-            .layer_count(1)
-            .build();
+        match shader_binding_table {
+            Ok(shader_binding_table) => Ok((pipeline, shader_binding_table)),
+            Err(err) => {
+                unsafe { backends.device.destroy_pipeline(pipeline, None) };
 
-        Self {
-            device: &backends.device,
-            pipeline,
-            ray_tracing_pipeline_properties: rt_pipeline_properties,
-            ray_tracing_pipeline: rt_pipeline,
+                Err(err)
+            }
         }
     }
 
@@ -410,19 +368,10 @@ impl<'a> Pipelines<'a> {
         (rt_pipeline_properties, rt_pipeline)
     }
 
-    fn create_pipeline_layout(device: &Device, bindings: &[DescriptorSetLayoutBinding]) -> (PipelineLayout, DescriptorSetLayout) {
-        let descriptor_set_layout = unsafe {
-            device.create_descriptor_set_layout(
-                &DescriptorSetLayoutCreateInfo::builder()
-                    .bindings(&bindings)
-                    .build(),
-                None,
-            ).unwrap()
-        };
-
+    fn create_pipeline_layout(device: &Device, descriptor_set_layout: DescriptorSetLayout) -> Result<PipelineLayout> {
         let push_constant_range = PushConstantRange::builder()
             .offset(0)
-            .size(4)
+            .size(PUSH_CONSTANT_SIZE)
             .stage_flags(ShaderStageFlags::RAYGEN_KHR)
             .build();
 
@@ -432,8 +381,17 @@ impl<'a> Pipelines<'a> {
             .push_constant_ranges(&[push_constant_range])
             .build();
 
-        let pipeline_layout = unsafe { device.create_pipeline_layout(&layout_create_info, None).unwrap() };
+        let pipeline_layout = unsafe { device.create_pipeline_layout(&layout_create_info, None)? };
 
-        (pipeline_layout, descriptor_set_layout)
+        Ok(pipeline_layout)
     }
-}
\ No newline at end of file
+}
+
+impl Drop for Pipelines<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_pipeline(self.pipeline, None);
+            self.device.destroy_pipeline_layout(self.pipeline_layout, None);
+        }
+    }
+}
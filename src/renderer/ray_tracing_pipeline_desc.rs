@@ -0,0 +1,453 @@
+use std::ffi::CString;
+use ash::vk::{PipelineShaderStageCreateInfo, RayTracingShaderGroupCreateInfoKHR, RayTracingShaderGroupTypeKHR, SHADER_UNUSED_KHR, ShaderModule, ShaderStageFlags};
+use crate::error::{CottonError, Result};
+use crate::renderer::shader_binding_table::{ShaderBindingTableBuilder, ShaderRecord};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HitGroupKind {
+    Triangles,
+    Procedural,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HitGroupDesc {
+    pub name: String,
+    pub kind: HitGroupKind,
+    pub closest_hit: Option<String>,
+    pub any_hit: Option<String>,
+    /// Proceduralの場合は必須
+    pub intersection: Option<String>,
+}
+
+/// entry point名でシェーダーを登録し、stageとgroupとSBTの並びを自動で決める
+#[derive(Clone, Debug, Default)]
+pub struct RayTracingPipelineDesc {
+    raygen: Vec<String>,
+    miss: Vec<String>,
+    hit_groups: Vec<HitGroupDesc>,
+    callable: Vec<String>,
+    max_recursion_depth: u32,
+}
+
+impl RayTracingPipelineDesc {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn raygen(mut self, entry_name: &str) -> Self {
+        self.raygen.push(entry_name.to_owned());
+        self
+    }
+
+    /// 登録した順番がtrace_rayのmiss_indexになる
+    pub fn miss(mut self, entry_name: &str) -> Self {
+        self.miss.push(entry_name.to_owned());
+        self
+    }
+
+    pub fn triangle_hit_group(mut self, name: &str, closest_hit: Option<&str>, any_hit: Option<&str>) -> Self {
+        self.hit_groups.push(HitGroupDesc {
+            name: name.to_owned(),
+            kind: HitGroupKind::Triangles,
+            closest_hit: closest_hit.map(str::to_owned),
+            any_hit: any_hit.map(str::to_owned),
+            intersection: None,
+        });
+        self
+    }
+
+    pub fn procedural_hit_group(
+        mut self,
+        name: &str,
+        intersection: &str,
+        closest_hit: Option<&str>,
+        any_hit: Option<&str>,
+    ) -> Self {
+        self.hit_groups.push(HitGroupDesc {
+            name: name.to_owned(),
+            kind: HitGroupKind::Procedural,
+            closest_hit: closest_hit.map(str::to_owned),
+            any_hit: any_hit.map(str::to_owned),
+            intersection: Some(intersection.to_owned()),
+        });
+        self
+    }
+
+    /// 登録した順番がexecute_callableのindexになる
+    pub fn callable(mut self, entry_name: &str) -> Self {
+        self.callable.push(entry_name.to_owned());
+        self
+    }
+
+    pub fn max_recursion_depth(mut self, max_recursion_depth: u32) -> Self {
+        self.max_recursion_depth = max_recursion_depth;
+        self
+    }
+
+    pub fn build(self) -> Result<RayTracingShaderGroups> {
+        if self.raygen.is_empty() {
+            return Err(CottonError::InvalidPipelineDesc("raygen shader is required".to_owned()));
+        }
+
+        for (i, hit_group) in self.hit_groups.iter().enumerate() {
+            if self.hit_groups[..i].iter().any(|other| other.name == hit_group.name) {
+                return Err(CottonError::InvalidPipelineDesc(format!(
+                    "hit group `{}` is declared more than once",
+                    hit_group.name,
+                )));
+            }
+
+            if hit_group.kind == HitGroupKind::Procedural && hit_group.intersection.is_none() {
+                return Err(CottonError::InvalidPipelineDesc(format!(
+                    "procedural hit group `{}` has no intersection shader",
+                    hit_group.name,
+                )));
+            }
+        }
+
+        let mut groups = RayTracingShaderGroups {
+            max_recursion_depth: self.max_recursion_depth,
+            ..Default::default()
+        };
+
+        for entry_name in self.raygen.iter() {
+            let group = groups.general_group(entry_name, ShaderStageFlags::RAYGEN_KHR)?;
+            groups.raygen_groups.push(group);
+        }
+
+        for entry_name in self.miss.iter() {
+            let group = groups.general_group(entry_name, ShaderStageFlags::MISS_KHR)?;
+            groups.miss_groups.push(group);
+        }
+
+        for hit_group in self.hit_groups.iter() {
+            let closest_hit = groups.optional_stage(hit_group.closest_hit.as_deref(), ShaderStageFlags::CLOSEST_HIT_KHR)?;
+            let any_hit = groups.optional_stage(hit_group.any_hit.as_deref(), ShaderStageFlags::ANY_HIT_KHR)?;
+            let intersection = groups.optional_stage(hit_group.intersection.as_deref(), ShaderStageFlags::INTERSECTION_KHR)?;
+
+            let ty = match hit_group.kind {
+                HitGroupKind::Triangles => RayTracingShaderGroupTypeKHR::TRIANGLES_HIT_GROUP,
+                HitGroupKind::Procedural => RayTracingShaderGroupTypeKHR::PROCEDURAL_HIT_GROUP,
+            };
+
+            let group = groups.push_group(ShaderGroup {
+                ty,
+                general: SHADER_UNUSED_KHR,
+                closest_hit,
+                any_hit,
+                intersection,
+            });
+
+            groups.hit_groups.push((hit_group.name.clone(), group));
+        }
+
+        for entry_name in self.callable.iter() {
+            let group = groups.general_group(entry_name, ShaderStageFlags::CALLABLE_KHR)?;
+            groups.callable_groups.push((entry_name.clone(), group));
+        }
+
+        Ok(groups)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShaderStage {
+    pub entry_name: CString,
+    pub stage: ShaderStageFlags,
+}
+
+/// indexはstagesの中の位置、使わない場合はSHADER_UNUSED_KHR
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ShaderGroup {
+    pub ty: RayTracingShaderGroupTypeKHR,
+    pub general: u32,
+    pub closest_hit: u32,
+    pub any_hit: u32,
+    pub intersection: u32,
+}
+
+/// RayTracingPipelineDescから求めたstageとgroupの並び
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RayTracingShaderGroups {
+    pub stages: Vec<ShaderStage>,
+    pub groups: Vec<ShaderGroup>,
+    /// 以下はgroupsの中のindex
+    pub raygen_groups: Vec<u32>,
+    pub miss_groups: Vec<u32>,
+    pub hit_groups: Vec<(String, u32)>,
+    /// entry point名とgroupのindex
+    pub callable_groups: Vec<(String, u32)>,
+    pub max_recursion_depth: u32,
+}
+
+impl RayTracingShaderGroups {
+    /// 同じentry pointとstageの組は一つのstageを共有する
+    fn stage(&mut self, entry_name: &str, stage: ShaderStageFlags) -> Result<u32> {
+        let entry_name = CString::new(entry_name)?;
+
+        if let Some(index) = self.stages
+            .iter()
+            .position(|existing| existing.entry_name == entry_name && existing.stage == stage)
+        {
+            return Ok(index as u32);
+        }
+
+        self.stages.push(ShaderStage { entry_name, stage });
+
+        Ok(self.stages.len() as u32 - 1)
+    }
+
+    fn optional_stage(&mut self, entry_name: Option<&str>, stage: ShaderStageFlags) -> Result<u32> {
+        match entry_name {
+            Some(entry_name) => self.stage(entry_name, stage),
+            None => Ok(SHADER_UNUSED_KHR),
+        }
+    }
+
+    fn push_group(&mut self, group: ShaderGroup) -> u32 {
+        self.groups.push(group);
+        self.groups.len() as u32 - 1
+    }
+
+    //GENERALはraygen, miss, callableのどれかの時に使用
+    fn general_group(&mut self, entry_name: &str, stage: ShaderStageFlags) -> Result<u32> {
+        let general = self.stage(entry_name, stage)?;
+
+        Ok(self.push_group(ShaderGroup {
+            ty: RayTracingShaderGroupTypeKHR::GENERAL,
+            general,
+            closest_hit: SHADER_UNUSED_KHR,
+            any_hit: SHADER_UNUSED_KHR,
+            intersection: SHADER_UNUSED_KHR,
+        }))
+    }
+
+    /// instanceのinstance_shader_binding_table_record_offsetに入れる値
+    pub fn hit_group_offset(&self, name: &str) -> Option<u32> {
+        self.hit_groups
+            .iter()
+            .position(|(hit_group_name, _)| hit_group_name == name)
+            .map(|offset| offset as u32)
+    }
+
+    /// execute_callableに渡すindex
+    pub fn callable_index(&self, entry_name: &str) -> Option<u32> {
+        self.callable_groups
+            .iter()
+            .position(|(callable_entry_name, _)| callable_entry_name == entry_name)
+            .map(|index| index as u32)
+    }
+
+    pub fn hit_group_names(&self) -> impl Iterator<Item = &str> {
+        self.hit_groups.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// シェーダーのリフレクション結果と照らし合わせる用
+    pub fn entry_points(&self) -> Vec<(&str, ShaderStageFlags)> {
+        self.stages
+            .iter()
+            .map(|stage| (stage.entry_name.to_str().unwrap_or_default(), stage.stage))
+            .collect()
+    }
+
+    /// 返り値はselfのentry_nameを指しているのでselfより長く生存させない
+    pub fn stage_create_infos(&self, shader_module: ShaderModule) -> Vec<PipelineShaderStageCreateInfo> {
+        self.stages
+            .iter()
+            .map(|stage| PipelineShaderStageCreateInfo::builder()
+                .stage(stage.stage)
+                .module(shader_module)
+                .name(&stage.entry_name)
+                .build())
+            .collect()
+    }
+
+    pub fn group_create_infos(&self) -> Vec<RayTracingShaderGroupCreateInfoKHR> {
+        self.groups
+            .iter()
+            .map(|group| RayTracingShaderGroupCreateInfoKHR::builder()
+                .ty(group.ty)
+                .general_shader(group.general)
+                .closest_hit_shader(group.closest_hit)
+                .any_hit_shader(group.any_hit)
+                .intersection_shader(group.intersection)
+                .build())
+            .collect()
+    }
+
+    /// 各regionに登録順でレコードを並べる
+    pub fn shader_binding_table_builder(&self) -> ShaderBindingTableBuilder {
+        let mut builder = ShaderBindingTableBuilder::new();
+
+        for group in self.raygen_groups.iter() {
+            builder = builder.raygen(ShaderRecord::new(*group));
+        }
+
+        for group in self.miss_groups.iter() {
+            builder = builder.miss(ShaderRecord::new(*group));
+        }
+
+        for (_, group) in self.hit_groups.iter() {
+            builder = builder.hit(ShaderRecord::new(*group));
+        }
+
+        for (_, group) in self.callable_groups.iter() {
+            builder = builder.callable(ShaderRecord::new(*group));
+        }
+
+        builder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ash::vk::DeviceSize;
+    use crate::renderer::shader_binding_table::{ShaderBindingTableRegionLayout, ShaderGroupHandleProperties};
+    use super::*;
+
+    fn desc(miss_count: usize, hit_count: usize) -> RayTracingPipelineDesc {
+        let mut desc = RayTracingPipelineDesc::new().raygen("main_raygen");
+
+        for i in 0..miss_count {
+            desc = desc.miss(&format!("miss_{}", i));
+        }
+
+        for i in 0..hit_count {
+            desc = desc.triangle_hit_group(&format!("hit_{}", i), Some(&format!("closest_hit_{}", i)), None);
+        }
+
+        desc
+    }
+
+    //空のregionは全て0になる
+    fn region(offset: DeviceSize, count: DeviceSize) -> ShaderBindingTableRegionLayout {
+        if count == 0 {
+            return ShaderBindingTableRegionLayout::default();
+        }
+
+        ShaderBindingTableRegionLayout { offset, stride: 32, size: 32 * count, count }
+    }
+
+    #[test]
+    fn groups_are_raygen_then_miss_then_hit() {
+        for (miss_count, hit_count) in [(1, 1), (2, 3), (3, 2), (0, 2), (1, 0)] {
+            let groups = desc(miss_count, hit_count).build().unwrap();
+            let miss_count = miss_count as u32;
+            let hit_count = hit_count as u32;
+
+            let types: Vec<_> = groups.groups.iter().map(|group| group.ty).collect();
+            let mut expected = vec![RayTracingShaderGroupTypeKHR::GENERAL; 1 + miss_count as usize];
+            expected.extend(vec![RayTracingShaderGroupTypeKHR::TRIANGLES_HIT_GROUP; hit_count as usize]);
+            assert_eq!(types, expected);
+
+            assert_eq!(groups.raygen_groups, vec![0]);
+            assert_eq!(groups.miss_groups, (1..1 + miss_count).collect::<Vec<_>>());
+
+            for i in 0..hit_count {
+                let (name, group) = &groups.hit_groups[i as usize];
+
+                assert_eq!(*name, format!("hit_{}", i));
+                assert_eq!(*group, 1 + miss_count + i);
+                assert_eq!(groups.hit_group_offset(name), Some(i));
+                //stageもgroupと同じ順に並ぶ
+                assert_eq!(groups.groups[*group as usize].closest_hit, 1 + miss_count + i);
+            }
+        }
+    }
+
+    #[test]
+    fn shader_binding_table_records_follow_the_groups() {
+        //(miss, hit, missの先頭, hitの先頭, 全体の大きさ)
+        let cases = [
+            (1, 1, 64, 128, 160),
+            (2, 3, 64, 128, 224),
+            (3, 2, 64, 192, 256),
+            (0, 2, 0, 64, 128),
+            (1, 0, 64, 0, 96),
+        ];
+
+        let properties = ShaderGroupHandleProperties {
+            handle_size: 32,
+            handle_alignment: 32,
+            base_alignment: 64,
+            max_stride: 4096,
+        };
+
+        for (miss_count, hit_count, miss_offset, hit_offset, total_size) in cases {
+            let groups = desc(miss_count, hit_count).build().unwrap();
+            let builder = groups.shader_binding_table_builder();
+
+            let mut expected = ShaderBindingTableBuilder::new().raygen(ShaderRecord::new(0));
+            for i in 0..miss_count as u32 {
+                expected = expected.miss(ShaderRecord::new(1 + i));
+            }
+            for i in 0..hit_count as u32 {
+                expected = expected.hit(ShaderRecord::new(1 + miss_count as u32 + i));
+            }
+            assert_eq!(builder, expected);
+
+            let layout = builder.layout(properties).unwrap();
+            assert_eq!(layout.raygen, ShaderBindingTableRegionLayout { offset: 0, stride: 64, size: 64, count: 1 });
+            assert_eq!(layout.miss, region(miss_offset, miss_count as DeviceSize));
+            assert_eq!(layout.hit, region(hit_offset, hit_count as DeviceSize));
+            assert_eq!(layout.total_size, total_size);
+
+            //instanceのrecord offsetから引いたレコードがそのhit groupを指す
+            for (name, group) in groups.hit_groups.iter() {
+                let offset = groups.hit_group_offset(name).unwrap() as DeviceSize;
+                let record_start = layout.hit.offset + offset * layout.hit.stride;
+
+                assert_eq!(record_start, hit_offset + (*group as DeviceSize - 1 - miss_count as DeviceSize) * 32);
+            }
+        }
+    }
+
+    #[test]
+    fn stages_are_shared_and_callables_come_last() {
+        let groups = RayTracingPipelineDesc::new()
+            .raygen("main_raygen")
+            .miss("main_miss")
+            .triangle_hit_group("opaque", Some("closest_hit"), None)
+            .procedural_hit_group("sphere", "sphere_intersection", Some("closest_hit"), Some("any_hit"))
+            .callable("lambert")
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            groups.entry_points(),
+            vec![
+                ("main_raygen", ShaderStageFlags::RAYGEN_KHR),
+                ("main_miss", ShaderStageFlags::MISS_KHR),
+                ("closest_hit", ShaderStageFlags::CLOSEST_HIT_KHR),
+                ("any_hit", ShaderStageFlags::ANY_HIT_KHR),
+                ("sphere_intersection", ShaderStageFlags::INTERSECTION_KHR),
+                ("lambert", ShaderStageFlags::CALLABLE_KHR),
+            ],
+        );
+
+        assert_eq!(groups.groups[2], ShaderGroup {
+            ty: RayTracingShaderGroupTypeKHR::TRIANGLES_HIT_GROUP,
+            general: SHADER_UNUSED_KHR,
+            closest_hit: 2,
+            any_hit: SHADER_UNUSED_KHR,
+            intersection: SHADER_UNUSED_KHR,
+        });
+        assert_eq!(groups.groups[3], ShaderGroup {
+            ty: RayTracingShaderGroupTypeKHR::PROCEDURAL_HIT_GROUP,
+            general: SHADER_UNUSED_KHR,
+            closest_hit: 2,
+            any_hit: 3,
+            intersection: 4,
+        });
+        assert_eq!(groups.callable_groups, vec![("lambert".to_owned(), 4)]);
+        assert_eq!(groups.callable_index("lambert"), Some(0));
+        assert_eq!(groups.hit_group_offset("sphere"), Some(1));
+        assert_eq!(groups.hit_group_offset("missing"), None);
+    }
+
+    #[test]
+    fn invalid_descriptions_are_rejected() {
+        assert!(RayTracingPipelineDesc::new().miss("main_miss").build().is_err());
+        assert!(desc(1, 1).triangle_hit_group("hit_0", None, None).build().is_err());
+    }
+}
@@ -2,6 +2,7 @@ use ash::Device;
 use ash::vk::{AccessFlags, AttachmentDescription, AttachmentLoadOp, AttachmentReference, AttachmentStoreOp, Extent2D, Format, Framebuffer, FramebufferCreateInfo, ImageLayout, ImageView, PipelineBindPoint, PipelineStageFlags, RenderPass, RenderPassCreateInfo, SampleCountFlags, SUBPASS_EXTERNAL, SubpassDependency, SubpassDescription};
 use log::info;
 
+use crate::error::Result;
 use crate::renderer::backends::Backends;
 
 pub struct RenderPasses<'a> {
@@ -16,7 +17,7 @@ impl<'a> RenderPasses<'a> {
         format: Format,
         image_views: Vec<ImageView>,
         extent: Extent2D,
-    ) -> Self {
+    ) -> Result<Self> {
         let color_attachment = AttachmentDescription::builder()
             .format(format)
             .samples(SampleCountFlags::TYPE_1)
@@ -55,10 +56,15 @@ impl<'a> RenderPasses<'a> {
 
         let render_pass =
             unsafe {
-                backends.device.create_render_pass(&render_pass_info, None).unwrap()
+                backends.device.create_render_pass(&render_pass_info, None)?
             };
 
-        let mut framebuffers = vec![];
+        //失敗した場合でもDropで解放されるように先に作っておく
+        let mut render_passes = Self {
+            device: &backends.device,
+            render_pass,
+            framebuffers: vec![],
+        };
 
         for image_view in image_views {
             let framebuffer_info = FramebufferCreateInfo::builder()
@@ -69,20 +75,16 @@ impl<'a> RenderPasses<'a> {
                 .layers(1)
                 .build();
 
-            framebuffers.push(
+            render_passes.framebuffers.push(
                 unsafe {
-                    backends.device.create_framebuffer(&framebuffer_info, None).unwrap()
+                    backends.device.create_framebuffer(&framebuffer_info, None)?
                 }
             );
         }
 
         info!("Create Render passes");
 
-        Self {
-            device: &backends.device,
-            render_pass,
-            framebuffers,
-        }
+        Ok(render_passes)
     }
 }
 
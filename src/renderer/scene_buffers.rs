@@ -0,0 +1,218 @@
+use ash::vk::{BufferUsageFlags, DeviceSize, MemoryPropertyFlags};
+use classical_raytracer_shader::{InstanceData, Vertex};
+use glam::Mat4;
+use crate::buffers::Buffers;
+use crate::error::{CottonError, Result};
+use crate::renderer::backends::Backends;
+use crate::renderer::materials::MaterialCallables;
+use crate::scene_description::SceneDescription;
+
+/// 全てのmeshを一つの配列に詰めたもの、first_verticesとfirst_indicesはmeshごとの先頭
+#[derive(Clone, Default)]
+pub struct PackedMeshes {
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u32>,
+    pub first_vertices: Vec<u32>,
+    pub first_indices: Vec<u32>,
+}
+
+impl PackedMeshes {
+    /// indexはmeshの中の番号のままにして、シェーダーでfirst_vertexを足す
+    pub fn new(scene: &SceneDescription) -> Self {
+        let mut packed = Self::default();
+
+        for mesh in scene.meshes.iter() {
+            packed.first_vertices.push(packed.vertices.len() as u32);
+            packed.first_indices.push(packed.indices.len() as u32);
+
+            packed.vertices.extend(mesh.vertices());
+            packed.indices.extend_from_slice(&mesh.indices);
+        }
+
+        packed
+    }
+
+    /// instance_custom_indexの順番、SceneDescription.instancesと同じ
+    pub fn instance_data(&self, scene: &SceneDescription) -> Vec<InstanceData> {
+        scene
+            .instances
+            .iter()
+            .map(|instance| InstanceData {
+                first_vertex: self.first_vertices[instance.mesh],
+                first_index: self.first_indices[instance.mesh],
+                material: instance.material as u32,
+                _padding: 0,
+                normal_matrix: normal_matrix_columns(instance.transform),
+            })
+            .collect()
+    }
+}
+
+/// host backendと同じく逆行列の転置で法線を変換する
+pub fn normal_matrix_columns(transform: Mat4) -> [[f32; 4]; 3] {
+    let normal_matrix = transform.inverse().transpose();
+
+    [
+        normal_matrix.x_axis.truncate().extend(0.0).to_array(),
+        normal_matrix.y_axis.truncate().extend(0.0).to_array(),
+        normal_matrix.z_axis.truncate().extend(0.0).to_array(),
+    ]
+}
+
+/// closest hitとcallableから参照するシーンのデータ
+/// BLASのbuildに使ったmeshごとのbufferとは別に、シェーダー用に一つに詰め直す
+pub struct SceneBuffers<'a> {
+    pub vertex_buffer: Buffers<'a>,
+    pub index_buffer: Buffers<'a>,
+    pub instance_buffer: Buffers<'a>,
+    pub material_buffer: Buffers<'a>,
+    packed: PackedMeshes,
+    instance_count: usize,
+    material_count: usize,
+}
+
+impl<'a> SceneBuffers<'a> {
+    pub fn new(backends: &'a Backends, scene: &SceneDescription, material_callables: &MaterialCallables) -> Result<Self> {
+        scene.validate()?;
+
+        let mut packed = PackedMeshes::new(scene);
+        let instances = packed.instance_data(scene);
+        let materials = material_callables.materials_data(&scene.materials)?;
+
+        let vertex_buffer = Self::storage_buffer(backends, &packed.vertices)?;
+        let index_buffer = Self::storage_buffer(backends, &packed.indices)?;
+        let instance_buffer = Self::storage_buffer(backends, &instances)?;
+        let material_buffer = Self::storage_buffer(backends, &materials)?;
+
+        //GPUに渡したので頂点は持っておかない
+        packed.vertices = vec![];
+        packed.indices = vec![];
+
+        Ok(Self {
+            vertex_buffer,
+            index_buffer,
+            instance_buffer,
+            material_buffer,
+            packed,
+            instance_count: instances.len(),
+            material_count: materials.len(),
+        })
+    }
+
+    pub fn instance_count(&self) -> usize {
+        self.instance_count
+    }
+
+    pub fn material_count(&self) -> usize {
+        self.material_count
+    }
+
+    /// transformかinstanceの参照先が変わった場合に呼ぶ、数が変わった場合はnewで作り直す
+    pub fn write_instances(&mut self, scene: &SceneDescription) -> Result<()> {
+        if scene.instances.len() != self.instance_count {
+            return Err(CottonError::InvalidScene(format!(
+                "scene has {} instances, but the instance buffer was created for {}",
+                scene.instances.len(),
+                self.instance_count,
+            )));
+        }
+
+        self.instance_buffer.store(&self.packed.instance_data(scene))
+    }
+
+    /// 数が変わった場合はnewで作り直す
+    pub fn write_materials(&mut self, scene: &SceneDescription, material_callables: &MaterialCallables) -> Result<()> {
+        if scene.materials.len() != self.material_count {
+            return Err(CottonError::InvalidScene(format!(
+                "scene has {} materials, but the material buffer was created for {}",
+                scene.materials.len(),
+                self.material_count,
+            )));
+        }
+
+        self.material_buffer.store(&material_callables.materials_data(&scene.materials)?)
+    }
+
+    //空の場合もdescriptorに書けるように一つ分は確保する
+    fn storage_buffer<T: Copy>(backends: &'a Backends, data: &[T]) -> Result<Buffers<'a>> {
+        let size = std::mem::size_of_val(data).max(std::mem::size_of::<T>());
+
+        let mut buffer = Buffers::new(
+            &backends.device,
+            backends.device_memory_properties,
+            size as DeviceSize,
+            BufferUsageFlags::STORAGE_BUFFER,
+            MemoryPropertyFlags::HOST_VISIBLE
+                | MemoryPropertyFlags::HOST_COHERENT,
+        )?;
+
+        buffer.store(data)?;
+
+        Ok(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{Vec2, Vec3};
+    use crate::renderer::materials::Material;
+    use crate::scene_description::{InstanceDescription, MeshDescription};
+    use super::*;
+
+    fn scene() -> SceneDescription {
+        let mut quad = MeshDescription::triangle([Vec3::ZERO, Vec3::X, Vec3::Y]);
+        quad.positions.push(Vec3::new(1.0, 1.0, 0.0));
+        quad.normals.push(Vec3::Z);
+        quad.tex_coords = vec![Vec2::ZERO, Vec2::X, Vec2::Y, Vec2::ONE];
+        quad.indices.extend_from_slice(&[1, 3, 2]);
+
+        SceneDescription {
+            meshes: vec![MeshDescription::triangle([Vec3::ZERO, Vec3::X, Vec3::Y]), quad],
+            instances: vec![
+                InstanceDescription { mesh: 1, transform: Mat4::IDENTITY, material: 1 },
+                InstanceDescription { mesh: 0, transform: Mat4::from_scale(Vec3::new(2.0, 1.0, 1.0)), material: 0 },
+                InstanceDescription { mesh: 1, transform: Mat4::from_translation(Vec3::X), material: 0 },
+            ],
+            materials: vec![Material::lambertian([0.5; 3]), Material::metal([0.9; 3], 0.1)],
+            textures: vec![],
+        }
+    }
+
+    #[test]
+    fn meshes_are_packed_back_to_back() {
+        let packed = PackedMeshes::new(&scene());
+
+        assert_eq!(packed.first_vertices, vec![0, 3]);
+        assert_eq!(packed.first_indices, vec![0, 3]);
+        assert_eq!(packed.vertices.len(), 7);
+        //indexはmeshの中の番号のまま
+        assert_eq!(packed.indices, vec![0, 1, 2, 0, 1, 2, 1, 3, 2]);
+        assert_eq!(packed.vertices[6].tex_coord, Vec2::ONE);
+    }
+
+    #[test]
+    fn every_instance_points_at_its_mesh_and_material() {
+        let scene = scene();
+        let instances = PackedMeshes::new(&scene).instance_data(&scene);
+
+        let offsets: Vec<_> = instances
+            .iter()
+            .map(|instance| (instance.first_vertex, instance.first_index, instance.material))
+            .collect();
+
+        assert_eq!(offsets, vec![(3, 3, 1), (0, 0, 0), (3, 3, 0)]);
+    }
+
+    #[test]
+    fn normal_matrix_undoes_non_uniform_scale() {
+        let columns = normal_matrix_columns(Mat4::from_scale(Vec3::new(2.0, 1.0, 1.0)) * Mat4::from_translation(Vec3::Y));
+
+        assert_eq!(columns, [[0.5, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0]]);
+    }
+
+    #[test]
+    fn instance_data_matches_std430_layout() {
+        assert_eq!(std::mem::size_of::<InstanceData>(), 64);
+        assert_eq!(std::mem::size_of::<Vertex>(), 64);
+    }
+}
@@ -0,0 +1,475 @@
+use ash::extensions::khr::RayTracingPipeline;
+use ash::vk::{BufferUsageFlags, DeviceSize, MemoryPropertyFlags, PhysicalDeviceRayTracingPipelinePropertiesKHR, Pipeline, StridedDeviceAddressRegionKHR};
+use log::debug;
+use crate::align_up;
+use crate::buffers::Buffers;
+use crate::error::{CottonError, Result};
+use crate::renderer::backends::Backends;
+
+/// SBTの配置に必要なデバイスのプロパティ
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ShaderGroupHandleProperties {
+    pub handle_size: u32,
+    /// レコードのstrideはこれの倍数
+    pub handle_alignment: u32,
+    /// 各regionの先頭アドレスはこれの倍数
+    pub base_alignment: u32,
+    pub max_stride: u32,
+}
+
+impl From<&PhysicalDeviceRayTracingPipelinePropertiesKHR> for ShaderGroupHandleProperties {
+    fn from(properties: &PhysicalDeviceRayTracingPipelinePropertiesKHR) -> Self {
+        Self {
+            handle_size: properties.shader_group_handle_size,
+            handle_alignment: properties.shader_group_handle_alignment,
+            base_alignment: properties.shader_group_base_alignment,
+            max_stride: properties.max_shader_group_stride,
+        }
+    }
+}
+
+/// SBTの1レコード、handleの後ろにdataがそのまま置かれる
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShaderRecord {
+    /// RayTracingShaderGroupCreateInfoKHRの配列でのindex
+    pub group_index: u32,
+    pub data: Vec<u8>,
+}
+
+impl ShaderRecord {
+    pub fn new(group_index: u32) -> Self {
+        Self {
+            group_index,
+            data: vec![],
+        }
+    }
+
+    pub fn with_data(group_index: u32, data: &[u8]) -> Self {
+        Self {
+            group_index,
+            data: data.to_vec(),
+        }
+    }
+}
+
+/// テーブル先頭からのオフセットで表したregion
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShaderBindingTableRegionLayout {
+    pub offset: DeviceSize,
+    pub stride: DeviceSize,
+    pub size: DeviceSize,
+    pub count: DeviceSize,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShaderBindingTableLayout {
+    pub raygen: ShaderBindingTableRegionLayout,
+    pub miss: ShaderBindingTableRegionLayout,
+    pub hit: ShaderBindingTableRegionLayout,
+    pub callable: ShaderBindingTableRegionLayout,
+    pub total_size: DeviceSize,
+}
+
+impl ShaderBindingTableLayout {
+    pub fn new(
+        properties: ShaderGroupHandleProperties,
+        raygen: &[ShaderRecord],
+        miss: &[ShaderRecord],
+        hit: &[ShaderRecord],
+        callable: &[ShaderRecord],
+    ) -> Result<Self> {
+        let mut offset = 0;
+
+        let mut region = |records: &[ShaderRecord], record_alignment: u32| -> Result<ShaderBindingTableRegionLayout> {
+            if records.is_empty() {
+                return Ok(ShaderBindingTableRegionLayout::default());
+            }
+
+            let max_data_size = records.iter().map(|record| record.data.len()).max().unwrap_or(0) as DeviceSize;
+            let stride = align_up(
+                properties.handle_size as DeviceSize + max_data_size,
+                record_alignment as DeviceSize,
+            );
+
+            if stride > properties.max_stride as DeviceSize {
+                return Err(CottonError::InvalidShaderBindingTable(format!(
+                    "record stride {} exceeds max shader group stride {}",
+                    stride,
+                    properties.max_stride,
+                )));
+            }
+
+            let count = records.len() as DeviceSize;
+            let region_offset = align_up(offset, properties.base_alignment as DeviceSize);
+            let size = stride * count;
+
+            offset = region_offset + size;
+
+            Ok(ShaderBindingTableRegionLayout {
+                offset: region_offset,
+                stride,
+                size,
+                count,
+            })
+        };
+
+        if raygen.is_empty() {
+            return Err(CottonError::InvalidShaderBindingTable("raygen record is required".to_owned()));
+        }
+
+        //raygenは1レコードずつregionとして渡すので、各レコードの先頭もbase_alignmentに揃える
+        let raygen = region(raygen, properties.handle_alignment.max(properties.base_alignment))?;
+        let miss = region(miss, properties.handle_alignment)?;
+        let hit = region(hit, properties.handle_alignment)?;
+        let callable = region(callable, properties.handle_alignment)?;
+
+        Ok(Self {
+            raygen,
+            miss,
+            hit,
+            callable,
+            total_size: offset,
+        })
+    }
+
+    /// index番目のraygenだけを指すregion、範囲外の場合は最後のもの
+    pub fn raygen_record(&self, index: u64) -> ShaderBindingTableRegionLayout {
+        let raygen = self.raygen;
+
+        ShaderBindingTableRegionLayout {
+            offset: raygen.offset + raygen.stride * index.min(raygen.count - 1),
+            stride: raygen.stride,
+            size: raygen.stride,
+            count: 1,
+        }
+    }
+
+    /// `handles`はget_ray_tracing_shader_group_handlesの戻り値をそのまま渡す
+    pub fn write(
+        &self,
+        handles: &[u8],
+        handle_size: usize,
+        raygen: &[ShaderRecord],
+        miss: &[ShaderRecord],
+        hit: &[ShaderRecord],
+        callable: &[ShaderRecord],
+    ) -> Result<Vec<u8>> {
+        let mut table = vec![0u8; self.total_size as usize];
+
+        for (region, records) in [
+            (self.raygen, raygen),
+            (self.miss, miss),
+            (self.hit, hit),
+            (self.callable, callable),
+        ] {
+            for (i, record) in records.iter().enumerate() {
+                let handle_start = record.group_index as usize * handle_size;
+                let handle = handles
+                    .get(handle_start..handle_start + handle_size)
+                    .ok_or_else(|| CottonError::InvalidShaderBindingTable(format!(
+                        "shader group {} has no handle",
+                        record.group_index,
+                    )))?;
+
+                let record_start = (region.offset + region.stride * i as DeviceSize) as usize;
+
+                table[record_start..record_start + handle_size].copy_from_slice(handle);
+                table[record_start + handle_size..record_start + handle_size + record.data.len()]
+                    .copy_from_slice(&record.data);
+            }
+        }
+
+        Ok(table)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShaderBindingTableBuilder {
+    raygen: Vec<ShaderRecord>,
+    miss: Vec<ShaderRecord>,
+    hit: Vec<ShaderRecord>,
+    callable: Vec<ShaderRecord>,
+}
+
+impl ShaderBindingTableBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn raygen(mut self, record: ShaderRecord) -> Self {
+        self.raygen.push(record);
+        self
+    }
+
+    pub fn miss(mut self, record: ShaderRecord) -> Self {
+        self.miss.push(record);
+        self
+    }
+
+    pub fn hit(mut self, record: ShaderRecord) -> Self {
+        self.hit.push(record);
+        self
+    }
+
+    pub fn callable(mut self, record: ShaderRecord) -> Self {
+        self.callable.push(record);
+        self
+    }
+
+    pub fn layout(&self, properties: ShaderGroupHandleProperties) -> Result<ShaderBindingTableLayout> {
+        ShaderBindingTableLayout::new(properties, &self.raygen, &self.miss, &self.hit, &self.callable)
+    }
+
+    pub fn build<'a>(
+        &self,
+        backends: &'a Backends,
+        ray_tracing_pipeline: &RayTracingPipeline,
+        pipeline: Pipeline,
+        group_count: u32,
+        properties: ShaderGroupHandleProperties,
+    ) -> Result<ShaderBindingTable<'a>> {
+        debug!("create shader binding table");
+
+        let layout = self.layout(properties)?;
+
+        let handle_size = properties.handle_size as usize;
+
+        let handles = unsafe {
+            ray_tracing_pipeline.get_ray_tracing_shader_group_handles(
+                pipeline,
+                0,
+                group_count,
+                group_count as usize * handle_size,
+            )?
+        };
+
+        let table_data = layout.write(&handles, handle_size, &self.raygen, &self.miss, &self.hit, &self.callable)?;
+
+        //バッファのアドレス自体もbase_alignmentに揃える必要があるので余分に確保しておく
+        let base_alignment = properties.base_alignment as DeviceSize;
+
+        let mut buffer = Buffers::new(
+            &backends.device,
+            backends.device_memory_properties,
+            layout.total_size + base_alignment,
+            BufferUsageFlags::SHADER_DEVICE_ADDRESS
+                | BufferUsageFlags::SHADER_BINDING_TABLE_KHR,
+            MemoryPropertyFlags::HOST_VISIBLE
+                | MemoryPropertyFlags::HOST_COHERENT
+        )?;
+
+        let buffer_address = buffer.get_buffer_address();
+        let address = align_up(buffer_address, base_alignment);
+        let padding = (address - buffer_address) as usize;
+
+        let mut data = vec![0u8; padding];
+        data.extend_from_slice(&table_data);
+
+        buffer.store(&data)?;
+
+        Ok(ShaderBindingTable {
+            buffer,
+            address,
+            layout,
+        })
+    }
+}
+
+pub struct ShaderBindingTable<'a> {
+    pub buffer: Buffers<'a>,
+    /// base_alignmentに揃えたテーブル先頭のアドレス
+    pub address: DeviceSize,
+    pub layout: ShaderBindingTableLayout,
+}
+
+impl ShaderBindingTable<'_> {
+    fn region(&self, region: ShaderBindingTableRegionLayout) -> StridedDeviceAddressRegionKHR {
+        if region.count == 0 {
+            return StridedDeviceAddressRegionKHR::default();
+        }
+
+        StridedDeviceAddressRegionKHR::builder()
+            .device_address(self.address + region.offset)
+            .stride(region.stride)
+            .size(region.size)
+            .build()
+    }
+
+    /// raygenはsizeとstrideが同じでなければならないので一つだけ指す
+    pub fn raygen_region_at(&self, index: u64) -> StridedDeviceAddressRegionKHR {
+        self.region(self.layout.raygen_record(index))
+    }
+
+    pub fn raygen_region(&self) -> StridedDeviceAddressRegionKHR {
+        self.raygen_region_at(0)
+    }
+
+    pub fn miss_region(&self) -> StridedDeviceAddressRegionKHR {
+        self.region(self.layout.miss)
+    }
+
+    pub fn hit_region(&self) -> StridedDeviceAddressRegionKHR {
+        self.region(self.layout.hit)
+    }
+
+    pub fn callable_region(&self) -> StridedDeviceAddressRegionKHR {
+        self.region(self.layout.callable)
+    }
+
+    /// cmd_trace_raysに渡す順番
+    pub fn regions(&self) -> [StridedDeviceAddressRegionKHR; 4] {
+        [
+            self.raygen_region(),
+            self.miss_region(),
+            self.hit_region(),
+            self.callable_region(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn properties(handle_size: u32, handle_alignment: u32, base_alignment: u32) -> ShaderGroupHandleProperties {
+        ShaderGroupHandleProperties {
+            handle_size,
+            handle_alignment,
+            base_alignment,
+            max_stride: 4096,
+        }
+    }
+
+    fn records(first_group: u32, count: u32) -> Vec<ShaderRecord> {
+        (first_group..first_group + count).map(ShaderRecord::new).collect()
+    }
+
+    #[test]
+    fn typical_layout() {
+        let layout = ShaderBindingTableLayout::new(
+            properties(32, 32, 64),
+            &records(0, 1),
+            &records(1, 2),
+            &records(3, 3),
+            &records(6, 4),
+        ).unwrap();
+
+        assert_eq!(layout.raygen, ShaderBindingTableRegionLayout { offset: 0, stride: 64, size: 64, count: 1 });
+        assert_eq!(layout.miss, ShaderBindingTableRegionLayout { offset: 64, stride: 32, size: 64, count: 2 });
+        assert_eq!(layout.hit, ShaderBindingTableRegionLayout { offset: 128, stride: 32, size: 96, count: 3 });
+        assert_eq!(layout.callable, ShaderBindingTableRegionLayout { offset: 256, stride: 32, size: 128, count: 4 });
+        assert_eq!(layout.total_size, 384);
+    }
+
+    #[test]
+    fn layout_invariants_hold_for_many_properties() {
+        let combinations = [
+            (32, 32, 64),
+            (32, 32, 32),
+            (32, 64, 64),
+            (16, 16, 64),
+            (16, 8, 32),
+            (32, 32, 256),
+            (64, 64, 64),
+        ];
+
+        for (handle_size, handle_alignment, base_alignment) in combinations {
+            let properties = properties(handle_size, handle_alignment, base_alignment);
+            let raygen = records(0, 3);
+            let miss = vec![ShaderRecord::new(3), ShaderRecord::with_data(4, &[1; 12])];
+            let hit = records(5, 5);
+            let callable = vec![ShaderRecord::with_data(10, &[2; 40])];
+
+            let layout = ShaderBindingTableLayout::new(properties, &raygen, &miss, &hit, &callable).unwrap();
+            let label = format!("{:?}", properties);
+
+            for (region, records) in [
+                (layout.raygen, &raygen),
+                (layout.miss, &miss),
+                (layout.hit, &hit),
+                (layout.callable, &callable),
+            ] {
+                let max_data = records.iter().map(|record| record.data.len()).max().unwrap() as DeviceSize;
+
+                assert_eq!(region.offset % base_alignment as DeviceSize, 0, "{}", label);
+                assert_eq!(region.stride % handle_alignment as DeviceSize, 0, "{}", label);
+                assert!(region.stride >= handle_size as DeviceSize + max_data, "{}", label);
+                assert_eq!(region.size, region.stride * records.len() as DeviceSize, "{}", label);
+                assert!(region.offset + region.size <= layout.total_size, "{}", label);
+            }
+
+            //regionが重ならない
+            assert!(layout.raygen.offset + layout.raygen.size <= layout.miss.offset, "{}", label);
+            assert!(layout.miss.offset + layout.miss.size <= layout.hit.offset, "{}", label);
+            assert!(layout.hit.offset + layout.hit.size <= layout.callable.offset, "{}", label);
+
+            //各raygenレコードは単独でregionとして渡せる
+            for index in 0..raygen.len() as u64 {
+                let record = layout.raygen_record(index);
+
+                assert_eq!(record.offset % base_alignment as DeviceSize, 0, "{} raygen {}", label, index);
+                assert_eq!(record.size, record.stride, "{}", label);
+            }
+        }
+    }
+
+    #[test]
+    fn raygen_record_index_is_clamped() {
+        let layout = ShaderBindingTableLayout::new(properties(32, 32, 64), &records(0, 2), &[], &[], &[]).unwrap();
+
+        assert_eq!(layout.raygen_record(1).offset, 64);
+        assert_eq!(layout.raygen_record(5).offset, 64);
+    }
+
+    #[test]
+    fn empty_regions_take_no_space() {
+        let layout = ShaderBindingTableLayout::new(properties(32, 32, 64), &records(0, 1), &[], &records(1, 1), &[]).unwrap();
+
+        assert_eq!(layout.miss, ShaderBindingTableRegionLayout::default());
+        assert_eq!(layout.callable, ShaderBindingTableRegionLayout::default());
+        assert_eq!(layout.hit.offset, 64);
+        assert_eq!(layout.total_size, 96);
+    }
+
+    #[test]
+    fn raygen_is_required() {
+        assert!(ShaderBindingTableLayout::new(properties(32, 32, 64), &[], &records(0, 1), &[], &[]).is_err());
+    }
+
+    #[test]
+    fn stride_over_limit_is_rejected() {
+        let mut properties = properties(32, 32, 64);
+        properties.max_stride = 64;
+
+        let hit = [ShaderRecord::with_data(1, &[0; 64])];
+
+        assert!(ShaderBindingTableLayout::new(properties, &records(0, 1), &[], &hit, &[]).is_err());
+    }
+
+    #[test]
+    fn write_places_handles_and_data() {
+        let handle_size = 4;
+        let properties = properties(handle_size, 4, 16);
+        let raygen = records(0, 1);
+        let miss = records(1, 1);
+        let hit = [ShaderRecord::with_data(2, &[0xee, 0xff])];
+
+        let layout = ShaderBindingTableLayout::new(properties, &raygen, &miss, &hit, &[]).unwrap();
+        let handles: Vec<u8> = (0..3u8).flat_map(|group| [group; 4]).collect();
+
+        let table = layout.write(&handles, handle_size as usize, &raygen, &miss, &hit, &[]).unwrap();
+
+        assert_eq!(table.len(), layout.total_size as usize);
+        assert_eq!(&table[0..4], &[0; 4]);
+        assert_eq!(&table[16..20], &[1; 4]);
+        assert_eq!(&table[32..38], &[2, 2, 2, 2, 0xee, 0xff]);
+    }
+
+    #[test]
+    fn write_rejects_missing_handle() {
+        let raygen = records(5, 1);
+        let layout = ShaderBindingTableLayout::new(properties(4, 4, 16), &raygen, &[], &[], &[]).unwrap();
+
+        assert!(layout.write(&[0; 8], 4, &raygen, &[], &[], &[]).is_err());
+    }
+}
@@ -1,32 +1,47 @@
 use std::io::Bytes;
+use std::path::Path;
 use std::ptr;
 use ash::Device;
 use ash::vk::{ShaderModule, ShaderModuleCreateFlags, ShaderModuleCreateInfo, StructureType};
+use crate::error::{CottonError, Result};
+use crate::renderer::spirv_reflection::ShaderReflection;
 
 pub struct ShaderModules<'a> {
     device: &'a Device,
     pub shader_module: ShaderModule,
+    pub reflection: ShaderReflection,
 }
 
 impl<'a> ShaderModules<'a> {
     pub fn new(
         device: &'a Device,
         code: &[u8]
-    ) -> Self {
-        let shader_module_create_info = ShaderModuleCreateInfo {
-            code_size: code.len(),
-            p_code: code.as_ptr() as *const u32,
-            ..Default::default()
-        };
+    ) -> Result<Self> {
+        //アライメントやマジックナンバーの確認も行ってくれる
+        let code = ash::util::read_spv(&mut std::io::Cursor::new(code))
+            .map_err(|err| CottonError::ShaderLoad(err.to_string()))?;
+
+        let reflection = ShaderReflection::parse(&code)?;
+
+        let shader_module_create_info = ShaderModuleCreateInfo::builder()
+            .code(&code)
+            .build();
 
         let shader_module = unsafe {
-            device.create_shader_module(&shader_module_create_info, None).unwrap()
+            device.create_shader_module(&shader_module_create_info, None)?
         };
 
-        Self {
+        Ok(Self {
             device,
             shader_module,
-        }
+            reflection,
+        })
+    }
+
+    pub fn from_file<P: AsRef<Path>>(device: &'a Device, path: P) -> Result<Self> {
+        let code = std::fs::read(path)?;
+
+        Self::new(device, &code)
     }
 }
 
@@ -0,0 +1,333 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use log::{info, warn};
+use crate::error::Result;
+
+//書き込み途中のファイルを読まないように、最後の変更から少し待ってから読み込む
+pub const DEFAULT_RELOAD_DEBOUNCE: Duration = Duration::from_millis(200);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FileEvent {
+    Modified,
+    Removed,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReloadState {
+    Watching,
+    Pending { ready_at: Instant },
+    Reloading,
+    /// 今までのpipelineを使い続けている
+    Failed { error: String },
+    /// ファイルが消えている、再び作られるまで今までのpipelineを使い続ける
+    Missing,
+}
+
+/// ファイルの変更からpipelineの再作成までの状態遷移
+/// 時刻とイベントは外から渡すので実際のファイルがなくても動かせる
+#[derive(Clone, Debug)]
+pub struct ReloadStateMachine {
+    state: ReloadState,
+    debounce: Duration,
+    /// 再読み込みに成功した回数
+    generation: u64,
+}
+
+impl ReloadStateMachine {
+    pub fn new(debounce: Duration) -> Self {
+        Self {
+            state: ReloadState::Watching,
+            debounce,
+            generation: 0,
+        }
+    }
+
+    pub fn state(&self) -> &ReloadState {
+        &self.state
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn on_event(&mut self, event: FileEvent, now: Instant) {
+        self.state = match event {
+            //待っている間に再び変更されたら待ち直す
+            FileEvent::Modified => ReloadState::Pending { ready_at: now + self.debounce },
+            FileEvent::Removed => ReloadState::Missing,
+        };
+    }
+
+    /// 再読み込みを始めるべきならtrueを返し、Reloadingに移る
+    pub fn poll(&mut self, now: Instant) -> bool {
+        match self.state {
+            ReloadState::Pending { ready_at } if now >= ready_at => {
+                self.state = ReloadState::Reloading;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn on_reloaded(&mut self, result: std::result::Result<(), String>) {
+        //再読み込み中に新しいイベントが来ていた場合はそちらを優先する
+        if self.state != ReloadState::Reloading {
+            return;
+        }
+
+        self.state = match result {
+            Ok(()) => {
+                self.generation += 1;
+                ReloadState::Watching
+            }
+            Err(error) => ReloadState::Failed { error },
+        };
+    }
+}
+
+impl Default for ReloadStateMachine {
+    fn default() -> Self {
+        Self::new(DEFAULT_RELOAD_DEBOUNCE)
+    }
+}
+
+/// 更新時刻をポーリングしてファイルの変更を検出する
+#[derive(Clone, Debug)]
+pub struct ShaderFileWatcher {
+    path: PathBuf,
+    last_modified: Option<SystemTime>,
+}
+
+impl ShaderFileWatcher {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let last_modified = Self::modified(&path);
+
+        Self {
+            path,
+            last_modified,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+    }
+
+    pub fn poll(&mut self) -> Option<FileEvent> {
+        let modified = Self::modified(&self.path);
+
+        if modified == self.last_modified {
+            return None;
+        }
+
+        self.last_modified = modified;
+
+        match modified {
+            Some(_) => Some(FileEvent::Modified),
+            None => Some(FileEvent::Removed),
+        }
+    }
+}
+
+/// ディスク上の.spvを監視し、変更されたら`reload`で作り直す
+pub struct ShaderHotReload {
+    watcher: ShaderFileWatcher,
+    state_machine: ReloadStateMachine,
+}
+
+impl ShaderHotReload {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            watcher: ShaderFileWatcher::new(path),
+            state_machine: ReloadStateMachine::default(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.watcher.path()
+    }
+
+    pub fn state(&self) -> &ReloadState {
+        self.state_machine.state()
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.state_machine.generation()
+    }
+
+    /// 毎フレーム呼ぶ、再読み込みを行った場合はその結果を返す
+    pub fn update<F>(&mut self, now: Instant, reload: F) -> Option<Result<()>>
+    where
+        F: FnOnce(&[u8]) -> Result<()>,
+    {
+        if let Some(event) = self.watcher.poll() {
+            if event == FileEvent::Removed {
+                warn!("shader {} was removed, keep current pipeline", self.path().display());
+            }
+
+            self.state_machine.on_event(event, now);
+        }
+
+        if !self.state_machine.poll(now) {
+            return None;
+        }
+
+        let result = std::fs::read(self.path())
+            .map_err(Into::into)
+            .and_then(|code| reload(&code));
+
+        match &result {
+            Ok(()) => info!("reloaded shader {}", self.path().display()),
+            Err(err) => warn!("failed to reload shader {}, keep current pipeline: {}", self.path().display(), err),
+        }
+
+        self.state_machine.on_reloaded(result.as_ref().map(|_| ()).map_err(|err| err.to_string()));
+
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::error::CottonError;
+    use super::*;
+
+    const DEBOUNCE: Duration = Duration::from_millis(200);
+
+    #[test]
+    fn reload_waits_for_debounce() {
+        let start = Instant::now();
+        let mut state_machine = ReloadStateMachine::new(DEBOUNCE);
+
+        state_machine.on_event(FileEvent::Modified, start);
+
+        assert!(!state_machine.poll(start + Duration::from_millis(199)));
+        assert!(state_machine.poll(start + DEBOUNCE));
+        assert_eq!(state_machine.state(), &ReloadState::Reloading);
+        //一度始めたら二重に再読み込みしない
+        assert!(!state_machine.poll(start + DEBOUNCE * 2));
+    }
+
+    #[test]
+    fn repeated_modifications_restart_debounce() {
+        let start = Instant::now();
+        let mut state_machine = ReloadStateMachine::new(DEBOUNCE);
+
+        state_machine.on_event(FileEvent::Modified, start);
+        state_machine.on_event(FileEvent::Modified, start + Duration::from_millis(150));
+
+        assert!(!state_machine.poll(start + DEBOUNCE));
+        assert!(state_machine.poll(start + Duration::from_millis(350)));
+    }
+
+    #[test]
+    fn successful_reload_returns_to_watching() {
+        let start = Instant::now();
+        let mut state_machine = ReloadStateMachine::new(DEBOUNCE);
+
+        state_machine.on_event(FileEvent::Modified, start);
+        assert!(state_machine.poll(start + DEBOUNCE));
+        state_machine.on_reloaded(Ok(()));
+
+        assert_eq!(state_machine.state(), &ReloadState::Watching);
+        assert_eq!(state_machine.generation(), 1);
+    }
+
+    #[test]
+    fn failed_reload_keeps_generation_until_fixed() {
+        let start = Instant::now();
+        let mut state_machine = ReloadStateMachine::new(DEBOUNCE);
+
+        state_machine.on_event(FileEvent::Modified, start);
+        assert!(state_machine.poll(start + DEBOUNCE));
+        state_machine.on_reloaded(Err("entry point `main_miss` not found".to_owned()));
+
+        assert_eq!(
+            state_machine.state(),
+            &ReloadState::Failed { error: "entry point `main_miss` not found".to_owned() },
+        );
+        assert_eq!(state_machine.generation(), 0);
+        assert!(!state_machine.poll(start + DEBOUNCE * 10));
+
+        //直したファイルが保存されたら再び読み込む
+        let fixed_at = start + Duration::from_secs(1);
+        state_machine.on_event(FileEvent::Modified, fixed_at);
+        assert!(state_machine.poll(fixed_at + DEBOUNCE));
+        state_machine.on_reloaded(Ok(()));
+
+        assert_eq!(state_machine.generation(), 1);
+    }
+
+    #[test]
+    fn event_during_reload_takes_priority() {
+        let start = Instant::now();
+        let mut state_machine = ReloadStateMachine::new(DEBOUNCE);
+
+        state_machine.on_event(FileEvent::Modified, start);
+        assert!(state_machine.poll(start + DEBOUNCE));
+
+        let modified_at = start + Duration::from_millis(250);
+        state_machine.on_event(FileEvent::Modified, modified_at);
+        state_machine.on_reloaded(Ok(()));
+
+        assert_eq!(state_machine.state(), &ReloadState::Pending { ready_at: modified_at + DEBOUNCE });
+        assert_eq!(state_machine.generation(), 0);
+    }
+
+    #[test]
+    fn removed_file_waits_for_recreation() {
+        let start = Instant::now();
+        let mut state_machine = ReloadStateMachine::new(DEBOUNCE);
+
+        state_machine.on_event(FileEvent::Removed, start);
+
+        assert_eq!(state_machine.state(), &ReloadState::Missing);
+        assert!(!state_machine.poll(start + DEBOUNCE * 10));
+
+        state_machine.on_event(FileEvent::Modified, start + DEBOUNCE);
+        assert!(state_machine.poll(start + DEBOUNCE * 2));
+    }
+
+    #[test]
+    fn hot_reload_keeps_old_code_when_reload_fails() {
+        let path = std::env::temp_dir().join(format!("cotton_hot_reload_{}.spv", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut hot_reload = ShaderHotReload::new(&path);
+        //pipelineの代わりに読み込んだコードを持っておく
+        let mut current = b"old".to_vec();
+        let mut reload = |code: &[u8]| -> Result<()> {
+            if code.starts_with(b"bad") {
+                return Err(CottonError::ShaderValidation(vec!["broken shader".to_owned()]));
+            }
+
+            current = code.to_vec();
+            Ok(())
+        };
+
+        let start = Instant::now();
+        fs::write(&path, b"bad").unwrap();
+
+        assert!(hot_reload.update(start, &mut reload).is_none());
+        assert!(hot_reload.update(start + DEBOUNCE, &mut reload).unwrap().is_err());
+        assert!(matches!(hot_reload.state(), ReloadState::Failed { .. }));
+        assert_eq!(hot_reload.generation(), 0);
+
+        fs::remove_file(&path).unwrap();
+        assert!(hot_reload.update(start + DEBOUNCE * 2, &mut reload).is_none());
+        assert_eq!(hot_reload.state(), &ReloadState::Missing);
+
+        fs::write(&path, b"new").unwrap();
+        assert!(hot_reload.update(start + DEBOUNCE * 3, &mut reload).is_none());
+        assert!(hot_reload.update(start + DEBOUNCE * 4, &mut reload).unwrap().is_ok());
+        assert_eq!(hot_reload.generation(), 1);
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(current, b"new");
+    }
+}
@@ -0,0 +1,796 @@
+use std::collections::HashMap;
+use ash::vk::{DescriptorType, ShaderStageFlags};
+use crate::error::{CottonError, Result};
+use crate::renderer::descriptor_sets::DescriptorSetDeclaration;
+
+const MAGIC_NUMBER: u32 = 0x0723_0203;
+const HEADER_WORDS: usize = 5;
+
+//https://registry.khronos.org/SPIR-V/specs/unified1/SPIRV.html
+const OP_NAME: u32 = 5;
+const OP_ENTRY_POINT: u32 = 15;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_IMAGE: u32 = 25;
+const OP_TYPE_SAMPLER: u32 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+const OP_TYPE_ACCELERATION_STRUCTURE_KHR: u32 = 5341;
+
+const DECORATION_BLOCK: u32 = 2;
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+const STORAGE_CLASS_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_CLASS_UNIFORM: u32 = 2;
+const STORAGE_CLASS_PUSH_CONSTANT: u32 = 9;
+const STORAGE_CLASS_STORAGE_BUFFER: u32 = 12;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExecutionModel {
+    Vertex,
+    Fragment,
+    GlCompute,
+    RayGeneration,
+    Intersection,
+    AnyHit,
+    ClosestHit,
+    Miss,
+    Callable,
+    Other(u32),
+}
+
+impl ExecutionModel {
+    fn from_raw(value: u32) -> Self {
+        match value {
+            0 => Self::Vertex,
+            4 => Self::Fragment,
+            5 => Self::GlCompute,
+            5313 => Self::RayGeneration,
+            5314 => Self::Intersection,
+            5315 => Self::AnyHit,
+            5316 => Self::ClosestHit,
+            5317 => Self::Miss,
+            5318 => Self::Callable,
+            other => Self::Other(other),
+        }
+    }
+
+    pub fn stage(&self) -> ShaderStageFlags {
+        match self {
+            Self::Vertex => ShaderStageFlags::VERTEX,
+            Self::Fragment => ShaderStageFlags::FRAGMENT,
+            Self::GlCompute => ShaderStageFlags::COMPUTE,
+            Self::RayGeneration => ShaderStageFlags::RAYGEN_KHR,
+            Self::Intersection => ShaderStageFlags::INTERSECTION_KHR,
+            Self::AnyHit => ShaderStageFlags::ANY_HIT_KHR,
+            Self::ClosestHit => ShaderStageFlags::CLOSEST_HIT_KHR,
+            Self::Miss => ShaderStageFlags::MISS_KHR,
+            Self::Callable => ShaderStageFlags::CALLABLE_KHR,
+            Self::Other(_) => ShaderStageFlags::empty(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryPoint {
+    pub name: String,
+    pub execution_model: ExecutionModel,
+    /// SPIR-V 1.4以降は使用する全てのグローバル変数が含まれる
+    pub interface: Vec<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReflectedBinding {
+    pub variable_id: u32,
+    pub name: Option<String>,
+    pub set: u32,
+    pub binding: u32,
+    pub descriptor_type: Option<DescriptorType>,
+    /// 0はruntime array
+    pub count: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShaderReflection {
+    pub version: (u32, u32),
+    pub entry_points: Vec<EntryPoint>,
+    pub bindings: Vec<ReflectedBinding>,
+    pub push_constant_size: u32,
+}
+
+#[derive(Clone, Debug)]
+enum SpirvType {
+    Int { width: u32 },
+    Float { width: u32 },
+    Vector { component: u32, count: u32 },
+    Matrix { column: u32, count: u32 },
+    Image { sampled: u32 },
+    Sampler,
+    SampledImage,
+    Array { element: u32, length_id: u32 },
+    RuntimeArray { element: u32 },
+    Struct { members: Vec<u32> },
+    Pointer { storage_class: u32, pointee: u32 },
+    AccelerationStructure,
+}
+
+#[derive(Default)]
+struct Module {
+    names: HashMap<u32, String>,
+    types: HashMap<u32, SpirvType>,
+    constants: HashMap<u32, u32>,
+    decorations: HashMap<(u32, u32), u32>,
+    member_decorations: HashMap<(u32, u32, u32), u32>,
+    variables: Vec<(u32, u32, u32)>,
+    entry_points: Vec<EntryPoint>,
+}
+
+fn parse_error(message: impl Into<String>) -> CottonError {
+    CottonError::ShaderLoad(message.into())
+}
+
+/// null終端のUTF-8文字列を読み、消費したword数も返す
+fn read_string(words: &[u32]) -> Result<(String, usize)> {
+    let mut bytes = vec![];
+
+    for (i, word) in words.iter().enumerate() {
+        for byte in word.to_le_bytes() {
+            if byte == 0 {
+                let name = String::from_utf8(bytes).map_err(|err| parse_error(err.to_string()))?;
+                return Ok((name, i + 1));
+            }
+            bytes.push(byte);
+        }
+    }
+
+    Err(parse_error("unterminated string literal"))
+}
+
+impl ShaderReflection {
+    pub fn parse(words: &[u32]) -> Result<Self> {
+        if words.len() < HEADER_WORDS || words[0] != MAGIC_NUMBER {
+            return Err(parse_error("invalid SPIR-V header"));
+        }
+
+        let version = ((words[1] >> 16) & 0xff, (words[1] >> 8) & 0xff);
+
+        let mut module = Module::default();
+        let mut position = HEADER_WORDS;
+
+        while position < words.len() {
+            let word_count = (words[position] >> 16) as usize;
+            let opcode = words[position] & 0xffff;
+
+            if word_count == 0 || position + word_count > words.len() {
+                return Err(parse_error(format!("invalid instruction at word {}", position)));
+            }
+
+            let operands = &words[position + 1..position + word_count];
+            module.instruction(opcode, operands)?;
+
+            position += word_count;
+        }
+
+        module.reflect(version)
+    }
+
+    pub fn entry_point(&self, name: &str) -> Option<&EntryPoint> {
+        self.entry_points.iter().find(|entry_point| entry_point.name == name)
+    }
+
+    /// 期待しているentry pointとlayoutに合っているかを確認し、食い違いを全て報告する
+    pub fn validate(
+        &self,
+        expected_entry_points: &[(&str, ShaderStageFlags)],
+        declaration: &DescriptorSetDeclaration,
+        push_constant_size: u32,
+    ) -> Result<()> {
+        let mut problems = vec![];
+
+        for (name, stage) in expected_entry_points {
+            match self.entry_point(name) {
+                None => problems.push(format!("entry point `{}` not found", name)),
+                Some(entry_point) if entry_point.execution_model.stage() != *stage => problems.push(format!(
+                    "entry point `{}` is {:?}, but used as {:?}",
+                    name,
+                    entry_point.execution_model,
+                    stage,
+                )),
+                Some(_) => {}
+            }
+        }
+
+        for reflected in self.bindings.iter() {
+            let label = reflected.name.clone().unwrap_or_else(|| format!("%{}", reflected.variable_id));
+
+            if reflected.set != 0 {
+                problems.push(format!("`{}` uses descriptor set {}, but only set 0 is bound", label, reflected.set));
+                continue;
+            }
+
+            let declared = declaration
+                .bindings()
+                .iter()
+                .find(|binding| binding.binding == reflected.binding);
+
+            let declared = match declared {
+                Some(declared) => declared,
+                None => {
+                    problems.push(format!("`{}` uses binding {}, which is not declared", label, reflected.binding));
+                    continue;
+                }
+            };
+
+            if let Some(descriptor_type) = reflected.descriptor_type {
+                if descriptor_type != declared.descriptor_type() {
+                    problems.push(format!(
+                        "`{}` at binding {} is {:?}, but declared as {:?}",
+                        label,
+                        reflected.binding,
+                        descriptor_type,
+                        declared.descriptor_type(),
+                    ));
+                }
+            }
+
+            //1.4より前はinterfaceにstorage classがInput/Output以外の変数が含まれないので確認できない
+            if self.version >= (1, 4) {
+                for (name, stage) in expected_entry_points {
+                    let used = self
+                        .entry_point(name)
+                        .map_or(false, |entry_point| entry_point.interface.contains(&reflected.variable_id));
+
+                    if used && !declared.stages.contains(*stage) {
+                        problems.push(format!(
+                            "`{}` at binding {} is used from `{}`, but not visible to {:?}",
+                            label,
+                            reflected.binding,
+                            name,
+                            stage,
+                        ));
+                    }
+                }
+            }
+
+            if reflected.count != 0 && reflected.count > declared.descriptor_count() {
+                problems.push(format!(
+                    "`{}` at binding {} has {} elements, but {} are declared",
+                    label,
+                    reflected.binding,
+                    reflected.count,
+                    declared.descriptor_count(),
+                ));
+            }
+        }
+
+        if self.push_constant_size > push_constant_size {
+            problems.push(format!(
+                "push constants use {} bytes, but the layout has {} bytes",
+                self.push_constant_size,
+                push_constant_size,
+            ));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(CottonError::ShaderValidation(problems))
+        }
+    }
+}
+
+impl Module {
+    fn instruction(&mut self, opcode: u32, operands: &[u32]) -> Result<()> {
+        let operand = |index: usize| -> Result<u32> {
+            operands
+                .get(index)
+                .copied()
+                .ok_or_else(|| parse_error(format!("missing operand {} of opcode {}", index, opcode)))
+        };
+
+        match opcode {
+            OP_NAME => {
+                let (name, _) = read_string(operands.get(1..).unwrap_or(&[]))?;
+                self.names.insert(operand(0)?, name);
+            }
+            OP_ENTRY_POINT => {
+                let execution_model = ExecutionModel::from_raw(operand(0)?);
+                let (name, name_words) = read_string(operands.get(2..).unwrap_or(&[]))?;
+
+                self.entry_points.push(EntryPoint {
+                    name,
+                    execution_model,
+                    interface: operands.get(2 + name_words..).unwrap_or(&[]).to_vec(),
+                });
+            }
+            OP_TYPE_INT => {
+                self.types.insert(operand(0)?, SpirvType::Int { width: operand(1)? });
+            }
+            OP_TYPE_FLOAT => {
+                self.types.insert(operand(0)?, SpirvType::Float { width: operand(1)? });
+            }
+            OP_TYPE_VECTOR => {
+                self.types.insert(operand(0)?, SpirvType::Vector { component: operand(1)?, count: operand(2)? });
+            }
+            OP_TYPE_MATRIX => {
+                self.types.insert(operand(0)?, SpirvType::Matrix { column: operand(1)?, count: operand(2)? });
+            }
+            OP_TYPE_IMAGE => {
+                //Sampledは7番目のオペランド、1ならsampled image、2ならstorage image
+                self.types.insert(operand(0)?, SpirvType::Image { sampled: operand(6)? });
+            }
+            OP_TYPE_SAMPLER => {
+                self.types.insert(operand(0)?, SpirvType::Sampler);
+            }
+            OP_TYPE_SAMPLED_IMAGE => {
+                self.types.insert(operand(0)?, SpirvType::SampledImage);
+            }
+            OP_TYPE_ARRAY => {
+                self.types.insert(operand(0)?, SpirvType::Array { element: operand(1)?, length_id: operand(2)? });
+            }
+            OP_TYPE_RUNTIME_ARRAY => {
+                self.types.insert(operand(0)?, SpirvType::RuntimeArray { element: operand(1)? });
+            }
+            OP_TYPE_STRUCT => {
+                self.types.insert(operand(0)?, SpirvType::Struct { members: operands[1..].to_vec() });
+            }
+            OP_TYPE_POINTER => {
+                self.types.insert(operand(0)?, SpirvType::Pointer { storage_class: operand(1)?, pointee: operand(2)? });
+            }
+            OP_TYPE_ACCELERATION_STRUCTURE_KHR => {
+                self.types.insert(operand(0)?, SpirvType::AccelerationStructure);
+            }
+            OP_CONSTANT => {
+                //64bitの定数は下位のみ使う、配列の長さなどでは問題ない
+                self.constants.insert(operand(1)?, operand(2)?);
+            }
+            OP_VARIABLE => {
+                self.variables.push((operand(0)?, operand(1)?, operand(2)?));
+            }
+            OP_DECORATE => {
+                //Blockなどのオペランドがないものは0として扱う
+                let value = operands.get(2).copied().unwrap_or(0);
+                self.decorations.insert((operand(0)?, operand(1)?), value);
+            }
+            OP_MEMBER_DECORATE => {
+                let value = operands.get(3).copied().unwrap_or(0);
+                self.member_decorations.insert((operand(0)?, operand(1)?, operand(2)?), value);
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn reflect(self, version: (u32, u32)) -> Result<ShaderReflection> {
+        let mut bindings = vec![];
+        let mut push_constant_size = 0;
+
+        for (result_type, variable_id, storage_class) in self.variables.iter().copied() {
+            let pointee = match self.types.get(&result_type) {
+                Some(SpirvType::Pointer { pointee, .. }) => *pointee,
+                _ => continue,
+            };
+
+            if storage_class == STORAGE_CLASS_PUSH_CONSTANT {
+                push_constant_size = push_constant_size.max(self.size_of(pointee, None)?);
+                continue;
+            }
+
+            if ![STORAGE_CLASS_UNIFORM_CONSTANT, STORAGE_CLASS_UNIFORM, STORAGE_CLASS_STORAGE_BUFFER].contains(&storage_class) {
+                continue;
+            }
+
+            let (set, binding) = match (
+                self.decorations.get(&(variable_id, DECORATION_DESCRIPTOR_SET)),
+                self.decorations.get(&(variable_id, DECORATION_BINDING)),
+            ) {
+                (Some(set), Some(binding)) => (*set, *binding),
+                _ => continue,
+            };
+
+            let (element, count) = match self.types.get(&pointee) {
+                Some(SpirvType::Array { element, length_id }) => {
+                    (*element, self.constants.get(length_id).copied().unwrap_or(1))
+                }
+                Some(SpirvType::RuntimeArray { element }) => (*element, 0),
+                _ => (pointee, 1),
+            };
+
+            bindings.push(ReflectedBinding {
+                variable_id,
+                name: self.names.get(&variable_id).cloned(),
+                set,
+                binding,
+                descriptor_type: self.descriptor_type(storage_class, element),
+                count,
+            });
+        }
+
+        bindings.sort_by_key(|binding| (binding.set, binding.binding));
+
+        Ok(ShaderReflection {
+            version,
+            entry_points: self.entry_points,
+            bindings,
+            push_constant_size,
+        })
+    }
+
+    fn descriptor_type(&self, storage_class: u32, type_id: u32) -> Option<DescriptorType> {
+        let ty = self.types.get(&type_id)?;
+
+        match (storage_class, ty) {
+            (STORAGE_CLASS_STORAGE_BUFFER, _) => Some(DescriptorType::STORAGE_BUFFER),
+            (STORAGE_CLASS_UNIFORM, SpirvType::Struct { .. }) => {
+                if self.decorations.contains_key(&(type_id, DECORATION_BUFFER_BLOCK)) {
+                    Some(DescriptorType::STORAGE_BUFFER)
+                } else if self.decorations.contains_key(&(type_id, DECORATION_BLOCK)) {
+                    Some(DescriptorType::UNIFORM_BUFFER)
+                } else {
+                    None
+                }
+            }
+            (STORAGE_CLASS_UNIFORM_CONSTANT, SpirvType::AccelerationStructure) => Some(DescriptorType::ACCELERATION_STRUCTURE_KHR),
+            (STORAGE_CLASS_UNIFORM_CONSTANT, SpirvType::Image { sampled: 2 }) => Some(DescriptorType::STORAGE_IMAGE),
+            (STORAGE_CLASS_UNIFORM_CONSTANT, SpirvType::Image { .. }) => Some(DescriptorType::SAMPLED_IMAGE),
+            (STORAGE_CLASS_UNIFORM_CONSTANT, SpirvType::SampledImage) => Some(DescriptorType::COMBINED_IMAGE_SAMPLER),
+            (STORAGE_CLASS_UNIFORM_CONSTANT, SpirvType::Sampler) => Some(DescriptorType::SAMPLER),
+            _ => None,
+        }
+    }
+
+    /// push constantのサイズを求めるためのもの、offsetの装飾がある構造体のみ正確に求まる
+    /// MatrixStrideは親の構造体のメンバーに付くので、メンバーとして求める場合に渡す
+    fn size_of(&self, type_id: u32, matrix_stride: Option<u32>) -> Result<u32> {
+        let ty = self.types
+            .get(&type_id)
+            .ok_or_else(|| parse_error(format!("unknown type %{}", type_id)))?;
+
+        let size = match ty {
+            SpirvType::Int { width } | SpirvType::Float { width } => width / 8,
+            SpirvType::Vector { component, count } => self.size_of(*component, None)? * count,
+            SpirvType::Matrix { column, count } => {
+                let stride = match matrix_stride {
+                    Some(stride) => stride,
+                    None => self.size_of(*column, None)?,
+                };
+                stride * count
+            }
+            SpirvType::Array { element, length_id } => {
+                let length = self.constants.get(length_id).copied().unwrap_or(1);
+                let stride = match self.decorations.get(&(type_id, DECORATION_ARRAY_STRIDE)) {
+                    Some(stride) => *stride,
+                    //行列の配列の場合は要素の行列にメンバーのMatrixStrideが適用される
+                    None => self.size_of(*element, matrix_stride)?,
+                };
+                stride * length
+            }
+            SpirvType::Struct { members } => {
+                let mut size = 0;
+                let mut offset = 0;
+
+                for (i, member) in members.iter().enumerate() {
+                    let member_offset = self.member_decorations
+                        .get(&(type_id, i as u32, DECORATION_OFFSET))
+                        .copied()
+                        .unwrap_or(offset);
+
+                    let member_matrix_stride = self.member_decorations
+                        .get(&(type_id, i as u32, DECORATION_MATRIX_STRIDE))
+                        .copied();
+
+                    offset = member_offset + self.size_of(*member, member_matrix_stride)?;
+                    size = size.max(offset);
+                }
+
+                size
+            }
+            _ => 0,
+        };
+
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ash::vk::{AccelerationStructureKHR, Buffer, Handle, ImageView};
+    use crate::renderer::descriptor_sets::{DescriptorResource, DescriptorSetBuilder};
+    use super::*;
+
+    const OP_CAPABILITY: u32 = 17;
+    const EXECUTION_MODEL_RAY_GENERATION: u32 = 5313;
+    const EXECUTION_MODEL_CLOSEST_HIT: u32 = 5316;
+    const EXECUTION_MODEL_MISS: u32 = 5317;
+
+    /// 手で組み立てるSPIR-Vモジュール
+    struct Assembler {
+        words: Vec<u32>,
+    }
+
+    impl Assembler {
+        fn new(version: (u32, u32)) -> Self {
+            Self {
+                words: vec![MAGIC_NUMBER, version.0 << 16 | version.1 << 8, 0, 100, 0],
+            }
+        }
+
+        fn op(mut self, opcode: u32, operands: &[u32]) -> Self {
+            self.words.push((operands.len() as u32 + 1) << 16 | opcode);
+            self.words.extend_from_slice(operands);
+            self
+        }
+
+        fn entry_point(self, execution_model: u32, function: u32, name: &str, interface: &[u32]) -> Self {
+            let mut operands = vec![execution_model, function];
+            operands.extend(string(name));
+            operands.extend_from_slice(interface);
+            self.op(OP_ENTRY_POINT, &operands)
+        }
+
+        fn name(self, id: u32, name: &str) -> Self {
+            let mut operands = vec![id];
+            operands.extend(string(name));
+            self.op(OP_NAME, &operands)
+        }
+
+        fn binding(self, variable: u32, set: u32, binding: u32) -> Self {
+            self.op(OP_DECORATE, &[variable, DECORATION_DESCRIPTOR_SET, set])
+                .op(OP_DECORATE, &[variable, DECORATION_BINDING, binding])
+        }
+    }
+
+    //null終端してwordに詰める
+    fn string(value: &str) -> Vec<u32> {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        while bytes.len() % 4 != 0 {
+            bytes.push(0);
+        }
+
+        bytes.chunks_exact(4).map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])).collect()
+    }
+
+    //classical_raytracer_shaderと同じ構成のモジュール
+    fn ray_tracing_module() -> Vec<u32> {
+        Assembler::new((1, 4))
+            .op(OP_CAPABILITY, &[4479])
+            .entry_point(EXECUTION_MODEL_RAY_GENERATION, 1, "main_ray_generation", &[10, 11, 40])
+            .entry_point(EXECUTION_MODEL_MISS, 2, "main_miss", &[])
+            .entry_point(EXECUTION_MODEL_CLOSEST_HIT, 3, "triangle_closest_hit", &[13, 14])
+            .name(10, "tlas")
+            .name(11, "image")
+            .binding(10, 0, 0)
+            .binding(11, 0, 1)
+            .binding(13, 0, 3)
+            .binding(14, 0, 5)
+            .op(OP_DECORATE, &[32, DECORATION_BLOCK])
+            .op(OP_MEMBER_DECORATE, &[32, 0, DECORATION_OFFSET, 0])
+            .op(OP_DECORATE, &[30, DECORATION_ARRAY_STRIDE, 4])
+            //型
+            .op(OP_TYPE_FLOAT, &[20, 32])
+            .op(OP_TYPE_INT, &[21, 32, 0])
+            .op(OP_TYPE_ACCELERATION_STRUCTURE_KHR, &[22])
+            .op(OP_TYPE_POINTER, &[23, STORAGE_CLASS_UNIFORM_CONSTANT, 22])
+            .op(OP_TYPE_IMAGE, &[24, 20, 1, 0, 0, 0, 2, 1])
+            .op(OP_TYPE_POINTER, &[25, STORAGE_CLASS_UNIFORM_CONSTANT, 24])
+            .op(OP_TYPE_IMAGE, &[26, 20, 1, 0, 0, 0, 1, 0])
+            .op(OP_TYPE_SAMPLED_IMAGE, &[27, 26])
+            .op(OP_TYPE_RUNTIME_ARRAY, &[28, 27])
+            .op(OP_TYPE_POINTER, &[29, STORAGE_CLASS_UNIFORM_CONSTANT, 28])
+            .op(OP_TYPE_RUNTIME_ARRAY, &[30, 21])
+            .op(OP_TYPE_STRUCT, &[32, 30])
+            .op(OP_TYPE_POINTER, &[33, STORAGE_CLASS_STORAGE_BUFFER, 32])
+            .op(OP_TYPE_POINTER, &[41, STORAGE_CLASS_PUSH_CONSTANT, 42])
+            .op(OP_DECORATE, &[42, DECORATION_BLOCK])
+            .op(OP_MEMBER_DECORATE, &[42, 0, DECORATION_OFFSET, 0])
+            .op(OP_MEMBER_DECORATE, &[42, 1, DECORATION_OFFSET, 4])
+            .op(OP_TYPE_STRUCT, &[42, 21, 20])
+            //変数
+            .op(OP_VARIABLE, &[23, 10, STORAGE_CLASS_UNIFORM_CONSTANT])
+            .op(OP_VARIABLE, &[25, 11, STORAGE_CLASS_UNIFORM_CONSTANT])
+            .op(OP_VARIABLE, &[33, 13, STORAGE_CLASS_STORAGE_BUFFER])
+            .op(OP_VARIABLE, &[29, 14, STORAGE_CLASS_UNIFORM_CONSTANT])
+            .op(OP_VARIABLE, &[41, 40, STORAGE_CLASS_PUSH_CONSTANT])
+            .words
+    }
+
+    fn declaration() -> DescriptorSetBuilder {
+        DescriptorSetBuilder::new()
+            .binding(
+                0,
+                ShaderStageFlags::RAYGEN_KHR,
+                DescriptorResource::acceleration_structure(AccelerationStructureKHR::from_raw(1)),
+            )
+            .binding(1, ShaderStageFlags::RAYGEN_KHR, DescriptorResource::storage_image(ImageView::from_raw(2)))
+            .binding(3, ShaderStageFlags::CLOSEST_HIT_KHR, DescriptorResource::storage_buffer(Buffer::from_raw(3)))
+            .binding(
+                5,
+                ShaderStageFlags::CLOSEST_HIT_KHR,
+                DescriptorResource::combined_image_samplers(&[ImageView::from_raw(4)], ash::vk::Sampler::from_raw(5)),
+            )
+    }
+
+    const EXPECTED_ENTRY_POINTS: [(&str, ShaderStageFlags); 3] = [
+        ("main_ray_generation", ShaderStageFlags::RAYGEN_KHR),
+        ("main_miss", ShaderStageFlags::MISS_KHR),
+        ("triangle_closest_hit", ShaderStageFlags::CLOSEST_HIT_KHR),
+    ];
+
+    #[test]
+    fn rejects_invalid_header() {
+        assert!(ShaderReflection::parse(&[]).is_err());
+        assert!(ShaderReflection::parse(&[0xdeadbeef, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_instruction() {
+        let mut words = Assembler::new((1, 4)).name(1, "main").words;
+        words.pop();
+
+        assert!(ShaderReflection::parse(&words).is_err());
+    }
+
+    #[test]
+    fn reflects_entry_points() {
+        let reflection = ShaderReflection::parse(&ray_tracing_module()).unwrap();
+
+        assert_eq!(reflection.version, (1, 4));
+        assert_eq!(reflection.entry_points.len(), 3);
+
+        let raygen = reflection.entry_point("main_ray_generation").unwrap();
+        assert_eq!(raygen.execution_model, ExecutionModel::RayGeneration);
+        assert_eq!(raygen.execution_model.stage(), ShaderStageFlags::RAYGEN_KHR);
+        assert_eq!(raygen.interface, vec![10, 11, 40]);
+
+        assert_eq!(reflection.entry_point("main_miss").unwrap().execution_model, ExecutionModel::Miss);
+        assert!(reflection.entry_point("sphere_intersection").is_none());
+    }
+
+    #[test]
+    fn reflects_bindings_and_push_constants() {
+        let reflection = ShaderReflection::parse(&ray_tracing_module()).unwrap();
+
+        let bindings: Vec<(u32, Option<DescriptorType>, u32, Option<&str>)> = reflection
+            .bindings
+            .iter()
+            .map(|binding| (binding.binding, binding.descriptor_type, binding.count, binding.name.as_deref()))
+            .collect();
+
+        assert_eq!(bindings, vec![
+            (0, Some(DescriptorType::ACCELERATION_STRUCTURE_KHR), 1, Some("tlas")),
+            (1, Some(DescriptorType::STORAGE_IMAGE), 1, Some("image")),
+            (3, Some(DescriptorType::STORAGE_BUFFER), 1, None),
+            (5, Some(DescriptorType::COMBINED_IMAGE_SAMPLER), 0, None),
+        ]);
+        assert_eq!(reflection.push_constant_size, 8);
+    }
+
+    #[test]
+    fn matrix_stride_comes_from_member_decoration() {
+        //4x3の行列、列はvec3だがMatrixStrideで16byteごとに並ぶ
+        let words = Assembler::new((1, 4))
+            .op(OP_MEMBER_DECORATE, &[5, 0, DECORATION_OFFSET, 0])
+            .op(OP_MEMBER_DECORATE, &[5, 0, DECORATION_MATRIX_STRIDE, 16])
+            .op(OP_MEMBER_DECORATE, &[5, 1, DECORATION_OFFSET, 64])
+            .op(OP_TYPE_FLOAT, &[1, 32])
+            .op(OP_TYPE_VECTOR, &[2, 1, 3])
+            .op(OP_TYPE_MATRIX, &[3, 2, 4])
+            .op(OP_TYPE_STRUCT, &[5, 3, 1])
+            .op(OP_TYPE_POINTER, &[6, STORAGE_CLASS_PUSH_CONSTANT, 5])
+            .op(OP_VARIABLE, &[6, 7, STORAGE_CLASS_PUSH_CONSTANT])
+            .words;
+
+        assert_eq!(ShaderReflection::parse(&words).unwrap().push_constant_size, 68);
+    }
+
+    #[test]
+    fn matrix_stride_applies_to_arrays_of_matrices() {
+        let words = Assembler::new((1, 4))
+            .op(OP_MEMBER_DECORATE, &[5, 0, DECORATION_OFFSET, 0])
+            .op(OP_MEMBER_DECORATE, &[5, 0, DECORATION_MATRIX_STRIDE, 16])
+            .op(OP_TYPE_FLOAT, &[1, 32])
+            .op(OP_TYPE_INT, &[8, 32, 0])
+            .op(OP_CONSTANT, &[8, 9, 2])
+            .op(OP_TYPE_VECTOR, &[2, 1, 3])
+            .op(OP_TYPE_MATRIX, &[3, 2, 4])
+            .op(OP_TYPE_ARRAY, &[4, 3, 9])
+            .op(OP_TYPE_STRUCT, &[5, 4])
+            .op(OP_TYPE_POINTER, &[6, STORAGE_CLASS_PUSH_CONSTANT, 5])
+            .op(OP_VARIABLE, &[6, 7, STORAGE_CLASS_PUSH_CONSTANT])
+            .words;
+
+        assert_eq!(ShaderReflection::parse(&words).unwrap().push_constant_size, 128);
+    }
+
+    #[test]
+    fn matching_layout_is_valid() {
+        let reflection = ShaderReflection::parse(&ray_tracing_module()).unwrap();
+        let declaration = declaration().build().unwrap();
+
+        reflection.validate(&EXPECTED_ENTRY_POINTS, &declaration, 8).unwrap();
+    }
+
+    #[test]
+    fn reports_every_mismatch() {
+        let reflection = ShaderReflection::parse(&ray_tracing_module()).unwrap();
+
+        //binding 3がない、binding 1の種類が違う、raygenからbinding 1が見えない
+        let declaration = DescriptorSetBuilder::new()
+            .binding(
+                0,
+                ShaderStageFlags::RAYGEN_KHR,
+                DescriptorResource::acceleration_structure(AccelerationStructureKHR::from_raw(1)),
+            )
+            .binding(1, ShaderStageFlags::CLOSEST_HIT_KHR, DescriptorResource::storage_buffer(Buffer::from_raw(2)))
+            .binding(
+                5,
+                ShaderStageFlags::CLOSEST_HIT_KHR,
+                DescriptorResource::combined_image_samplers(&[ImageView::from_raw(4)], ash::vk::Sampler::from_raw(5)),
+            )
+            .build()
+            .unwrap();
+
+        let expected = [
+            ("main_ray_generation", ShaderStageFlags::RAYGEN_KHR),
+            ("main_miss", ShaderStageFlags::CLOSEST_HIT_KHR),
+            ("sphere_intersection", ShaderStageFlags::INTERSECTION_KHR),
+        ];
+
+        let problems = match reflection.validate(&expected, &declaration, 4) {
+            Err(CottonError::ShaderValidation(problems)) => problems,
+            result => panic!("unexpected result {:?}", result),
+        };
+
+        assert_eq!(problems, vec![
+            "entry point `main_miss` is Miss, but used as CLOSEST_HIT_KHR".to_owned(),
+            "entry point `sphere_intersection` not found".to_owned(),
+            "`image` at binding 1 is STORAGE_IMAGE, but declared as STORAGE_BUFFER".to_owned(),
+            "`image` at binding 1 is used from `main_ray_generation`, but not visible to RAYGEN_KHR".to_owned(),
+            "`%13` uses binding 3, which is not declared".to_owned(),
+            "push constants use 8 bytes, but the layout has 4 bytes".to_owned(),
+        ]);
+    }
+
+    #[test]
+    fn interface_is_not_checked_before_spirv_1_4() {
+        let mut words = ray_tracing_module();
+        words[1] = 1 << 16 | 3 << 8;
+
+        let reflection = ShaderReflection::parse(&words).unwrap();
+        //raygenから見えないが1.3ではinterfaceに含まれないので分からない
+        let declaration = DescriptorSetBuilder::new()
+            .binding(
+                0,
+                ShaderStageFlags::RAYGEN_KHR,
+                DescriptorResource::acceleration_structure(AccelerationStructureKHR::from_raw(1)),
+            )
+            .binding(1, ShaderStageFlags::CLOSEST_HIT_KHR, DescriptorResource::storage_image(ImageView::from_raw(2)))
+            .binding(3, ShaderStageFlags::CLOSEST_HIT_KHR, DescriptorResource::storage_buffer(Buffer::from_raw(3)))
+            .binding(
+                5,
+                ShaderStageFlags::CLOSEST_HIT_KHR,
+                DescriptorResource::combined_image_samplers(&[ImageView::from_raw(4)], ash::vk::Sampler::from_raw(5)),
+            )
+            .build()
+            .unwrap();
+
+        assert_eq!(reflection.version, (1, 3));
+        reflection.validate(&EXPECTED_ENTRY_POINTS, &declaration, 8).unwrap();
+    }
+}
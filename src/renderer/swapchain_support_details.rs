@@ -2,9 +2,10 @@ use std::borrow::Borrow;
 use std::ffi::CStr;
 use ash::extensions::khr::Swapchain;
 use ash::{Instance, vk};
-use ash::vk::{Extent2D, PhysicalDevice, PresentInfoKHR, PresentModeKHR, SurfaceCapabilitiesKHR, SurfaceFormatKHR};
+use ash::vk::{Extent2D, ImageUsageFlags, PhysicalDevice, PresentInfoKHR, PresentModeKHR, SurfaceCapabilitiesKHR, SurfaceFormatKHR};
 use log::info;
 use winit::dpi::Size;
+use crate::error::{CottonError, Result};
 use crate::renderer::backends::surfaces::Surfaces;
 
 pub struct SwapchainSupportDetails {
@@ -17,40 +18,37 @@ impl SwapchainSupportDetails {
     pub fn new(
         physical_device: PhysicalDevice,
         surfaces: &Surfaces,
-    ) -> Self {
+    ) -> Result<Self> {
         let capabilities = unsafe {
             surfaces.surface
-                .get_physical_device_surface_capabilities(physical_device, surfaces.surface_khr)
-                .unwrap()
+                .get_physical_device_surface_capabilities(physical_device, surfaces.surface_khr)?
         };
 
         let formats = unsafe {
             surfaces.surface
-                .get_physical_device_surface_formats(physical_device, surfaces.surface_khr)
-                .unwrap()
+                .get_physical_device_surface_formats(physical_device, surfaces.surface_khr)?
         };
 
         let present_modes = unsafe {
             surfaces.surface
-                .get_physical_device_surface_present_modes(physical_device, surfaces.surface_khr)
-                .unwrap()
+                .get_physical_device_surface_present_modes(physical_device, surfaces.surface_khr)?
         };
 
-        Self {
+        Ok(Self {
             capabilities,
             formats,
             present_modes
-        }
+        })
     }
 
     pub fn check_swapchain_support(
         instance: &Instance,
         physical_device: PhysicalDevice,
-    ) -> bool {
+    ) -> Result<bool> {
         let required_extension = Swapchain::name();
 
         let extensions = unsafe {
-            instance.enumerate_device_extension_properties(physical_device).unwrap()
+            instance.enumerate_device_extension_properties(physical_device)?
         };
 
 
@@ -59,24 +57,33 @@ impl SwapchainSupportDetails {
             required_extension == name
         });
 
-        if !found {
-            return false;
-        }
-
-        true
+        Ok(found)
     }
 
     ///使用するformatsを選択する
-    pub fn choose_swapchain_surface_format(&self) -> SurfaceFormatKHR {
+    pub fn choose_swapchain_surface_format(&self) -> Result<SurfaceFormatKHR> {
         for available_format in self.formats.iter() {
             if available_format.format == vk::Format::R8G8B8A8_SRGB
                 && available_format.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
             {
-                return *available_format;
+                return Ok(*available_format);
             }
         }
 
-        *self.formats.first().unwrap()
+        self.formats.first().copied().ok_or(CottonError::NoSurfaceFormat)
+    }
+
+    /// offscreenの画像をblitするのでTRANSFER_DSTも必要
+    pub fn choose_swapchain_image_usage(&self) -> Result<ImageUsageFlags> {
+        let required = ImageUsageFlags::COLOR_ATTACHMENT | ImageUsageFlags::TRANSFER_DST;
+
+        if !self.capabilities.supported_usage_flags.contains(required) {
+            return Err(CottonError::UnsupportedSwapchainUsage(
+                required & !self.capabilities.supported_usage_flags,
+            ));
+        }
+
+        Ok(required)
     }
 
     pub fn choose_swapchain_present_mode(&self) -> PresentModeKHR {
@@ -113,3 +120,37 @@ impl SwapchainSupportDetails {
         Extent2D { width, height }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn details(supported_usage_flags: ImageUsageFlags) -> SwapchainSupportDetails {
+        SwapchainSupportDetails {
+            capabilities: SurfaceCapabilitiesKHR {
+                supported_usage_flags,
+                ..Default::default()
+            },
+            formats: vec![],
+            present_modes: vec![],
+        }
+    }
+
+    #[test]
+    fn image_usage_requires_transfer_dst() {
+        let supported = ImageUsageFlags::COLOR_ATTACHMENT | ImageUsageFlags::TRANSFER_DST | ImageUsageFlags::STORAGE;
+
+        assert_eq!(
+            details(supported).choose_swapchain_image_usage().unwrap(),
+            ImageUsageFlags::COLOR_ATTACHMENT | ImageUsageFlags::TRANSFER_DST,
+        );
+    }
+
+    #[test]
+    fn missing_usage_is_reported() {
+        assert!(matches!(
+            details(ImageUsageFlags::COLOR_ATTACHMENT).choose_swapchain_image_usage(),
+            Err(CottonError::UnsupportedSwapchainUsage(missing)) if missing == ImageUsageFlags::TRANSFER_DST,
+        ));
+    }
+}
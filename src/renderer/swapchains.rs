@@ -3,10 +3,12 @@ use std::ops::Deref;
 use std::path::is_separator;
 use ash::extensions::khr::Swapchain;
 use ash::{vk, Device};
-use ash::vk::{CompositeAlphaFlagsKHR, Extent2D, Format, Image, ImageUsageFlags, SharingMode, SwapchainCreateInfoKHR, SwapchainKHR};
+use ash::vk::{CompositeAlphaFlagsKHR, Extent2D, Fence, Format, Image, PresentInfoKHR, Queue, Semaphore, SharingMode, SwapchainCreateInfoKHR, SwapchainKHR};
 use log::{debug, info};
 use winit::dpi::{LogicalSize, Size};
+use crate::error::{CottonError, Result};
 use crate::renderer::backends::Backends;
+use crate::renderer::frame_state::{AcquireOutcome, PresentOutcome};
 use crate::renderer::images::Images;
 use crate::renderer::backends::queue_family_indices::QueueFamilyIndices;
 use crate::renderer::backends::surfaces::Surfaces;
@@ -23,16 +25,26 @@ impl Swapchains {
     pub fn new<S: Into<Size>>(
         backends: &Backends,
         window_size: S,
-    ) -> Self {
+    ) -> Result<Self> {
+        Self::create(backends, window_size, SwapchainKHR::null())
+    }
+
+    /// 古いswapchainを渡すと表示中のimageを引き継ぎながら作り直せる
+    fn create<S: Into<Size>>(
+        backends: &Backends,
+        window_size: S,
+        old_swapchain: SwapchainKHR,
+    ) -> Result<Self> {
         debug!("create swapchains");
 
         //as_refはOptionなどの中身に対してborrowすることが出来る
-        let surfaces = backends.surfaces.as_ref().expect("Not found surfaces");
+        let surfaces = backends.surfaces.as_ref().ok_or(CottonError::SurfaceRequired)?;
 
-        let swapchain_support = SwapchainSupportDetails::new(backends.physical_device, surfaces);
+        let swapchain_support = SwapchainSupportDetails::new(backends.physical_device, surfaces)?;
 
-        let surface_format = swapchain_support.choose_swapchain_surface_format();
+        let surface_format = swapchain_support.choose_swapchain_surface_format()?;
         let present_mode = swapchain_support.choose_swapchain_present_mode();
+        let image_usage = swapchain_support.choose_swapchain_image_usage()?;
         let extent = swapchain_support.choose_swapchain_extent(window_size);
 
         let mut image_count = swapchain_support.capabilities.min_image_count + 1;
@@ -49,16 +61,16 @@ impl Swapchains {
             .image_color_space(surface_format.color_space)
             .image_extent(extent)
             .image_array_layers(1)
-            .image_usage(ImageUsageFlags::COLOR_ATTACHMENT);
+            .image_usage(image_usage);
 
         let indices = QueueFamilyIndices::new(
             &backends.instance,
             backends.surfaces.as_ref(),
             backends.physical_device
-        );
+        )?;
 
-        let graphics_family = indices.graphics_family.expect("Graphics family is not exist");
-        let present_family = indices.present_family.expect("Present family is not exist");
+        let graphics_family = indices.graphics_family.ok_or(CottonError::MissingQueueFamily("graphics"))?;
+        let present_family = indices.present_family.ok_or(CottonError::MissingQueueFamily("present"))?;
 
         let queue_family_indices = [
             graphics_family,
@@ -80,26 +92,66 @@ impl Swapchains {
             .composite_alpha(CompositeAlphaFlagsKHR::OPAQUE)
             .present_mode(present_mode)
             .clipped(true)
-            .old_swapchain(SwapchainKHR::null())
+            .old_swapchain(old_swapchain)
             .build();
 
         let swapchain = Swapchain::new(&backends.instance, &backends.device);
-        let swapchain_khr = unsafe { swapchain.create_swapchain(&create_info, None).unwrap() };
+        let swapchain_khr = unsafe { swapchain.create_swapchain(&create_info, None)? };
 
         info!("swapchain: {:?}", swapchain_khr);
 
-        Self {
+        Ok(Self {
             swapchain,
             swapchain_khr,
             format: surface_format.format,
             extent,
+        })
+    }
+
+    /// リサイズやOUT_OF_DATEの後に呼ぶ、古いswapchainのimageは使用が終わっている必要がある
+    pub fn recreate<S: Into<Size>>(&mut self, backends: &Backends, window_size: S) -> Result<()> {
+        let swapchains = Self::create(backends, window_size, self.swapchain_khr)?;
+
+        //Dropで古いswapchainが破棄される
+        let _old = std::mem::replace(self, swapchains);
+
+        Ok(())
+    }
+
+    pub fn acquire_next_image(&self, image_available: Semaphore) -> Result<AcquireOutcome> {
+        let result = unsafe {
+            self.swapchain.acquire_next_image(self.swapchain_khr, u64::MAX, image_available, Fence::null())
+        };
+
+        match result {
+            Ok((image_index, suboptimal)) => Ok(AcquireOutcome::Acquired { image_index, suboptimal }),
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => Ok(AcquireOutcome::OutOfDate),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn present(&self, present_queue: Queue, image_index: u32, render_finished: Semaphore) -> Result<PresentOutcome> {
+        let wait_semaphores = [render_finished];
+        let swapchains = [self.swapchain_khr];
+        let image_indices = [image_index];
+
+        let present_info = PresentInfoKHR::builder()
+            .wait_semaphores(&wait_semaphores)
+            .swapchains(&swapchains)
+            .image_indices(&image_indices)
+            .build();
+
+        match unsafe { self.swapchain.queue_present(present_queue, &present_info) } {
+            Ok(suboptimal) => Ok(PresentOutcome::Presented { suboptimal }),
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => Ok(PresentOutcome::OutOfDate),
+            Err(err) => Err(err.into()),
         }
     }
 
-    pub fn get_swapchain_images<'a>(&'a self, backends: &'a Backends) -> Images {
+    pub fn get_swapchain_images<'a>(&self, backends: &'a Backends) -> Result<Images<'a>> {
         debug!("get swapchain images");
 
-        let images = unsafe { self.swapchain.get_swapchain_images(self.swapchain_khr).unwrap() };
+        let images = unsafe { self.swapchain.get_swapchain_images(self.swapchain_khr)? };
 
         Images::create_images_for_swapchain_images(backends, images, self.format)
     }
@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use ash::vk::{AccessFlags, BorderColor, BufferImageCopy, BufferUsageFlags, DependencyFlags, DescriptorImageInfo, DeviceMemory, DeviceSize, Extent3D, Filter, Format, FormatFeatureFlags, Image, ImageAspectFlags, ImageCreateInfo, ImageLayout, ImageMemoryBarrier, ImageSubresourceLayers, ImageTiling, ImageType, ImageUsageFlags, ImageView, ImageViewCreateInfo, ImageViewType, MemoryAllocateInfo, MemoryPropertyFlags, PipelineStageFlags, Queue, SampleCountFlags, Sampler, SamplerAddressMode, SamplerCreateInfo, SamplerMipmapMode, SharingMode};
+use log::{debug, warn};
+use crate::buffers::Buffers;
+use crate::error::{CottonError, Result};
+use crate::get_memory_type_index;
+use crate::renderer::backends::Backends;
+use crate::renderer::color_subresource_range;
+use crate::renderer::descriptor_sets::DescriptorResource;
+use crate::textures::{AddressMode, SamplerDescription, Texture, TextureData, TextureFilter};
+
+//host backendと同じ値を読めるようにリニアのままfloatで持つ
+const TEXTURE_FORMAT: Format = Format::R32G32B32A32_SFLOAT;
+
+/// formatがlinear filterに対応していない場合はNearestにする
+pub fn supported_sampler(description: SamplerDescription, features: FormatFeatureFlags) -> SamplerDescription {
+    if description.filter == TextureFilter::Linear && !features.contains(FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR) {
+        return SamplerDescription {
+            filter: TextureFilter::Nearest,
+            ..description
+        };
+    }
+
+    description
+}
+
+/// シーンのテクスチャをsampled imageとしてアップロードしたもの
+/// 配列の順番はSceneDescription.texturesと同じ
+pub struct TextureImages<'a> {
+    backends: &'a Backends,
+    pub images: Vec<Image>,
+    pub image_views: Vec<ImageView>,
+    device_memories: Vec<DeviceMemory>,
+    /// image_viewsと同じ順番の、それぞれのテクスチャのsampler
+    pub samplers: Vec<Sampler>,
+    //同じ設定のsamplerは一つだけ作る
+    sampler_cache: HashMap<SamplerDescription, Sampler>,
+}
+
+impl<'a> TextureImages<'a> {
+    /// テクスチャがない場合もdescriptorが空にならないように1x1の白を置く
+    pub fn new(backends: &'a Backends, textures: &[Texture], graphics_queue: Queue) -> Result<Self> {
+        debug!("upload {} textures", textures.len());
+
+        let features = Self::check_format_support(backends)?;
+
+        if textures.iter().any(|texture| supported_sampler(texture.sampler, features) != texture.sampler) {
+            warn!("{:?} does not support linear filtering, textures are sampled with nearest", TEXTURE_FORMAT);
+        }
+
+        let mut texture_images = Self {
+            backends,
+            images: vec![],
+            image_views: vec![],
+            device_memories: vec![],
+            samplers: vec![],
+            sampler_cache: HashMap::new(),
+        };
+
+        let placeholder;
+        let textures = if textures.is_empty() {
+            placeholder = [Texture::new(TextureData::solid([1.0; 4]))];
+            &placeholder[..]
+        } else {
+            textures
+        };
+
+        //途中で失敗した場合は作ったものをDropで破棄する
+        for texture in textures.iter() {
+            texture_images.upload(&texture.image, graphics_queue)?;
+
+            let sampler = texture_images.sampler(supported_sampler(texture.sampler, features))?;
+            texture_images.samplers.push(sampler);
+        }
+
+        Ok(texture_images)
+    }
+
+    pub fn len(&self) -> usize {
+        self.image_views.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.image_views.is_empty()
+    }
+
+    /// テクスチャごとにsamplerが違うのでcombined image samplerの配列にする
+    pub fn descriptor_resource(&self) -> DescriptorResource {
+        DescriptorResource::CombinedImageSamplers(
+            self.image_views
+                .iter()
+                .zip(self.samplers.iter())
+                .map(|(image_view, sampler)| DescriptorImageInfo::builder()
+                    .image_layout(ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                    .image_view(*image_view)
+                    .sampler(*sampler)
+                    .build())
+                .collect()
+        )
+    }
+
+    //floatのformatはlinear filterに対応していないことがあるので、それは必須にせずにfeaturesを返す
+    fn check_format_support(backends: &Backends) -> Result<FormatFeatureFlags> {
+        let properties = unsafe {
+            backends
+                .instance
+                .get_physical_device_format_properties(backends.physical_device, TEXTURE_FORMAT)
+        };
+
+        let required = FormatFeatureFlags::SAMPLED_IMAGE | FormatFeatureFlags::TRANSFER_DST;
+
+        if !properties.optimal_tiling_features.contains(required) {
+            return Err(CottonError::UnsupportedFormat {
+                format: TEXTURE_FORMAT,
+                features: required,
+            });
+        }
+
+        Ok(properties.optimal_tiling_features)
+    }
+
+    fn upload(&mut self, texture: &TextureData, graphics_queue: Queue) -> Result<()> {
+        let device = &self.backends.device;
+
+        let extent = Extent3D {
+            width: texture.width,
+            height: texture.height,
+            depth: 1,
+        };
+
+        let image_create_info = ImageCreateInfo::builder()
+            .image_type(ImageType::TYPE_2D)
+            .format(TEXTURE_FORMAT)
+            .extent(extent)
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(SampleCountFlags::TYPE_1)
+            .tiling(ImageTiling::OPTIMAL)
+            .usage(ImageUsageFlags::SAMPLED | ImageUsageFlags::TRANSFER_DST)
+            .sharing_mode(SharingMode::EXCLUSIVE)
+            .initial_layout(ImageLayout::UNDEFINED)
+            .build();
+
+        let image = unsafe { device.create_image(&image_create_info, None)? };
+        self.images.push(image);
+
+        let memory_requirement = unsafe { device.get_image_memory_requirements(image) };
+
+        let memory_type_index = get_memory_type_index(
+            &self.backends.device_memory_properties,
+            memory_requirement.memory_type_bits,
+            MemoryPropertyFlags::DEVICE_LOCAL,
+        ).ok_or(CottonError::NoMemoryType {
+            type_filter: memory_requirement.memory_type_bits,
+            property_flags: MemoryPropertyFlags::DEVICE_LOCAL,
+        })?;
+
+        let memory_alloc_info = MemoryAllocateInfo::builder()
+            .allocation_size(memory_requirement.size)
+            .memory_type_index(memory_type_index);
+
+        let device_memory = unsafe { device.allocate_memory(&memory_alloc_info, None)? };
+        self.device_memories.push(device_memory);
+
+        unsafe { device.bind_image_memory(image, device_memory, 0)? };
+
+        let mut staging_buffer = Buffers::new(
+            device,
+            self.backends.device_memory_properties,
+            std::mem::size_of_val(texture.pixels.as_slice()) as DeviceSize,
+            BufferUsageFlags::TRANSFER_SRC,
+            MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT,
+        )?;
+
+        staging_buffer.store(&texture.pixels)?;
+
+        self.backends.submit_and_wait(graphics_queue, |command_buffer| unsafe {
+            let transfer_barrier = ImageMemoryBarrier::builder()
+                .src_access_mask(AccessFlags::empty())
+                .dst_access_mask(AccessFlags::TRANSFER_WRITE)
+                .old_layout(ImageLayout::UNDEFINED)
+                .new_layout(ImageLayout::TRANSFER_DST_OPTIMAL)
+                .image(image)
+                .subresource_range(color_subresource_range())
+                .build();
+
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                PipelineStageFlags::TOP_OF_PIPE,
+                PipelineStageFlags::TRANSFER,
+                DependencyFlags::empty(),
+                &[],
+                &[],
+                &[transfer_barrier],
+            );
+
+            //bufferは隙間なく並べているのでrow_lengthとimage_heightは0
+            let region = BufferImageCopy::builder()
+                .buffer_offset(0)
+                .buffer_row_length(0)
+                .buffer_image_height(0)
+                .image_subresource(
+                    ImageSubresourceLayers::builder()
+                        .aspect_mask(ImageAspectFlags::COLOR)
+                        .mip_level(0)
+                        .base_array_layer(0)
+                        .layer_count(1)
+                        .build()
+                )
+                .image_extent(extent)
+                .build();
+
+            device.cmd_copy_buffer_to_image(
+                command_buffer,
+                staging_buffer.buffer,
+                image,
+                ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[region],
+            );
+
+            let shader_read_barrier = ImageMemoryBarrier::builder()
+                .src_access_mask(AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(AccessFlags::SHADER_READ)
+                .old_layout(ImageLayout::TRANSFER_DST_OPTIMAL)
+                .new_layout(ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .image(image)
+                .subresource_range(color_subresource_range())
+                .build();
+
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                PipelineStageFlags::TRANSFER,
+                PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+                DependencyFlags::empty(),
+                &[],
+                &[],
+                &[shader_read_barrier],
+            );
+        })?;
+
+        let image_view_create_info = ImageViewCreateInfo::builder()
+            .view_type(ImageViewType::TYPE_2D)
+            .format(TEXTURE_FORMAT)
+            .subresource_range(color_subresource_range())
+            .image(image)
+            .build();
+
+        let image_view = unsafe { device.create_image_view(&image_view_create_info, None)? };
+        self.image_views.push(image_view);
+
+        Ok(())
+    }
+
+    fn sampler(&mut self, description: SamplerDescription) -> Result<Sampler> {
+        if let Some(sampler) = self.sampler_cache.get(&description) {
+            return Ok(*sampler);
+        }
+
+        let filter = match description.filter {
+            TextureFilter::Nearest => Filter::NEAREST,
+            TextureFilter::Linear => Filter::LINEAR,
+        };
+
+        let address_mode = match description.address_mode {
+            AddressMode::Repeat => SamplerAddressMode::REPEAT,
+            AddressMode::MirroredRepeat => SamplerAddressMode::MIRRORED_REPEAT,
+            AddressMode::ClampToEdge => SamplerAddressMode::CLAMP_TO_EDGE,
+        };
+
+        //mipmapを作らないのでlodは0に固定する
+        let sampler_create_info = SamplerCreateInfo::builder()
+            .mag_filter(filter)
+            .min_filter(filter)
+            .mipmap_mode(SamplerMipmapMode::NEAREST)
+            .address_mode_u(address_mode)
+            .address_mode_v(address_mode)
+            .address_mode_w(address_mode)
+            .anisotropy_enable(false)
+            .min_lod(0.0)
+            .max_lod(0.0)
+            .border_color(BorderColor::FLOAT_OPAQUE_BLACK)
+            .unnormalized_coordinates(false)
+            .build();
+
+        let sampler = unsafe { self.backends.device.create_sampler(&sampler_create_info, None)? };
+        self.sampler_cache.insert(description, sampler);
+
+        Ok(sampler)
+    }
+}
+
+impl Drop for TextureImages<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            for image_view in self.image_views.iter() {
+                self.backends.device.destroy_image_view(*image_view, None);
+            }
+
+            for image in self.images.iter() {
+                self.backends.device.destroy_image(*image, None);
+            }
+
+            for device_memory in self.device_memories.iter() {
+                self.backends.device.free_memory(*device_memory, None);
+            }
+
+            for sampler in self.sampler_cache.values() {
+                self.backends.device.destroy_sampler(*sampler, None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn linear_falls_back_to_nearest_without_filter_support() {
+        let sampled = FormatFeatureFlags::SAMPLED_IMAGE | FormatFeatureFlags::TRANSFER_DST;
+        let filterable = sampled | FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR;
+
+        let linear = SamplerDescription {
+            filter: TextureFilter::Linear,
+            address_mode: AddressMode::ClampToEdge,
+        };
+        let nearest = SamplerDescription {
+            filter: TextureFilter::Nearest,
+            ..linear
+        };
+
+        assert_eq!(supported_sampler(linear, filterable), linear);
+        assert_eq!(supported_sampler(linear, sampled), nearest);
+        assert_eq!(supported_sampler(nearest, sampled), nearest);
+        assert_eq!(supported_sampler(nearest, filterable), nearest);
+        assert_eq!(supported_sampler(SamplerDescription::default(), sampled).address_mode, AddressMode::Repeat);
+    }
+}
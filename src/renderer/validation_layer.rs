@@ -1,13 +1,89 @@
 use std::ffi::{c_char, c_void, CStr, CString};
+use std::ptr;
+use std::sync::{Arc, Mutex};
 use ash::{Entry, Instance, vk};
 use ash::extensions::ext::DebugUtils;
-use ash::prelude::VkResult;
 use ash::vk::{DebugUtilsMessageSeverityFlagsEXT, DebugUtilsMessageTypeFlagsEXT, DebugUtilsMessengerCreateInfoEXT, DebugUtilsMessengerEXT};
-use log::{debug, info};
-use tobj::LoadError::NormalParseError;
+use log::{debug, error, info, warn};
+use crate::error::{CottonError, Result};
 
 pub const REQUIRED_LAYERS: [&str; 1] = ["VK_LAYER_KHRONOS_validation"];
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ValidationSeverity {
+    Verbose,
+    Info,
+    Warning,
+    Error,
+}
+
+impl From<DebugUtilsMessageSeverityFlagsEXT> for ValidationSeverity {
+    fn from(flags: DebugUtilsMessageSeverityFlagsEXT) -> Self {
+        if flags.contains(DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+            Self::Error
+        } else if flags.contains(DebugUtilsMessageSeverityFlagsEXT::WARNING) {
+            Self::Warning
+        } else if flags.contains(DebugUtilsMessageSeverityFlagsEXT::INFO) {
+            Self::Info
+        } else {
+            Self::Verbose
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationMessage {
+    pub severity: ValidationSeverity,
+    pub message_type: String,
+    pub message: String,
+}
+
+/// コールバックから受け取ったメッセージを貯めておく
+/// コールバックは別スレッドから呼ばれることがあるのでMutexで守る
+#[derive(Clone, Debug, Default)]
+pub struct ValidationMessages {
+    messages: Arc<Mutex<Vec<ValidationMessage>>>,
+}
+
+impl ValidationMessages {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, message: ValidationMessage) {
+        //panicしたスレッドがあってもメッセージは残したい
+        let mut messages = self.messages.lock().unwrap_or_else(|err| err.into_inner());
+        messages.push(message);
+    }
+
+    pub fn messages(&self) -> Vec<ValidationMessage> {
+        self.messages.lock().unwrap_or_else(|err| err.into_inner()).clone()
+    }
+
+    pub fn errors(&self) -> Vec<ValidationMessage> {
+        self.messages()
+            .into_iter()
+            .filter(|message| message.severity == ValidationSeverity::Error)
+            .collect()
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.errors().len()
+    }
+
+    pub fn clear(&self) {
+        self.messages.lock().unwrap_or_else(|err| err.into_inner()).clear();
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ValidationSettings {
+    /// ERRORを受け取ったらメッセージを標準エラーに出してabortする
+    /// コールバックはFFI越しに呼ばれるのでpanicでunwindすることはできない
+    pub abort_on_error: bool,
+    pub collector: Option<ValidationMessages>,
+}
+
 pub struct ValidationLayer {
     debug_utils: DebugUtils,
     debug_utils_messenger_ext: DebugUtilsMessengerEXT,
@@ -22,21 +98,24 @@ impl ValidationLayer {
         REQUIRED_LAYERS.map(|item| CString::new(item).unwrap()).into_iter().collect()
     }
 
-    pub fn require_validation_layer_extension_names_c_char() -> Vec<*const c_char> {
-        Self::require_validation_layer_extension_names_cstring()
-            .iter()
-            .map(|item| item.as_ptr())
-            .into_iter()
-            .collect()
+    pub fn new_default(entry: &Entry, instance: &Instance) -> Result<Self> {
+        Self::create(entry, instance, Self::populate_debug_messenger_create_info(None))
     }
 
-    pub fn new_default(entry: &Entry, instance: &Instance) -> VkResult<Self> {
+    /// settingsはこのValidationLayerと、settingsを渡して作成したInstanceよりも長く生存している必要がある
+    pub fn new(entry: &Entry, instance: &Instance, settings: &ValidationSettings) -> Result<Self> {
+        Self::create(entry, instance, Self::populate_debug_messenger_create_info(Some(settings)))
+    }
+
+    fn create(
+        entry: &Entry,
+        instance: &Instance,
+        create_info: DebugUtilsMessengerCreateInfoEXT,
+    ) -> Result<Self> {
         debug!("Enable validation layer");
 
         let debug_utils = DebugUtils::new(entry, instance);
 
-        let create_info = Self::populate_debug_messenger_create_info();
-
         let debug_utils_messenger_ext = unsafe { debug_utils.create_debug_utils_messenger(&create_info, None)? };
 
         Ok(Self{
@@ -45,30 +124,50 @@ impl ValidationLayer {
         })
     }
 
-    pub fn check_validation_layer_support(entry: &Entry) {
-        for required in Self::require_validation_layer_extension_names_cstring() {
-            let found = entry
-                .enumerate_instance_layer_properties()
-                .unwrap()
-                .iter()
-                .any(|layer| {
+    pub fn check_validation_layer_support(entry: &Entry) -> Result<()> {
+        let layer_properties = entry.enumerate_instance_layer_properties()?;
+
+        let missing: Vec<String> = Self::require_validation_layer_extension_names_cstring()
+            .into_iter()
+            .filter(|required| {
+                !layer_properties.iter().any(|layer| {
                     let name = unsafe { CStr::from_ptr(layer.layer_name.as_ptr()) };
-                    //let name = name.to_str().expect("Failed to get layer name pointer");
                     required.as_c_str() == name
-                });
+                })
+            })
+            .map(|required| required.to_string_lossy().into_owned())
+            .collect();
 
-            if !found {
-                panic!("Validation layer not supported: {:?}", required);
-            }
+        if !missing.is_empty() {
+            return Err(CottonError::MissingLayers(missing));
         }
 
         info!("Validation layer supported");
+
+        Ok(())
     }
 
-    pub fn populate_debug_messenger_create_info() -> DebugUtilsMessengerCreateInfoEXT {
+    /// レイヤーがない場合は警告を出して無効にする
+    pub fn is_validation_layer_available(entry: &Entry) -> Result<bool> {
+        match Self::check_validation_layer_support(entry) {
+            Ok(()) => Ok(true),
+            Err(CottonError::MissingLayers(layers)) => {
+                warn!("Validation layer not available, continue without it: {:?}", layers);
+
+                Ok(false)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn populate_debug_messenger_create_info(settings: Option<&ValidationSettings>) -> DebugUtilsMessengerCreateInfoEXT {
+        let user_data = settings
+            .map_or(ptr::null_mut(), |settings| settings as *const ValidationSettings as *mut c_void);
+
         DebugUtilsMessengerCreateInfoEXT::builder()
             .message_severity(
                 DebugUtilsMessageSeverityFlagsEXT::VERBOSE
+                    | DebugUtilsMessageSeverityFlagsEXT::INFO
                     | DebugUtilsMessageSeverityFlagsEXT::WARNING
                     | DebugUtilsMessageSeverityFlagsEXT::ERROR,
             )
@@ -78,6 +177,7 @@ impl ValidationLayer {
                     | DebugUtilsMessageTypeFlagsEXT::PERFORMANCE,
             )
             .pfn_user_callback(Some(vulkan_debug_callback))
+            .user_data(user_data)
             .build()
     }
 }
@@ -96,15 +196,9 @@ unsafe extern "system" fn vulkan_debug_callback(
     message_severity: DebugUtilsMessageSeverityFlagsEXT,
     message_type: DebugUtilsMessageTypeFlagsEXT,
     p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
-    _p_user_data: *mut c_void,
+    p_user_data: *mut c_void,
 ) -> vk::Bool32 {
-    let severity = match message_severity {
-        DebugUtilsMessageSeverityFlagsEXT::VERBOSE => "VERBOSE",
-        DebugUtilsMessageSeverityFlagsEXT::WARNING => "WARNING",
-        DebugUtilsMessageSeverityFlagsEXT::ERROR => "ERROR",
-        DebugUtilsMessageSeverityFlagsEXT::INFO => "INFO",
-        _ => "???",
-    };
+    let severity = ValidationSeverity::from(message_severity);
 
     let types = match message_type {
         DebugUtilsMessageTypeFlagsEXT::GENERAL => "GENERAL",
@@ -114,9 +208,122 @@ unsafe extern "system" fn vulkan_debug_callback(
     };
 
     let data = *p_callback_data;
-    let message = CStr::from_ptr(data.p_message);
+    let message = if data.p_message.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(data.p_message).to_string_lossy().into_owned()
+    };
+
+    match severity {
+        ValidationSeverity::Error => error!("validation layer: [{}] {}", types, message),
+        ValidationSeverity::Warning => warn!("validation layer: [{}] {}", types, message),
+        ValidationSeverity::Info => info!("validation layer: [{}] {}", types, message),
+        ValidationSeverity::Verbose => debug!("validation layer: [{}] {}", types, message),
+    }
 
-    debug!("validation layer: [{}, {}] {:?}", severity, types, message);
+    if !p_user_data.is_null() {
+        let settings = &*(p_user_data as *const ValidationSettings);
+
+        let message = ValidationMessage {
+            severity,
+            message_type: types.to_owned(),
+            message,
+        };
+
+        if record_message(settings, &message) {
+            //loggerが無効でも何で止まったのかが分かるようにする
+            eprintln!("aborting on validation error: [{}] {}", message.message_type, message.message);
+            std::process::abort();
+        }
+    }
 
     vk::FALSE
-}
\ No newline at end of file
+}
+
+/// collectorに貯め、abortする必要があるかを返す
+fn record_message(settings: &ValidationSettings, message: &ValidationMessage) -> bool {
+    if let Some(collector) = settings.collector.as_ref() {
+        collector.push(message.clone());
+    }
+
+    settings.abort_on_error && message.severity == ValidationSeverity::Error
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use super::*;
+
+    fn message(severity: ValidationSeverity, text: &str) -> ValidationMessage {
+        ValidationMessage {
+            severity,
+            message_type: "VALIDATION".to_owned(),
+            message: text.to_owned(),
+        }
+    }
+
+    #[test]
+    fn severity_uses_the_highest_flag() {
+        assert_eq!(ValidationSeverity::from(DebugUtilsMessageSeverityFlagsEXT::VERBOSE), ValidationSeverity::Verbose);
+        assert_eq!(ValidationSeverity::from(DebugUtilsMessageSeverityFlagsEXT::INFO), ValidationSeverity::Info);
+        assert_eq!(ValidationSeverity::from(DebugUtilsMessageSeverityFlagsEXT::WARNING), ValidationSeverity::Warning);
+        assert_eq!(
+            ValidationSeverity::from(DebugUtilsMessageSeverityFlagsEXT::WARNING | DebugUtilsMessageSeverityFlagsEXT::ERROR),
+            ValidationSeverity::Error,
+        );
+    }
+
+    #[test]
+    fn collector_counts_only_errors() {
+        let collector = ValidationMessages::new();
+        let settings = ValidationSettings {
+            abort_on_error: false,
+            collector: Some(collector.clone()),
+        };
+
+        assert!(!record_message(&settings, &message(ValidationSeverity::Info, "loader info")));
+        assert!(!record_message(&settings, &message(ValidationSeverity::Warning, "performance warning")));
+        assert!(!record_message(&settings, &message(ValidationSeverity::Error, "VUID-vkCmdTraceRaysKHR")));
+
+        assert_eq!(collector.messages().len(), 3);
+        assert_eq!(collector.error_count(), 1);
+        assert_eq!(collector.errors(), vec![message(ValidationSeverity::Error, "VUID-vkCmdTraceRaysKHR")]);
+
+        collector.clear();
+        assert_eq!(collector.error_count(), 0);
+    }
+
+    #[test]
+    fn collector_is_shared_between_threads() {
+        let collector = ValidationMessages::new();
+
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let collector = collector.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        collector.push(message(ValidationSeverity::Warning, &format!("thread {}", i)));
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(collector.messages().len(), 400);
+        assert_eq!(collector.error_count(), 0);
+    }
+
+    #[test]
+    fn aborts_only_on_errors_when_enabled() {
+        let settings = ValidationSettings {
+            abort_on_error: true,
+            collector: None,
+        };
+
+        assert!(!record_message(&settings, &message(ValidationSeverity::Warning, "warning")));
+        assert!(record_message(&settings, &message(ValidationSeverity::Error, "error")));
+    }
+}
@@ -1,7 +1,9 @@
 use ash::Device;
 use ash::vk::{AccelerationStructureInstanceKHR, AccelerationStructureReferenceKHR, Buffer, BufferCopy, BufferUsageFlags, DeviceAddress, DeviceSize, GeometryInstanceFlagsKHR, MemoryPropertyFlags, Packed24_8, PhysicalDeviceMemoryProperties, TransformMatrixKHR};
+use glam::Mat4;
 use log::debug;
 use crate::buffers::Buffers;
+use crate::error::{CottonError, Result};
 use crate::renderer::acceleration_structures::triangle_bottom_level_acceleration_structure::TriangleBottomLevelAccelerationStructure;
 use crate::renderer::backends::Backends;
 
@@ -16,7 +18,9 @@ impl<'a> Scene<'a> {
         backends: &'a Backends,
         //色々なモデルに対応したい場合はここを複数受け取れるように
         triangle_bottom_acceleration_structure_handle: DeviceAddress,
-    ) -> Self {
+        //RayTracingShaderGroups::hit_group_offsetで求めたもの
+        triangle_hit_group_offset: u32,
+    ) -> Result<Self> {
         debug!("build scene");
 
         let size = 1.0;
@@ -27,6 +31,8 @@ impl<'a> Scene<'a> {
         //現在はインスタンス一つのみ対応
         let instance = Self::create_triangle_instance(
             triangle_bottom_acceleration_structure_handle,
+            0,
+            triangle_hit_group_offset,
             //TODO: SRT行列対応
             TransformMatrixKHR {
                 matrix: [
@@ -37,8 +43,14 @@ impl<'a> Scene<'a> {
             }
         );
 
-        let instances = vec![instance];
+        Self::from_instances(backends, vec![instance])
+    }
 
+    /// instanceのBLASは先に作っておく
+    pub fn from_instances(
+        backends: &'a Backends,
+        instances: Vec<AccelerationStructureInstanceKHR>,
+    ) -> Result<Self> {
         let instance_buffer_size =
             std::mem::size_of::<AccelerationStructureInstanceKHR>() * instances.len();
 
@@ -51,19 +63,58 @@ impl<'a> Scene<'a> {
             MemoryPropertyFlags::HOST_VISIBLE
                 | MemoryPropertyFlags::HOST_COHERENT
                 | MemoryPropertyFlags::DEVICE_LOCAL
-        );
+        )?;
 
-        instance_buffer.store(&instances);
+        instance_buffer.store(&instances)?;
 
-        Self {
+        Ok(Self {
             backends,
             instances,
             instance_buffer,
+        })
+    }
+
+    /// instance_bufferのfirst番目から上書きする、数を変える場合はfrom_instancesで作り直す
+    /// TLASに反映するにはrefitかrebuildが必要
+    pub fn write_instances(
+        &mut self,
+        first: usize,
+        instances: &[AccelerationStructureInstanceKHR],
+    ) -> Result<()> {
+        if first + instances.len() > self.instances.len() {
+            return Err(CottonError::InvalidScene(format!(
+                "cannot write instances {}..{}, the scene has only {} instances",
+                first,
+                first + instances.len(),
+                self.instances.len(),
+            )));
         }
+
+        let offset = std::mem::size_of::<AccelerationStructureInstanceKHR>() * first;
+        self.instance_buffer.store_at(offset as DeviceSize, instances)?;
+
+        self.instances[first..first + instances.len()].copy_from_slice(instances);
+
+        Ok(())
     }
 
-    fn create_triangle_instance(
+    pub fn set_instance_transform(&mut self, index: usize, transform: TransformMatrixKHR) -> Result<()> {
+        let mut instance = *self.instances.get(index).ok_or_else(|| CottonError::InvalidScene(format!(
+            "instance {} is out of range, the scene has only {} instances",
+            index,
+            self.instances.len(),
+        )))?;
+
+        instance.transform = transform;
+
+        self.write_instances(index, &[instance])
+    }
+
+    /// custom_indexはシェーダーがinstance bufferを引くのに使う
+    pub fn create_triangle_instance(
         handle: DeviceAddress,
+        custom_index: u32,
+        hit_group_offset: u32,
         transform: TransformMatrixKHR,
     ) -> AccelerationStructureInstanceKHR {
         AccelerationStructureInstanceKHR {
@@ -71,10 +122,10 @@ impl<'a> Scene<'a> {
             //Packed24_8はu32で24bitの型を表現するもの
             //instance_custom_indexが24bit、maskが8
             //maskは他のinstanceと交差判定を行うかどうか
-            instance_custom_index_and_mask: Packed24_8::new(0, 0xff),
+            instance_custom_index_and_mask: Packed24_8::new(custom_index, 0xff),
             //instance_shader_binding_table_record_offsetが24bit、flagsが8bit
             instance_shader_binding_table_record_offset_and_flags: Packed24_8::new(
-                0,
+                hit_group_offset,
                 GeometryInstanceFlagsKHR::FORCE_OPAQUE.as_raw() as u8,
             ),
             acceleration_structure_reference: AccelerationStructureReferenceKHR {
@@ -83,3 +134,13 @@ impl<'a> Scene<'a> {
         }
     }
 }
+
+/// glamは列優先、TransformMatrixKHRは行優先の3x4
+pub fn to_transform_matrix(transform: Mat4) -> TransformMatrixKHR {
+    let rows = transform.transpose().to_cols_array();
+
+    let mut matrix = [0.0; 12];
+    matrix.copy_from_slice(&rows[..12]);
+
+    TransformMatrixKHR { matrix }
+}
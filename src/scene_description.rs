@@ -0,0 +1,242 @@
+use classical_raytracer_shader::Vertex;
+use glam::{Mat4, Vec2, Vec3, Vec3A, Vec4};
+use crate::error::{CottonError, Result};
+use crate::renderer::materials::Material;
+use crate::textures::Texture;
+
+/// Vulkanに依存しないシーンの記述、どのバックエンドにもこれを渡す
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SceneDescription {
+    pub meshes: Vec<MeshDescription>,
+    pub instances: Vec<InstanceDescription>,
+    pub materials: Vec<Material>,
+    /// materialsからindexで参照する
+    pub textures: Vec<Texture>,
+}
+
+/// 三角形のリスト、normalsはpositionsと同じ数だけ持つ
+/// tex_coordsとtangentsは持たない場合は空にする
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MeshDescription {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub tex_coords: Vec<Vec2>,
+    /// xyzが接線、wが従法線の向き(1か-1)、tangents::generate_tangentsで作る
+    pub tangents: Vec<Vec4>,
+    pub indices: Vec<u32>,
+}
+
+/// 前のフレームのシーンから変わったもの、バックエンドは変わったものだけ作り直す
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SceneChanges {
+    pub meshes: bool,
+    /// instanceの数か、参照するmeshやmaterialが変わった
+    pub instances: bool,
+    pub transforms: bool,
+    pub materials: bool,
+    pub textures: bool,
+}
+
+impl SceneChanges {
+    pub fn all() -> Self {
+        Self {
+            meshes: true,
+            instances: true,
+            transforms: true,
+            materials: true,
+            textures: true,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct InstanceDescription {
+    /// meshesの中のindex
+    pub mesh: usize,
+    pub transform: Mat4,
+    /// materialsの中のindex
+    pub material: usize,
+}
+
+impl MeshDescription {
+    /// 頂点の法線は面の法線を使う
+    pub fn triangle(positions: [Vec3; 3]) -> Self {
+        let normal = (positions[1] - positions[0])
+            .cross(positions[2] - positions[0])
+            .normalize_or_zero();
+
+        Self {
+            positions: positions.to_vec(),
+            normals: vec![normal; 3],
+            indices: vec![0, 1, 2],
+            ..Self::default()
+        }
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    pub fn has_tex_coords(&self) -> bool {
+        !self.tex_coords.is_empty()
+    }
+
+    pub fn has_tangents(&self) -> bool {
+        !self.tangents.is_empty()
+    }
+
+    /// vertex bufferに入れる形、接線とUVがない場合は0を入れる
+    pub fn vertices(&self) -> Vec<Vertex> {
+        self.positions
+            .iter()
+            .zip(self.normals.iter())
+            .enumerate()
+            .map(|(i, (position, normal))| Vertex {
+                position: Vec3A::from(*position),
+                normal: Vec3A::from(*normal),
+                tangent: self.tangents.get(i).copied().unwrap_or(Vec4::ZERO),
+                tex_coord: self.tex_coords.get(i).copied().unwrap_or(Vec2::ZERO),
+                _padding: Vec2::ZERO,
+            })
+            .collect()
+    }
+}
+
+impl SceneDescription {
+    /// 今までハードコードしていた三角形一つのシーン
+    pub fn classical() -> Self {
+        //頂点の並びはTriangleBottomLevelAccelerationStructureと同じ
+        let mesh = MeshDescription {
+            positions: vec![
+                Vec3::new(1.0, -1.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+                Vec3::new(-1.0, -1.0, 0.0),
+            ],
+            normals: vec![Vec3::Z; 3],
+            indices: vec![0, 1, 2],
+            ..MeshDescription::default()
+        };
+
+        Self {
+            meshes: vec![mesh],
+            instances: vec![InstanceDescription {
+                mesh: 0,
+                transform: Mat4::from_translation(Vec3::new(0.0, 1.0, 0.0)),
+                material: 0,
+            }],
+            materials: vec![Material::lambertian([0.8, 0.3, 0.3])],
+            textures: vec![],
+        }
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.instances
+            .iter()
+            .filter_map(|instance| self.meshes.get(instance.mesh))
+            .map(MeshDescription::triangle_count)
+            .sum()
+    }
+
+    pub fn changes_from(&self, previous: &SceneDescription) -> SceneChanges {
+        let same_instances = self.instances.len() == previous.instances.len();
+        let mut instance_pairs = self.instances.iter().zip(previous.instances.iter());
+
+        SceneChanges {
+            meshes: self.meshes != previous.meshes,
+            instances: !same_instances || instance_pairs
+                .clone()
+                .any(|(current, previous)| current.mesh != previous.mesh || current.material != previous.material),
+            transforms: !same_instances || instance_pairs.any(|(current, previous)| current.transform != previous.transform),
+            materials: self.materials != previous.materials,
+            textures: self.textures != previous.textures,
+        }
+    }
+
+    /// バックエンドに渡す前に範囲外の参照がないかを確認する
+    pub fn validate(&self) -> Result<()> {
+        for (i, mesh) in self.meshes.iter().enumerate() {
+            if mesh.indices.len() % 3 != 0 {
+                return Err(CottonError::InvalidScene(format!(
+                    "mesh {} has {} indices, which is not a multiple of 3",
+                    i,
+                    mesh.indices.len(),
+                )));
+            }
+
+            if mesh.normals.len() != mesh.positions.len() {
+                return Err(CottonError::InvalidScene(format!(
+                    "mesh {} has {} normals for {} positions",
+                    i,
+                    mesh.normals.len(),
+                    mesh.positions.len(),
+                )));
+            }
+
+            if mesh.has_tex_coords() && mesh.tex_coords.len() != mesh.positions.len() {
+                return Err(CottonError::InvalidScene(format!(
+                    "mesh {} has {} texture coordinates for {} positions",
+                    i,
+                    mesh.tex_coords.len(),
+                    mesh.positions.len(),
+                )));
+            }
+
+            if mesh.has_tangents() && mesh.tangents.len() != mesh.positions.len() {
+                return Err(CottonError::InvalidScene(format!(
+                    "mesh {} has {} tangents for {} positions",
+                    i,
+                    mesh.tangents.len(),
+                    mesh.positions.len(),
+                )));
+            }
+
+            if let Some(index) = mesh.indices.iter().find(|index| **index as usize >= mesh.positions.len()) {
+                return Err(CottonError::InvalidScene(format!(
+                    "mesh {} refers to vertex {}, but has only {} vertices",
+                    i,
+                    index,
+                    mesh.positions.len(),
+                )));
+            }
+        }
+
+        for (i, instance) in self.instances.iter().enumerate() {
+            if instance.mesh >= self.meshes.len() {
+                return Err(CottonError::InvalidScene(format!(
+                    "instance {} refers to mesh {}, but there are only {} meshes",
+                    i,
+                    instance.mesh,
+                    self.meshes.len(),
+                )));
+            }
+
+            if instance.material >= self.materials.len() {
+                return Err(CottonError::InvalidScene(format!(
+                    "instance {} refers to material {}, but there are only {} materials",
+                    i,
+                    instance.material,
+                    self.materials.len(),
+                )));
+            }
+        }
+
+        for (i, material) in self.materials.iter().enumerate() {
+            if let Some(texture) = material.albedo_texture {
+                if texture >= self.textures.len() {
+                    return Err(CottonError::InvalidScene(format!(
+                        "material {} refers to texture {}, but there are only {} textures",
+                        i,
+                        texture,
+                        self.textures.len(),
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,574 @@
+use std::collections::HashMap;
+use glam::{Vec3, Vec4};
+use crate::error::{CottonError, Result};
+use crate::mesh_processing::validate_mesh;
+use crate::scene_description::MeshDescription;
+
+//同じ接線とみなす内積
+const SAME_TANGENT_COS: f32 = 1.0 - 1e-6;
+
+/// 三角形の接線、UVの面積が0の場合などは決められないので隣の三角形のものを使う
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct FaceTangent {
+    /// dP/duの向き
+    tangent: Vec3,
+    /// UVの巻き順が位置の巻き順と同じ
+    orientation_preserving: bool,
+}
+
+/// MikkTSpaceと同じ方法で接線と従法線の向きを作り、tangentsに入れる
+/// 頂点を囲む三角形のうち辺でつながっていてUVの向きが同じものを角度の重み付きで平均する
+/// 同じ頂点で接線が分かれる場合は頂点を複製するので、頂点の数が増えることがある
+pub fn generate_tangents(mesh: &MeshDescription) -> Result<MeshDescription> {
+    validate_mesh(mesh)?;
+
+    if !mesh.has_tex_coords() {
+        return Err(CottonError::InvalidScene(
+            "mesh has no texture coordinates to generate tangents from".to_string(),
+        ));
+    }
+
+    if mesh.normals.len() != mesh.positions.len() {
+        return Err(CottonError::InvalidScene(format!(
+            "mesh has {} normals for {} positions",
+            mesh.normals.len(),
+            mesh.positions.len(),
+        )));
+    }
+
+    let triangles: Vec<[usize; 3]> = mesh
+        .indices
+        .chunks_exact(3)
+        .map(|indices| [indices[0] as usize, indices[1] as usize, indices[2] as usize])
+        .collect();
+
+    //MikkTSpaceは位置、法線、UVが全て同じ頂点を同じものとして扱う
+    let identical = identical_vertices(mesh);
+    let faces: Vec<Option<FaceTangent>> = triangles
+        .iter()
+        .map(|triangle| face_tangent(mesh, triangle))
+        .collect();
+
+    let groups = corner_groups(&triangles, &faces, &identical);
+
+    //groupごとの角度の重み付きの和
+    let mut sums: HashMap<usize, Vec3> = HashMap::new();
+    for (t, triangle) in triangles.iter().enumerate() {
+        let face = match faces[t] {
+            Some(face) => face,
+            None => continue,
+        };
+
+        for c in 0..3 {
+            let normal = mesh.normals[triangle[c]].normalize_or_zero();
+            let weighted = project(face.tangent, normal).normalize_or_zero() * corner_angle(mesh, triangle, c, normal);
+
+            *sums.entry(groups[t * 3 + c].group).or_insert(Vec3::ZERO) += weighted;
+        }
+    }
+
+    let mut processed = mesh.clone();
+    processed.tangents = vec![Vec4::ZERO; mesh.positions.len()];
+
+    //元の頂点ごとに作った(接線, 新しいindex)
+    let mut split: Vec<Vec<(Vec4, u32)>> = vec![vec![]; mesh.positions.len()];
+
+    for (t, triangle) in triangles.iter().enumerate() {
+        for (c, vertex) in triangle.iter().enumerate() {
+            let vertex = *vertex;
+            let CornerGroup { group, orientation_preserving } = groups[t * 3 + c];
+
+            let normal = mesh.normals[vertex].normalize_or_zero();
+            let tangent = sums.get(&group).copied().unwrap_or(Vec3::ZERO).normalize_or_zero();
+            let tangent = if tangent == Vec3::ZERO { fallback_tangent(normal) } else { tangent };
+            let sign = if orientation_preserving { 1.0 } else { -1.0 };
+            let tangent = tangent.extend(sign);
+
+            let existing = split[vertex]
+                .iter()
+                .find(|(existing, _)| same_tangent(*existing, tangent))
+                .map(|(_, index)| *index);
+
+            let index = match existing {
+                Some(index) => index,
+                //最初のものは元の頂点をそのまま使う
+                None if split[vertex].is_empty() => {
+                    processed.tangents[vertex] = tangent;
+                    vertex as u32
+                }
+                None => {
+                    let index = processed.positions.len() as u32;
+                    processed.positions.push(mesh.positions[vertex]);
+                    processed.normals.push(mesh.normals[vertex]);
+                    processed.tex_coords.push(mesh.tex_coords[vertex]);
+                    processed.tangents.push(tangent);
+                    index
+                }
+            };
+
+            split[vertex].push((tangent, index));
+            processed.indices[t * 3 + c] = index;
+        }
+    }
+
+    //どの三角形からも参照されない頂点にも向きの合う接線を入れておく
+    for (vertex, variants) in split.iter().enumerate() {
+        if variants.is_empty() {
+            processed.tangents[vertex] = fallback_tangent(mesh.normals[vertex].normalize_or_zero()).extend(1.0);
+        }
+    }
+
+    Ok(processed)
+}
+
+//各頂点を位置、法線、UVが全く同じ最初の頂点のindexに置き換える
+fn identical_vertices(mesh: &MeshDescription) -> Vec<usize> {
+    let mut first: HashMap<[u32; 8], usize> = HashMap::new();
+
+    (0..mesh.positions.len())
+        .map(|i| {
+            let p = mesh.positions[i];
+            let n = mesh.normals[i];
+            let uv = mesh.tex_coords[i];
+            let key = [p.x, p.y, p.z, n.x, n.y, n.z, uv.x, uv.y].map(f32::to_bits);
+
+            *first.entry(key).or_insert(i)
+        })
+        .collect()
+}
+
+//MikkTSpaceのInitTriInfoと同じ、位置が重なっている三角形とUVの面積が0の三角形はNone
+fn face_tangent(mesh: &MeshDescription, triangle: &[usize; 3]) -> Option<FaceTangent> {
+    let [p0, p1, p2] = triangle.map(|i| mesh.positions[i]);
+    let [t0, t1, t2] = triangle.map(|i| mesh.tex_coords[i]);
+
+    if p0 == p1 || p1 == p2 || p2 == p0 {
+        return None;
+    }
+
+    let d1 = p1 - p0;
+    let d2 = p2 - p0;
+    let t21 = t1 - t0;
+    let t31 = t2 - t0;
+
+    let signed_area = t21.x * t31.y - t21.y * t31.x;
+    let tangent = d1 * t31.y - d2 * t21.y;
+
+    if signed_area.abs() <= f32::MIN_POSITIVE || tangent.length() <= f32::MIN_POSITIVE {
+        return None;
+    }
+
+    let orientation_preserving = signed_area > 0.0;
+    let sign = if orientation_preserving { 1.0 } else { -1.0 };
+
+    Some(FaceTangent {
+        tangent: tangent.normalize() * sign,
+        orientation_preserving,
+    })
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct CornerGroup {
+    group: usize,
+    orientation_preserving: bool,
+}
+
+/// 三角形の角(t * 3 + c)ごとのgroup
+/// 同じ頂点で辺を共有していてUVの向きが同じ角を同じgroupにする
+/// 接線を決められない三角形の角は辺でつながった角か、同じ頂点の他の角のgroupに入る
+fn corner_groups(
+    triangles: &[[usize; 3]],
+    faces: &[Option<FaceTangent>],
+    identical: &[usize],
+) -> Vec<CornerGroup> {
+    let mut parents: Vec<usize> = (0..triangles.len() * 3).collect();
+
+    //(頂点, 辺の反対側の頂点)ごとの角
+    let mut edges: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+    let mut vertices: HashMap<usize, Vec<usize>> = HashMap::new();
+
+    for (t, triangle) in triangles.iter().enumerate() {
+        for c in 0..3 {
+            let corner = t * 3 + c;
+            let vertex = identical[triangle[c]];
+
+            edges.entry((vertex, identical[triangle[(c + 1) % 3]])).or_default().push(corner);
+            edges.entry((vertex, identical[triangle[(c + 2) % 3]])).or_default().push(corner);
+            vertices.entry(vertex).or_default().push(corner);
+        }
+    }
+
+    let orientation = |corner: usize| faces[corner / 3].map(|face| face.orientation_preserving);
+
+    for corners in edges.values() {
+        //UVの向きごとに最初の角にまとめる
+        let mut first: HashMap<bool, usize> = HashMap::new();
+
+        for corner in corners.iter() {
+            if let Some(orientation_preserving) = orientation(*corner) {
+                let first = *first.entry(orientation_preserving).or_insert(*corner);
+                union(&mut parents, first, *corner);
+            }
+        }
+    }
+
+    let mut groups: Vec<CornerGroup> = (0..parents.len())
+        .map(|corner| CornerGroup {
+            group: find(&mut parents, corner),
+            orientation_preserving: orientation(corner).unwrap_or(true),
+        })
+        .collect();
+
+    //決められない角は辺でつながった角を優先し、なければ同じ頂点の最初の角に合わせる
+    for (t, triangle) in triangles.iter().enumerate() {
+        if faces[t].is_some() {
+            continue;
+        }
+
+        for c in 0..3 {
+            let vertex = identical[triangle[c]];
+            let neighbours = [
+                (vertex, identical[triangle[(c + 1) % 3]]),
+                (vertex, identical[triangle[(c + 2) % 3]]),
+            ];
+
+            let resolved = neighbours
+                .iter()
+                .filter_map(|edge| edges.get(edge))
+                .flatten()
+                .chain(vertices[&vertex].iter())
+                .find(|corner| orientation(**corner).is_some());
+
+            if let Some(corner) = resolved {
+                groups[t * 3 + c] = groups[*corner];
+            }
+        }
+    }
+
+    groups
+}
+
+fn find(parents: &mut [usize], corner: usize) -> usize {
+    let mut root = corner;
+    while parents[root] != root {
+        root = parents[root];
+    }
+
+    let mut current = corner;
+    while parents[current] != root {
+        let next = parents[current];
+        parents[current] = root;
+        current = next;
+    }
+
+    root
+}
+
+fn union(parents: &mut [usize], a: usize, b: usize) {
+    let a = find(parents, a);
+    let b = find(parents, b);
+    parents[a.max(b)] = a.min(b);
+}
+
+//法線に垂直な平面に射影する
+fn project(vector: Vec3, normal: Vec3) -> Vec3 {
+    vector - normal * normal.dot(vector)
+}
+
+//法線に垂直な平面に射影した2辺のなす角
+fn corner_angle(mesh: &MeshDescription, triangle: &[usize; 3], c: usize, normal: Vec3) -> f32 {
+    let p = mesh.positions[triangle[c]];
+    let previous = mesh.positions[triangle[(c + 2) % 3]] - p;
+    let next = mesh.positions[triangle[(c + 1) % 3]] - p;
+
+    let previous = project(previous, normal).normalize_or_zero();
+    let next = project(next, normal).normalize_or_zero();
+
+    previous.dot(next).clamp(-1.0, 1.0).acos()
+}
+
+//接線を決められない場合に法線に垂直な適当な向きを使う
+fn fallback_tangent(normal: Vec3) -> Vec3 {
+    let tangent = project(Vec3::X, normal).normalize_or_zero();
+
+    if tangent == Vec3::ZERO {
+        project(Vec3::Y, normal).normalize_or_zero()
+    } else {
+        tangent
+    }
+}
+
+fn same_tangent(a: Vec4, b: Vec4) -> bool {
+    a.w == b.w && a.truncate().dot(b.truncate()) >= SAME_TANGENT_COS
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec2;
+    use super::*;
+
+    //x0から幅1の四角形、UVは位置から決める
+    fn quad(x0: f32, uv: impl Fn(Vec3) -> Vec2) -> MeshDescription {
+        let positions = vec![
+            Vec3::new(x0, 0.0, 0.0),
+            Vec3::new(x0 + 1.0, 0.0, 0.0),
+            Vec3::new(x0 + 1.0, 1.0, 0.0),
+            Vec3::new(x0, 1.0, 0.0),
+        ];
+
+        MeshDescription {
+            tex_coords: positions.iter().map(|position| uv(*position)).collect(),
+            normals: vec![Vec3::Z; 4],
+            positions,
+            indices: vec![0, 1, 2, 0, 2, 3],
+            ..MeshDescription::default()
+        }
+    }
+
+    //面ごとにUVを展開した立方体、uの向きは面ごとのu_axis
+    fn cube() -> (MeshDescription, Vec<Vec3>) {
+        let faces = [
+            (Vec3::new(-1.0, -1.0, 1.0), Vec3::X, Vec3::Y),
+            (Vec3::new(1.0, -1.0, -1.0), -Vec3::X, Vec3::Y),
+            (Vec3::new(1.0, -1.0, 1.0), -Vec3::Z, Vec3::Y),
+            (Vec3::new(-1.0, -1.0, -1.0), Vec3::Z, Vec3::Y),
+            (Vec3::new(-1.0, 1.0, 1.0), Vec3::X, -Vec3::Z),
+            (Vec3::new(-1.0, -1.0, -1.0), Vec3::X, Vec3::Z),
+        ];
+
+        let mut mesh = MeshDescription::default();
+        let mut u_axes = vec![];
+
+        for (origin, u_axis, v_axis) in faces.iter() {
+            let n = mesh.positions.len() as u32;
+
+            for uv in [Vec2::ZERO, Vec2::X, Vec2::ONE, Vec2::Y] {
+                mesh.positions.push(*origin + *u_axis * uv.x * 2.0 + *v_axis * uv.y * 2.0);
+                mesh.normals.push(u_axis.cross(*v_axis));
+                mesh.tex_coords.push(uv);
+                u_axes.push(*u_axis);
+            }
+
+            mesh.indices.extend([n, n + 1, n + 2, n, n + 2, n + 3]);
+        }
+
+        (mesh, u_axes)
+    }
+
+    fn assert_tangent(actual: Vec4, expected: Vec4) {
+        assert!((actual - expected).length() < 1e-5, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn straight_uv_points_along_u() {
+        let mesh = generate_tangents(&quad(0.0, |p| p.truncate())).unwrap();
+
+        assert_eq!(mesh.positions.len(), 4);
+        for tangent in mesh.tangents.iter() {
+            assert_tangent(*tangent, Vec4::new(1.0, 0.0, 0.0, 1.0));
+        }
+    }
+
+    #[test]
+    fn mirrored_u_flips_tangent_and_sign() {
+        let mesh = generate_tangents(&quad(0.0, |p| Vec2::new(1.0 - p.x, p.y))).unwrap();
+
+        for tangent in mesh.tangents.iter() {
+            assert_tangent(*tangent, Vec4::new(-1.0, 0.0, 0.0, -1.0));
+        }
+    }
+
+    #[test]
+    fn rotated_uv_rotates_tangent() {
+        let mesh = generate_tangents(&quad(0.0, |p| Vec2::new(p.y, -p.x))).unwrap();
+
+        for tangent in mesh.tangents.iter() {
+            assert_tangent(*tangent, Vec4::new(0.0, 1.0, 0.0, 1.0));
+        }
+    }
+
+    #[test]
+    fn mirrored_seam_splits_shared_vertices() {
+        //x = 1を境にUVを折り返した2枚の四角形で、境界の2頂点を共有する
+        let mut mesh = quad(0.0, |p| Vec2::new((p.x - 1.0).abs(), p.y));
+        let right = quad(1.0, |p| Vec2::new((p.x - 1.0).abs(), p.y));
+        let n = mesh.positions.len() as u32;
+
+        mesh.positions.extend(&right.positions[1..3]);
+        mesh.normals.extend(&right.normals[1..3]);
+        mesh.tex_coords.extend(&right.tex_coords[1..3]);
+        mesh.indices.extend([1, n, n + 1, 1, n + 1, 2]);
+
+        let processed = generate_tangents(&mesh).unwrap();
+
+        //境界の2頂点が左右で分かれる
+        assert_eq!(processed.positions.len(), 8);
+
+        for (t, triangle) in processed.indices.chunks_exact(3).enumerate() {
+            let expected = if t < 2 {
+                Vec4::new(-1.0, 0.0, 0.0, -1.0)
+            } else {
+                Vec4::new(1.0, 0.0, 0.0, 1.0)
+            };
+
+            for index in triangle.iter() {
+                assert_tangent(processed.tangents[*index as usize], expected);
+            }
+        }
+    }
+
+    #[test]
+    fn cube_faces_follow_their_uv_seams() {
+        let (mesh, u_axes) = cube();
+        let processed = generate_tangents(&mesh).unwrap();
+
+        //面ごとに頂点が分かれているので増えない
+        assert_eq!(processed.positions.len(), 24);
+
+        for (tangent, u_axis) in processed.tangents.iter().zip(u_axes.iter()) {
+            assert_tangent(*tangent, u_axis.extend(1.0));
+        }
+    }
+
+    #[test]
+    fn tangents_are_orthogonal_to_tilted_normals() {
+        let mut mesh = quad(0.0, |p| Vec2::new(p.x * 2.0 + p.y, p.y));
+        mesh.normals = mesh.positions.iter().map(|p| (Vec3::Z + *p * 0.3).normalize()).collect();
+
+        let processed = generate_tangents(&mesh).unwrap();
+
+        for (tangent, normal) in processed.tangents.iter().zip(processed.normals.iter()) {
+            assert!(tangent.truncate().dot(*normal).abs() < 1e-5);
+            assert!((tangent.truncate().length() - 1.0).abs() < 1e-5);
+            assert_eq!(tangent.w, 1.0);
+        }
+    }
+
+    #[test]
+    fn degenerate_uv_borrows_neighbouring_tangent() {
+        //2つ目の三角形はUVの面積が0
+        let mut mesh = quad(0.0, |p| p.truncate());
+        mesh.tex_coords[3] = mesh.tex_coords[0];
+
+        let processed = generate_tangents(&mesh).unwrap();
+
+        for tangent in processed.tangents.iter() {
+            assert_tangent(*tangent, Vec4::new(1.0, 0.0, 0.0, 1.0));
+        }
+    }
+
+    //bevy_mikktspace 0.10.1 (MikkTSpaceのCの実装の移植)で三角形の角ごとに生成した値
+    const GRID_REFERENCE: [[f32; 4]; 24] = [
+        [0.937577, -0.075515, -0.339479, 1.0],
+        [0.984077, -0.099013, 0.147612, 1.0],
+        [0.995037, -0.099504, 0.000000, 1.0],
+        [0.937577, -0.075515, -0.339479, 1.0],
+        [0.995037, -0.099504, 0.000000, 1.0],
+        [0.887699, -0.072358, -0.454703, 1.0],
+        [0.984077, -0.099013, 0.147612, 1.0],
+        [0.831635, -0.069384, 0.550970, 1.0],
+        [0.887699, -0.072358, 0.454703, 1.0],
+        [0.984077, -0.099013, 0.147612, 1.0],
+        [0.887699, -0.072358, 0.454703, 1.0],
+        [0.995037, -0.099504, 0.000000, 1.0],
+        [0.887699, -0.072358, -0.454703, 1.0],
+        [0.995037, -0.099504, 0.000000, 1.0],
+        [0.984077, -0.099013, -0.147612, 1.0],
+        [0.887699, -0.072358, -0.454703, 1.0],
+        [0.984077, -0.099013, -0.147612, 1.0],
+        [0.831635, -0.069384, -0.550970, 1.0],
+        [0.995037, -0.099504, 0.000000, 1.0],
+        [0.887699, -0.072358, 0.454703, 1.0],
+        [0.937577, -0.075515, 0.339479, 1.0],
+        [0.995037, -0.099504, 0.000000, 1.0],
+        [0.937577, -0.075515, 0.339479, 1.0],
+        [0.984077, -0.099013, -0.147612, 1.0],
+    ];
+
+    const MIRRORED_REFERENCE: [[f32; 4]; 12] = [
+        [-0.980581, 0.000000, -0.196116, -1.0],
+        [-1.000000, 0.000000, 0.000000, -1.0],
+        [-0.999952, -0.009741, -0.000974, -1.0],
+        [-0.980581, 0.000000, -0.196116, -1.0],
+        [-0.999952, -0.009741, -0.000974, -1.0],
+        [-0.980581, 0.000000, -0.196116, -1.0],
+        [0.999951, 0.009806, 0.000981, 1.0],
+        [0.981307, 0.037743, -0.188713, 1.0],
+        [0.981096, 0.058061, -0.184607, 1.0],
+        [0.999951, 0.009806, 0.000981, 1.0],
+        [0.981096, 0.058061, -0.184607, 1.0],
+        [0.999802, 0.019798, 0.001980, 1.0],
+    ];
+
+    //columns x rowsの頂点を並べ、位置、法線、UVはx, yから決める
+    fn curved_grid(
+        columns: u32,
+        rows: u32,
+        vertex: impl Fn(f32, f32) -> (Vec3, Vec3, Vec2),
+    ) -> MeshDescription {
+        let mut mesh = MeshDescription::default();
+
+        for y in 0..rows {
+            for x in 0..columns {
+                let (position, normal, tex_coord) = vertex(x as f32, y as f32);
+
+                mesh.positions.push(position);
+                mesh.normals.push(normal.normalize());
+                mesh.tex_coords.push(tex_coord);
+            }
+        }
+
+        for y in 0..rows - 1 {
+            for x in 0..columns - 1 {
+                let i = y * columns + x;
+                mesh.indices.extend([i, i + 1, i + 1 + columns, i, i + 1 + columns, i + columns]);
+            }
+        }
+
+        mesh
+    }
+
+    fn assert_matches_reference(mesh: &MeshDescription, reference: &[[f32; 4]]) {
+        let processed = generate_tangents(mesh).unwrap();
+
+        assert_eq!(processed.indices.len(), reference.len());
+        for (index, expected) in processed.indices.iter().zip(reference.iter()) {
+            let actual = processed.tangents[*index as usize];
+            assert!((actual - Vec4::from(*expected)).length() < 1e-4, "{} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn curved_grid_matches_mikktspace() {
+        let mesh = curved_grid(3, 3, |x, y| {
+            let (x, y) = (x - 1.0, y - 1.0);
+
+            (
+                Vec3::new(x, y, 0.25 * x * x - 0.15 * x * y),
+                Vec3::new(-0.5 * x + 0.15 * y, 0.15 * x, 1.0),
+                Vec2::new(0.5 * x + 0.1 * y * y, 0.5 * y + 0.05 * x),
+            )
+        });
+
+        assert_matches_reference(&mesh, &GRID_REFERENCE);
+    }
+
+    #[test]
+    fn mirrored_quads_match_mikktspace() {
+        //x = 1でuを折り返し、境界の頂点は左右で共有する
+        let mesh = curved_grid(3, 2, |x, y| {
+            (
+                Vec3::new(x, y, 0.2 * y * (x - 1.0)),
+                Vec3::new(0.2 * (x - 1.0), -0.1 * x, 1.0),
+                Vec2::new((x - 1.0).abs() * 0.8 + 0.1 * y, y),
+            )
+        });
+
+        assert_matches_reference(&mesh, &MIRRORED_REFERENCE);
+    }
+
+    #[test]
+    fn mesh_without_uv_is_rejected() {
+        assert!(generate_tangents(&MeshDescription::triangle([Vec3::ZERO, Vec3::X, Vec3::Y])).is_err());
+    }
+}
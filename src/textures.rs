@@ -0,0 +1,393 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use glam::{Vec2, Vec4};
+use crate::error::{CottonError, Result};
+
+mod hdr;
+
+/// 8bitや16bitの画像の色の扱い、HDRは常にリニア
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ColorSpace {
+    /// albedoなど、読み込み時にリニアに変換する
+    Srgb,
+    /// 法線マップなど、0..1にするだけ
+    Linear,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TextureFilter {
+    Nearest,
+    Linear,
+}
+
+/// 0..1の外側のUVの扱い、Vulkanのaddress modeと同じ
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum AddressMode {
+    Repeat,
+    MirroredRepeat,
+    ClampToEdge,
+}
+
+/// VkSamplerの設定のうち使うもの、mipmapは使わない
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SamplerDescription {
+    pub filter: TextureFilter,
+    pub address_mode: AddressMode,
+}
+
+//formatがlinear filterに対応していないデバイスではNearestで作られる
+impl Default for SamplerDescription {
+    fn default() -> Self {
+        Self {
+            filter: TextureFilter::Linear,
+            address_mode: AddressMode::Repeat,
+        }
+    }
+}
+
+/// リニアなRGBAで持つ、GPUにも同じ値をR32G32B32A32_SFLOATで渡す
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextureData {
+    pub width: u32,
+    pub height: u32,
+    /// 上の行から順に並べる、UVの(0, 0)が先頭になる
+    pub pixels: Vec<[f32; 4]>,
+}
+
+impl TextureData {
+    pub fn new(width: u32, height: u32, pixels: Vec<[f32; 4]>) -> Result<Self> {
+        if width == 0 || height == 0 {
+            return Err(CottonError::TextureDecode(format!("texture is {}x{}", width, height)));
+        }
+
+        if pixels.len() != width as usize * height as usize {
+            return Err(CottonError::TextureDecode(format!(
+                "expected {} pixels for {}x{} texture, but got {}",
+                width as usize * height as usize,
+                width,
+                height,
+                pixels.len(),
+            )));
+        }
+
+        Ok(Self {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    /// 1x1の単色
+    pub fn solid(color: [f32; 4]) -> Self {
+        Self {
+            width: 1,
+            height: 1,
+            pixels: vec![color],
+        }
+    }
+
+    /// 拡張子で形式を決める
+    pub fn load<P: AsRef<Path>>(path: P, color_space: ColorSpace) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)?;
+
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+
+        match extension.as_deref() {
+            Some("png") => Self::from_png(&bytes, color_space),
+            Some("jpg") | Some("jpeg") => Self::from_jpeg(&bytes, color_space),
+            Some("hdr") => Self::from_hdr(&bytes),
+            _ => Err(CottonError::TextureDecode(format!(
+                "unsupported texture format: {}",
+                path.display(),
+            ))),
+        }
+    }
+
+    pub fn from_png(bytes: &[u8], color_space: ColorSpace) -> Result<Self> {
+        let mut decoder = png::Decoder::new(bytes);
+        //パレットと8bit未満のグレースケールを展開し、tRNSをalphaにする
+        decoder.set_transformations(png::Transformations::EXPAND);
+
+        let mut reader = decoder.read_info().map_err(decode_error)?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer).map_err(decode_error)?;
+        let buffer = &buffer[..info.buffer_size()];
+
+        let values: Vec<f32> = match info.bit_depth {
+            png::BitDepth::Sixteen => buffer
+                .chunks_exact(2)
+                .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]) as f32 / u16::MAX as f32)
+                .collect(),
+            _ => buffer.iter().map(|value| *value as f32 / u8::MAX as f32).collect(),
+        };
+
+        Self::from_normalized(info.width, info.height, info.color_type.samples(), &values, color_space)
+    }
+
+    pub fn from_jpeg(bytes: &[u8], color_space: ColorSpace) -> Result<Self> {
+        let mut decoder = jpeg_decoder::Decoder::new(bytes);
+        let data = decoder.decode().map_err(decode_error)?;
+        let info = decoder
+            .info()
+            .ok_or_else(|| CottonError::TextureDecode("jpeg has no frame header".to_owned()))?;
+
+        let (channels, values): (usize, Vec<f32>) = match info.pixel_format {
+            jpeg_decoder::PixelFormat::L8 => (1, data.iter().map(|value| *value as f32 / u8::MAX as f32).collect()),
+            jpeg_decoder::PixelFormat::L16 => (
+                1,
+                data.chunks_exact(2)
+                    .map(|bytes| u16::from_ne_bytes([bytes[0], bytes[1]]) as f32 / u16::MAX as f32)
+                    .collect(),
+            ),
+            jpeg_decoder::PixelFormat::RGB24 => (3, data.iter().map(|value| *value as f32 / u8::MAX as f32).collect()),
+            jpeg_decoder::PixelFormat::CMYK32 => {
+                return Err(CottonError::TextureDecode("CMYK jpeg is not supported".to_owned()));
+            }
+        };
+
+        Self::from_normalized(info.width as u32, info.height as u32, channels, &values, color_space)
+    }
+
+    /// Radiance RGBE
+    pub fn from_hdr(bytes: &[u8]) -> Result<Self> {
+        hdr::decode(bytes)
+    }
+
+    //チャンネルごとに0..1にした値から作る、alphaはsRGBでも変換しない
+    fn from_normalized(width: u32, height: u32, channels: usize, values: &[f32], color_space: ColorSpace) -> Result<Self> {
+        let color = |value: f32| match color_space {
+            ColorSpace::Srgb => srgb_to_linear(value),
+            ColorSpace::Linear => value,
+        };
+
+        let pixels = values
+            .chunks_exact(channels)
+            .map(|texel| match texel {
+                [l] => [color(*l), color(*l), color(*l), 1.0],
+                [l, a] => [color(*l), color(*l), color(*l), *a],
+                [r, g, b] => [color(*r), color(*g), color(*b), 1.0],
+                [r, g, b, a, ..] => [color(*r), color(*g), color(*b), *a],
+                [] => [0.0; 4],
+            })
+            .collect();
+
+        Self::new(width, height, pixels)
+    }
+
+    pub fn texel(&self, x: u32, y: u32) -> Vec4 {
+        Vec4::from(self.pixels[y as usize * self.width as usize + x as usize])
+    }
+
+    /// Vulkanのサンプラーと同じ規則でフィルタする
+    /// Linearの場合はテクセルの中心を基準に4つを補間し、それぞれにaddress modeを適用する
+    pub fn sample(&self, sampler: &SamplerDescription, uv: Vec2) -> Vec4 {
+        let u = uv.x * self.width as f32;
+        let v = uv.y * self.height as f32;
+        let mode = sampler.address_mode;
+
+        match sampler.filter {
+            TextureFilter::Nearest => self.texel(
+                wrap(u.floor() as i64, self.width, mode),
+                wrap(v.floor() as i64, self.height, mode),
+            ),
+            TextureFilter::Linear => {
+                let u = u - 0.5;
+                let v = v - 0.5;
+                let alpha = u - u.floor();
+                let beta = v - v.floor();
+
+                let [x0, x1] = [u.floor() as i64, u.floor() as i64 + 1].map(|x| wrap(x, self.width, mode));
+                let [y0, y1] = [v.floor() as i64, v.floor() as i64 + 1].map(|y| wrap(y, self.height, mode));
+
+                self.texel(x0, y0) * (1.0 - alpha) * (1.0 - beta)
+                    + self.texel(x1, y0) * alpha * (1.0 - beta)
+                    + self.texel(x0, y1) * (1.0 - alpha) * beta
+                    + self.texel(x1, y1) * alpha * beta
+            }
+        }
+    }
+}
+
+/// 整数のテクセル座標に対するVulkanのwrapping
+pub fn wrap(coordinate: i64, size: u32, mode: AddressMode) -> u32 {
+    let size = size as i64;
+
+    let wrapped = match mode {
+        AddressMode::Repeat => coordinate.rem_euclid(size),
+        AddressMode::MirroredRepeat => {
+            let mirrored = coordinate.rem_euclid(2 * size) - size;
+            let mirrored = if mirrored >= 0 { mirrored } else { -(1 + mirrored) };
+
+            (size - 1) - mirrored
+        }
+        AddressMode::ClampToEdge => coordinate.clamp(0, size - 1),
+    };
+
+    wrapped as u32
+}
+
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn decode_error(err: impl fmt::Display) -> CottonError {
+    CottonError::TextureDecode(err.to_string())
+}
+
+/// マテリアルから参照するテクスチャ、画像はアニメーションのフレーム間で共有する
+#[derive(Clone, Debug)]
+pub struct Texture {
+    pub image: Arc<TextureData>,
+    pub sampler: SamplerDescription,
+}
+
+impl Texture {
+    pub fn new(image: TextureData) -> Self {
+        Self {
+            image: Arc::new(image),
+            sampler: SamplerDescription::default(),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P, color_space: ColorSpace) -> Result<Self> {
+        Ok(Self::new(TextureData::load(path, color_space)?))
+    }
+
+    pub fn sampler(mut self, sampler: SamplerDescription) -> Self {
+        self.sampler = sampler;
+        self
+    }
+
+    pub fn sample(&self, uv: Vec2) -> Vec4 {
+        self.image.sample(&self.sampler, uv)
+    }
+}
+
+//同じ画像を共有している場合は画素を比べない
+impl PartialEq for Texture {
+    fn eq(&self, other: &Self) -> bool {
+        self.sampler == other.sampler
+            && (Arc::ptr_eq(&self.image, &other.image) || self.image == other.image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn black_white() -> TextureData {
+        TextureData::new(2, 1, vec![[0.0, 0.0, 0.0, 1.0], [1.0, 1.0, 1.0, 1.0]]).unwrap()
+    }
+
+    fn sampler(filter: TextureFilter, address_mode: AddressMode) -> SamplerDescription {
+        SamplerDescription { filter, address_mode }
+    }
+
+    #[test]
+    fn linear_sampling_hits_texel_centres() {
+        let texture = black_white();
+        let sampler = SamplerDescription::default();
+
+        assert_eq!(texture.sample(&sampler, Vec2::new(0.25, 0.5)), Vec4::new(0.0, 0.0, 0.0, 1.0));
+        assert_eq!(texture.sample(&sampler, Vec2::new(0.75, 0.5)), Vec4::ONE);
+        assert_eq!(texture.sample(&sampler, Vec2::new(0.5, 0.5)), Vec4::new(0.5, 0.5, 0.5, 1.0));
+    }
+
+    #[test]
+    fn address_modes_at_the_edges() {
+        let texture = black_white();
+
+        //端では反対側のテクセルと混ざる
+        let repeat = texture.sample(&sampler(TextureFilter::Linear, AddressMode::Repeat), Vec2::new(0.0, 0.5));
+        assert_eq!(repeat, Vec4::new(0.5, 0.5, 0.5, 1.0));
+
+        let clamp = texture.sample(&sampler(TextureFilter::Linear, AddressMode::ClampToEdge), Vec2::new(0.0, 0.5));
+        assert_eq!(clamp, Vec4::new(0.0, 0.0, 0.0, 1.0));
+
+        let nearest = sampler(TextureFilter::Nearest, AddressMode::Repeat);
+        assert_eq!(texture.sample(&nearest, Vec2::new(1.25, 0.5)), Vec4::new(0.0, 0.0, 0.0, 1.0));
+        assert_eq!(texture.sample(&nearest, Vec2::new(-0.25, 0.5)), Vec4::ONE);
+
+        let mirrored = sampler(TextureFilter::Nearest, AddressMode::MirroredRepeat);
+        assert_eq!(texture.sample(&mirrored, Vec2::new(1.25, 0.5)), Vec4::ONE);
+        assert_eq!(texture.sample(&mirrored, Vec2::new(-0.25, 0.5)), Vec4::new(0.0, 0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn wrap_matches_vulkan() {
+        let wrapped = |mode| [-3, -2, -1, 0, 1, 2, 3, 4].map(|x| wrap(x, 2, mode));
+
+        assert_eq!(wrapped(AddressMode::Repeat), [1, 0, 1, 0, 1, 0, 1, 0]);
+        assert_eq!(wrapped(AddressMode::MirroredRepeat), [1, 1, 0, 0, 1, 1, 0, 0]);
+        assert_eq!(wrapped(AddressMode::ClampToEdge), [0, 0, 0, 0, 1, 1, 1, 1]);
+        assert_eq!(wrap(-7, 1, AddressMode::MirroredRepeat), 0);
+    }
+
+    fn encode_png(width: u32, height: u32, data: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut encoder = png::Encoder::new(&mut bytes, width, height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(data).unwrap();
+        writer.finish().unwrap();
+
+        bytes
+    }
+
+    #[test]
+    fn png_linear_and_srgb() {
+        let bytes = encode_png(2, 1, &[255, 0, 128, 0, 255, 0]);
+
+        let linear = TextureData::from_png(&bytes, ColorSpace::Linear).unwrap();
+        assert_eq!((linear.width, linear.height), (2, 1));
+        assert_eq!(linear.pixels, vec![[1.0, 0.0, 128.0 / 255.0, 1.0], [0.0, 1.0, 0.0, 1.0]]);
+
+        let srgb = TextureData::from_png(&bytes, ColorSpace::Srgb).unwrap();
+        assert_eq!(srgb.pixels[0][2], srgb_to_linear(128.0 / 255.0));
+        assert_eq!(srgb.pixels[1], [0.0, 1.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn hdr_flat_scanline() {
+        let mut bytes = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 2\n".to_vec();
+        bytes.extend([128, 64, 0, 129, 0, 0, 0, 0]);
+
+        let texture = TextureData::from_hdr(&bytes).unwrap();
+        assert_eq!(texture.pixels, vec![[1.0, 0.5, 0.0, 1.0], [0.0, 0.0, 0.0, 1.0]]);
+    }
+
+    #[test]
+    fn hdr_run_length_scanline() {
+        let mut bytes = b"#?RADIANCE\n\n-Y 1 +X 8\n".to_vec();
+        bytes.extend([2, 2, 0, 8]);
+        //チャンネルごとに8回の繰り返し
+        for channel in [128, 0, 0, 129] {
+            bytes.extend([128 + 8, channel]);
+        }
+
+        let texture = TextureData::from_hdr(&bytes).unwrap();
+        assert_eq!(texture.pixels, vec![[1.0, 0.0, 0.0, 1.0]; 8]);
+
+        bytes.pop();
+        assert!(TextureData::from_hdr(&bytes).is_err());
+    }
+
+    #[test]
+    fn hdr_rejects_bad_dimensions_before_allocating() {
+        for resolution in ["-Y 1 +X 100000", "-Y 4000000000 +X 4000000000", "-Y 0 +X 2", "+Y 1 +X 2"] {
+            let bytes = format!("#?RADIANCE\n\n{}\n", resolution).into_bytes();
+            assert!(matches!(TextureData::from_hdr(&bytes), Err(CottonError::TextureDecode(_))));
+        }
+    }
+}
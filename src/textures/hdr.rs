@@ -0,0 +1,175 @@
+use crate::error::{CottonError, Result};
+use crate::textures::TextureData;
+
+//新しいRLEが使える幅
+const RLE_WIDTH_RANGE: std::ops::Range<usize> = 8..0x8000;
+
+//壊れたヘッダーで巨大な確保をしないための上限
+const MAX_DIMENSION: u32 = 16384;
+
+/// Radiance RGBE(.hdr)を読む、向きは-Y +Xのみ対応する
+pub(super) fn decode(bytes: &[u8]) -> Result<TextureData> {
+    let mut position = 0;
+
+    let magic = read_line(bytes, &mut position)?;
+    if !magic.starts_with("#?") {
+        return Err(CottonError::TextureDecode("hdr has no radiance header".to_owned()));
+    }
+
+    //空行までがヘッダー
+    loop {
+        let line = read_line(bytes, &mut position)?;
+
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some(format) = line.strip_prefix("FORMAT=") {
+            if format.trim() != "32-bit_rle_rgbe" {
+                return Err(CottonError::TextureDecode(format!("unsupported hdr format: {}", format)));
+            }
+        }
+    }
+
+    let resolution = read_line(bytes, &mut position)?;
+    let (width, height) = match resolution.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["-Y", height, "+X", width] => (
+            width.parse::<u32>().map_err(|_| invalid_resolution(&resolution))?,
+            height.parse::<u32>().map_err(|_| invalid_resolution(&resolution))?,
+        ),
+        _ => return Err(invalid_resolution(&resolution)),
+    };
+
+    if width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(CottonError::TextureDecode(format!(
+            "hdr is {}x{}, must be between 1 and {}",
+            width, height, MAX_DIMENSION,
+        )));
+    }
+
+    let mut scanline = vec![[0u8; 4]; width as usize];
+    let mut pixels = Vec::with_capacity(width as usize * height as usize);
+
+    for _ in 0..height {
+        read_scanline(bytes, &mut position, &mut scanline)?;
+        pixels.extend(scanline.iter().map(|rgbe| rgbe_to_linear(*rgbe)));
+    }
+
+    TextureData::new(width, height, pixels)
+}
+
+fn read_line(bytes: &[u8], position: &mut usize) -> Result<String> {
+    let rest = bytes.get(*position..).unwrap_or_default();
+    let length = rest
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .ok_or_else(truncated)?;
+
+    *position += length + 1;
+
+    Ok(String::from_utf8_lossy(&rest[..length]).into_owned())
+}
+
+fn read_bytes<'a>(bytes: &'a [u8], position: &mut usize, count: usize) -> Result<&'a [u8]> {
+    let read = bytes.get(*position..*position + count).ok_or_else(truncated)?;
+    *position += count;
+
+    Ok(read)
+}
+
+fn read_scanline(bytes: &[u8], position: &mut usize, scanline: &mut [[u8; 4]]) -> Result<()> {
+    let width = scanline.len();
+    let header = bytes.get(*position..*position + 4).ok_or_else(truncated)?;
+
+    //新しいRLEは2, 2の後に幅が入り、チャンネルごとに並ぶ
+    if RLE_WIDTH_RANGE.contains(&width) && header[0] == 2 && header[1] == 2 && header[2] & 0x80 == 0 {
+        if ((header[2] as usize) << 8 | header[3] as usize) != width {
+            return Err(CottonError::TextureDecode("hdr scanline width mismatch".to_owned()));
+        }
+
+        *position += 4;
+
+        for channel in 0..4 {
+            let mut x = 0;
+
+            while x < width {
+                let count = read_bytes(bytes, position, 1)?[0] as usize;
+
+                if count > 128 {
+                    let run = count - 128;
+                    let value = read_bytes(bytes, position, 1)?[0];
+
+                    if x + run > width {
+                        return Err(overrun());
+                    }
+
+                    scanline[x..x + run].iter_mut().for_each(|rgbe| rgbe[channel] = value);
+                    x += run;
+                } else {
+                    if count == 0 || x + count > width {
+                        return Err(overrun());
+                    }
+
+                    let values = read_bytes(bytes, position, count)?;
+
+                    for (rgbe, value) in scanline[x..x + count].iter_mut().zip(values) {
+                        rgbe[channel] = *value;
+                    }
+
+                    x += count;
+                }
+            }
+        }
+
+        return Ok(());
+    }
+
+    //RLEなしか古いRLE、1, 1, 1の後の値だけ前の画素を繰り返す
+    let mut x = 0;
+    let mut shift = 0u32;
+
+    while x < width {
+        let rgbe = read_bytes(bytes, position, 4)?;
+
+        if rgbe[0] == 1 && rgbe[1] == 1 && rgbe[2] == 1 {
+            let count = (rgbe[3] as usize).checked_shl(shift).ok_or_else(overrun)?;
+
+            if x == 0 || x + count > width {
+                return Err(overrun());
+            }
+
+            let previous = scanline[x - 1];
+            scanline[x..x + count].iter_mut().for_each(|rgbe| *rgbe = previous);
+            x += count;
+            shift += 8;
+        } else {
+            scanline[x] = [rgbe[0], rgbe[1], rgbe[2], rgbe[3]];
+            x += 1;
+            shift = 0;
+        }
+    }
+
+    Ok(())
+}
+
+fn rgbe_to_linear([r, g, b, e]: [u8; 4]) -> [f32; 4] {
+    if e == 0 {
+        return [0.0, 0.0, 0.0, 1.0];
+    }
+
+    let scale = 2f32.powi(e as i32 - (128 + 8));
+
+    [r as f32 * scale, g as f32 * scale, b as f32 * scale, 1.0]
+}
+
+fn invalid_resolution(line: &str) -> CottonError {
+    CottonError::TextureDecode(format!("unsupported hdr resolution: {}", line))
+}
+
+fn truncated() -> CottonError {
+    CottonError::TextureDecode("hdr is truncated".to_owned())
+}
+
+fn overrun() -> CottonError {
+    CottonError::TextureDecode("hdr scanline overruns its width".to_owned())
+}
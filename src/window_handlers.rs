@@ -2,6 +2,7 @@ use winit::dpi::Size;
 use winit::event_loop::EventLoop;
 use winit::window::{Window, WindowBuilder};
 use crate::constants;
+use crate::error::Result;
 
 pub struct WindowHandlers {
     pub event_loop: EventLoop<()>,
@@ -9,19 +10,18 @@ pub struct WindowHandlers {
 }
 
 impl WindowHandlers {
-    pub fn new<S: Into<Size>>(window_size: S) -> Self {
+    pub fn new<S: Into<Size>>(window_size: S) -> Result<Self> {
         let event_loop = winit::event_loop::EventLoop::new();
 
         let window = WindowBuilder::new()
             .with_title("cotton")
             .with_inner_size(window_size)
             .with_resizable(true)
-            .build(&event_loop)
-            .unwrap();
+            .build(&event_loop)?;
 
-        Self {
+        Ok(Self {
             event_loop,
             window,
-        }
+        })
     }
 }
\ No newline at end of file